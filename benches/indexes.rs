@@ -0,0 +1,62 @@
+//! Queries over 100k documents in memory, with and without indexes.
+
+use std::collections::HashMap;
+
+use criterion::{criterion_group, criterion_main, Criterion};
+use serde_json::{json, Value};
+use store::{
+    index::Index,
+    query::{Query, QueryLimit},
+    store::{Persistence, TestPersistence},
+};
+
+fn queries() -> Vec<(&'static str, Query)> {
+    let mut top = Query::builder().order_by_desc("score").build();
+    top.limit = Some(QueryLimit {
+        limit: Some(10),
+        offset: None,
+    });
+    vec![
+        ("eq", Query::builder().eq("group", json!(42)).build()),
+        (
+            "in",
+            Query::builder()
+                .is_in("group", vec![json!(1), json!(2), json!(3)])
+                .build(),
+        ),
+        (
+            "range",
+            Query::builder()
+                .gte("score", json!(1000))
+                .lt("score", json!(1100))
+                .build(),
+        ),
+        ("top 10", top),
+    ]
+}
+
+fn bench_indexes(c: &mut Criterion) {
+    let docs: Vec<Value> = (0..100_000)
+        .map(|n| json!({"id": n, "group": n % 100, "score": (n * 7919) % 100_000}))
+        .collect();
+    let records = HashMap::from([("bench".to_string(), docs)]);
+    let scanned = TestPersistence::new(records.clone());
+    let indexed = TestPersistence::new(records);
+    indexed.create_index("bench", Index::hash("group"));
+    indexed.create_index("bench", Index::ordered("score"));
+
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    for (name, query) in queries() {
+        let mut group = c.benchmark_group(name);
+        for (label, persistence) in [("scan", &scanned), ("indexed", &indexed)] {
+            group.bench_function(label, |b| {
+                b.to_async(&runtime)
+                    .iter(|| persistence.find("bench", Some(query.clone())))
+            });
+        }
+        group.finish();
+    }
+}
+
+criterion_group!(benches, bench_indexes);
+criterion_main!(benches);
@@ -0,0 +1,485 @@
+use std::cmp::Ordering;
+
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Number, Value};
+
+use crate::error::{Result, StoreError};
+use crate::query::{
+    compare_sort_values, items_match, json_eq, resolve_path, sort_rank, Query, QueryFilterItem,
+    QueryLimit, QuerySortDirection, QuerySortItem, ANY_ELEMENT,
+};
+
+/// A reporting query: the documents matching `match` are grouped by the
+/// values of the `group` fields, and each group becomes one row holding its
+/// keys and the results of the accumulators, under their names. The rows are
+/// then sorted and limited like documents are by a [`Query`].
+///
+/// Without `group` fields all matching documents form a single group, which
+/// yields one row even when nothing matches, as SQL aggregates do.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct Aggregation {
+    #[serde(rename = "match")]
+    pub filter: Option<Vec<QueryFilterItem>>,
+    #[serde(default)]
+    pub group: Vec<GroupKey>,
+    #[serde(default)]
+    pub accumulators: Vec<Accumulator>,
+    pub sort: Option<Vec<QuerySortItem>>,
+    pub limit: Option<QueryLimit>,
+}
+
+/// A field to group by, and the name its value gets in the rows. A missing
+/// field groups as `null`, and numbers equal in value group together.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct GroupKey {
+    pub name: String,
+    pub field: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Accumulator {
+    pub name: String,
+    pub operator: AccumulatorOperator,
+    /// The field accumulated, which every operator but `count` needs.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub field: Option<String>,
+}
+
+/// How the values of a field are accumulated over a group. `sum` and `avg`
+/// only take numbers, and are `null` for a group without any. `min` and `max`
+/// take any value but `null`, in sort order. `count` counts documents.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub enum AccumulatorOperator {
+    Sum,
+    Avg,
+    Min,
+    Max,
+    Count,
+}
+
+impl Aggregation {
+    pub fn builder() -> AggregationBuilder {
+        AggregationBuilder::default()
+    }
+
+    /// Rows are objects keyed by the group and accumulator names, so those
+    /// must be distinct top level keys, and fields must reach a single value.
+    pub fn check(&self) -> Result<()> {
+        if self.group.is_empty() && self.accumulators.is_empty() {
+            return Err(StoreError::InvalidQuery(
+                "an aggregation needs a group key or an accumulator".to_string(),
+            ));
+        }
+
+        let mut names: Vec<&str> = vec![];
+        let keys = self.group.iter().map(|key| (&key.name, Some(&key.field)));
+        let accumulators = self
+            .accumulators
+            .iter()
+            .map(|accumulator| (&accumulator.name, accumulator.field.as_ref()));
+        for (name, field) in keys.chain(accumulators) {
+            if name.is_empty() || name.contains('.') {
+                return Err(StoreError::InvalidQuery(format!(
+                    "invalid aggregation name: {:?}",
+                    name
+                )));
+            }
+            if names.contains(&name.as_str()) {
+                return Err(StoreError::InvalidQuery(format!(
+                    "aggregation name {:?} is used twice",
+                    name
+                )));
+            }
+            names.push(name);
+
+            if let Some(field) = field {
+                if field.split('.').any(|segment| segment == ANY_ELEMENT) {
+                    return Err(StoreError::InvalidQuery(format!(
+                        "can't aggregate '{}': the field can't address every array element",
+                        field
+                    )));
+                }
+            }
+        }
+
+        for accumulator in self.accumulators.iter() {
+            let counts = accumulator.operator == AccumulatorOperator::Count;
+            if !counts && accumulator.field.is_none() {
+                return Err(StoreError::InvalidQuery(format!(
+                    "accumulator {:?} needs a field",
+                    accumulator.name
+                )));
+            }
+        }
+        self.query().check_sort()
+    }
+
+    /// The query that sorts and limits the rows.
+    pub fn query(&self) -> Query {
+        Query {
+            filter: self.filter.clone(),
+            sort: self.sort.clone(),
+            limit: self.limit.clone(),
+            after: None,
+            projection: None,
+        }
+    }
+
+    /// Runs the aggregation over documents in memory, the way
+    /// `sql::aggregate_to_sql` does in SQL.
+    pub fn apply<'a>(&self, documents: impl IntoIterator<Item = &'a Value>) -> Result<Vec<Value>> {
+        self.check()?;
+
+        let mut groups: Vec<(Vec<Value>, Vec<&Value>)> = vec![];
+        if self.group.is_empty() {
+            groups.push((vec![], vec![]));
+        }
+        for document in documents {
+            if let Some(filter) = &self.filter {
+                if !items_match(filter, document)? {
+                    continue;
+                }
+            }
+
+            let keys: Vec<Value> = self
+                .group
+                .iter()
+                .map(|key| field_value(document, &key.field).cloned())
+                .map(|value| value.unwrap_or(Value::Null))
+                .collect();
+            let group = groups
+                .iter_mut()
+                .find(|(group, _)| group.iter().zip(keys.iter()).all(|(a, b)| json_eq(a, b)));
+            match group {
+                Some((_, documents)) => documents.push(document),
+                None => groups.push((keys, vec![document])),
+            }
+        }
+
+        let mut rows = vec![];
+        for (keys, documents) in groups {
+            let mut row = Map::new();
+            for (key, value) in self.group.iter().zip(keys) {
+                row.insert(key.name.clone(), value);
+            }
+            for accumulator in self.accumulators.iter() {
+                row.insert(accumulator.name.clone(), accumulator.apply(&documents));
+            }
+            rows.push(Value::Object(row));
+        }
+
+        let query = self.query();
+        query.sort(&mut rows)?;
+        if let Some(limit) = &query.limit {
+            let offset = limit.offset.unwrap_or(0) as usize;
+            let limit = limit.limit.map_or(usize::MAX, |limit| limit as usize);
+            rows = rows.into_iter().skip(offset).take(limit).collect();
+        }
+        Ok(rows)
+    }
+}
+
+fn field_value<'a>(document: &'a Value, field: &str) -> Option<&'a Value> {
+    resolve_path(document, field).first().copied()
+}
+
+fn number(value: &Value) -> Option<&Number> {
+    match value {
+        Value::Number(number) => Some(number),
+        _ => None,
+    }
+}
+
+impl Accumulator {
+    fn apply(&self, documents: &[&Value]) -> Value {
+        let values = documents.iter().filter_map(|document| {
+            let field = self.field.as_deref()?;
+            field_value(document, field).filter(|value| sort_rank(Some(value)) > 0)
+        });
+
+        match self.operator {
+            AccumulatorOperator::Count => documents.len().into(),
+            AccumulatorOperator::Sum => sum(values.filter_map(number)).0,
+            AccumulatorOperator::Avg => {
+                let numbers: Vec<&Number> = values.filter_map(number).collect();
+                let (sum, count) = sum(numbers.into_iter());
+                match sum.as_f64() {
+                    Some(sum) if count > 0 => (sum / count as f64).into(),
+                    _ => Value::Null,
+                }
+            }
+            AccumulatorOperator::Min => extreme(values, Ordering::Less),
+            AccumulatorOperator::Max => extreme(values, Ordering::Greater),
+        }
+    }
+}
+
+/// Sums numbers, staying an integer while every number is one and the sum
+/// fits, like SQL `sum` does. Returns `null` for no numbers, and the count.
+fn sum<'a>(numbers: impl Iterator<Item = &'a Number>) -> (Value, usize) {
+    let mut integer: Option<i64> = Some(0);
+    let mut float = 0.0;
+    let mut count = 0;
+    for number in numbers {
+        integer = integer
+            .zip(number.as_i64())
+            .and_then(|(sum, number)| sum.checked_add(number));
+        float += number.as_f64().unwrap_or(0.0);
+        count += 1;
+    }
+
+    let sum = match (count, integer) {
+        (0, _) => Value::Null,
+        (_, Some(integer)) => integer.into(),
+        (_, None) => float.into(),
+    };
+    (sum, count)
+}
+
+/// The first value that sorts furthest towards `ordering`.
+fn extreme<'a>(values: impl Iterator<Item = &'a Value>, ordering: Ordering) -> Value {
+    let mut extreme: Option<&Value> = None;
+    for value in values {
+        if extreme.is_none_or(|extreme| compare_sort_values(Some(value), Some(extreme)) == ordering)
+        {
+            extreme = Some(value);
+        }
+    }
+    extreme.cloned().unwrap_or(Value::Null)
+}
+
+#[derive(Debug, Default)]
+pub struct AggregationBuilder {
+    aggregation: Aggregation,
+}
+
+#[allow(dead_code)]
+impl AggregationBuilder {
+    /// Aggregates only the documents the query's filter matches.
+    pub fn matching(&mut self, query: &Query) -> &mut AggregationBuilder {
+        self.aggregation.filter = query.filter.clone();
+        self
+    }
+
+    pub fn group_by(&mut self, name: &str, field: &str) -> &mut AggregationBuilder {
+        self.aggregation.group.push(GroupKey {
+            name: name.to_string(),
+            field: field.to_string(),
+        });
+        self
+    }
+
+    pub fn sum(&mut self, name: &str, field: &str) -> &mut AggregationBuilder {
+        self.accumulate(name, AccumulatorOperator::Sum, Some(field))
+    }
+
+    pub fn avg(&mut self, name: &str, field: &str) -> &mut AggregationBuilder {
+        self.accumulate(name, AccumulatorOperator::Avg, Some(field))
+    }
+
+    pub fn min(&mut self, name: &str, field: &str) -> &mut AggregationBuilder {
+        self.accumulate(name, AccumulatorOperator::Min, Some(field))
+    }
+
+    pub fn max(&mut self, name: &str, field: &str) -> &mut AggregationBuilder {
+        self.accumulate(name, AccumulatorOperator::Max, Some(field))
+    }
+
+    pub fn count(&mut self, name: &str) -> &mut AggregationBuilder {
+        self.accumulate(name, AccumulatorOperator::Count, None)
+    }
+
+    fn accumulate(
+        &mut self,
+        name: &str,
+        operator: AccumulatorOperator,
+        field: Option<&str>,
+    ) -> &mut AggregationBuilder {
+        self.aggregation.accumulators.push(Accumulator {
+            name: name.to_string(),
+            operator,
+            field: field.map(str::to_string),
+        });
+        self
+    }
+
+    pub fn order_by(&mut self, name: &str) -> &mut AggregationBuilder {
+        self.order(name, QuerySortDirection::Ascending)
+    }
+
+    pub fn order_by_desc(&mut self, name: &str) -> &mut AggregationBuilder {
+        self.order(name, QuerySortDirection::Descending)
+    }
+
+    fn order(&mut self, name: &str, direction: QuerySortDirection) -> &mut AggregationBuilder {
+        self.aggregation
+            .sort
+            .get_or_insert_with(Vec::new)
+            .push(QuerySortItem {
+                field: name.to_string(),
+                direction,
+            });
+        self
+    }
+
+    pub fn limit(&mut self, limit: u32) -> &mut AggregationBuilder {
+        self.aggregation.limit = Some(QueryLimit {
+            limit: Some(limit),
+            offset: None,
+        });
+        self
+    }
+
+    pub fn build(&self) -> Aggregation {
+        self.aggregation.clone()
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    pub(crate) fn sales_docs() -> Vec<Value> {
+        vec![
+            json!({"id": 1, "region": "north", "amount": 10, "paid": true, "sold": "2024-03-01"}),
+            json!({"id": 2, "region": "north", "amount": 2.5, "paid": true, "sold": "2024-01-15"}),
+            json!({"id": 3, "region": "south", "amount": 7, "paid": false, "sold": "2024-02-01"}),
+            json!({"id": 4, "region": "south", "amount": "n/a", "paid": true}),
+            json!({"id": 5, "amount": 1, "paid": true, "sold": null}),
+            json!({"id": 6, "region": null, "amount": 4, "paid": true, "sold": "2023-12-31"}),
+        ]
+    }
+
+    /// Aggregations over [`sales_docs`] with the rows they yield.
+    pub(crate) fn sales_aggregations() -> Vec<(Aggregation, Vec<Value>)> {
+        vec![
+            (
+                Aggregation::builder()
+                    .group_by("region", "region")
+                    .sum("total", "amount")
+                    .avg("average", "amount")
+                    .count("sales")
+                    .order_by("region")
+                    .build(),
+                vec![
+                    json!({"region": null, "total": 5, "average": 2.5, "sales": 2}),
+                    json!({"region": "north", "total": 12.5, "average": 6.25, "sales": 2}),
+                    json!({"region": "south", "total": 7, "average": 7.0, "sales": 2}),
+                ],
+            ),
+            (
+                Aggregation::builder()
+                    .matching(&Query::builder().eq("paid", json!(true)).build())
+                    .group_by("region", "region")
+                    .min("first", "sold")
+                    .max("last", "sold")
+                    .max("largest", "amount")
+                    .order_by_desc("largest")
+                    .limit(2)
+                    .build(),
+                vec![
+                    json!({"region": "south", "first": null, "last": null, "largest": "n/a"}),
+                    json!({"region": "north", "first": "2024-01-15", "last": "2024-03-01", "largest": 10}),
+                ],
+            ),
+            (
+                Aggregation::builder()
+                    .sum("total", "amount")
+                    .min("smallest", "amount")
+                    .count("sales")
+                    .build(),
+                vec![json!({"total": 24.5, "smallest": 1, "sales": 6})],
+            ),
+            (
+                Aggregation::builder()
+                    .matching(&Query::builder().eq("region", json!("west")).build())
+                    .sum("total", "amount")
+                    .max("last", "sold")
+                    .count("sales")
+                    .build(),
+                vec![json!({"total": null, "last": null, "sales": 0})],
+            ),
+            (
+                Aggregation::builder()
+                    .matching(&Query::builder().eq("region", json!("west")).build())
+                    .group_by("region", "region")
+                    .count("sales")
+                    .build(),
+                vec![],
+            ),
+        ]
+    }
+
+    #[test]
+    fn test_apply() -> anyhow::Result<()> {
+        let docs = sales_docs();
+        for (aggregation, expected) in sales_aggregations() {
+            let rows = aggregation.apply(docs.iter())?;
+            assert_eq!(rows.len(), expected.len(), "{:?}", aggregation);
+            for (row, expected) in rows.iter().zip(expected.iter()) {
+                assert!(json_eq(row, expected), "{} != {}", row, expected);
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_check() {
+        let invalid = vec![
+            Aggregation::default(),
+            Aggregation::builder().count("a.b").build(),
+            Aggregation::builder().group_by("a", "a").count("a").build(),
+            Aggregation::builder().sum("total", "items.*.price").build(),
+            Aggregation {
+                accumulators: vec![Accumulator {
+                    name: "total".to_string(),
+                    operator: AccumulatorOperator::Sum,
+                    field: None,
+                }],
+                ..Default::default()
+            },
+        ];
+        for aggregation in invalid {
+            assert!(aggregation.check().is_err(), "{:?}", aggregation);
+        }
+    }
+
+    #[test]
+    fn test_serialize() -> anyhow::Result<()> {
+        let aggregation = Aggregation::builder()
+            .matching(&Query::builder().eq("paid", json!(true)).build())
+            .group_by("region", "region")
+            .sum("total", "amount")
+            .count("sales")
+            .order_by_desc("total")
+            .limit(5)
+            .build();
+
+        let json = serde_json::to_value(&aggregation)?;
+        assert_eq!(json["match"][0]["filter"]["field"], "paid");
+        assert_eq!(
+            json["group"],
+            json!([{"name": "region", "field": "region"}])
+        );
+        assert_eq!(
+            json["accumulators"],
+            json!([
+                {"name": "total", "operator": "sum", "field": "amount"},
+                {"name": "sales", "operator": "count"},
+            ])
+        );
+        assert_eq!(json["sort"], json!([{"field": "total", "direction": "-1"}]));
+
+        let parsed: Aggregation = serde_json::from_value(json)?;
+        assert_eq!(parsed.accumulators, aggregation.accumulators);
+        let parsed: Aggregation = serde_json::from_str(
+            r#"{"match": null, "accumulators": [{"name": "n", "operator": "count"}]}"#,
+        )?;
+        assert!(parsed.group.is_empty());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,1249 @@
+//! The behavior every [`Persistence`] is held to, as a suite a backend runs
+//! against itself. Each section seeds the collection with its own documents
+//! and checks fixed answers, so backends agree with the spec rather than with
+//! each other:
+//!
+//! ```ignore
+//! #[tokio::test]
+//! async fn test_conformance() -> anyhow::Result<()> {
+//!     let persistence = MyPersistence::connect().await?;
+//!     store::conformance::check(&persistence, "conformance").await?;
+//!     Ok(())
+//! }
+//! ```
+//!
+//! The collection must exist and be writable; it's emptied before each
+//! section. `{collection}_missing` must not exist. A failed check panics with
+//! the section and query it failed on, and backend errors are returned.
+
+use futures::{future, StreamExt, TryStreamExt};
+use serde_json::{json, Value};
+
+use crate::{
+    aggregate::Aggregation,
+    error::{Result, StoreError},
+    index::Index,
+    query::{json_eq, resolve_path, Projection, Query, QueryLimit},
+    store::{Persistence, STREAM_BATCH_SIZE},
+    update::Update,
+};
+
+/// Runs every section of the suite.
+pub async fn check(persistence: &dyn Persistence, collection: &str) -> Result<()> {
+    check_crud(persistence, collection).await?;
+    check_operators(persistence, collection).await?;
+    check_boolean(persistence, collection).await?;
+    check_nested(persistence, collection).await?;
+    check_sort(persistence, collection).await?;
+    check_limit(persistence, collection).await?;
+    check_after(persistence, collection).await?;
+    check_projection(persistence, collection).await?;
+    check_stream(persistence, collection).await?;
+    check_counts(persistence, collection).await?;
+    check_aggregate(persistence, collection).await?;
+    check_patch(persistence, collection).await?;
+    check_conditional_writes(persistence, collection).await?;
+    check_counters(persistence, collection).await?;
+    check_ensure_collection(persistence, collection).await?;
+    check_transactions(persistence, collection).await?;
+    check_edge_cases(persistence, collection).await?;
+    Ok(())
+}
+
+/// Inserts, replaces, upserts and deletes documents, and reads them back
+/// unchanged.
+pub async fn check_crud(persistence: &dyn Persistence, collection: &str) -> Result<()> {
+    seed(persistence, collection, vec![]).await?;
+
+    let docs = vec![
+        json!({"id": 1, "name": "John", "tags": ["a", "b"], "address": {"city": "Lisbon"}}),
+        json!({"id": 2, "name": "Zoë 🦀", "score": 1.5, "empty": {}, "none": [], "nil": null}),
+        json!({"id": 3, "big": i64::MAX, "small": i64::MIN, "nested": [[1, [2]], {"a": {}}]}),
+    ];
+    for doc in &docs {
+        let inserted = persistence.insert(collection, doc.clone()).await?;
+        assert_eq!(&inserted, doc, "crud: insert returns the document");
+    }
+    let mut found = persistence.find(collection, None).await?;
+    found.sort_by_key(|doc| doc["id"].as_i64());
+    assert_eq!(found, docs, "crud: documents read back as inserted");
+
+    let one = by_id(1);
+    let replacement = json!({"id": 1, "name": "Johnny"});
+    let replaced = persistence
+        .update(collection, one.clone(), replacement.clone())
+        .await?;
+    assert_eq!(replaced, 1, "crud: update counts the replaced documents");
+    let stored = persistence.find_one(collection, Some(one.clone())).await?;
+    assert_eq!(
+        stored,
+        Some(replacement),
+        "crud: update replaces the document"
+    );
+    let replaced = persistence
+        .update(collection, by_id(9), json!({"id": 9}))
+        .await?;
+    assert_eq!(replaced, 0, "crud: update without a match replaces nothing");
+    assert_eq!(all_ids(persistence, collection).await?, vec![1, 2, 3]);
+
+    let upserted = persistence
+        .upsert(collection, by_id(4), json!({"id": 4, "name": "new"}))
+        .await?;
+    assert_eq!(
+        upserted,
+        json!({"id": 4, "name": "new"}),
+        "crud: upsert inserts"
+    );
+    let upserted = persistence
+        .upsert(collection, by_id(4), json!({"id": 4, "name": "again"}))
+        .await?;
+    assert_eq!(
+        upserted,
+        json!({"id": 4, "name": "again"}),
+        "crud: upsert replaces"
+    );
+    let names = persistence.find(collection, Some(by_id(4))).await?;
+    assert_eq!(
+        names,
+        vec![json!({"id": 4, "name": "again"})],
+        "crud: upsert keeps one document"
+    );
+    let inserted = persistence
+        .insert_if_absent(collection, by_id(4), json!({"id": 4, "name": "other"}))
+        .await?;
+    assert_eq!(inserted, None, "crud: insert_if_absent with a match");
+    let inserted = persistence
+        .insert_if_absent(collection, by_id(5), json!({"id": 5}))
+        .await?;
+    assert_eq!(
+        inserted,
+        Some(json!({"id": 5})),
+        "crud: insert_if_absent without a match"
+    );
+
+    assert_eq!(
+        persistence.delete(collection, one.clone()).await?,
+        1,
+        "crud: delete"
+    );
+    assert_eq!(
+        persistence.delete(collection, one).await?,
+        0,
+        "crud: delete twice"
+    );
+    assert_eq!(all_ids(persistence, collection).await?, vec![2, 3, 4, 5]);
+    let all = Query::builder().build();
+    assert_eq!(
+        persistence.delete(collection, all).await?,
+        4,
+        "crud: delete all"
+    );
+    assert_eq!(all_ids(persistence, collection).await?, Vec::<i64>::new());
+
+    Ok(())
+}
+
+/// Every filter operator, across numbers, strings, dates, booleans, `null`
+/// and missing fields. Numbers compare by value, strings by their bytes, and
+/// ranges never compare values of different types.
+pub async fn check_operators(persistence: &dyn Persistence, collection: &str) -> Result<()> {
+    seed(
+        persistence,
+        collection,
+        vec![
+            json!({"id": 1, "age": 30, "score": 1.5, "name": "John", "created": "2022-11-05T10:00:00Z", "nickname": null}),
+            json!({"id": 2, "age": 17.5, "name": "jane", "created": "2021-01-01T00:00:00Z"}),
+            json!({"id": 3, "age": "30", "name": "Zoe", "nickname": "zed"}),
+            json!({"id": 4, "age": true, "name": "Émile"}),
+            json!({"id": 5, "score": 2}),
+        ],
+    )
+    .await?;
+
+    let cases = vec![
+        (Query::builder().eq("age", json!(30.0)).build(), vec![1]),
+        (Query::builder().eq("age", json!("30")).build(), vec![3]),
+        (Query::builder().eq("age", json!(true)).build(), vec![4]),
+        (
+            Query::builder().eq("nickname", Value::Null).build(),
+            vec![1],
+        ),
+        (Query::builder().eq("missing", json!(1)).build(), vec![]),
+        (
+            Query::builder().not_eq("age", json!(30)).build(),
+            vec![2, 3, 4],
+        ),
+        (Query::builder().gt("age", json!(17)).build(), vec![1, 2]),
+        (Query::builder().gte("age", json!(17.5)).build(), vec![1, 2]),
+        (Query::builder().lt("age", json!(30)).build(), vec![2]),
+        (Query::builder().lte("age", json!("30")).build(), vec![3]),
+        (Query::builder().gt("score", json!(1)).build(), vec![1, 5]),
+        (
+            Query::builder().gt("name", json!("Zoe")).build(),
+            vec![2, 4],
+        ),
+        (
+            Query::builder().lt("name", json!("john")).build(),
+            vec![1, 2, 3],
+        ),
+        (
+            Query::builder().gte("created", json!("2022-01-01")).build(),
+            vec![1],
+        ),
+        (
+            Query::builder()
+                .lt("created", json!("2022-11-05T10:00:00Z"))
+                .build(),
+            vec![2],
+        ),
+        (Query::builder().exists("nickname").build(), vec![1, 3]),
+        (
+            Query::builder().not_exists("created").build(),
+            vec![3, 4, 5],
+        ),
+        (
+            Query::builder()
+                .is_in("age", vec![json!(30), json!(true)])
+                .build(),
+            vec![1, 4],
+        ),
+        (Query::builder().is_in("age", vec![]).build(), vec![]),
+        (
+            Query::builder().not_in("age", vec![json!(30)]).build(),
+            vec![2, 3, 4],
+        ),
+        (
+            Query::builder().not_in("created", vec![]).build(),
+            vec![1, 2],
+        ),
+    ];
+    check_cases(persistence, collection, "operators", cases).await?;
+
+    let query = Query::builder().gt("age", json!([1])).build();
+    assert!(
+        matches!(
+            persistence.find(collection, Some(query)).await,
+            Err(StoreError::UnsupportedOperator(_))
+        ),
+        "operators: a range over an array is rejected"
+    );
+
+    Ok(())
+}
+
+/// `and` binds tighter than `or`, and `not` negates what it's given, treating
+/// a filter on a missing field as false before negating it.
+pub async fn check_boolean(persistence: &dyn Persistence, collection: &str) -> Result<()> {
+    seed(
+        persistence,
+        collection,
+        vec![
+            json!({"id": 1, "a": 1, "b": 1}),
+            json!({"id": 2, "a": 1, "b": 2}),
+            json!({"id": 3, "a": 2, "b": 1}),
+            json!({"id": 4, "a": 2}),
+        ],
+    )
+    .await?;
+
+    let cases = vec![
+        (
+            Query::builder()
+                .wher("a", json!(1))
+                .or_wher("a", json!(2))
+                .and_wher("b", json!(1))
+                .build(),
+            vec![1, 2, 3],
+        ),
+        (Query::builder().not_wher("b", json!(1)).build(), vec![2, 4]),
+        (
+            Query::builder()
+                .wher("a", json!(2))
+                .not_wher("b", json!(1))
+                .build(),
+            vec![4],
+        ),
+        (
+            Query::builder()
+                .not(|mut q| q.wher("a", json!(1)).or_wher("b", json!(1)).build())
+                .build(),
+            vec![4],
+        ),
+        (
+            Query::builder()
+                .and(|mut q| q.wher("a", json!(1)).or_wher("a", json!(2)).build())
+                .and_wher("b", json!(2))
+                .build(),
+            vec![2],
+        ),
+        (
+            Query::builder()
+                .and(|q| q.build())
+                .and_wher("a", json!(2))
+                .build(),
+            vec![3, 4],
+        ),
+    ];
+    check_cases(persistence, collection, "boolean", cases).await
+}
+
+/// Dotted paths into objects and arrays, negative indexes counting from the
+/// end, and `*` matching when any element does.
+pub async fn check_nested(persistence: &dyn Persistence, collection: &str) -> Result<()> {
+    seed(
+        persistence,
+        collection,
+        vec![
+            json!({"id": 1, "address": {"city": "Lisbon"}, "items": [{"sku": "A1", "qty": 2}]}),
+            json!({"id": 2, "address": {"city": "Porto"}, "items": [{"sku": "B2", "qty": 5}, {"sku": "A1", "qty": 1}]}),
+            json!({"id": 3, "address": {"city": 7}, "items": []}),
+            json!({"id": 4, "address": "unknown", "items": [[{"sku": "A1"}]]}),
+        ],
+    )
+    .await?;
+
+    let cases = vec![
+        (
+            Query::builder().eq("address.city", json!("Lisbon")).build(),
+            vec![1],
+        ),
+        (
+            Query::builder().gte("address.city", json!("M")).build(),
+            vec![2],
+        ),
+        (
+            Query::builder().exists("address.city").build(),
+            vec![1, 2, 3],
+        ),
+        (
+            Query::builder().eq("items.0.sku", json!("A1")).build(),
+            vec![1],
+        ),
+        (
+            Query::builder().eq("items.-1.sku", json!("A1")).build(),
+            vec![1, 2],
+        ),
+        (
+            Query::builder().eq("items.*.sku", json!("A1")).build(),
+            vec![1, 2],
+        ),
+        (
+            Query::builder().lt("items.*.qty", json!(2)).build(),
+            vec![2],
+        ),
+        (
+            Query::builder().not_eq("items.*.sku", json!("A1")).build(),
+            vec![],
+        ),
+        (
+            Query::builder()
+                .not_in("items.*.sku", vec![json!("B2")])
+                .build(),
+            vec![1],
+        ),
+        (Query::builder().exists("items.*").build(), vec![1, 2, 4]),
+        (
+            Query::builder().not_exists("items.*.qty").build(),
+            vec![3, 4],
+        ),
+        (
+            Query::builder().eq("items.*.*.sku", json!("A1")).build(),
+            vec![4],
+        ),
+    ];
+    check_cases(persistence, collection, "nested", cases).await?;
+
+    let sorted = vec![
+        (
+            Query::builder().order_by_desc("address.city").build(),
+            vec![2, 1, 3, 4],
+        ),
+        (
+            Query::builder()
+                .order_by("items.0.qty")
+                .order_by("id")
+                .build(),
+            vec![3, 4, 1, 2],
+        ),
+    ];
+    for (query, expected) in sorted {
+        let found = ids(persistence, collection, Some(query.clone())).await?;
+        assert_eq!(found, expected, "nested: {:?}", query.sort);
+    }
+
+    Ok(())
+}
+
+/// Multi-key sorting in either direction. Values of different types sort by
+/// type: missing, `null`, numbers, strings, booleans, then arrays and objects.
+pub async fn check_sort(persistence: &dyn Persistence, collection: &str) -> Result<()> {
+    seed(
+        persistence,
+        collection,
+        vec![
+            json!({"id": 1, "rank": "b", "score": 10}),
+            json!({"id": 2, "rank": "a", "score": 2.5}),
+            json!({"id": 3, "rank": "B"}),
+            json!({"id": 4, "rank": "a", "score": "10"}),
+            json!({"id": 5, "rank": true, "score": null}),
+            json!({"id": 6, "rank": [1], "score": false}),
+            json!({"id": 7, "rank": 3, "score": -1}),
+        ],
+    )
+    .await?;
+
+    let cases = vec![
+        (
+            Query::builder().order_by("score").build(),
+            vec![3, 5, 7, 2, 1, 4, 6],
+        ),
+        (
+            Query::builder().order_by_desc("score").build(),
+            vec![6, 4, 1, 2, 7, 3, 5],
+        ),
+        (
+            Query::builder()
+                .order_by("rank")
+                .order_by_desc("score")
+                .build(),
+            vec![7, 3, 4, 2, 1, 5, 6],
+        ),
+    ];
+    for (query, expected) in cases {
+        let found = ids(persistence, collection, Some(query.clone())).await?;
+        assert_eq!(found, expected, "sort: {:?}", query.sort);
+
+        let mut streamed = vec![];
+        let mut stream = persistence.stream(collection, Some(query.clone())).await?;
+        while let Some(doc) = stream.next().await {
+            streamed.push(id_of(&doc?));
+        }
+        assert_eq!(streamed, expected, "sort: streamed {:?}", query.sort);
+    }
+
+    Ok(())
+}
+
+/// Limits and offsets apply after sorting, and `find_one` honors the offset.
+pub async fn check_limit(persistence: &dyn Persistence, collection: &str) -> Result<()> {
+    seed(
+        persistence,
+        collection,
+        (1..=5).map(|id| json!({"id": id})).collect(),
+    )
+    .await?;
+
+    let cases = vec![
+        (Some(2), None, vec![1, 2]),
+        (Some(2), Some(1), vec![2, 3]),
+        (None, Some(3), vec![4, 5]),
+        (Some(10), Some(4), vec![5]),
+        (Some(2), Some(10), vec![]),
+        (Some(0), None, vec![]),
+    ];
+    for (limit, offset, expected) in cases {
+        let mut query = Query::builder().order_by("id").build();
+        query.limit = Some(QueryLimit { limit, offset });
+        let found = ids(persistence, collection, Some(query.clone())).await?;
+        assert_eq!(found, expected, "limit: {:?}", query.limit);
+    }
+
+    let mut query = Query::builder().order_by_desc("id").build();
+    query.limit = Some(QueryLimit {
+        limit: None,
+        offset: Some(1),
+    });
+    let doc = persistence.find_one(collection, Some(query)).await?;
+    assert_eq!(
+        doc,
+        Some(json!({"id": 4})),
+        "limit: find_one skips the offset"
+    );
+
+    Ok(())
+}
+
+/// `after` keeps the documents that sort strictly after the given sort key
+/// values, a missing field standing as `null`, which is how `Store::page`
+/// continues from one page to the next.
+pub async fn check_after(persistence: &dyn Persistence, collection: &str) -> Result<()> {
+    seed(
+        persistence,
+        collection,
+        vec![
+            json!({"id": 1, "score": 3}),
+            json!({"id": 2, "score": "b"}),
+            json!({"id": 3}),
+            json!({"id": 4, "score": 3.0}),
+            json!({"id": 5, "score": 1.5}),
+            json!({"id": 6, "score": null}),
+            json!({"id": 7, "score": true}),
+            json!({"id": 8, "score": "a"}),
+            json!({"id": 9, "score": 3}),
+            json!({"id": 10, "score": [1]}),
+            json!({"id": 11, "score": {"x": 1}}),
+            json!({"id": 12, "score": [2]}),
+        ],
+    )
+    .await?;
+
+    let cases = vec![
+        (
+            Query::builder()
+                .order_by_desc("score")
+                .order_by("id")
+                .build(),
+            vec![11, 10, 12, 7, 2, 8, 1, 4, 9, 5, 3, 6],
+        ),
+        (
+            Query::builder()
+                .not_eq("id", json!(5))
+                .order_by("score")
+                .order_by("id")
+                .build(),
+            vec![3, 6, 1, 4, 9, 8, 2, 7, 10, 12, 11],
+        ),
+    ];
+    for (query, expected) in cases {
+        let docs = persistence.find(collection, Some(query.clone())).await?;
+        let found: Vec<i64> = docs.iter().map(id_of).collect();
+        assert_eq!(found, expected, "after: {:?}", query.sort);
+
+        for (position, doc) in docs.iter().enumerate() {
+            let mut after = query.clone();
+            let values = ["score", "id"].map(|field| {
+                let value = resolve_path(doc, field).first().copied();
+                value.cloned().unwrap_or(Value::Null)
+            });
+            after.after = Some(values.to_vec());
+            after.limit = Some(QueryLimit {
+                limit: Some(3),
+                offset: None,
+            });
+            let found = ids(persistence, collection, Some(after)).await?;
+            let rest: Vec<i64> = expected
+                .iter()
+                .skip(position + 1)
+                .take(3)
+                .copied()
+                .collect();
+            assert_eq!(found, rest, "after: {:?} after {}", query.sort, doc);
+        }
+    }
+
+    let mut query = Query::builder().order_by("score").build();
+    query.after = Some(vec![json!(1), json!(2)]);
+    assert!(
+        matches!(
+            persistence.find(collection, Some(query)).await,
+            Err(StoreError::InvalidQuery(_))
+        ),
+        "after: one value per sort key"
+    );
+
+    Ok(())
+}
+
+/// Projections return the documents `Projection::apply` makes of them, from
+/// `find`, `find_one` and `stream` alike.
+pub async fn check_projection(persistence: &dyn Persistence, collection: &str) -> Result<()> {
+    let docs = vec![
+        json!({
+            "id": 1, "name": "Ann", "email": "ann@example.com", "active": true,
+            "address": {"city": "Lisbon", "zip": "1000", "geo": {"lat": 38.7, "lng": -9.1}},
+            "tags": ["a", "b"],
+        }),
+        json!({"id": 2, "name": "Bob", "email": null, "active": false, "address": "unknown", "tags": []}),
+        json!({"id": 3, "name": "Cid", "address": {"city": "Porto", "geo": null}, "score": 1.5}),
+        json!({
+            "id": 4, "name": "Dee", "active": true,
+            "address": {"zip": "4000", "geo": {"lat": 41.1}},
+            "notes": {"x": {"y": "z"}, "w": 1},
+        }),
+    ];
+    seed(persistence, collection, docs.clone()).await?;
+
+    let fields = |fields: &[&str]| fields.iter().map(|field| field.to_string()).collect();
+    let projections = [
+        Projection::Include(fields(&["id", "name"])),
+        Projection::Include(fields(&["id", "address.city", "address.geo.lat"])),
+        Projection::Include(fields(&["address.city", "address"])),
+        Projection::Include(fields(&["active", "missing", "notes.x.y"])),
+        Projection::Include(vec![]),
+        Projection::Exclude(fields(&["address", "email"])),
+        Projection::Exclude(fields(&["address.geo", "notes.x.y", "tags"])),
+        Projection::Exclude(vec![]),
+    ];
+    for projection in projections {
+        let mut query = Query::builder().order_by("id").build();
+        query.projection = Some(projection.clone());
+        let expected = Value::Array(
+            docs.iter()
+                .map(|doc| projection.apply(doc.clone()))
+                .collect::<Result<_>>()?,
+        );
+
+        let found = Value::Array(persistence.find(collection, Some(query.clone())).await?);
+        assert!(
+            json_eq(&found, &expected),
+            "projection: {:?} gave {}",
+            projection,
+            found
+        );
+        let streamed: Vec<Value> = persistence
+            .stream(collection, Some(query.clone()))
+            .await?
+            .try_collect()
+            .await?;
+        assert!(
+            json_eq(&Value::Array(streamed), &expected),
+            "projection: {:?} streamed",
+            projection
+        );
+        let one = persistence.find_one(collection, Some(query)).await?;
+        assert!(
+            json_eq(&one.unwrap_or_default(), &expected[0]),
+            "projection: {:?} of one",
+            projection
+        );
+    }
+
+    let invalid = Query::builder().select(&["tags.*"]).build();
+    assert!(
+        persistence.find(collection, Some(invalid)).await.is_err(),
+        "projection: a wildcard is rejected"
+    );
+
+    Ok(())
+}
+
+/// Streams more documents than a stream reads ahead, in the order `find`
+/// returns them, and inside a transaction. A stream dropped half way lets go
+/// of what it holds.
+pub async fn check_stream(persistence: &dyn Persistence, collection: &str) -> Result<()> {
+    let count = STREAM_BATCH_SIZE as i64 * 2 + 100;
+    seed(
+        persistence,
+        collection,
+        (1..=count)
+            .map(|id| json!({"id": id, "v": id % 7}))
+            .collect(),
+    )
+    .await?;
+
+    let streamed: Vec<Value> = persistence
+        .stream(collection, None)
+        .await?
+        .try_collect()
+        .await?;
+    assert_eq!(streamed.len() as i64, count, "stream: all");
+
+    let query = Query::builder()
+        .gt("v", json!(0))
+        .order_by_desc("v")
+        .order_by("id")
+        .build();
+    let found = ids(persistence, collection, Some(query.clone())).await?;
+    let streamed: Vec<Value> = persistence
+        .stream(collection, Some(query))
+        .await?
+        .try_collect()
+        .await?;
+    let streamed: Vec<i64> = streamed.iter().map(id_of).collect();
+    assert_eq!(streamed, found, "stream: sorted");
+
+    let first: Vec<Result<Value>> = persistence
+        .stream(collection, None)
+        .await?
+        .take(3)
+        .collect()
+        .await;
+    assert_eq!(first.len(), 3, "stream: taken");
+    assert!(
+        persistence
+            .find_one(collection, Some(by_id(1)))
+            .await?
+            .is_some(),
+        "stream: read after a dropped stream"
+    );
+
+    let tx = persistence.begin().await?;
+    tx.insert(collection, json!({"id": count + 1})).await?;
+    let mut stream = tx.stream(collection, None).await?;
+    let mut streamed = 0;
+    while let Some(doc) = stream.next().await {
+        doc?;
+        streamed += 1;
+    }
+    drop(stream);
+    assert_eq!(streamed, count + 1, "stream: own writes in a transaction");
+    tx.rollback().await?;
+    assert_eq!(persistence.count(collection, None).await? as i64, count);
+
+    Ok(())
+}
+
+/// `count`, `exists` and `distinct` see the documents `find` does.
+pub async fn check_counts(persistence: &dyn Persistence, collection: &str) -> Result<()> {
+    seed(
+        persistence,
+        collection,
+        vec![
+            json!({"id": 1, "region": "north", "amount": 10}),
+            json!({"id": 2, "region": "south", "amount": 2.5}),
+            json!({"id": 3, "region": "north", "amount": 5.0}),
+            json!({"id": 4, "amount": "n/a"}),
+            json!({"id": 5, "region": null}),
+        ],
+    )
+    .await?;
+
+    let north = Query::builder().eq("region", json!("north")).build();
+    let none = Query::builder().eq("region", json!("west")).build();
+    assert_eq!(persistence.count(collection, None).await?, 5, "counts: all");
+    assert_eq!(
+        persistence.count(collection, Some(north.clone())).await?,
+        2,
+        "counts: north"
+    );
+    assert!(
+        persistence.exists(collection, Some(north.clone())).await?,
+        "counts: exists"
+    );
+    assert!(
+        !persistence.exists(collection, Some(none)).await?,
+        "counts: not exists"
+    );
+
+    let regions = persistence.distinct(collection, "region", None).await?;
+    assert_eq!(
+        regions,
+        vec![json!(null), json!("north"), json!("south")],
+        "counts: distinct"
+    );
+    let amounts = persistence
+        .distinct(collection, "amount", Some(north))
+        .await?;
+    assert_eq!(
+        amounts,
+        vec![json!(5.0), json!(10)],
+        "counts: distinct numbers"
+    );
+
+    let query = Query::builder().eq("amount", json!(5)).build();
+    assert_eq!(
+        persistence.count(collection, Some(query)).await?,
+        1,
+        "counts: numbers by value"
+    );
+    assert!(
+        persistence
+            .distinct(collection, "tags.*", None)
+            .await
+            .is_err(),
+        "counts: distinct over a wildcard is rejected"
+    );
+
+    Ok(())
+}
+
+/// Groups, sums, averages, minimums and maximums over mixed types, with
+/// numbers equal in value grouped together.
+pub async fn check_aggregate(persistence: &dyn Persistence, collection: &str) -> Result<()> {
+    seed(
+        persistence,
+        collection,
+        vec![
+            json!({"id": 1, "region": "north", "amount": 10, "paid": true, "sold": "2024-03-01"}),
+            json!({"id": 2, "region": "north", "amount": 2.5, "paid": true, "sold": "2024-01-15"}),
+            json!({"id": 3, "region": "south", "amount": 7, "paid": false, "sold": "2024-02-01"}),
+            json!({"id": 4, "region": "south", "amount": "n/a", "paid": true}),
+            json!({"id": 5, "amount": 1, "paid": true, "sold": null}),
+            json!({"id": 6, "region": null, "amount": 4, "paid": true, "sold": "2023-12-31"}),
+        ],
+    )
+    .await?;
+
+    let cases = vec![
+        (
+            Aggregation::builder()
+                .group_by("region", "region")
+                .sum("total", "amount")
+                .avg("average", "amount")
+                .count("sales")
+                .order_by("region")
+                .build(),
+            json!([
+                {"region": null, "total": 5, "average": 2.5, "sales": 2},
+                {"region": "north", "total": 12.5, "average": 6.25, "sales": 2},
+                {"region": "south", "total": 7, "average": 7.0, "sales": 2},
+            ]),
+        ),
+        (
+            Aggregation::builder()
+                .matching(&Query::builder().eq("paid", json!(true)).build())
+                .group_by("region", "region")
+                .min("first", "sold")
+                .max("last", "sold")
+                .max("largest", "amount")
+                .order_by_desc("largest")
+                .limit(2)
+                .build(),
+            json!([
+                {"region": "south", "first": null, "last": null, "largest": "n/a"},
+                {"region": "north", "first": "2024-01-15", "last": "2024-03-01", "largest": 10},
+            ]),
+        ),
+        (
+            Aggregation::builder()
+                .sum("total", "amount")
+                .min("smallest", "amount")
+                .count("sales")
+                .build(),
+            json!([{"total": 24.5, "smallest": 1, "sales": 6}]),
+        ),
+        (
+            Aggregation::builder()
+                .matching(&Query::builder().eq("region", json!("west")).build())
+                .sum("total", "amount")
+                .max("last", "sold")
+                .count("sales")
+                .build(),
+            json!([{"total": null, "last": null, "sales": 0}]),
+        ),
+        (
+            Aggregation::builder()
+                .matching(&Query::builder().eq("region", json!("west")).build())
+                .group_by("region", "region")
+                .count("sales")
+                .build(),
+            json!([]),
+        ),
+    ];
+    for (aggregation, expected) in cases {
+        let rows = Value::Array(
+            persistence
+                .aggregate(collection, aggregation.clone())
+                .await?,
+        );
+        assert!(
+            json_eq(&rows, &expected),
+            "aggregate: {:?} gave {}",
+            aggregation,
+            rows
+        );
+    }
+
+    persistence
+        .insert(collection, json!({"id": 7, "region": 1, "amount": 1}))
+        .await?;
+    persistence
+        .insert(collection, json!({"id": 8, "region": 1.0, "amount": 2}))
+        .await?;
+    let aggregation = Aggregation::builder()
+        .matching(&Query::builder().gt("region", json!(0)).build())
+        .group_by("region", "region")
+        .sum("total", "amount")
+        .build();
+    let rows = Value::Array(persistence.aggregate(collection, aggregation).await?);
+    assert!(
+        json_eq(&rows, &json!([{"region": 1, "total": 3}])),
+        "aggregate: numbers grouped by value, got {}",
+        rows
+    );
+
+    let invalid = Aggregation::builder().count("a.b").build();
+    assert!(
+        persistence.aggregate(collection, invalid).await.is_err(),
+        "aggregate: a dotted output name is rejected"
+    );
+
+    Ok(())
+}
+
+/// Partial updates leave the documents `Update::apply` makes of them, count
+/// only the ones they change, and don't lose increments made at once.
+pub async fn check_patch(persistence: &dyn Persistence, collection: &str) -> Result<()> {
+    let docs = vec![
+        json!({"id": 1, "name": "Ann", "n": 1, "tags": ["a", "b", "a"], "address": {"city": "Lisbon"}}),
+        json!({"id": 2, "name": "Bob", "n": 2.5, "tags": "none", "address": "unknown"}),
+        json!({"id": 3, "name": "Cid", "address": {"city": "Porto", "geo": null}, "old": true}),
+        json!({"id": 4, "name": "Dee", "n": "many", "tags": [1, 1.0, 2]}),
+    ];
+    let all = || Query::builder().build();
+    let cases = vec![
+        (all(), Update::builder().inc("n", 10).build()),
+        (
+            Query::builder().exists("n").build(),
+            Update::builder().set("n", json!(2.5)).build(),
+        ),
+        (
+            all(),
+            Update::builder()
+                .set("address.geo.lat", json!(38.7))
+                .unset("address.city")
+                .unset("missing.field")
+                .build(),
+        ),
+        (
+            all(),
+            Update::builder()
+                .push("tags", json!({"c": [1]}))
+                .pull("tags", json!("a"))
+                .pull("tags", json!(1))
+                .build(),
+        ),
+        (
+            all(),
+            Update::builder()
+                .rename("old", "new")
+                .rename("name", "profile.name")
+                .rename("address.city", "city")
+                .build(),
+        ),
+    ];
+    for (query, update) in cases {
+        seed(persistence, collection, docs.clone()).await?;
+        let mut expected = docs.clone();
+        let mut modified = 0;
+        for doc in expected.iter_mut() {
+            if query.matches(doc)? && update.apply(doc)? {
+                modified += 1;
+            }
+        }
+
+        let count = persistence.patch(collection, query, update.clone()).await?;
+        assert_eq!(count, modified, "patch: {:?} counts", update);
+        let mut found = persistence.find(collection, None).await?;
+        found.sort_by_key(|doc| doc["id"].as_i64());
+        let found = Value::Array(found);
+        assert!(
+            json_eq(&found, &Value::Array(expected)),
+            "patch: {:?} gave {}",
+            update,
+            found
+        );
+    }
+
+    let invalid = Update::builder().rename("address", "address.city").build();
+    assert!(
+        persistence.patch(collection, all(), invalid).await.is_err(),
+        "patch: a rename into the field itself is rejected"
+    );
+
+    seed(persistence, collection, vec![json!({"id": 1, "hits": 0})]).await?;
+    let increments = (0..20).map(|_| {
+        let update = Update::builder().inc("hits", 1).build();
+        persistence.patch(collection, by_id(1), update)
+    });
+    for modified in future::try_join_all(increments).await? {
+        assert_eq!(modified, 1, "patch: concurrent increment");
+    }
+    let doc = persistence.find_one(collection, Some(by_id(1))).await?;
+    assert_eq!(
+        doc,
+        Some(json!({"id": 1, "hits": 20})),
+        "patch: concurrent increments"
+    );
+
+    Ok(())
+}
+
+/// Writes conditional on a version, as `Store::save` makes them, succeed
+/// once when made at once: `update` with the version in its query and
+/// `insert_if_absent`.
+pub async fn check_conditional_writes(
+    persistence: &dyn Persistence,
+    collection: &str,
+) -> Result<()> {
+    seed(persistence, collection, vec![json!({"id": 1, "_rev": 1})]).await?;
+
+    let at = |rev: i64| {
+        Query::builder()
+            .eq("id", json!(1))
+            .and_wher("_rev", json!(rev))
+            .build()
+    };
+    let updates =
+        (0..8).map(|n| persistence.update(collection, at(1), json!({"id": 1, "_rev": 2, "n": n})));
+    let updated: u64 = future::try_join_all(updates).await?.into_iter().sum();
+    assert_eq!(updated, 1, "conditional writes: one update wins");
+    let updated = persistence
+        .update(collection, at(1), json!({"id": 1, "_rev": 2}))
+        .await?;
+    assert_eq!(updated, 0, "conditional writes: stale update");
+    let doc = persistence.find_one(collection, Some(by_id(1))).await?;
+    assert_eq!(
+        doc.map(|doc| doc["_rev"].clone()),
+        Some(json!(2)),
+        "conditional writes: updated"
+    );
+
+    let inserts = (0..8)
+        .map(|n| persistence.insert_if_absent(collection, by_id(2), json!({"id": 2, "n": n})));
+    let inserted = future::try_join_all(inserts).await?;
+    assert_eq!(
+        inserted.iter().flatten().count(),
+        1,
+        "conditional writes: one insert wins"
+    );
+    assert_eq!(
+        persistence.count(collection, Some(by_id(2))).await?,
+        1,
+        "conditional writes: inserted once"
+    );
+
+    Ok(())
+}
+
+/// Id counters count up by one. A backend that records migrations never
+/// moves a collection's version back; one that doesn't reports 0.
+pub async fn check_counters(persistence: &dyn Persistence, collection: &str) -> Result<()> {
+    let first = persistence.increment_counter(collection).await?;
+    let next =
+        future::try_join_all((0..4).map(|_| persistence.increment_counter(collection))).await?;
+    let mut next = next;
+    next.sort();
+    assert_eq!(
+        next,
+        (first + 1..=first + 4).collect::<Vec<_>>(),
+        "counters: increments"
+    );
+
+    persistence.record_migration(collection, 3).await?;
+    let version = persistence.migrated_version(collection).await?;
+    assert!(
+        version == 0 || version == 3,
+        "counters: migrated version {}",
+        version
+    );
+    persistence.record_migration(collection, 2).await?;
+    assert_eq!(
+        persistence.migrated_version(collection).await?,
+        version,
+        "counters: a migrated version never moves back"
+    );
+
+    Ok(())
+}
+
+/// Ensuring a collection twice, with indexes of every kind, leaves it
+/// writable and queryable as before.
+pub async fn check_ensure_collection(
+    persistence: &dyn Persistence,
+    collection: &str,
+) -> Result<()> {
+    let indexes = [
+        Index::ordered("city"),
+        Index::hash("address.zip"),
+        Index::gin("tags"),
+    ];
+    persistence.ensure_collection(collection, &indexes).await?;
+    persistence.ensure_collection(collection, &indexes).await?;
+    seed(
+        persistence,
+        collection,
+        vec![
+            json!({"id": 1, "city": "Lisbon", "address": {"zip": "1000-001"}, "tags": ["a"]}),
+            json!({"id": 2, "city": "Porto", "address": {"zip": "4000-001"}, "tags": []}),
+            json!({"id": 3, "city": 7}),
+        ],
+    )
+    .await?;
+
+    let cases = vec![
+        (
+            Query::builder()
+                .eq("address.zip", json!("4000-001"))
+                .build(),
+            vec![2],
+        ),
+        (Query::builder().gte("city", json!("M")).build(), vec![2]),
+        (Query::builder().lt("city", json!(10)).build(), vec![3]),
+        (Query::builder().eq("tags.*", json!("a")).build(), vec![1]),
+    ];
+    check_cases(persistence, collection, "ensure collection", cases).await
+}
+
+/// Writes in a transaction are seen only once it commits.
+pub async fn check_transactions(persistence: &dyn Persistence, collection: &str) -> Result<()> {
+    seed(persistence, collection, vec![json!({"id": 1})]).await?;
+
+    let tx = persistence.begin().await?;
+    tx.insert(collection, json!({"id": 2})).await?;
+    tx.delete(collection, by_id(1)).await?;
+    assert_eq!(
+        all_ids(tx.as_ref(), collection).await?,
+        vec![2],
+        "transactions: own writes"
+    );
+    tx.rollback().await?;
+    assert_eq!(
+        all_ids(persistence, collection).await?,
+        vec![1],
+        "transactions: rollback"
+    );
+
+    let tx = persistence.begin().await?;
+    tx.insert(collection, json!({"id": 2})).await?;
+    assert!(
+        matches!(tx.begin().await, Err(StoreError::UnsupportedOperator(_))),
+        "transactions: nested"
+    );
+    tx.commit().await?;
+    assert_eq!(
+        all_ids(persistence, collection).await?,
+        vec![1, 2],
+        "transactions: commit"
+    );
+
+    let tx = persistence.begin().await?;
+    tx.delete(collection, Query::builder().build()).await?;
+    drop(tx);
+    assert_eq!(
+        all_ids(persistence, collection).await?,
+        vec![1, 2],
+        "transactions: dropped unfinished"
+    );
+
+    Ok(())
+}
+
+/// Empty results, a collection that doesn't exist, and field names that
+/// would break out of a SQL string if they weren't bound as parameters.
+pub async fn check_edge_cases(persistence: &dyn Persistence, collection: &str) -> Result<()> {
+    seed(persistence, collection, vec![]).await?;
+    assert_eq!(
+        persistence.find(collection, None).await?,
+        Vec::<Value>::new()
+    );
+    assert_eq!(
+        persistence.find_one(collection, None).await?,
+        None,
+        "edge cases: empty"
+    );
+    assert_eq!(persistence.count(collection, None).await?, 0);
+
+    seed(persistence, collection, vec![json!({"id": 1})]).await?;
+    let none = Query::builder().eq("id", json!(2)).build();
+    assert_eq!(
+        persistence.find_one(collection, Some(none)).await?,
+        None,
+        "edge cases: no match"
+    );
+
+    // A collection never written to reads as empty, and the first write
+    // creates it.
+    let missing = format!("{}_missing", collection);
+    assert!(
+        persistence.find(&missing, None).await?.is_empty(),
+        "edge cases: missing collection"
+    );
+    assert_eq!(
+        persistence.find_one(&missing, None).await?,
+        None,
+        "edge cases: missing collection"
+    );
+    assert_eq!(persistence.count(&missing, None).await?, 0);
+    persistence.insert(&missing, json!({"id": 1})).await?;
+    assert_eq!(persistence.count(&missing, None).await?, 1);
+
+    for key in [
+        "'); DROP TABLE x; --",
+        "\" OR 1=1 --",
+        "a?b",
+        "$1",
+        "{a,b}",
+        "back\\slash",
+        "🦀",
+    ] {
+        seed(
+            persistence,
+            collection,
+            vec![
+                json!({"id": 1, key: "x"}),
+                json!({"id": 2, key: "y"}),
+                json!({"id": 3, key: {key: "y"}}),
+            ],
+        )
+        .await?;
+        let query = Query::builder().eq(key, json!("y")).order_by(key).build();
+        assert_eq!(
+            ids(persistence, collection, Some(query)).await?,
+            vec![2],
+            "edge cases: key {}",
+            key
+        );
+        let nested = format!("{}.{}", key, key);
+        let query = Query::builder()
+            .eq(&nested, json!("y"))
+            .order_by_desc(&nested)
+            .build();
+        assert_eq!(
+            ids(persistence, collection, Some(query)).await?,
+            vec![3],
+            "edge cases: nested key {}",
+            key
+        );
+    }
+
+    Ok(())
+}
+
+/// Empties the collection and inserts `docs`.
+async fn seed(persistence: &dyn Persistence, collection: &str, docs: Vec<Value>) -> Result<()> {
+    persistence
+        .delete(collection, Query::builder().build())
+        .await?;
+    for doc in docs {
+        persistence.insert(collection, doc).await?;
+    }
+    Ok(())
+}
+
+/// Checks each query selects the documents with the expected ids, and that
+/// `count` and `exists` agree. Without a sort the order is the backend's, so
+/// the ids are compared sorted.
+async fn check_cases(
+    persistence: &dyn Persistence,
+    collection: &str,
+    section: &str,
+    cases: Vec<(Query, Vec<i64>)>,
+) -> Result<()> {
+    for (query, expected) in cases {
+        let mut found = ids(persistence, collection, Some(query.clone())).await?;
+        found.sort();
+        assert_eq!(found, expected, "{}: {:?}", section, query.filter);
+        let count = persistence.count(collection, Some(query.clone())).await?;
+        assert_eq!(count, expected.len() as u64, "{}: count", section);
+        let exists = persistence.exists(collection, Some(query.clone())).await?;
+        assert_eq!(exists, !expected.is_empty(), "{}: exists", section);
+    }
+    Ok(())
+}
+
+async fn ids(
+    persistence: &dyn Persistence,
+    collection: &str,
+    query: Option<Query>,
+) -> Result<Vec<i64>> {
+    let docs = persistence.find(collection, query).await?;
+    Ok(docs.iter().map(id_of).collect())
+}
+
+/// The ids of the whole collection, sorted.
+async fn all_ids(persistence: &dyn Persistence, collection: &str) -> Result<Vec<i64>> {
+    let mut ids = ids(persistence, collection, None).await?;
+    ids.sort();
+    Ok(ids)
+}
+
+fn id_of(doc: &Value) -> i64 {
+    doc["id"]
+        .as_i64()
+        .unwrap_or_else(|| panic!("document without an integer id: {}", doc))
+}
+
+fn by_id(id: i64) -> Query {
+    Query::builder().eq("id", json!(id)).build()
+}
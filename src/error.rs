@@ -0,0 +1,129 @@
+use std::fmt;
+
+use crate::schema::FieldError;
+use crate::store::VersionConflict;
+
+/// The ways an operation of the crate fails, for callers to match on.
+#[derive(Debug)]
+pub enum StoreError {
+    /// A collection that doesn't exist in the backend, such as a table that
+    /// was never created.
+    NotFound(String),
+    /// A save that lost to a concurrent one, see [`VersionConflict`].
+    Conflict(VersionConflict),
+    /// A query, update, aggregation, cursor, collection name or JSON Schema
+    /// that can't be used as given.
+    InvalidQuery(String),
+    /// A document, query or update that doesn't conform to the collection's
+    /// schema, with every field at fault, see [`crate::schema::Schema`].
+    Validation(Vec<FieldError>),
+    /// An operator applied to a value it has no meaning for, or a feature the
+    /// backend doesn't have, such as sequences or nested transactions.
+    UnsupportedOperator(String),
+    /// A record that doesn't convert to or from its JSON document.
+    Deserialize(serde_json::Error),
+    /// The backend failed, or was used in a state it can't serve.
+    Backend(Box<dyn std::error::Error + Send + Sync>),
+}
+
+pub type Result<T, E = StoreError> = std::result::Result<T, E>;
+
+impl StoreError {
+    /// A `Deserialize` error for a record that serde converted but that isn't
+    /// the document the store needs, such as one that isn't an object.
+    pub(crate) fn document(message: impl fmt::Display) -> Self {
+        StoreError::Deserialize(serde::de::Error::custom(message))
+    }
+
+    pub(crate) fn backend(message: impl Into<String>) -> Self {
+        StoreError::Backend(message.into().into())
+    }
+}
+
+impl fmt::Display for StoreError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StoreError::NotFound(message) => write!(f, "not found: {}", message),
+            StoreError::Conflict(conflict) => write!(f, "conflict: {}", conflict),
+            StoreError::InvalidQuery(message) => write!(f, "invalid query: {}", message),
+            StoreError::Validation(errors) => {
+                let errors: Vec<String> = errors.iter().map(FieldError::to_string).collect();
+                write!(f, "validation failed: {}", errors.join("; "))
+            }
+            StoreError::UnsupportedOperator(message) => write!(f, "unsupported: {}", message),
+            StoreError::Deserialize(err) => write!(f, "invalid document: {}", err),
+            StoreError::Backend(err) => write!(f, "backend error: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for StoreError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            StoreError::Conflict(conflict) => Some(conflict),
+            StoreError::Deserialize(err) => Some(err),
+            StoreError::Backend(err) => Some(err.as_ref()),
+            _ => None,
+        }
+    }
+}
+
+impl From<VersionConflict> for StoreError {
+    fn from(conflict: VersionConflict) -> Self {
+        StoreError::Conflict(conflict)
+    }
+}
+
+impl From<serde_json::Error> for StoreError {
+    fn from(err: serde_json::Error) -> Self {
+        StoreError::Deserialize(err)
+    }
+}
+
+/// A missing table is `NotFound`; every other database error is `Backend`.
+impl From<tokio_postgres::Error> for StoreError {
+    fn from(err: tokio_postgres::Error) -> Self {
+        match err.as_db_error() {
+            Some(db) if *db.code() == tokio_postgres::error::SqlState::UNDEFINED_TABLE => {
+                StoreError::NotFound(db.message().to_string())
+            }
+            _ => StoreError::Backend(Box::new(err)),
+        }
+    }
+}
+
+impl From<bb8::RunError<tokio_postgres::Error>> for StoreError {
+    fn from(err: bb8::RunError<tokio_postgres::Error>) -> Self {
+        match err {
+            bb8::RunError::User(err) => err.into(),
+            bb8::RunError::TimedOut => StoreError::backend("timed out waiting for a connection"),
+        }
+    }
+}
+
+/// Like Postgres, a missing table is `NotFound`. SQLite only tells it apart by
+/// its message.
+impl From<rusqlite::Error> for StoreError {
+    fn from(err: rusqlite::Error) -> Self {
+        match &err {
+            rusqlite::Error::SqliteFailure(_, Some(message))
+                if message.starts_with("no such table") =>
+            {
+                StoreError::NotFound(message.clone())
+            }
+            _ => StoreError::Backend(Box::new(err)),
+        }
+    }
+}
+
+impl From<tokio::task::JoinError> for StoreError {
+    fn from(err: tokio::task::JoinError) -> Self {
+        StoreError::Backend(Box::new(err))
+    }
+}
+
+impl From<std::io::Error> for StoreError {
+    fn from(err: std::io::Error) -> Self {
+        StoreError::Backend(Box::new(err))
+    }
+}
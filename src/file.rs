@@ -0,0 +1,980 @@
+use std::{
+    collections::{HashMap, HashSet},
+    io::Write,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{
+    aggregate::Aggregation,
+    error::{Result, StoreError},
+    index::Index,
+    memory::{
+        delete_at, matching_positions, patched_at, push_record, replace_at, Records,
+        TestPersistence,
+    },
+    query::Query,
+    sql::quote_identifier,
+    store::{Data, DataStream, Persistence, Transaction},
+    update::Update,
+};
+
+fn update_records(records: &mut [Data], query: &Query, record: &Data) -> Result<u64> {
+    let mut updated = 0;
+    for existing in records.iter_mut() {
+        if query.matches(existing)? {
+            *existing = record.clone();
+            updated += 1;
+        }
+    }
+    Ok(updated)
+}
+
+fn patch_records(records: &mut [Data], query: &Query, update: &Update) -> Result<u64> {
+    let mut modified = 0;
+    for record in records.iter_mut() {
+        if query.matches(record)? && update.apply(record)? {
+            modified += 1;
+        }
+    }
+    Ok(modified)
+}
+
+/// Matches every record before removing any, so a query that fails leaves
+/// them all in place.
+fn delete_records(records: &mut Vec<Data>, query: &Query) -> Result<u64> {
+    let matched = records
+        .iter()
+        .map(|record| query.matches(record))
+        .collect::<Result<Vec<bool>>>()?;
+    let before = records.len();
+    let mut matched = matched.into_iter();
+    records.retain(|_| !matched.next().unwrap_or(false));
+    Ok((before - records.len()) as u64)
+}
+
+/// How many lines a collection's log holds before `FilePersistence` considers
+/// compacting it, and how many times its number of documents it must reach.
+const COMPACT_AFTER_ENTRIES: usize = 1000;
+const COMPACT_RATIO: usize = 2;
+
+/// The file of a `FilePersistence` directory that lists the transactions
+/// which wrote to several collections and were committed, one id per line.
+const COMMITS_FILE: &str = "commits.log";
+
+/// A line of a `FilePersistence` log: a write as it was applied, or the new
+/// value of the collection's id counter.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "op", rename_all = "camelCase")]
+enum LogEntry {
+    Insert {
+        record: Data,
+    },
+    Update {
+        query: Query,
+        record: Data,
+    },
+    Patch {
+        query: Query,
+        update: Update,
+    },
+    Upsert {
+        query: Query,
+        record: Data,
+    },
+    InsertIfAbsent {
+        query: Query,
+        record: Data,
+    },
+    Delete {
+        query: Query,
+    },
+    Counter {
+        value: i64,
+    },
+    /// The writes a transaction made to the collection, replayed all at once.
+    /// When it wrote to other collections too, it carries an id and is only
+    /// replayed once that id is in the commits file.
+    Commit {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        transaction: Option<String>,
+        entries: Vec<LogEntry>,
+    },
+}
+
+impl LogEntry {
+    /// Applies a write to a collection's records, returning how many it
+    /// changed. A write that fails may have changed some of them already.
+    fn apply(&self, records: &mut Vec<Data>) -> Result<u64> {
+        match self {
+            LogEntry::Insert { record } => {
+                records.push(record.clone());
+                Ok(1)
+            }
+            LogEntry::Update { query, record } => update_records(records, query, record),
+            LogEntry::Patch { query, update } => {
+                update.check()?;
+                patch_records(records, query, update)
+            }
+            LogEntry::Upsert { query, record } => {
+                if update_records(records, query, record)? == 0 {
+                    records.push(record.clone());
+                }
+                Ok(1)
+            }
+            LogEntry::InsertIfAbsent { query, record } => {
+                for existing in records.iter() {
+                    if query.matches(existing)? {
+                        return Ok(0);
+                    }
+                }
+                records.push(record.clone());
+                Ok(1)
+            }
+            LogEntry::Delete { query } => delete_records(records, query),
+            LogEntry::Counter { .. } => Ok(0),
+            LogEntry::Commit { entries, .. } => {
+                let mut changed = 0;
+                for entry in entries {
+                    changed += entry.apply(records)?;
+                }
+                Ok(changed)
+            }
+        }
+    }
+}
+
+/// The changes a write makes to a collection's records, worked out before it
+/// is logged and applied in place once it is, which can't fail.
+enum Staged {
+    Insert(Data),
+    Replace(Vec<(usize, Data)>),
+    Delete(Vec<usize>),
+}
+
+impl Staged {
+    fn changed(&self) -> u64 {
+        match self {
+            Staged::Insert(_) => 1,
+            Staged::Replace(replaced) => replaced.len() as u64,
+            Staged::Delete(positions) => positions.len() as u64,
+        }
+    }
+}
+
+/// The open log of a collection.
+struct FileLog {
+    file: Arc<std::fs::File>,
+    /// Lines in the file, to tell when compacting it pays off.
+    entries: usize,
+}
+
+type FileLogs = HashMap<String, FileLog>;
+
+/// Documents in a directory, one append-only log of JSON lines per collection.
+/// Every write is appended to its collection's log and synced before it is
+/// visible, and the logs are replayed on open; a transaction's writes are
+/// replayed all or not at all. Reads are served from memory
+/// like `TestPersistence`. A log that has grown to several times its number
+/// of documents is rewritten to hold just them.
+#[derive(Clone)]
+pub struct FilePersistence {
+    dir: PathBuf,
+    memory: TestPersistence,
+    logs: Arc<tokio::sync::Mutex<FileLogs>>,
+    compact_after: usize,
+    /// Set on the handles `begin` returns. Writes to files wait until the
+    /// transaction finishes, as they do on SQLite.
+    transaction: Option<Arc<FileTransaction>>,
+}
+
+/// An open transaction: it holds the logs, so no one else writes, and keeps
+/// its writes to append them on commit.
+struct FileTransaction {
+    logs: tokio::sync::Mutex<Option<tokio::sync::OwnedMutexGuard<FileLogs>>>,
+    pending: Mutex<Vec<(String, LogEntry)>>,
+    /// The records the transaction was started from, replaced on commit.
+    parent: TestPersistence,
+}
+
+impl FilePersistence {
+    /// Opens the logs in `dir`, creating it if needed, and replays them.
+    pub async fn open(dir: impl Into<PathBuf>) -> Result<Self> {
+        let dir = dir.into();
+        let (memory, logs) = {
+            let dir = dir.clone();
+            tokio::task::spawn_blocking(move || load_logs(&dir)).await??
+        };
+        Ok(Self {
+            dir,
+            memory,
+            logs: Arc::new(tokio::sync::Mutex::new(logs)),
+            compact_after: COMPACT_AFTER_ENTRIES,
+            transaction: None,
+        })
+    }
+
+    /// Rewrites every log to hold just its collection's documents and counter.
+    pub async fn compact(&self) -> Result<()> {
+        if self.transaction.is_some() {
+            return Err(StoreError::UnsupportedOperator(
+                "logs can't be compacted in a transaction".to_string(),
+            ));
+        }
+        let mut logs = self.logs.lock().await;
+        let collections: Vec<String> = logs.keys().cloned().collect();
+        for collection in collections {
+            self.compact_log(&mut logs, &self.memory.records, &collection)
+                .await?;
+        }
+        Ok(())
+    }
+
+    /// Applies and logs a write, returning how many documents it changed.
+    async fn write(&self, collection: &str, entry: LogEntry) -> Result<u64> {
+        quote_identifier(collection)?;
+        if let Some(transaction) = &self.transaction {
+            let slot = transaction.logs.lock().await;
+            if slot.is_none() {
+                return Err(StoreError::backend("transaction already finished"));
+            }
+            let staged = self.stage(collection, &entry)?;
+            let changed = staged.changed();
+            self.publish(collection, staged);
+            if changed > 0 {
+                let mut pending = transaction.pending.lock().unwrap();
+                pending.push((collection.to_string(), entry));
+            }
+            return Ok(changed);
+        }
+
+        // The logs stay locked until the write is applied, so no other write
+        // moves the records it staged.
+        let mut logs = self.logs.lock().await;
+        let staged = self.stage(collection, &entry)?;
+        let changed = staged.changed();
+        if changed > 0 {
+            append_log(&self.dir, &mut logs, collection, &[entry]).await?;
+        }
+        self.publish(collection, staged);
+        self.compact_if_grown(&mut logs, &self.memory.records, collection)
+            .await?;
+        Ok(changed)
+    }
+
+    fn stage(&self, collection: &str, entry: &LogEntry) -> Result<Staged> {
+        if let LogEntry::Patch { update, .. } = entry {
+            update.check()?;
+        }
+        self.memory
+            .read(collection, |records, indexes| match entry {
+                LogEntry::Insert { record } => Ok(Staged::Insert(record.clone())),
+                LogEntry::Update { query, record } => {
+                    let matched = matching_positions(records, indexes, query)?;
+                    let replaced = matched
+                        .into_iter()
+                        .map(|position| (position, record.clone()));
+                    Ok(Staged::Replace(replaced.collect()))
+                }
+                LogEntry::Patch { query, update } => {
+                    let matched = matching_positions(records, indexes, query)?;
+                    Ok(Staged::Replace(patched_at(records, matched, update)?))
+                }
+                LogEntry::Upsert { query, record } => {
+                    let matched = matching_positions(records, indexes, query)?;
+                    if matched.is_empty() {
+                        return Ok(Staged::Insert(record.clone()));
+                    }
+                    let replaced = matched
+                        .into_iter()
+                        .map(|position| (position, record.clone()));
+                    Ok(Staged::Replace(replaced.collect()))
+                }
+                LogEntry::InsertIfAbsent { query, record } => {
+                    if !matching_positions(records, indexes, query)?.is_empty() {
+                        return Ok(Staged::Replace(vec![]));
+                    }
+                    Ok(Staged::Insert(record.clone()))
+                }
+                LogEntry::Delete { query } => {
+                    Ok(Staged::Delete(matching_positions(records, indexes, query)?))
+                }
+                LogEntry::Counter { .. } | LogEntry::Commit { .. } => Err(StoreError::backend(
+                    "only writes of documents can be staged",
+                )),
+            })
+    }
+
+    fn publish(&self, collection: &str, staged: Staged) {
+        let _ = self.memory.write(collection, |records, indexes| {
+            match staged {
+                Staged::Insert(record) => push_record(records, indexes, record),
+                Staged::Replace(replaced) => replace_at(records, indexes, replaced),
+                Staged::Delete(positions) => {
+                    delete_at(records, indexes, positions);
+                }
+            }
+            Ok(())
+        });
+    }
+
+    async fn compact_if_grown(
+        &self,
+        logs: &mut FileLogs,
+        records: &Records,
+        collection: &str,
+    ) -> Result<()> {
+        let Some(log) = logs.get(collection) else {
+            return Ok(());
+        };
+        let documents = records.read().unwrap().get(collection).map_or(0, Vec::len);
+        if log.entries < self.compact_after || log.entries <= COMPACT_RATIO * (documents + 1) {
+            return Ok(());
+        }
+        self.compact_log(logs, records, collection).await
+    }
+
+    async fn compact_log(
+        &self,
+        logs: &mut FileLogs,
+        records: &Records,
+        collection: &str,
+    ) -> Result<()> {
+        let documents = records
+            .read()
+            .unwrap()
+            .get(collection)
+            .cloned()
+            .unwrap_or_default();
+        let counter = self
+            .memory
+            .counters
+            .lock()
+            .unwrap()
+            .get(collection)
+            .copied();
+        let path = log_path(&self.dir, collection);
+        let log =
+            tokio::task::spawn_blocking(move || write_log(&path, documents, counter)).await??;
+        logs.insert(collection.to_string(), log);
+        Ok(())
+    }
+}
+
+fn log_path(dir: &Path, collection: &str) -> PathBuf {
+    dir.join(format!("{}.jsonl", collection))
+}
+
+/// Appends entries to a collection's log, opening it on first use, and syncs
+/// them to disk.
+async fn append_log(
+    dir: &Path,
+    logs: &mut FileLogs,
+    collection: &str,
+    entries: &[LogEntry],
+) -> Result<()> {
+    let mut lines = String::new();
+    for entry in entries {
+        lines.push_str(&serde_json::to_string(entry)?);
+        lines.push('\n');
+    }
+
+    if !logs.contains_key(collection) {
+        let path = log_path(dir, collection);
+        let file = tokio::task::spawn_blocking(move || {
+            std::fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+        })
+        .await??;
+        logs.insert(
+            collection.to_string(),
+            FileLog {
+                file: Arc::new(file),
+                entries: 0,
+            },
+        );
+    }
+    let log = logs.get_mut(collection).unwrap();
+    let file = log.file.clone();
+    tokio::task::spawn_blocking(move || {
+        (&*file).write_all(lines.as_bytes())?;
+        file.sync_data()
+    })
+    .await??;
+    log.entries += entries.len();
+    Ok(())
+}
+
+/// Marks a transaction that wrote to several collections as committed, once
+/// its lines are in their logs.
+async fn append_commit(dir: &Path, id: String) -> Result<()> {
+    let path = dir.join(COMMITS_FILE);
+    tokio::task::spawn_blocking(move || {
+        let mut file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)?;
+        file.write_all(format!("{}\n", id).as_bytes())?;
+        file.sync_data()
+    })
+    .await??;
+    Ok(())
+}
+
+/// The ids of the committed transactions in the commits file, but for a last
+/// line cut short by a crash.
+fn read_commits(path: &Path) -> Result<HashSet<String>> {
+    let text = match std::fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(HashSet::new()),
+        Err(err) => return Err(err.into()),
+    };
+    let complete = text.rfind('\n').map_or("", |end| &text[..end]);
+    Ok(complete.lines().map(String::from).collect())
+}
+
+/// Writes a log holding just `records` and the counter, swapping it in with a
+/// rename so a crash leaves either the old log or the new one.
+fn write_log(path: &Path, records: Vec<Data>, counter: Option<i64>) -> Result<FileLog> {
+    let compacted = path.with_extension("jsonl.compact");
+    let mut writer = std::io::BufWriter::new(std::fs::File::create(&compacted)?);
+    let mut entries = 0;
+    let counter = counter.map(|value| LogEntry::Counter { value });
+    let inserts = records
+        .into_iter()
+        .map(|record| LogEntry::Insert { record });
+    for entry in counter.into_iter().chain(inserts) {
+        serde_json::to_writer(&mut writer, &entry)?;
+        writer.write_all(b"\n")?;
+        entries += 1;
+    }
+    let file = writer.into_inner().map_err(|err| err.into_error())?;
+    file.sync_all()?;
+    std::fs::rename(&compacted, path)?;
+
+    let file = std::fs::OpenOptions::new().append(true).open(path)?;
+    Ok(FileLog {
+        file: Arc::new(file),
+        entries,
+    })
+}
+
+/// Replays every log in `dir`. A last line cut short by a crash was never
+/// acknowledged, so it's dropped, and the log rewritten without it. So are the
+/// lines of transactions missing from the commits file, which then isn't
+/// needed anymore: logs with lines of transactions are rewritten without
+/// them, and the file removed.
+fn load_logs(dir: &Path) -> Result<(TestPersistence, FileLogs)> {
+    std::fs::create_dir_all(dir)?;
+    let commits_path = dir.join(COMMITS_FILE);
+    let commits = read_commits(&commits_path)?;
+    let mut records = HashMap::new();
+    let mut counters = HashMap::new();
+    let mut logs = HashMap::new();
+    for file in std::fs::read_dir(dir)? {
+        let path = file?.path();
+        let collection = match (path.file_stem(), path.extension()) {
+            (Some(stem), Some(extension)) if extension == "jsonl" => stem.to_string_lossy(),
+            _ => continue,
+        };
+        if quote_identifier(&collection).is_err() {
+            continue;
+        }
+
+        let text = std::fs::read_to_string(&path)?;
+        let torn = !text.is_empty() && !text.ends_with('\n');
+        let mut lines: Vec<&str> = text.lines().collect();
+        if torn {
+            lines.pop();
+        }
+        let mut documents = vec![];
+        let mut counter = None;
+        let mut transactions = false;
+        for (number, line) in lines.iter().enumerate() {
+            let entry: LogEntry = serde_json::from_str(line).map_err(|err| {
+                StoreError::backend(format!("{} line {}: {}", path.display(), number + 1, err))
+            })?;
+            match entry {
+                LogEntry::Counter { value } => counter = Some(value),
+                LogEntry::Commit {
+                    transaction: Some(id),
+                    ..
+                } if !commits.contains(&id) => transactions = true,
+                entry => {
+                    transactions |= matches!(
+                        entry,
+                        LogEntry::Commit {
+                            transaction: Some(_),
+                            ..
+                        }
+                    );
+                    entry.apply(&mut documents)?;
+                }
+            }
+        }
+
+        let log = if torn || transactions {
+            write_log(&path, documents.clone(), counter)?
+        } else {
+            let file = std::fs::OpenOptions::new().append(true).open(&path)?;
+            FileLog {
+                file: Arc::new(file),
+                entries: lines.len(),
+            }
+        };
+        if let Some(counter) = counter {
+            counters.insert(collection.to_string(), counter);
+        }
+        records.insert(collection.to_string(), documents);
+        logs.insert(collection.to_string(), log);
+    }
+
+    match std::fs::remove_file(&commits_path) {
+        Err(err) if err.kind() != std::io::ErrorKind::NotFound => return Err(err.into()),
+        _ => {}
+    }
+
+    let memory = TestPersistence::new(records);
+    *memory.counters.lock().unwrap() = counters;
+    Ok((memory, logs))
+}
+
+#[async_trait]
+impl Persistence for FilePersistence {
+    async fn find(&self, collection: &str, query: Option<Query>) -> Result<Vec<Data>> {
+        self.memory.find(collection, query).await
+    }
+
+    async fn find_one(&self, collection: &str, query: Option<Query>) -> Result<Option<Data>> {
+        self.memory.find_one(collection, query).await
+    }
+
+    async fn count(&self, collection: &str, query: Option<Query>) -> Result<u64> {
+        self.memory.count(collection, query).await
+    }
+
+    async fn exists(&self, collection: &str, query: Option<Query>) -> Result<bool> {
+        self.memory.exists(collection, query).await
+    }
+
+    async fn distinct(
+        &self,
+        collection: &str,
+        field: &str,
+        query: Option<Query>,
+    ) -> Result<Vec<Value>> {
+        self.memory.distinct(collection, field, query).await
+    }
+
+    async fn aggregate(&self, collection: &str, aggregation: Aggregation) -> Result<Vec<Value>> {
+        self.memory.aggregate(collection, aggregation).await
+    }
+
+    async fn stream(&self, collection: &str, query: Option<Query>) -> Result<DataStream> {
+        self.memory.stream(collection, query).await
+    }
+
+    async fn insert(&self, collection: &str, record: Data) -> Result<Data> {
+        let entry = LogEntry::Insert {
+            record: record.clone(),
+        };
+        self.write(collection, entry).await?;
+        Ok(record)
+    }
+
+    async fn update(&self, collection: &str, query: Query, record: Data) -> Result<u64> {
+        self.write(collection, LogEntry::Update { query, record })
+            .await
+    }
+
+    async fn patch(&self, collection: &str, query: Query, update: Update) -> Result<u64> {
+        self.write(collection, LogEntry::Patch { query, update })
+            .await
+    }
+
+    async fn upsert(&self, collection: &str, query: Query, record: Data) -> Result<Data> {
+        let entry = LogEntry::Upsert {
+            query,
+            record: record.clone(),
+        };
+        self.write(collection, entry).await?;
+        Ok(record)
+    }
+
+    async fn insert_if_absent(
+        &self,
+        collection: &str,
+        query: Query,
+        record: Data,
+    ) -> Result<Option<Data>> {
+        let entry = LogEntry::InsertIfAbsent {
+            query,
+            record: record.clone(),
+        };
+        let inserted = self.write(collection, entry).await?;
+        Ok((inserted > 0).then_some(record))
+    }
+
+    async fn delete(&self, collection: &str, query: Query) -> Result<u64> {
+        self.write(collection, LogEntry::Delete { query }).await
+    }
+
+    async fn increment_counter(&self, collection: &str) -> Result<i64> {
+        quote_identifier(collection)?;
+        // Counters stay outside of transactions, but an open one holds the
+        // logs, so its handle appends through it.
+        let mut slot;
+        let mut logs;
+        let logs: &mut FileLogs = match &self.transaction {
+            Some(transaction) => {
+                slot = transaction.logs.lock().await;
+                slot.as_mut()
+                    .ok_or_else(|| StoreError::backend("transaction already finished"))?
+            }
+            None => {
+                logs = self.logs.lock().await;
+                &mut logs
+            }
+        };
+
+        let value = {
+            let mut counters = self.memory.counters.lock().unwrap();
+            let counter = counters.entry(collection.to_string()).or_default();
+            *counter += 1;
+            *counter
+        };
+        append_log(&self.dir, logs, collection, &[LogEntry::Counter { value }]).await?;
+        Ok(value)
+    }
+
+    /// Indexes are kept in memory and not logged, so they are created again
+    /// after each open.
+    async fn ensure_collection(&self, collection: &str, indexes: &[Index]) -> Result<()> {
+        quote_identifier(collection)?;
+        self.memory.ensure_collection(collection, indexes).await
+    }
+
+    async fn begin(&self) -> Result<Arc<dyn Transaction>> {
+        if self.transaction.is_some() {
+            return Err(StoreError::UnsupportedOperator(
+                "nested transactions are not supported".to_string(),
+            ));
+        }
+
+        let logs = self.logs.clone().lock_owned().await;
+        Ok(Arc::new(Self {
+            dir: self.dir.clone(),
+            memory: self.memory.snapshot(),
+            logs: self.logs.clone(),
+            compact_after: self.compact_after,
+            transaction: Some(Arc::new(FileTransaction {
+                logs: tokio::sync::Mutex::new(Some(logs)),
+                pending: Mutex::new(vec![]),
+                parent: self.memory.clone(),
+            })),
+        }))
+    }
+}
+
+#[async_trait]
+impl Transaction for FilePersistence {
+    async fn commit(&self) -> Result<()> {
+        let transaction = self
+            .transaction
+            .as_ref()
+            .ok_or_else(|| StoreError::backend("no transaction in progress"))?;
+        let mut logs = transaction
+            .logs
+            .lock()
+            .await
+            .take()
+            .ok_or_else(|| StoreError::backend("transaction already finished"))?;
+
+        // Each collection's writes are appended as one line, in the order they
+        // were made. Those of a transaction that wrote to several collections
+        // only count once its id is in the commits file, so a crash or an
+        // error part way leaves none of them.
+        let pending = std::mem::take(&mut *transaction.pending.lock().unwrap());
+        let mut writes: Vec<(String, Vec<LogEntry>)> = vec![];
+        for (collection, entry) in pending {
+            match writes
+                .iter_mut()
+                .find(|(written, _)| *written == collection)
+            {
+                Some((_, entries)) => entries.push(entry),
+                None => writes.push((collection, vec![entry])),
+            }
+        }
+        let id = (writes.len() > 1).then(|| uuid::Uuid::new_v4().to_string());
+        let collections: Vec<String> = writes
+            .iter()
+            .map(|(collection, _)| collection.clone())
+            .collect();
+        for (collection, entries) in writes {
+            let entry = LogEntry::Commit {
+                transaction: id.clone(),
+                entries,
+            };
+            append_log(&self.dir, &mut logs, &collection, &[entry]).await?;
+        }
+        if let Some(id) = id {
+            append_commit(&self.dir, id).await?;
+        }
+
+        transaction.parent.replace_with(&self.memory);
+        for collection in &collections {
+            self.compact_if_grown(&mut logs, &transaction.parent.records, collection)
+                .await?;
+        }
+        Ok(())
+    }
+
+    async fn rollback(&self) -> Result<()> {
+        let transaction = self
+            .transaction
+            .as_ref()
+            .ok_or_else(|| StoreError::backend("no transaction in progress"))?;
+        transaction
+            .logs
+            .lock()
+            .await
+            .take()
+            .ok_or_else(|| StoreError::backend("transaction already finished"))?;
+        transaction.pending.lock().unwrap().clear();
+        self.memory.records.write().unwrap().clear();
+        self.memory.indexes.write().unwrap().clear();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+
+    use serde_json::json;
+
+    use super::*;
+    use crate::{
+        conformance,
+        memory::tests::{assert_index_maintenance, assert_stream_while_writing},
+        store::{tests::Order, Store},
+    };
+    /// A directory for a `FilePersistence` that no other test uses.
+    fn temp_dir(name: &str) -> std::path::PathBuf {
+        env::temp_dir().join(format!("store-{}-{}", name, uuid::Uuid::new_v4()))
+    }
+
+    #[tokio::test]
+    async fn test_conformance_with_files() -> anyhow::Result<()> {
+        let dir = temp_dir("conformance");
+        let persistence = FilePersistence::open(&dir).await?;
+        conformance::check(&persistence, "conformance").await?;
+        std::fs::remove_dir_all(dir)?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_transaction_keeps_outside_indexes_with_files() -> anyhow::Result<()> {
+        let dir = temp_dir("transaction-indexes");
+        let files = FilePersistence::open(&dir).await?;
+        let tx = files.begin().await?;
+        tx.insert("docs", json!({"id": 1, "v": 1})).await?;
+        files.ensure_collection("docs", &[Index::hash("v")]).await?;
+        tx.commit().await?;
+
+        let indexes: Vec<Index> = files.memory.indexes.read().unwrap()["docs"]
+            .iter()
+            .map(|index| index.index.clone())
+            .collect();
+        assert_eq!(indexes, [Index::hash("v")]);
+        let query = Query::builder().eq("v", json!(1)).build();
+        assert_eq!(files.count("docs", Some(query)).await?, 1);
+        std::fs::remove_dir_all(dir)?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_file_replay() -> anyhow::Result<()> {
+        let dir = temp_dir("replay");
+        let files = FilePersistence::open(&dir).await?;
+        for id in 1..=5 {
+            files.insert("docs", json!({"id": id, "n": id})).await?;
+        }
+        let by_id = |id: i64| Query::builder().eq("id", json!(id)).build();
+        files
+            .update("docs", by_id(1), json!({"id": 1, "n": 10}))
+            .await?;
+        let update = Update::builder().inc("n", 1).build();
+        let query = Query::builder().gt("n", json!(3)).build();
+        assert_eq!(files.patch("docs", query, update).await?, 3);
+        files.upsert("docs", by_id(6), json!({"id": 6})).await?;
+        files.delete("docs", by_id(2)).await?;
+
+        let store = Store::new(files.clone());
+        store
+            .insert(&Order {
+                id: None,
+                total: 1.0,
+            })
+            .await?;
+        let tx = files.begin().await?;
+        tx.insert("docs", json!({"id": 7})).await?;
+        tx.rollback().await?;
+        let tx = files.begin().await?;
+        tx.insert("docs", json!({"id": 8})).await?;
+        tx.commit().await?;
+
+        let docs = files.find("docs", None).await?;
+        assert_eq!(
+            docs,
+            vec![
+                json!({"id": 1, "n": 11}),
+                json!({"id": 3, "n": 3}),
+                json!({"id": 4, "n": 5}),
+                json!({"id": 5, "n": 6}),
+                json!({"id": 6}),
+                json!({"id": 8}),
+            ]
+        );
+        drop(store);
+        drop(files);
+
+        let reopened = FilePersistence::open(&dir).await?;
+        assert_eq!(reopened.find("docs", None).await?, docs);
+        assert_eq!(reopened.find("orders", None).await?.len(), 1);
+        assert_eq!(reopened.increment_counter("orders").await?, 2);
+
+        // a write cut short by a crash is dropped, and the log repaired
+        let path = dir.join("docs.jsonl");
+        let mut log = std::fs::OpenOptions::new().append(true).open(&path)?;
+        log.write_all(br#"{"op":"insert","record":{"id":9"#)?;
+        drop(reopened);
+        let reopened = FilePersistence::open(&dir).await?;
+        assert_eq!(reopened.find("docs", None).await?, docs);
+        reopened.insert("docs", json!({"id": 9})).await?;
+        let reopened = FilePersistence::open(&dir).await?;
+        assert_eq!(reopened.count("docs", None).await?, 7);
+
+        std::fs::write(dir.join("broken.jsonl"), "not json\n")?;
+        assert!(FilePersistence::open(&dir).await.is_err());
+
+        std::fs::remove_dir_all(dir)?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_file_commits() -> anyhow::Result<()> {
+        let dir = temp_dir("commits");
+        let files = FilePersistence::open(&dir).await?;
+        let tx = files.begin().await?;
+        tx.insert("docs", json!({"id": 1})).await?;
+        tx.insert("docs", json!({"id": 2})).await?;
+        tx.insert("others", json!({"id": 1})).await?;
+        tx.commit().await?;
+        let tx = files.begin().await?;
+        tx.insert("docs", json!({"id": 3})).await?;
+        tx.commit().await?;
+        drop(files);
+
+        // each collection's writes are on one line
+        let lines = |collection: &str| -> anyhow::Result<usize> {
+            Ok(
+                std::fs::read_to_string(dir.join(format!("{}.jsonl", collection)))?
+                    .lines()
+                    .count(),
+            )
+        };
+        assert_eq!((lines("docs")?, lines("others")?), (2, 1));
+        assert!(dir.join(COMMITS_FILE).exists());
+
+        // a transaction cut short before it was marked committed, or before
+        // its other collection's line was written, is left out entirely
+        let commit = |id: &str, record: Value| {
+            let entry = LogEntry::Commit {
+                transaction: Some(id.to_string()),
+                entries: vec![LogEntry::Insert { record }],
+            };
+            format!("{}\n", serde_json::to_string(&entry).unwrap())
+        };
+        let mut docs = std::fs::OpenOptions::new()
+            .append(true)
+            .open(dir.join("docs.jsonl"))?;
+        docs.write_all(commit("lost", json!({"id": 4})).as_bytes())?;
+
+        let reopened = FilePersistence::open(&dir).await?;
+        assert_eq!(reopened.count("docs", None).await?, 3);
+        assert_eq!(reopened.count("others", None).await?, 1);
+        // the logs were rewritten without transaction ids, which the commits
+        // file is then no longer needed for
+        assert!(!dir.join(COMMITS_FILE).exists());
+        drop(reopened);
+        let reopened = FilePersistence::open(&dir).await?;
+        assert_eq!(reopened.count("docs", None).await?, 3);
+        assert_eq!(reopened.count("others", None).await?, 1);
+
+        std::fs::remove_dir_all(dir)?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_file_compaction() -> anyhow::Result<()> {
+        let dir = temp_dir("compaction");
+        let mut files = FilePersistence::open(&dir).await?;
+        files.compact_after = 10;
+        let lines = || -> anyhow::Result<usize> {
+            Ok(std::fs::read_to_string(dir.join("counters.jsonl"))?
+                .lines()
+                .count())
+        };
+
+        files.insert("counters", json!({"id": 1, "n": 0})).await?;
+        files.insert("counters", json!({"id": 2, "n": 0})).await?;
+        let query = Query::builder().eq("id", json!(1)).build();
+        for _ in 0..30 {
+            let update = Update::builder().inc("n", 1).build();
+            files.patch("counters", query.clone(), update).await?;
+        }
+        assert!(lines()? < 10, "{} lines", lines()?);
+        files.increment_counter("counters").await?;
+        files.delete("counters", query).await?;
+        files.compact().await?;
+        assert_eq!(lines()?, 2);
+
+        let reopened = FilePersistence::open(&dir).await?;
+        assert_eq!(
+            reopened.find("counters", None).await?,
+            vec![json!({"id": 2, "n": 0})]
+        );
+        assert_eq!(reopened.increment_counter("counters").await?, 2);
+
+        std::fs::remove_dir_all(dir)?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_index_maintenance_with_files() -> anyhow::Result<()> {
+        let dir = temp_dir("indexes");
+        let indexed = FilePersistence::open(&dir).await?;
+        let indexes = ["id", "a", "b"].map(|field| [Index::hash(field), Index::ordered(field)]);
+        indexed
+            .ensure_collection("maintained", indexes.as_flattened())
+            .await?;
+        assert_index_maintenance(&indexed).await?;
+        std::fs::remove_dir_all(dir)?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_stream_while_writing_with_files() -> anyhow::Result<()> {
+        let dir = temp_dir("stream");
+        assert_stream_while_writing(&FilePersistence::open(&dir).await?).await?;
+        std::fs::remove_dir_all(dir)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,113 @@
+use serde_json::Value;
+
+use crate::error::Result;
+use crate::store::Persistence;
+
+/// How a collection gets ids for records that are stored without one.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub enum IdStrategy {
+    /// Records bring their own id; nothing is generated.
+    #[default]
+    Provided,
+    /// A random UUID, as a hyphenated string.
+    UuidV4,
+    /// A time-ordered UUID, as a hyphenated string.
+    UuidV7,
+    /// A time-ordered ULID, as a 26 character string.
+    Ulid,
+    /// A number from a counter kept by the backend for each collection,
+    /// starting at 1.
+    Sequential,
+    /// The next value of the named Postgres sequence.
+    Sequence(String),
+}
+
+impl IdStrategy {
+    /// Generates a new id for `collection`, or `None` for `Provided`.
+    pub async fn generate(
+        &self,
+        persistence: &dyn Persistence,
+        collection: &str,
+    ) -> Result<Option<Value>> {
+        let id = match self {
+            IdStrategy::Provided => return Ok(None),
+            IdStrategy::UuidV4 => Value::String(uuid::Uuid::new_v4().to_string()),
+            IdStrategy::UuidV7 => Value::String(uuid::Uuid::now_v7().to_string()),
+            IdStrategy::Ulid => Value::String(ulid::Ulid::new().to_string()),
+            IdStrategy::Sequential => persistence.increment_counter(collection).await?.into(),
+            IdStrategy::Sequence(sequence) => {
+                persistence.next_sequence_value(sequence).await?.into()
+            }
+        };
+        Ok(Some(id))
+    }
+}
+
+/// Whether an id still has to be generated: it is missing, `null` or an empty
+/// string.
+pub fn is_unset(id: Option<&Value>) -> bool {
+    match id {
+        None | Some(Value::Null) => true,
+        Some(Value::String(id)) => id.is_empty(),
+        Some(_) => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn test_is_unset() {
+        assert!(is_unset(None));
+        assert!(is_unset(Some(&json!(null))));
+        assert!(is_unset(Some(&json!(""))));
+        assert!(!is_unset(Some(&json!("a"))));
+        assert!(!is_unset(Some(&json!(0))));
+    }
+
+    #[tokio::test]
+    async fn test_generate() -> anyhow::Result<()> {
+        let persistence = crate::store::TestPersistence::new(HashMap::new());
+
+        assert_eq!(
+            IdStrategy::Provided.generate(&persistence, "users").await?,
+            None
+        );
+
+        let id = IdStrategy::UuidV4.generate(&persistence, "users").await?;
+        let id = uuid::Uuid::parse_str(id.unwrap().as_str().unwrap())?;
+        assert_eq!(id.get_version_num(), 4);
+
+        // v7 UUIDs sort by creation time
+        let mut ids = vec![];
+        for _ in 0..3 {
+            let id = IdStrategy::UuidV7.generate(&persistence, "users").await?;
+            ids.push(id.unwrap().as_str().unwrap().to_string());
+            std::thread::sleep(std::time::Duration::from_millis(2));
+        }
+        assert!(ids.windows(2).all(|pair| pair[0] < pair[1]));
+        let id = IdStrategy::Ulid.generate(&persistence, "users").await?;
+        assert_eq!(id.unwrap().as_str().unwrap().len(), 26);
+
+        for expected in 1..=3 {
+            let id = IdStrategy::Sequential
+                .generate(&persistence, "users")
+                .await?;
+            assert_eq!(id, Some(json!(expected)));
+        }
+        let id = IdStrategy::Sequential
+            .generate(&persistence, "products")
+            .await?;
+        assert_eq!(id, Some(json!(1)));
+
+        let sequence = IdStrategy::Sequence("users_id_seq".to_string());
+        assert!(sequence.generate(&persistence, "users").await.is_err());
+
+        Ok(())
+    }
+}
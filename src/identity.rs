@@ -1,67 +1,63 @@
 use serde_json::Value;
 
-use crate::query::Query;
+use crate::{error::Result, query::Query};
 
+/// Derives `Identity` from the field marked `#[store(id)]`, or the field named
+/// `id`, keyed by the name serde serializes it under. A field marked
+/// `#[store(version)]` becomes the `version_key`.
+pub use store_derive::Identity;
+
+/// How a document is identified within its collection.
+///
+/// # Example
+///
+/// ```
+/// use serde::{Deserialize, Serialize};
+/// use serde_json::json;
+/// use store::identity::Identity;
+///
+/// #[derive(Serialize, Deserialize, Identity)]
+/// struct User {
+///     #[serde(rename = "_id")]
+///     #[store(id)]
+///     user_id: String,
+///     name: String,
+/// }
+///
+/// let user = User {
+///     user_id: "123".to_string(),
+///     name: "John".to_string(),
+/// };
+/// assert_eq!(user.key(), "_id");
+/// assert_eq!(User::identity_key(), "_id");
+/// assert_eq!(user.id()?, json!("123"));
+/// assert_eq!(user.identity()?, json!({"_id": "123"}));
+/// assert!(User::identity_query(json!("123")).matches(&json!({"_id": "123"}))?);
+/// # Ok::<(), store::error::StoreError>(())
+/// ```
 pub trait Identity {
+    /// The query that selects the document with the given id.
     fn identity_query(id: Value) -> Query;
 
-    /// The attribute that uniquely identifies the object when serialized.
-    ///
-    /// # Example
-    ///
-    /// ```
-    /// #[derive(Serialize, Deserialize)]
-    /// struct User {
-    ///     #[serde(rename = "_id")]
-    ///     id: String,
-    ///     name: String,
-    /// }
-    ///
-    /// impl Identity for User {
-    ///     fn identity() -> &'static str {
-    ///         "_id"
-    ///     }
-    /// }
-    /// ```
+    /// The attribute that uniquely identifies the object when serialized, such
+    /// as `_id` for a field renamed by serde.
     fn key(&self) -> &str;
 
-    /// The value of the identity attribute.
-    ///
-    /// # Example
-    ///
-    /// ```
-    /// #[derive(Serialize, Deserialize)]
-    /// struct User {
-    ///    #[serde(rename = "_id")]
-    ///    id: String,
-    ///    name: String,
-    /// }
-    ///
-    /// impl Identity for User {
-    ///    fn id() -> &'static str {
-    ///        "_id"
-    ///    }
-    /// }
-    fn id(&self) -> Value;
+    /// The same attribute as `key`, for when there is no instance at hand.
+    fn identity_key() -> &'static str;
+
+    /// The value of the identity attribute. Fails when it doesn't serialize
+    /// to JSON, such as a map whose keys aren't strings.
+    fn id(&self) -> Result<Value>;
+
+    /// The identity key-pair, such as `{"_id": "123"}`.
+    fn identity(&self) -> Result<Value>;
 
-    /// The representation of how the identity key-pair should be.
-    ///
-    /// # Example
-    ///
-    /// ```
-    /// #[derive(Serialize, Deserialize)]
-    /// struct User {
-    ///    #[serde(rename = "_id")]
-    ///   id: String,
-    ///   name: String,
-    /// }
-    ///
-    /// impl Identity for User {
-    ///    fn identity() -> Value {
-    ///       json!({
-    ///         "_id": self.id
-    ///       })
-    ///    }
-    /// }
-    fn identity(&self) -> Value;
+    /// The attribute holding the document's version, an integer `Store` sets
+    /// to 1 on insert and bumps on every save. A save whose version isn't the
+    /// stored one fails with `store::VersionConflict` instead of overwriting a
+    /// change it hasn't seen. `None`, the default, leaves documents unversioned.
+    fn version_key() -> Option<&'static str> {
+        None
+    }
 }
@@ -0,0 +1,465 @@
+use std::{
+    cmp::Ordering,
+    collections::{BTreeMap, BTreeSet, HashMap},
+    ops::Bound,
+};
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::error::Result;
+use crate::query::{
+    resolve_path, Query, QueryFilter, QueryFilterItem, QueryFilterOperation, QueryFilterOperator,
+    QuerySortDirection, ANY_ELEMENT,
+};
+
+/// An index on a field of a collection's documents, a dotted path as in
+/// queries. A path with `*` segments indexes every value it reaches.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Index {
+    pub field: String,
+    pub kind: IndexKind,
+}
+
+/// How an index organizes its values: `hash` answers `eq` and `in`, and
+/// `ordered` answers those as well as ranges and sorting. `gin` is a Postgres
+/// GIN index for containment (`@>`) on the field, which queries written
+/// against the table directly use; the in-memory backend keeps it as a hash
+/// index.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum IndexKind {
+    Hash,
+    Ordered,
+    Gin,
+}
+
+impl Index {
+    pub fn hash(field: &str) -> Index {
+        Index {
+            field: field.to_string(),
+            kind: IndexKind::Hash,
+        }
+    }
+
+    pub fn ordered(field: &str) -> Index {
+        Index {
+            field: field.to_string(),
+            kind: IndexKind::Ordered,
+        }
+    }
+
+    pub fn gin(field: &str) -> Index {
+        Index {
+            field: field.to_string(),
+            kind: IndexKind::Gin,
+        }
+    }
+}
+
+/// An index of the in-memory backend, mapping the values of its field to the
+/// positions of the records holding them.
+///
+/// Keys are coarser than the query semantics: numbers are keyed by their
+/// `f64` value, and an ordered index only tells arrays and objects apart by
+/// type. Lookups therefore return candidates, which are checked against the
+/// query like a scan would.
+#[derive(Debug, Clone)]
+pub(crate) struct MemoryIndex {
+    pub index: Index,
+    entries: Entries,
+    /// Positions of the records without a value for the field, which sort
+    /// with `null`.
+    missing: BTreeSet<usize>,
+}
+
+#[derive(Debug, Clone)]
+enum Entries {
+    Hash(HashMap<HashKey, Vec<usize>>),
+    Ordered(BTreeMap<OrderedKey, Vec<usize>>),
+}
+
+/// A value as a hash index keys it, with numbers equal in value keyed alike.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum HashKey {
+    Null,
+    Bool(bool),
+    Number(u64),
+    String(String),
+    Array(Vec<HashKey>),
+    Object(Vec<(String, HashKey)>),
+}
+
+impl HashKey {
+    fn new(value: &Value) -> HashKey {
+        match value {
+            Value::Null => HashKey::Null,
+            Value::Bool(value) => HashKey::Bool(*value),
+            Value::Number(number) => HashKey::Number(number_key(number).0.to_bits()),
+            Value::String(value) => HashKey::String(value.clone()),
+            Value::Array(values) => HashKey::Array(values.iter().map(HashKey::new).collect()),
+            Value::Object(object) => {
+                let mut fields: Vec<(String, HashKey)> = object
+                    .iter()
+                    .map(|(key, value)| (key.clone(), HashKey::new(value)))
+                    .collect();
+                fields.sort_by(|a, b| a.0.cmp(&b.0));
+                HashKey::Object(fields)
+            }
+        }
+    }
+}
+
+/// A value as an ordered index keys it, in the sort order of queries. Its
+/// variants are declared in that order.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+enum OrderedKey {
+    Null,
+    Number(Float),
+    String(String),
+    False,
+    True,
+    Array,
+    Object,
+}
+
+impl OrderedKey {
+    fn new(value: &Value) -> OrderedKey {
+        match value {
+            Value::Null => OrderedKey::Null,
+            Value::Number(number) => OrderedKey::Number(number_key(number)),
+            Value::String(value) => OrderedKey::String(value.clone()),
+            Value::Bool(false) => OrderedKey::False,
+            Value::Bool(true) => OrderedKey::True,
+            Value::Array(_) => OrderedKey::Array,
+            Value::Object(_) => OrderedKey::Object,
+        }
+    }
+}
+
+/// A number's `f64` value, totally ordered. Converting to `f64` never reverses
+/// the order of two numbers, so a range over these keys taken inclusively
+/// holds every number in the exact range.
+#[derive(Debug, Clone, Copy)]
+struct Float(f64);
+
+impl PartialEq for Float {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Float {}
+
+impl PartialOrd for Float {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Float {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.0.total_cmp(&other.0)
+    }
+}
+
+fn number_key(number: &serde_json::Number) -> Float {
+    let value = number.as_f64().unwrap_or(0.0);
+    // -0.0 equals 0.0 in queries
+    Float(if value == 0.0 { 0.0 } else { value })
+}
+
+impl MemoryIndex {
+    pub fn build(index: Index, records: &[Value]) -> MemoryIndex {
+        let entries = match index.kind {
+            IndexKind::Hash | IndexKind::Gin => Entries::Hash(HashMap::new()),
+            IndexKind::Ordered => Entries::Ordered(BTreeMap::new()),
+        };
+        let mut built = MemoryIndex {
+            index,
+            entries,
+            missing: BTreeSet::new(),
+        };
+        for (position, record) in records.iter().enumerate() {
+            built.insert(position, record);
+        }
+        built
+    }
+
+    pub fn insert(&mut self, position: usize, record: &Value) {
+        let values = resolve_path(record, &self.index.field);
+        if values.is_empty() {
+            self.missing.insert(position);
+        }
+        for value in values {
+            let positions = match &mut self.entries {
+                Entries::Hash(entries) => entries.entry(HashKey::new(value)).or_default(),
+                Entries::Ordered(entries) => entries.entry(OrderedKey::new(value)).or_default(),
+            };
+            if let Err(at) = positions.binary_search(&position) {
+                positions.insert(at, position);
+            }
+        }
+    }
+
+    /// Removes the entries `insert` made for the record at `position`.
+    pub fn remove(&mut self, position: usize, record: &Value) {
+        self.missing.remove(&position);
+        for value in resolve_path(record, &self.index.field) {
+            let remove = |positions: &mut Vec<usize>| {
+                if let Ok(at) = positions.binary_search(&position) {
+                    positions.remove(at);
+                }
+                !positions.is_empty()
+            };
+            match &mut self.entries {
+                Entries::Hash(entries) => {
+                    let key = HashKey::new(value);
+                    if let Some(positions) = entries.get_mut(&key) {
+                        if !remove(positions) {
+                            entries.remove(&key);
+                        }
+                    }
+                }
+                Entries::Ordered(entries) => {
+                    let key = OrderedKey::new(value);
+                    if let Some(positions) = entries.get_mut(&key) {
+                        if !remove(positions) {
+                            entries.remove(&key);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// Moves positions down past those of deleted records, which are in
+    /// ascending order and whose entries were removed, to where they are once
+    /// the records are gone. Keeps every list in order, so nothing is sorted.
+    pub fn close_gaps(&mut self, deleted: &[usize]) {
+        let Some(&first) = deleted.first() else {
+            return;
+        };
+        let shift = |position: &mut usize| {
+            if *position > first {
+                *position -= deleted.partition_point(|deleted| deleted < position);
+            }
+        };
+        match &mut self.entries {
+            Entries::Hash(entries) => entries.values_mut().flatten().for_each(shift),
+            Entries::Ordered(entries) => entries.values_mut().flatten().for_each(shift),
+        }
+        self.missing = std::mem::take(&mut self.missing)
+            .into_iter()
+            .map(|mut position| {
+                shift(&mut position);
+                position
+            })
+            .collect();
+    }
+
+    /// The positions of the records that may match the filter, or `None`
+    /// when the index can't tell.
+    fn lookup(&self, filter: &QueryFilter) -> Option<Vec<usize>> {
+        if filter.field != self.index.field {
+            return None;
+        }
+        let values = match filter.operator {
+            QueryFilterOperator::Equals => std::slice::from_ref(&filter.value),
+            QueryFilterOperator::In => filter.value.as_array()?.as_slice(),
+            _ => return self.range(filter),
+        };
+
+        let mut positions = vec![];
+        for value in values {
+            match &self.entries {
+                Entries::Hash(entries) => {
+                    positions.extend(entries.get(&HashKey::new(value)).into_iter().flatten());
+                }
+                Entries::Ordered(entries) => {
+                    let key = OrderedKey::new(value);
+                    positions.extend(entries.range(key.clone()..=key).flat_map(|(_, p)| p));
+                }
+            }
+        }
+        positions.sort_unstable();
+        positions.dedup();
+        Some(positions)
+    }
+
+    /// Like `lookup`, for the ordering operators, which only match values of
+    /// the filter value's type.
+    fn range(&self, filter: &QueryFilter) -> Option<Vec<usize>> {
+        let Entries::Ordered(entries) = &self.entries else {
+            return None;
+        };
+        let key = OrderedKey::new(&filter.value);
+        let (first, after_last) = match &filter.value {
+            Value::Number(_) => (
+                OrderedKey::Number(Float(f64::NEG_INFINITY)),
+                OrderedKey::String(String::new()),
+            ),
+            Value::String(_) => (OrderedKey::String(String::new()), OrderedKey::False),
+            _ => return None,
+        };
+        let bounds = match filter.operator {
+            QueryFilterOperator::GreaterThan | QueryFilterOperator::GreaterThanOrEquals => {
+                (Bound::Included(key), Bound::Excluded(after_last))
+            }
+            QueryFilterOperator::LessThan | QueryFilterOperator::LessThanOrEquals => {
+                (Bound::Included(first), Bound::Included(key))
+            }
+            _ => return None,
+        };
+
+        let mut positions: Vec<usize> = entries
+            .range(bounds)
+            .flat_map(|(_, p)| p)
+            .copied()
+            .collect();
+        positions.sort_unstable();
+        positions.dedup();
+        Some(positions)
+    }
+}
+
+/// The positions of the records a query matches, in position order, using
+/// the indexes to skip records that can't match.
+///
+/// When the query has a limit and sorts first by a field with an ordered
+/// index, the index is walked in sort order and stops once enough records
+/// matched: what's returned then holds every record the limit keeps, but not
+/// every match.
+pub(crate) fn select(
+    records: &[Value],
+    indexes: &[MemoryIndex],
+    query: &Query,
+) -> Result<Vec<usize>> {
+    query.check_filter()?;
+    query.check_after()?;
+    let mut matched = vec![];
+    if let Some(filter) = &query.filter {
+        if let Some(candidates) = candidates(indexes, filter) {
+            for position in candidates {
+                if query.matches(&records[position])? {
+                    matched.push(position);
+                }
+            }
+            return Ok(matched);
+        }
+    }
+    if let Some(matched) = select_sorted(records, indexes, query)? {
+        return Ok(matched);
+    }
+
+    for (position, record) in records.iter().enumerate() {
+        if query.matches(record)? {
+            matched.push(position);
+        }
+    }
+    Ok(matched)
+}
+
+/// The candidates of a list of filter items, following the grouping of
+/// `items_match`: the union over its `or` groups of the narrowest candidates
+/// of each group's items. Negated items can't narrow a group, and a group no
+/// index narrows makes the whole list need a scan.
+fn candidates(indexes: &[MemoryIndex], items: &[QueryFilterItem]) -> Option<Vec<usize>> {
+    let mut groups: Vec<Vec<&QueryFilterItem>> = vec![];
+    for (i, item) in items.iter().enumerate() {
+        match groups.last_mut() {
+            Some(group) if i == 0 || !matches!(item.operation(), QueryFilterOperation::Or) => {
+                group.push(item)
+            }
+            _ => groups.push(vec![item]),
+        }
+    }
+    if groups.is_empty() {
+        return None;
+    }
+
+    let mut positions = vec![];
+    for group in groups {
+        let narrowest = group
+            .into_iter()
+            .filter(|item| !matches!(item.operation(), QueryFilterOperation::Not))
+            .filter_map(|item| match item {
+                QueryFilterItem::Filter(filter) => indexes
+                    .iter()
+                    .filter_map(|index| index.lookup(&filter.filter))
+                    .min_by_key(Vec::len),
+                QueryFilterItem::Condition(condition) => candidates(indexes, &condition.filter),
+            })
+            .min_by_key(Vec::len)?;
+        positions.extend(narrowest);
+    }
+    positions.sort_unstable();
+    positions.dedup();
+    Some(positions)
+}
+
+/// Walks an ordered index on the first sort key until `offset + limit`
+/// records matched, or returns `None` when the query has no limit or no such
+/// index.
+///
+/// Records are walked a key at a time, so any record left out has a greater
+/// key than every record walked, and sorts after them. Records without the
+/// field sort first, with `null`, so ascending they count toward the limit;
+/// descending they sort last and are only kept for the final sort to place.
+fn select_sorted(
+    records: &[Value],
+    indexes: &[MemoryIndex],
+    query: &Query,
+) -> Result<Option<Vec<usize>>> {
+    let Some(limit) = query.limit.as_ref().and_then(|limit| {
+        let offset = limit.offset.unwrap_or(0) as usize;
+        limit
+            .limit
+            .map(|limit| offset.saturating_add(limit as usize))
+    }) else {
+        return Ok(None);
+    };
+    let Some(sort) = query.sort.iter().flatten().next() else {
+        return Ok(None);
+    };
+    if sort.field.split('.').any(|segment| segment == ANY_ELEMENT) {
+        return Ok(None);
+    }
+    let Some(entries) = indexes.iter().find_map(|index| match &index.entries {
+        Entries::Ordered(entries) if index.index.field == sort.field => Some((index, entries)),
+        _ => None,
+    }) else {
+        return Ok(None);
+    };
+    let (index, entries) = entries;
+
+    let mut matched = vec![];
+    let mut counted = 0;
+    let ascending = sort.direction == QuerySortDirection::Ascending;
+    for &position in &index.missing {
+        if query.matches(&records[position])? {
+            matched.push(position);
+            if ascending {
+                counted += 1;
+            }
+        }
+    }
+    let groups: Box<dyn Iterator<Item = &Vec<usize>>> = if ascending {
+        Box::new(entries.values())
+    } else {
+        Box::new(entries.values().rev())
+    };
+    for positions in groups {
+        if counted >= limit {
+            break;
+        }
+        for &position in positions {
+            if query.matches(&records[position])? {
+                matched.push(position);
+                counted += 1;
+            }
+        }
+    }
+    matched.sort_unstable();
+    Ok(Some(matched))
+}
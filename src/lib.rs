@@ -0,0 +1,23 @@
+pub mod aggregate;
+pub mod conformance;
+pub mod error;
+mod file;
+pub mod id;
+pub mod identity;
+pub mod index;
+mod memory;
+pub mod migration;
+mod postgres;
+pub mod query;
+pub mod schema;
+pub mod sql;
+mod sqlite;
+pub mod store;
+pub mod update;
+
+/// Paths the derive macros expand to, so models don't need their own
+/// `serde_json` dependency.
+#[doc(hidden)]
+pub mod __private {
+    pub use serde_json;
+}
@@ -1,11 +1,6 @@
 // use serde_json::{json, Map, Value};
 // use std::sync::Arc;
 
-mod identity;
-mod query;
-mod sql;
-mod store;
-
 fn main() {
     // store = Store::new(TestPersistence);
 }
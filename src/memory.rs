@@ -0,0 +1,869 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex, RwLock},
+};
+
+use async_trait::async_trait;
+use futures::StreamExt;
+use serde_json::Value;
+
+use crate::{
+    aggregate::Aggregation,
+    error::{Result, StoreError},
+    index::{self, Index, MemoryIndex},
+    query::{resolve_path, Query, QueryLimit},
+    store::{distinct_values, Data, DataStream, Persistence, Transaction},
+    update::Update,
+};
+
+pub(crate) type Records = Arc<RwLock<HashMap<String, Vec<Data>>>>;
+
+pub(crate) type Indexes = Arc<RwLock<HashMap<String, Vec<MemoryIndex>>>>;
+
+/// Documents in memory, for tests and caches. Nothing outlives the process;
+/// `FilePersistence` keeps the same in-memory copy backed by files.
+#[derive(Clone)]
+pub struct TestPersistence {
+    pub(crate) records: Records,
+    /// The indexes of each collection. Writers update them while holding the
+    /// write lock of `records`, and readers take both, `records` first.
+    pub(crate) indexes: Indexes,
+    pub(crate) counters: Arc<Mutex<HashMap<String, i64>>>,
+    /// Held by an open transaction, and for each write outside of one, so
+    /// those wait for the transaction to finish.
+    writer: Arc<tokio::sync::Mutex<()>>,
+    /// Set on the handles `begin` returns, which work on a snapshot of the
+    /// records.
+    transaction: Option<Arc<MemoryTransaction>>,
+}
+
+/// An open transaction: the handle it was started from and the writer lock,
+/// held until the snapshot replaces its records on commit or is dropped on
+/// rollback. No write can be made to the records in between, so none is lost
+/// to the snapshot.
+struct MemoryTransaction {
+    parent: TestPersistence,
+    writer: Mutex<Option<tokio::sync::OwnedMutexGuard<()>>>,
+}
+
+impl TestPersistence {
+    pub fn new(records: HashMap<String, Vec<Data>>) -> Self {
+        Self {
+            records: Arc::new(RwLock::new(records)),
+            indexes: Arc::new(RwLock::new(HashMap::new())),
+            counters: Arc::new(Mutex::new(HashMap::new())),
+            writer: Arc::new(tokio::sync::Mutex::new(())),
+            transaction: None,
+        }
+    }
+
+    /// Indexes a field of a collection unless it already has that index.
+    /// Queries use it from then on.
+    pub fn create_index(&self, collection: &str, index: Index) {
+        let records = self.records.read().unwrap();
+        let mut indexes = self.indexes.write().unwrap();
+        let indexes = indexes.entry(collection.to_string()).or_default();
+        if indexes.iter().any(|existing| existing.index == index) {
+            return;
+        }
+        let records = records.get(collection).map_or(&[][..], Vec::as_slice);
+        indexes.push(MemoryIndex::build(index, records));
+    }
+
+    pub(crate) fn read<T>(
+        &self,
+        collection: &str,
+        f: impl FnOnce(&[Data], &[MemoryIndex]) -> T,
+    ) -> T {
+        let records = self.records.read().unwrap();
+        let indexes = self.indexes.read().unwrap();
+        f(
+            records.get(collection).map_or(&[][..], Vec::as_slice),
+            indexes.get(collection).map_or(&[][..], Vec::as_slice),
+        )
+    }
+
+    pub(crate) fn write<T>(
+        &self,
+        collection: &str,
+        f: impl FnOnce(&mut Vec<Data>, &mut [MemoryIndex]) -> Result<T>,
+    ) -> Result<T> {
+        let mut records = self.records.write().unwrap();
+        let mut indexes = self.indexes.write().unwrap();
+        f(
+            records.entry(collection.to_string()).or_default(),
+            indexes
+                .get_mut(collection)
+                .map_or(&mut [][..], Vec::as_mut_slice),
+        )
+    }
+
+    fn push_record(&self, collection: &str, record: Data) {
+        let _ = self.write(collection, |records, indexes| {
+            push_record(records, indexes, record);
+            Ok(())
+        });
+    }
+
+    /// A copy of the records and indexes for a transaction to work on.
+    pub(crate) fn snapshot(&self) -> TestPersistence {
+        let records = self.records.read().unwrap();
+        let indexes = self.indexes.read().unwrap();
+        TestPersistence {
+            records: Arc::new(RwLock::new(records.clone())),
+            indexes: Arc::new(RwLock::new(indexes.clone())),
+            counters: self.counters.clone(),
+            writer: self.writer.clone(),
+            transaction: None,
+        }
+    }
+
+    /// Waits for an open transaction to finish before writing outside of it.
+    /// A transaction's own writes already hold the lock.
+    async fn lock_writes(&self) -> Option<tokio::sync::MutexGuard<'_, ()>> {
+        match self.transaction {
+            Some(_) => None,
+            None => Some(self.writer.lock().await),
+        }
+    }
+
+    /// Moves the records and indexes of a snapshot into these. Indexes
+    /// created here after the snapshot was taken are kept, built on its
+    /// records.
+    pub(crate) fn replace_with(&self, snapshot: &TestPersistence) {
+        let records = std::mem::take(&mut *snapshot.records.write().unwrap());
+        let mut merged = std::mem::take(&mut *snapshot.indexes.write().unwrap());
+        let mut parent = self.records.write().unwrap();
+        let mut indexes = self.indexes.write().unwrap();
+        for (collection, created) in indexes.drain() {
+            let kept = merged.entry(collection.clone()).or_default();
+            let built = records.get(&collection).map_or(&[][..], Vec::as_slice);
+            for index in created {
+                if !kept.iter().any(|existing| existing.index == index.index) {
+                    kept.push(MemoryIndex::build(index.index, built));
+                }
+            }
+        }
+        *parent = records;
+        *indexes = merged;
+    }
+}
+
+/// Filters, sorts, paginates and projects records the way `to_sql` does in
+/// SQL.
+fn select_records(records: &[Data], indexes: &[MemoryIndex], query: &Query) -> Result<Vec<Data>> {
+    let positions = select_positions(records, indexes, query)?;
+    positions
+        .into_iter()
+        .map(|position| query.project(records[position].clone()))
+        .collect()
+}
+
+/// The positions of the records `select_records` selects, in its order.
+fn select_positions(
+    records: &[Data],
+    indexes: &[MemoryIndex],
+    query: &Query,
+) -> Result<Vec<usize>> {
+    query.check_sort()?;
+    if let Some(projection) = &query.projection {
+        projection.check()?;
+    }
+    let mut selected = index::select(records, indexes, query)?;
+    selected.sort_by(|a, b| query.compare(&records[*a], &records[*b]));
+
+    if let Some(limit) = &query.limit {
+        let offset = limit.offset.unwrap_or(0) as usize;
+        let limit = limit.limit.map_or(usize::MAX, |limit| limit as usize);
+        selected = selected.into_iter().skip(offset).take(limit).collect();
+    }
+    Ok(selected)
+}
+
+/// The positions of the records a query matches, ignoring its sort and
+/// limit, in position order.
+pub(crate) fn matching_positions(
+    records: &[Data],
+    indexes: &[MemoryIndex],
+    query: &Query,
+) -> Result<Vec<usize>> {
+    let mut query = query.clone();
+    query.limit = None;
+    index::select(records, indexes, &query)
+}
+
+pub(crate) fn push_record(records: &mut Vec<Data>, indexes: &mut [MemoryIndex], record: Data) {
+    for index in indexes.iter_mut() {
+        index.insert(records.len(), &record);
+    }
+    records.push(record);
+}
+
+/// Replaces the records at `positions` with the new versions given, keeping
+/// the indexes in step.
+pub(crate) fn replace_at(
+    records: &mut [Data],
+    indexes: &mut [MemoryIndex],
+    replaced: impl IntoIterator<Item = (usize, Data)>,
+) {
+    for (position, record) in replaced {
+        let old = std::mem::replace(&mut records[position], record);
+        for index in indexes.iter_mut() {
+            index.remove(position, &old);
+            index.insert(position, &records[position]);
+        }
+    }
+}
+
+/// The new versions of the records at `positions` an update changes.
+pub(crate) fn patched_at(
+    records: &[Data],
+    positions: Vec<usize>,
+    update: &Update,
+) -> Result<Vec<(usize, Data)>> {
+    let mut patched = vec![];
+    for position in positions {
+        let mut record = records[position].clone();
+        if update.apply(&mut record)? {
+            patched.push((position, record));
+        }
+    }
+    Ok(patched)
+}
+
+/// Removes the records at `positions`, which are in ascending order, keeping
+/// the indexes in step, and returns how many it removed.
+pub(crate) fn delete_at(
+    records: &mut Vec<Data>,
+    indexes: &mut [MemoryIndex],
+    positions: Vec<usize>,
+) -> u64 {
+    if positions.is_empty() {
+        return 0;
+    }
+    for index in indexes.iter_mut() {
+        for &position in &positions {
+            index.remove(position, &records[position]);
+        }
+        index.close_gaps(&positions);
+    }
+    let mut matched = positions.into_iter().peekable();
+    let mut position = 0;
+    records.retain(|_| {
+        let deleted = matched.next_if_eq(&position).is_some();
+        position += 1;
+        !deleted
+    });
+    position as u64 - records.len() as u64
+}
+
+// Writes find the records they change before changing any, so one that fails
+// leaves the collection as it was.
+#[async_trait]
+impl Persistence for TestPersistence {
+    async fn find(&self, collection: &str, query: Option<Query>) -> Result<Vec<Data>> {
+        self.read(collection, |records, indexes| match query {
+            Some(query) => select_records(records, indexes, &query),
+            None => Ok(records.to_vec()),
+        })
+    }
+
+    async fn find_one(&self, collection: &str, query: Option<Query>) -> Result<Option<Data>> {
+        self.read(collection, |records, indexes| {
+            let Some(mut query) = query else {
+                return Ok(records.first().cloned());
+            };
+
+            query.limit = Some(QueryLimit {
+                limit: Some(1),
+                offset: query.limit.and_then(|limit| limit.offset),
+            });
+            Ok(select_records(records, indexes, &query)?.pop())
+        })
+    }
+
+    async fn count(&self, collection: &str, query: Option<Query>) -> Result<u64> {
+        self.read(collection, |records, indexes| match query {
+            Some(query) => Ok(matching_positions(records, indexes, &query)?.len() as u64),
+            None => Ok(records.len() as u64),
+        })
+    }
+
+    async fn exists(&self, collection: &str, query: Option<Query>) -> Result<bool> {
+        self.read(collection, |records, indexes| match query {
+            Some(mut query) => {
+                // Any single match will do, so the first in sort order is
+                // as good as another.
+                query.limit = Some(QueryLimit {
+                    limit: Some(1),
+                    offset: None,
+                });
+                Ok(!index::select(records, indexes, &query)?.is_empty())
+            }
+            None => Ok(!records.is_empty()),
+        })
+    }
+
+    async fn distinct(
+        &self,
+        collection: &str,
+        field: &str,
+        query: Option<Query>,
+    ) -> Result<Vec<Value>> {
+        crate::query::check_distinct_field(field)?;
+        self.read(collection, |records, indexes| {
+            let query = query.unwrap_or_else(|| Query::builder().build());
+            let values = matching_positions(records, indexes, &query)?
+                .into_iter()
+                .filter_map(|position| resolve_path(&records[position], field).first().copied())
+                .cloned();
+            Ok(distinct_values(values))
+        })
+    }
+
+    async fn aggregate(&self, collection: &str, aggregation: Aggregation) -> Result<Vec<Value>> {
+        self.read(collection, |records, _| aggregation.apply(records))
+    }
+
+    async fn stream(&self, collection: &str, query: Option<Query>) -> Result<DataStream> {
+        // The matched records are copied up front, so the stream reads them as
+        // they were when it opened, like a Postgres portal does. Writes made
+        // in the meantime move records around and would otherwise shift it.
+        // Projections are applied as the stream gets to each record.
+        let query = query.unwrap_or_else(|| Query::builder().build());
+        let matched: Vec<Data> = self.read(collection, |records, indexes| {
+            let positions = select_positions(records, indexes, &query)?;
+            Ok::<_, StoreError>(
+                positions
+                    .into_iter()
+                    .map(|position| records[position].clone())
+                    .collect(),
+            )
+        })?;
+
+        let stream = futures::stream::iter(matched).map(move |record| query.project(record));
+        Ok(stream.boxed())
+    }
+
+    async fn insert(&self, collection: &str, record: Data) -> Result<Data> {
+        let _writer = self.lock_writes().await;
+        self.push_record(collection, record.clone());
+        Ok(record)
+    }
+
+    async fn update(&self, collection: &str, query: Query, record: Data) -> Result<u64> {
+        let _writer = self.lock_writes().await;
+        self.write(collection, |records, indexes| {
+            let matched = matching_positions(records, indexes, &query)?;
+            let updated = matched.len() as u64;
+            let replaced = matched
+                .into_iter()
+                .map(|position| (position, record.clone()));
+            replace_at(records, indexes, replaced);
+            Ok(updated)
+        })
+    }
+
+    async fn patch(&self, collection: &str, query: Query, update: Update) -> Result<u64> {
+        update.check()?;
+        let _writer = self.lock_writes().await;
+        self.write(collection, |records, indexes| {
+            let matched = matching_positions(records, indexes, &query)?;
+            let patched = patched_at(records, matched, &update)?;
+            let modified = patched.len() as u64;
+            replace_at(records, indexes, patched);
+            Ok(modified)
+        })
+    }
+
+    async fn upsert(&self, collection: &str, query: Query, record: Data) -> Result<Data> {
+        let _writer = self.lock_writes().await;
+        self.write(collection, |records, indexes| {
+            let matched = matching_positions(records, indexes, &query)?;
+            if matched.is_empty() {
+                push_record(records, indexes, record.clone());
+            } else {
+                let replaced = matched
+                    .into_iter()
+                    .map(|position| (position, record.clone()));
+                replace_at(records, indexes, replaced);
+            }
+            Ok(())
+        })?;
+        Ok(record)
+    }
+
+    async fn insert_if_absent(
+        &self,
+        collection: &str,
+        query: Query,
+        record: Data,
+    ) -> Result<Option<Data>> {
+        let _writer = self.lock_writes().await;
+        self.write(collection, |records, indexes| {
+            if !matching_positions(records, indexes, &query)?.is_empty() {
+                return Ok(None);
+            }
+            push_record(records, indexes, record.clone());
+            Ok(Some(record))
+        })
+    }
+
+    async fn delete(&self, collection: &str, query: Query) -> Result<u64> {
+        let _writer = self.lock_writes().await;
+        self.write(collection, |records, indexes| {
+            let matched = matching_positions(records, indexes, &query)?;
+            Ok(delete_at(records, indexes, matched))
+        })
+    }
+
+    async fn increment_counter(&self, collection: &str) -> Result<i64> {
+        let mut counters = self.counters.lock().unwrap();
+        let counter = counters.entry(collection.to_string()).or_default();
+        *counter += 1;
+        Ok(*counter)
+    }
+
+    async fn ensure_collection(&self, collection: &str, indexes: &[Index]) -> Result<()> {
+        for index in indexes {
+            self.create_index(collection, index.clone());
+        }
+        Ok(())
+    }
+
+    async fn begin(&self) -> Result<Arc<dyn Transaction>> {
+        if self.transaction.is_some() {
+            return Err(StoreError::UnsupportedOperator(
+                "nested transactions are not supported".to_string(),
+            ));
+        }
+
+        let writer = self.writer.clone().lock_owned().await;
+        let mut snapshot = self.snapshot();
+        snapshot.transaction = Some(Arc::new(MemoryTransaction {
+            parent: self.clone(),
+            writer: Mutex::new(Some(writer)),
+        }));
+        Ok(Arc::new(snapshot))
+    }
+}
+
+#[async_trait]
+impl Transaction for TestPersistence {
+    async fn commit(&self) -> Result<()> {
+        let transaction = self
+            .transaction
+            .as_ref()
+            .ok_or_else(|| StoreError::backend("no transaction in progress"))?;
+        transaction.parent.replace_with(self);
+        transaction.writer.lock().unwrap().take();
+        Ok(())
+    }
+
+    async fn rollback(&self) -> Result<()> {
+        let transaction = self
+            .transaction
+            .as_ref()
+            .ok_or_else(|| StoreError::backend("no transaction in progress"))?;
+        self.records.write().unwrap().clear();
+        self.indexes.write().unwrap().clear();
+        transaction.writer.lock().unwrap().take();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use futures::TryStreamExt;
+    use serde_json::json;
+
+    use super::*;
+    use crate::{
+        conformance,
+        query::{
+            tests::{boolean_cases, boolean_docs},
+            QueryFilterItem, QuerySortDirection, QuerySortItem,
+        },
+    };
+    #[tokio::test]
+    async fn test_conformance() -> anyhow::Result<()> {
+        let persistence = TestPersistence::new(HashMap::new());
+        conformance::check(&persistence, "conformance").await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_transaction_keeps_outside_writes() -> anyhow::Result<()> {
+        let persistence = TestPersistence::new(HashMap::new());
+        persistence.insert("docs", json!({"id": 1, "v": 1})).await?;
+        persistence.create_index("docs", Index::hash("v"));
+
+        let tx = persistence.begin().await?;
+        tx.insert("docs", json!({"id": 2, "v": 1})).await?;
+        let outside = persistence.clone();
+        let outside =
+            tokio::spawn(async move { outside.insert("docs", json!({"id": 3, "v": 1})).await });
+        tokio::task::yield_now().await;
+        assert!(!outside.is_finished());
+        let update = Update::builder().set("v", json!(2)).build();
+        tx.patch("docs", Query::builder().eq("v", json!(1)).build(), update)
+            .await?;
+        tx.commit().await?;
+        outside.await??;
+
+        let ids = |v| {
+            let query = Query::builder().eq("v", json!(v)).order_by("id").build();
+            let persistence = persistence.clone();
+            async move {
+                let records = persistence.find("docs", Some(query)).await?;
+                anyhow::Ok(
+                    records
+                        .into_iter()
+                        .map(|record| record["id"].clone())
+                        .collect::<Vec<_>>(),
+                )
+            }
+        };
+        assert_eq!(ids(2).await?, [json!(1), json!(2)]);
+        assert_eq!(ids(1).await?, [json!(3)]);
+        Ok(())
+    }
+
+    /// Deletes a record a stream has yielded, and inserts one, before reading
+    /// the rest of it.
+    pub(crate) async fn assert_stream_while_writing(
+        persistence: &dyn Persistence,
+    ) -> anyhow::Result<()> {
+        for (id, v) in [(1, 1), (2, 2), (3, 1)] {
+            persistence
+                .insert("docs", json!({"id": id, "v": v}))
+                .await?;
+        }
+        let query = Query::builder().eq("v", json!(1)).build();
+        let mut stream = persistence.stream("docs", Some(query)).await?;
+        assert_eq!(
+            stream.next().await.transpose()?,
+            Some(json!({"id": 1, "v": 1}))
+        );
+
+        let first = Query::builder().eq("id", json!(1)).build();
+        persistence.delete("docs", first).await?;
+        persistence.insert("docs", json!({"id": 4, "v": 1})).await?;
+        let rest: Vec<Data> = stream.try_collect().await?;
+        assert_eq!(rest, [json!({"id": 3, "v": 1})]);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_stream_while_writing() -> anyhow::Result<()> {
+        assert_stream_while_writing(&TestPersistence::new(HashMap::new())).await
+    }
+
+    fn operator_docs() -> Vec<Data> {
+        vec![
+            json!({"id": "1", "age": 30, "name": "John", "created": "2022-11-05T10:00:00Z"}),
+            json!({"id": "2", "age": 17.5, "name": "jane", "created": "2021-01-01T00:00:00Z"}),
+            json!({"id": "3", "age": "30", "name": "Zoe", "nickname": null}),
+            json!({"id": "4", "name": "Émile", "age": true}),
+        ]
+    }
+
+    fn operator_queries() -> Vec<Query> {
+        vec![
+            Query::builder().eq("age", json!(30.0)).build(),
+            Query::builder().not_eq("age", json!(30)).build(),
+            Query::builder().gt("age", json!(17)).build(),
+            Query::builder().gte("age", json!(17.5)).build(),
+            Query::builder().lt("age", json!(30)).build(),
+            Query::builder().lte("age", json!("30")).build(),
+            Query::builder().gt("name", json!("Zoe")).build(),
+            Query::builder().lt("name", json!("john")).build(),
+            Query::builder().gte("created", json!("2022-01-01")).build(),
+            Query::builder().exists("nickname").build(),
+            Query::builder().not_exists("created").build(),
+            Query::builder()
+                .is_in("age", vec![json!(30), json!(true)])
+                .build(),
+            Query::builder().not_in("age", vec![json!(30)]).build(),
+            Query::builder().is_in("age", vec![]).build(),
+            Query::builder().not_in("created", vec![]).build(),
+        ]
+    }
+
+    fn nested_docs() -> Vec<Data> {
+        vec![
+            json!({"id": 1, "address": {"city": "Lisbon"}, "items": [{"sku": "A1", "qty": 2}]}),
+            json!({"id": 2, "address": {"city": "Porto"}, "items": [{"sku": "B2", "qty": 5}, {"sku": "A1", "qty": 1}]}),
+            json!({"id": 3, "address": {"city": 7}, "items": []}),
+            json!({"id": 4, "address": "unknown", "items": [[{"sku": "A1"}]]}),
+        ]
+    }
+
+    fn nested_queries() -> Vec<Query> {
+        vec![
+            Query::builder().eq("address.city", json!("Lisbon")).build(),
+            Query::builder().gte("address.city", json!("M")).build(),
+            Query::builder().eq("items.0.sku", json!("A1")).build(),
+            Query::builder().eq("items.-1.sku", json!("A1")).build(),
+            Query::builder().eq("items.*.sku", json!("A1")).build(),
+            Query::builder().lt("items.*.qty", json!(2)).build(),
+            Query::builder().not_eq("items.*.sku", json!("A1")).build(),
+            Query::builder()
+                .not_in("items.*.sku", vec![json!("B2")])
+                .build(),
+            Query::builder().exists("items.*").build(),
+            Query::builder().not_exists("items.*.qty").build(),
+            Query::builder().eq("items.*.*.sku", json!("A1")).build(),
+            Query::builder().order_by_desc("address.city").build(),
+            Query::builder()
+                .order_by("items.0.qty")
+                .order_by("id")
+                .build(),
+        ]
+    }
+
+    fn sort_docs() -> Vec<Data> {
+        vec![
+            json!({"id": 1, "rank": "b", "score": 10}),
+            json!({"id": 2, "rank": "a", "score": 2.5}),
+            json!({"id": 3, "rank": "B"}),
+            json!({"id": 4, "rank": "a", "score": "10"}),
+            json!({"id": 5, "rank": true, "score": null}),
+            json!({"id": 6, "rank": [1], "score": false}),
+            json!({"id": 7, "rank": 3, "score": -1}),
+        ]
+    }
+
+    fn sort_queries() -> Vec<(Query, Vec<i64>)> {
+        vec![
+            (
+                Query::builder().order_by("score").build(),
+                vec![3, 5, 7, 2, 1, 4, 6],
+            ),
+            (
+                Query::builder().order_by_desc("score").build(),
+                vec![6, 4, 1, 2, 7, 3, 5],
+            ),
+            (
+                Query::builder()
+                    .order_by("rank")
+                    .order_by_desc("score")
+                    .build(),
+                vec![7, 3, 4, 2, 1, 5, 6],
+            ),
+        ]
+    }
+
+    /// Indexes every field the queries filter or sort by, both ways.
+    fn index_fields(persistence: &TestPersistence, collection: &str, queries: &[Query]) {
+        fn filter_fields(items: &[QueryFilterItem], fields: &mut Vec<String>) {
+            for item in items {
+                match item {
+                    QueryFilterItem::Filter(filter) => fields.push(filter.filter.field.clone()),
+                    QueryFilterItem::Condition(condition) => {
+                        filter_fields(&condition.filter, fields)
+                    }
+                }
+            }
+        }
+
+        let mut fields = vec!["id".to_string()];
+        for query in queries {
+            filter_fields(query.filter.as_deref().unwrap_or_default(), &mut fields);
+            fields.extend(query.sort.iter().flatten().map(|item| item.field.clone()));
+        }
+        for field in fields {
+            persistence.create_index(collection, Index::hash(&field));
+            persistence.create_index(collection, Index::ordered(&field));
+        }
+    }
+
+    /// Variants of a query that take the other paths through the indexes:
+    /// limited, and sorted by `id` so the ordered index is walked.
+    fn index_variants(query: Query) -> Vec<Query> {
+        let mut variants = vec![query.clone()];
+        let mut sorted = query.clone();
+        if sorted.sort.is_none() {
+            sorted.sort = Some(vec![QuerySortItem {
+                field: "id".to_string(),
+                direction: QuerySortDirection::Descending,
+            }]);
+        }
+        for base in [query, sorted] {
+            for (limit, offset) in [(1, 0), (2, 1), (0, 0)] {
+                let mut limited = base.clone();
+                limited.limit = Some(QueryLimit {
+                    limit: Some(limit),
+                    offset: Some(offset),
+                });
+                variants.push(limited);
+            }
+            variants.push(base);
+        }
+        variants
+    }
+
+    async fn assert_same_results(
+        scanned: &dyn Persistence,
+        indexed: &dyn Persistence,
+        collection: &str,
+        query: Query,
+    ) -> anyhow::Result<()> {
+        let expected = scanned.find(collection, Some(query.clone())).await?;
+        let actual = indexed.find(collection, Some(query.clone())).await?;
+        assert_eq!(actual, expected, "{:?}", query);
+        let expected = scanned.count(collection, Some(query.clone())).await?;
+        let actual = indexed.count(collection, Some(query.clone())).await?;
+        assert_eq!(actual, expected, "{:?}", query);
+        let expected = scanned.exists(collection, Some(query.clone())).await?;
+        let actual = indexed.exists(collection, Some(query.clone())).await?;
+        assert_eq!(actual, expected, "{:?}", query);
+        let expected = scanned
+            .distinct(collection, "id", Some(query.clone()))
+            .await?;
+        let actual = indexed
+            .distinct(collection, "id", Some(query.clone()))
+            .await?;
+        assert_eq!(actual, expected, "{:?}", query);
+        let expected: Vec<Data> = scanned
+            .stream(collection, Some(query.clone()))
+            .await?
+            .try_collect()
+            .await?;
+        let actual: Vec<Data> = indexed
+            .stream(collection, Some(query.clone()))
+            .await?
+            .try_collect()
+            .await?;
+        assert_eq!(actual, expected, "{:?}", query);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_indexes() -> anyhow::Result<()> {
+        let boolean_queries = boolean_cases().into_iter().map(|case| case.query).collect();
+        let sort_queries = sort_queries().into_iter().map(|(query, _)| query).collect();
+        let fixtures: Vec<(Vec<Data>, Vec<Query>)> = vec![
+            (operator_docs(), operator_queries()),
+            (nested_docs(), nested_queries()),
+            (boolean_docs(), boolean_queries),
+            (sort_docs(), sort_queries),
+        ];
+
+        for (docs, queries) in fixtures {
+            let records = HashMap::from([("indexed".to_string(), docs)]);
+            let scanned = TestPersistence::new(records.clone());
+            let indexed = TestPersistence::new(records);
+            index_fields(&indexed, "indexed", &queries);
+            for query in queries.into_iter().flat_map(index_variants) {
+                assert_same_results(&scanned, &indexed, "indexed", query).await?;
+            }
+        }
+
+        // filters are checked even when no record is a candidate
+        let indexed = TestPersistence::new(HashMap::new());
+        indexed.create_index("empty", Index::ordered("n"));
+        let query = Query::builder().gt("n", json!([1])).build();
+        assert!(matches!(
+            indexed.find("empty", Some(query)).await,
+            Err(StoreError::UnsupportedOperator(_))
+        ));
+
+        Ok(())
+    }
+
+    /// Makes the same writes through `indexed` and an unindexed copy, checking
+    /// after each that queries the indexes answer agree with a scan.
+    pub(crate) async fn assert_index_maintenance(indexed: &dyn Persistence) -> anyhow::Result<()> {
+        let scanned = TestPersistence::new(HashMap::new());
+        let queries = [
+            Query::builder().eq("a", json!(1)).build(),
+            Query::builder()
+                .is_in("a", vec![json!(2), json!(3)])
+                .build(),
+            Query::builder().gte("b", json!(2)).build(),
+            Query::builder().lt("b", json!("z")).build(),
+            Query::builder()
+                .eq("a", json!(1))
+                .or_wher("b", json!(5))
+                .build(),
+            Query::builder().order_by_desc("b").build(),
+        ];
+        let check = || async {
+            for query in queries.iter().cloned().flat_map(index_variants) {
+                assert_same_results(&scanned, indexed, "maintained", query).await?;
+            }
+            anyhow::Ok(())
+        };
+
+        for persistence in [&scanned as &dyn Persistence, indexed] {
+            for doc in boolean_docs() {
+                persistence.insert("maintained", doc).await?;
+            }
+        }
+        check().await?;
+
+        let query = Query::builder().eq("id", json!(2)).build();
+        let update = Update::builder().set("a", json!(3)).inc("b", 4).build();
+        let replaced = json!({"id": 3, "a": "x", "b": "y"});
+        let bad = Query::builder().gt("b", json!(null)).build();
+        for persistence in [&scanned as &dyn Persistence, indexed] {
+            persistence
+                .patch("maintained", query.clone(), update.clone())
+                .await?;
+            let by_id = Query::builder().eq("id", json!(3)).build();
+            persistence
+                .update("maintained", by_id, replaced.clone())
+                .await?;
+            let query = Query::builder().eq("id", json!(5)).build();
+            persistence
+                .upsert("maintained", query, json!({"id": 5, "a": 1, "b": 5}))
+                .await?;
+            let query = Query::builder()
+                .eq("a", json!(1))
+                .and_wher("b", json!(1))
+                .build();
+            persistence.delete("maintained", query).await?;
+            assert!(persistence.delete("maintained", bad.clone()).await.is_err());
+        }
+        check().await?;
+
+        // deleting records spread across the collection moves the rest down
+        for persistence in [&scanned as &dyn Persistence, indexed] {
+            for id in 10..20 {
+                let doc = json!({"id": id, "a": id % 3, "b": id % 4});
+                persistence.insert("maintained", doc).await?;
+            }
+            let query = Query::builder().eq("b", json!(1)).build();
+            persistence.delete("maintained", query).await?;
+        }
+        check().await?;
+
+        for persistence in [&scanned as &dyn Persistence, indexed] {
+            let tx = persistence.begin().await?;
+            tx.insert("maintained", json!({"id": 6, "a": 2, "b": 2}))
+                .await?;
+            tx.rollback().await?;
+            let tx = persistence.begin().await?;
+            tx.delete("maintained", Query::builder().eq("id", json!(4)).build())
+                .await?;
+            tx.insert("maintained", json!({"id": 7, "a": 3, "b": 0}))
+                .await?;
+            tx.commit().await?;
+        }
+        check().await?;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_index_maintenance() -> anyhow::Result<()> {
+        let indexed = TestPersistence::new(HashMap::new());
+        for field in ["id", "a", "b"] {
+            indexed.create_index("maintained", Index::hash(field));
+            indexed.create_index("maintained", Index::ordered(field));
+        }
+        assert_index_maintenance(&indexed).await
+    }
+}
@@ -0,0 +1,293 @@
+use std::fmt;
+use std::sync::Arc;
+
+use serde_json::Value;
+
+use crate::error::{Result, StoreError};
+use crate::query::{resolve_path, Query};
+use crate::update::Update;
+
+/// The key holding the schema version of a document, written by `Store` in the
+/// collections that have migrations. Documents without it are at version 0.
+pub const SCHEMA_VERSION_KEY: &str = "_schema";
+
+/// How a document is changed in a closure step, see
+/// [`MigrationsBuilder::transform`].
+pub type Transform = Arc<dyn Fn(&mut Value) -> Result<()> + Send + Sync>;
+
+/// The migrations of a collection: steps that each belong to a schema version,
+/// and bring a document stored at an older version up to the latest one.
+///
+/// Steps run in order of their version, and those of one version in the order
+/// they were declared. `Store` upgrades the documents it reads in memory, and
+/// `Store::migrate` rewrites the stored ones. Queries still run against the
+/// stored documents, so they only see new field names once migrated.
+#[derive(Clone, Default)]
+pub struct Migrations {
+    steps: Vec<(u32, MigrationStep)>,
+}
+
+#[derive(Clone)]
+enum MigrationStep {
+    Update(Update),
+    Default { field: String, value: Value },
+    Transform(Transform),
+}
+
+impl fmt::Debug for Migrations {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let versions: Vec<u32> = self.steps.iter().map(|(version, _)| *version).collect();
+        f.debug_struct("Migrations")
+            .field("versions", &versions)
+            .finish()
+    }
+}
+
+impl Migrations {
+    pub fn builder() -> MigrationsBuilder {
+        MigrationsBuilder::default()
+    }
+
+    /// The latest schema version, that of the last step, or 0 without any.
+    pub fn version(&self) -> u32 {
+        self.steps.last().map_or(0, |(version, _)| *version)
+    }
+
+    /// The schema version a document is stored at: 0 when it has none.
+    pub fn document_version(document: &Value) -> Result<u32> {
+        match document.get(SCHEMA_VERSION_KEY) {
+            None | Some(Value::Null) => Ok(0),
+            Some(version) => version
+                .as_u64()
+                .and_then(|version| u32::try_from(version).ok())
+                .ok_or_else(|| {
+                    StoreError::document(format!(
+                        "the {} of a document is not a schema version: {}",
+                        SCHEMA_VERSION_KEY, version
+                    ))
+                }),
+        }
+    }
+
+    /// Runs the steps of the versions past the document's on it and stamps it
+    /// with the latest version, returning whether it was behind. A document
+    /// from a newer schema is left as it is.
+    pub fn upgrade(&self, document: &mut Value) -> Result<bool> {
+        let from = Self::document_version(document)?;
+        if from >= self.version() {
+            return Ok(false);
+        }
+        for (_, step) in self.steps.iter().filter(|(version, _)| *version > from) {
+            match step {
+                MigrationStep::Update(update) => {
+                    update.apply(document)?;
+                }
+                MigrationStep::Default { field, value } => {
+                    if resolve_path(document, field).is_empty() {
+                        Update::builder()
+                            .set(field, value.clone())
+                            .build()
+                            .apply(document)?;
+                    }
+                }
+                MigrationStep::Transform(transform) => transform(document)?,
+            }
+        }
+        self.stamp(document)?;
+        Ok(true)
+    }
+
+    /// Sets the latest schema version on a document about to be written, when
+    /// there are migrations at all.
+    pub fn stamp(&self, document: &mut Value) -> Result<()> {
+        let version = self.version();
+        if version == 0 {
+            return Ok(());
+        }
+        match document {
+            Value::Object(map) => {
+                map.insert(SCHEMA_VERSION_KEY.to_string(), version.into());
+                Ok(())
+            }
+            _ => Err(StoreError::document(
+                "cannot set the schema version of a document that is not an object",
+            )),
+        }
+    }
+
+    /// Selects the documents stored at an older schema version.
+    pub fn outdated(&self) -> Query {
+        Query::builder()
+            .not_exists(SCHEMA_VERSION_KEY)
+            .or_wher(SCHEMA_VERSION_KEY, Value::Null)
+            .or(|mut query| query.lt(SCHEMA_VERSION_KEY, self.version().into()).build())
+            .build()
+    }
+
+    /// Selects a document while it is still at schema version `version`, so a
+    /// migrated copy doesn't overwrite a write made since it was read.
+    pub fn at_version(version: u32) -> Query {
+        match version {
+            0 => Query::builder()
+                .not_exists(SCHEMA_VERSION_KEY)
+                .or(|mut query| {
+                    query
+                        .is_in(SCHEMA_VERSION_KEY, vec![Value::Null, 0.into()])
+                        .build()
+                })
+                .build(),
+            _ => Query::builder()
+                .eq(SCHEMA_VERSION_KEY, version.into())
+                .build(),
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct MigrationsBuilder {
+    migrations: Migrations,
+}
+
+#[allow(dead_code)]
+impl MigrationsBuilder {
+    /// Moves a field's value to `to`, like `Update::rename`.
+    pub fn rename(&mut self, version: u32, field: &str, to: &str) -> &mut MigrationsBuilder {
+        let update = Update::builder().rename(field, to).build();
+        self.push_step(version, MigrationStep::Update(update))
+    }
+
+    /// Sets a field that is missing. One that is `null` is kept.
+    pub fn set_default(
+        &mut self,
+        version: u32,
+        field: &str,
+        value: Value,
+    ) -> &mut MigrationsBuilder {
+        self.push_step(
+            version,
+            MigrationStep::Default {
+                field: field.to_string(),
+                value,
+            },
+        )
+    }
+
+    /// Applies partial update operators, see [`Update`].
+    pub fn update(&mut self, version: u32, update: Update) -> &mut MigrationsBuilder {
+        self.push_step(version, MigrationStep::Update(update))
+    }
+
+    /// Changes the document in a closure. An error fails the read or the
+    /// migration of the document.
+    pub fn transform<F>(&mut self, version: u32, transform: F) -> &mut MigrationsBuilder
+    where
+        F: Fn(&mut Value) -> Result<()> + Send + Sync + 'static,
+    {
+        self.push_step(version, MigrationStep::Transform(Arc::new(transform)))
+    }
+
+    fn push_step(&mut self, version: u32, step: MigrationStep) -> &mut MigrationsBuilder {
+        assert!(version > 0, "schema versions of migrations start at 1");
+        self.migrations.steps.push((version, step));
+        self
+    }
+
+    /// The migrations, with the steps ordered by version.
+    pub fn build(&self) -> Migrations {
+        let mut migrations = self.migrations.clone();
+        migrations.steps.sort_by_key(|(version, _)| *version);
+        migrations
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn migrations() -> Migrations {
+        Migrations::builder()
+            .transform(3, |document| {
+                if let Some(Value::String(email)) = document.get_mut("email") {
+                    *email = email.to_lowercase();
+                }
+                Ok(())
+            })
+            .rename(1, "name", "full_name")
+            .set_default(2, "active", json!(true))
+            .set_default(2, "address.country", json!("PT"))
+            .build()
+    }
+
+    #[test]
+    fn test_upgrade() -> anyhow::Result<()> {
+        let migrations = migrations();
+        assert_eq!(migrations.version(), 3);
+        assert_eq!(Migrations::default().version(), 0);
+
+        let cases = [
+            (
+                json!({"name": "Ann", "email": "ANN@X"}),
+                json!({
+                    "full_name": "Ann", "email": "ann@x", "active": true,
+                    "address": {"country": "PT"}, "_schema": 3,
+                }),
+            ),
+            // steps of the versions already applied are skipped
+            (
+                json!({"name": "Bob", "active": null, "email": "B@X", "_schema": 2}),
+                json!({"name": "Bob", "active": null, "email": "b@x", "_schema": 3}),
+            ),
+            (
+                json!({"full_name": "Cid", "email": "C@X", "_schema": 3}),
+                json!({"full_name": "Cid", "email": "C@X", "_schema": 3}),
+            ),
+            (json!({"_schema": 4}), json!({"_schema": 4})),
+        ];
+        for (mut document, expected) in cases {
+            let behind = Migrations::document_version(&document)? < 3;
+            assert_eq!(migrations.upgrade(&mut document)?, behind);
+            assert_eq!(document, expected);
+        }
+
+        for version in [json!("1"), json!(-1), json!(1.5)] {
+            let mut document = json!({"_schema": version});
+            assert!(matches!(
+                migrations.upgrade(&mut document),
+                Err(StoreError::Deserialize(_))
+            ));
+        }
+        assert!(migrations.stamp(&mut json!([1])).is_err());
+
+        let failing = Migrations::builder()
+            .transform(1, |_| Err(StoreError::document("no")))
+            .build();
+        assert!(failing.upgrade(&mut json!({})).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_outdated() -> anyhow::Result<()> {
+        let migrations = migrations();
+        let documents = [
+            (json!({"id": 1}), true),
+            (json!({"id": 2, "_schema": null}), true),
+            (json!({"id": 3, "_schema": 2}), true),
+            (json!({"id": 4, "_schema": 3}), false),
+            (json!({"id": 5, "_schema": 4}), false),
+        ];
+        for (document, outdated) in documents {
+            assert_eq!(migrations.outdated().matches(&document)?, outdated);
+        }
+
+        assert!(Migrations::at_version(0).matches(&json!({"id": 1}))?);
+        assert!(Migrations::at_version(0).matches(&json!({"_schema": 0}))?);
+        assert!(!Migrations::at_version(0).matches(&json!({"_schema": 1}))?);
+        assert!(Migrations::at_version(2).matches(&json!({"_schema": 2}))?);
+        assert!(!Migrations::at_version(2).matches(&json!({"_schema": 3}))?);
+
+        Ok(())
+    }
+}
@@ -1,12 +1,25 @@
-use anyhow::{anyhow, Context};
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
+
 use serde::{Deserialize, Serialize};
-use serde_json::Value;
+use serde_json::{Map, Number, Value};
+
+use crate::error::{Result, StoreError};
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Query {
     pub filter: Option<Vec<QueryFilterItem>>,
     pub sort: Option<Vec<QuerySortItem>>,
     pub limit: Option<QueryLimit>,
+    /// Keeps only the documents that sort after a document with these values
+    /// of the sort keys, one per `sort` item. This is what continues a keyset
+    /// paginated query, see `Store::page`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub after: Option<Vec<Value>>,
+    /// Which fields of the matched documents to return; whole documents
+    /// when unset.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub projection: Option<Projection>,
 }
 
 impl Query {
@@ -15,24 +28,134 @@ impl Query {
     }
 
     #[allow(unused)]
-    pub fn from_json(json: &str) -> anyhow::Result<Query> {
-        serde_json::from_str(json)
-            .context(format!("Failed to parse query from JSON string: {}", json))
+    pub fn from_json(json: &str) -> Result<Query> {
+        serde_json::from_str(json).map_err(|err| {
+            StoreError::InvalidQuery(format!(
+                "failed to parse query from JSON string {}: {}",
+                json, err
+            ))
+        })
+    }
+
+    pub fn matches(&self, value: &Value) -> Result<bool> {
+        if let Some(after) = self.check_after()? {
+            if !self.is_after(value, after) {
+                return Ok(false);
+            }
+        }
+        match &self.filter {
+            Some(filter) => items_match(filter, value),
+            None => Ok(true),
+        }
+    }
+
+    /// Returns `after` once checked to hold one value per sort key.
+    pub fn check_after(&self) -> Result<Option<&[Value]>> {
+        let Some(after) = &self.after else {
+            return Ok(None);
+        };
+        let keys = self.sort.as_ref().map_or(0, Vec::len);
+        if after.len() != keys {
+            return Err(StoreError::InvalidQuery(format!(
+                "'after' has {} values, but the query sorts by {} keys",
+                after.len(),
+                keys
+            )));
+        }
+        self.check_sort()?;
+        Ok(Some(after))
+    }
+
+    /// Whether a document sorts strictly after the sort key values `after`.
+    fn is_after(&self, value: &Value, after: &[Value]) -> bool {
+        self.sort
+            .iter()
+            .flatten()
+            .zip(after)
+            .map(|(item, after)| item.compare_to(value, after))
+            .find(|ordering| ordering.is_ne())
+            .is_some_and(|ordering| ordering.is_gt())
     }
 
-    pub fn matches(&self, value: &Value) -> anyhow::Result<bool> {
-        if let Some(filter) = &self.filter {
-            for filter_item in filter {
-                if !filter_item.matches(value)? {
-                    return Ok(false);
+    /// Orders documents by the sort keys, leaving ties in their current order.
+    pub fn sort(&self, values: &mut [Value]) -> Result<()> {
+        self.check_sort()?;
+        values.sort_by(|a, b| self.compare(a, b));
+        Ok(())
+    }
+
+    /// Applies `projection` to a matched document.
+    pub fn project(&self, value: Value) -> Result<Value> {
+        match &self.projection {
+            Some(projection) => projection.apply(value),
+            None => Ok(value),
+        }
+    }
+
+    /// Rejects filter values their operators can't be applied to, see
+    /// [`QueryFilter::check_value`]. `matches` only finds them on the
+    /// documents it is given, so this is for when there may be none.
+    pub fn check_filter(&self) -> Result<()> {
+        fn check(items: &[QueryFilterItem]) -> Result<()> {
+            for item in items {
+                match item {
+                    QueryFilterItem::Filter(filter) => filter.filter.check_value()?,
+                    QueryFilterItem::Condition(condition) => check(&condition.filter)?,
                 }
             }
+            Ok(())
         }
+        check(self.filter.as_deref().unwrap_or_default())
+    }
+
+    /// Rejects sort keys that can't be sorted by, see
+    /// [`QuerySortItem::check_field`].
+    pub fn check_sort(&self) -> Result<()> {
+        for item in self.sort.iter().flatten() {
+            item.check_field()?;
+        }
+        Ok(())
+    }
 
-        Ok(true)
+    /// How two documents order under the sort keys; `Equal` when there are
+    /// none or they tie on all of them.
+    pub fn compare(&self, a: &Value, b: &Value) -> Ordering {
+        self.sort
+            .iter()
+            .flatten()
+            .map(|item| item.compare(a, b))
+            .find(|ordering| ordering.is_ne())
+            .unwrap_or(Ordering::Equal)
     }
 }
 
+/// Evaluates a list of filter items against a document.
+///
+/// Each item's `operation` joins it to the items before it: `and` and `or` as
+/// their names say, and `not` as `AND NOT`. The operation of the first item
+/// only matters when it is `not`, which negates it. `and` binds tighter than
+/// `or`, so `a or b and c` reads as `a OR (b AND c)`, and an empty list
+/// matches everything. `sql::items_to_sql` renders the same structure.
+pub fn items_match(items: &[QueryFilterItem], value: &Value) -> Result<bool> {
+    let mut matched = false;
+    let mut group = true;
+    for (i, item) in items.iter().enumerate() {
+        let mut item_matched = item.matches(value)?;
+        if matches!(item.operation(), QueryFilterOperation::Not) {
+            item_matched = !item_matched;
+        }
+
+        if i > 0 && matches!(item.operation(), QueryFilterOperation::Or) {
+            matched |= group;
+            group = item_matched;
+        } else {
+            group &= item_matched;
+        }
+    }
+
+    Ok(matched || group)
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 #[serde(tag = "type", rename_all = "camelCase")]
 pub enum QueryFilterItem {
@@ -41,12 +164,20 @@ pub enum QueryFilterItem {
 }
 
 impl QueryFilterItem {
-    pub fn matches(&self, value: &Value) -> anyhow::Result<bool> {
+    /// Evaluates the item on its own, ignoring its `operation`.
+    pub fn matches(&self, value: &Value) -> Result<bool> {
         match self {
             QueryFilterItem::Filter(filter) => filter.matches(value),
             QueryFilterItem::Condition(condition) => condition.matches(value),
         }
     }
+
+    pub fn operation(&self) -> &QueryFilterOperation {
+        match self {
+            QueryFilterItem::Filter(filter) => &filter.operation,
+            QueryFilterItem::Condition(condition) => &condition.operation,
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -56,7 +187,7 @@ pub struct QueryFilterFilter {
 }
 
 impl QueryFilterFilter {
-    pub fn matches(&self, value: &Value) -> anyhow::Result<bool> {
+    pub fn matches(&self, value: &Value) -> Result<bool> {
         self.filter.matches(value)
     }
 }
@@ -68,14 +199,8 @@ pub struct QueryFilterCondition {
 }
 
 impl QueryFilterCondition {
-    pub fn matches(&self, value: &Value) -> anyhow::Result<bool> {
-        for filter_item in &self.filter {
-            if !filter_item.matches(value)? {
-                return Ok(false);
-            }
-        }
-
-        Ok(true)
+    pub fn matches(&self, value: &Value) -> Result<bool> {
+        items_match(&self.filter, value)
     }
 }
 
@@ -87,71 +212,158 @@ pub struct QueryFilter {
 }
 
 impl QueryFilter {
-    pub fn matches(&self, value: &Value) -> anyhow::Result<bool> {
-        let field_value = value.get(&self.field).context(format!(
-            "field '{}' not found on value: {:?}",
-            &self.field, value
-        ))?;
+    /// Evaluates the filter against a document.
+    ///
+    /// `field` is a dotted path resolved by [`resolve_path`], and the filter
+    /// matches when any of the values it reaches satisfies the operator.
+    /// `NotEquals` and `NotIn` are the exception: they need at least one value
+    /// and none of them may match. A missing field never satisfies a
+    /// comparison; use `Exists`/`NotExists` to test for presence. Ordering
+    /// operators only match fields of the same JSON type as the value, which
+    /// must be a number or a string. Strings compare bytewise, so dates stored
+    /// as RFC 3339 strings with a common offset compare chronologically.
+    pub fn matches(&self, value: &Value) -> Result<bool> {
+        self.check_value()?;
+        let candidates = resolve_path(value, &self.field);
+
+        let matched = match &self.operator {
+            QueryFilterOperator::Exists => !candidates.is_empty(),
+            QueryFilterOperator::NotExists => candidates.is_empty(),
+            QueryFilterOperator::NotEquals => {
+                !candidates.is_empty() && candidates.iter().all(|c| !json_eq(c, &self.value))
+            }
+            QueryFilterOperator::NotIn => {
+                let values = self.values()?;
+                !candidates.is_empty()
+                    && candidates
+                        .iter()
+                        .all(|c| !values.iter().any(|v| json_eq(c, v)))
+            }
+            _ => candidates.iter().any(|c| self.matches_value(c)),
+        };
+        Ok(matched)
+    }
 
+    /// Applies a positive operator to a single resolved value.
+    fn matches_value(&self, field_value: &Value) -> bool {
+        let ordering = || self.compare(field_value);
         match &self.operator {
-            QueryFilterOperator::Equals => Ok(field_value == &self.value),
-            QueryFilterOperator::NotEquals => Ok(field_value != &self.value),
-            QueryFilterOperator::LessThan => {
-                if let Value::Number(field_value) = field_value {
-                    if let Value::Number(value) = &self.value {
-                        let field_value = field_value.as_f64().context(format!(
-                            "can't transform field value {:?} to f64",
-                            field_value
-                        ))?;
-                        let value = value.as_f64().context(format!(
-                            "can't transform comparison value {:?} to f64",
-                            value
-                        ))?;
-                        Ok(field_value < value)
-                    } else {
-                        // false
-                        Err(anyhow!(
-                            "Error comparing values: {:?} < {:?}",
-                            field_value,
-                            value
-                        ))
-                    }
-                } else {
-                    Err(anyhow!(
-                        "Error comparing values: {:?} < {:?}",
-                        field_value,
-                        value
-                    ))
-                }
+            QueryFilterOperator::Equals => json_eq(field_value, &self.value),
+            QueryFilterOperator::In => match &self.value {
+                Value::Array(values) => values.iter().any(|v| json_eq(field_value, v)),
+                _ => false,
+            },
+            QueryFilterOperator::GreaterThan => ordering() == Some(Ordering::Greater),
+            QueryFilterOperator::GreaterThanOrEquals => {
+                matches!(ordering(), Some(Ordering::Greater | Ordering::Equal))
             }
-            // QueryFilterOperator::LessThanOrEqual => field_value <= &self.value,
-            // QueryFilterOperator::GreaterThan => field_value > &self.value,
-            // QueryFilterOperator::GreaterThanOrEqual => field_value >= &self.value,
-            QueryFilterOperator::In => {
-                if let Value::Array(array) = &self.value {
-                    Ok(array.contains(field_value))
-                } else {
-                    Err(anyhow!(
-                        "Error comparing values: {:?} in {:?}",
-                        field_value,
-                        self.value
-                    ))
-                }
+            QueryFilterOperator::LessThan => ordering() == Some(Ordering::Less),
+            QueryFilterOperator::LessThanOrEquals => {
+                matches!(ordering(), Some(Ordering::Less | Ordering::Equal))
             }
-            QueryFilterOperator::NotIn => {
-                if let Value::Array(array) = &self.value {
-                    Ok(!array.contains(field_value))
-                } else {
-                    Err(anyhow!(
-                        "Error comparing values: {:?} not in {:?}",
-                        field_value,
-                        self.value
-                    ))
+            operator => unreachable!("operator {:?} is not applied per value", operator),
+        }
+    }
+
+    /// Rejects values the operator can't be applied to.
+    pub fn check_value(&self) -> Result<()> {
+        match &self.operator {
+            QueryFilterOperator::In | QueryFilterOperator::NotIn => self.values().map(|_| ()),
+            QueryFilterOperator::GreaterThan
+            | QueryFilterOperator::GreaterThanOrEquals
+            | QueryFilterOperator::LessThan
+            | QueryFilterOperator::LessThanOrEquals => match &self.value {
+                Value::Number(_) | Value::String(_) => Ok(()),
+                value => Err(StoreError::UnsupportedOperator(format!(
+                    "operator {:?} on field '{}' requires a number or a string, got {}",
+                    self.operator, self.field, value
+                ))),
+            },
+            _ => Ok(()),
+        }
+    }
+
+    /// The candidate list of an `In`/`NotIn` filter.
+    pub fn values(&self) -> Result<&Vec<Value>> {
+        match &self.value {
+            Value::Array(values) => Ok(values),
+            value => Err(StoreError::UnsupportedOperator(format!(
+                "operator {:?} on field '{}' requires an array, got {}",
+                self.operator, self.field, value
+            ))),
+        }
+    }
+
+    /// Orders `field_value` against the filter value, or `None` when their
+    /// types differ.
+    fn compare(&self, field_value: &Value) -> Option<Ordering> {
+        match (field_value, &self.value) {
+            (Value::Number(a), Value::Number(b)) => compare_numbers(a, b),
+            (Value::String(a), Value::String(b)) => Some(a.as_bytes().cmp(b.as_bytes())),
+            _ => None,
+        }
+    }
+}
+
+/// Path segment addressing every element of an array.
+pub const ANY_ELEMENT: &str = "*";
+
+/// Resolves a dotted field path such as `address.city` or `items.0.sku`.
+///
+/// Each segment looks up a key on an object or, when it parses as an integer,
+/// an element of an array, counting from the end when negative; this is what
+/// Postgres' `#>` does with the same path. A `*` segment fans out over every
+/// element of an array, so `items.*.sku` reaches the `sku` of each line item.
+pub fn resolve_path<'a>(value: &'a Value, path: &str) -> Vec<&'a Value> {
+    let mut current = vec![value];
+    for segment in path.split('.') {
+        let mut next = vec![];
+        for value in current {
+            match value {
+                Value::Array(array) if segment == ANY_ELEMENT => next.extend(array),
+                Value::Object(object) => next.extend(object.get(segment)),
+                Value::Array(array) => {
+                    let index = segment.parse::<i64>().ok().and_then(|index| match index {
+                        index if index < 0 => {
+                            array.len().checked_sub(index.unsigned_abs() as usize)
+                        }
+                        index => Some(index as usize),
+                    });
+                    next.extend(index.and_then(|index| array.get(index)));
                 }
+                _ => {}
             }
-            operator => unimplemented!("operator {:?} not implemented", operator),
         }
+        current = next;
+    }
+    current
+}
+
+/// JSON equality with numbers compared by value, so `1` equals `1.0` as it
+/// does for Postgres `jsonb`.
+pub fn json_eq(a: &Value, b: &Value) -> bool {
+    match (a, b) {
+        (Value::Number(a), Value::Number(b)) => compare_numbers(a, b) == Some(Ordering::Equal),
+        (Value::Array(a), Value::Array(b)) => {
+            a.len() == b.len() && a.iter().zip(b).all(|(a, b)| json_eq(a, b))
+        }
+        (Value::Object(a), Value::Object(b)) => {
+            a.len() == b.len()
+                && a.iter()
+                    .all(|(key, a)| b.get(key).is_some_and(|b| json_eq(a, b)))
+        }
+        (a, b) => a == b,
+    }
+}
+
+fn compare_numbers(a: &Number, b: &Number) -> Option<Ordering> {
+    if let (Some(a), Some(b)) = (a.as_i64(), b.as_i64()) {
+        return Some(a.cmp(&b));
+    }
+    if let (Some(a), Some(b)) = (a.as_u64(), b.as_u64()) {
+        return Some(a.cmp(&b));
     }
+    a.as_f64()?.partial_cmp(&b.as_f64()?)
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -177,13 +389,88 @@ pub enum QueryFilterOperator {
     NotIn,
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct QuerySortItem {
-    field: String,
-    direction: QuerySortDirection,
+    pub field: String,
+    pub direction: QuerySortDirection,
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+impl QuerySortItem {
+    /// Sort keys must reach a single value, so `*` segments are rejected.
+    pub fn check_field(&self) -> Result<()> {
+        if self.field.split('.').any(|segment| segment == ANY_ELEMENT) {
+            return Err(StoreError::InvalidQuery(format!(
+                "can't sort by '{}': sort fields can't address every array element",
+                self.field
+            )));
+        }
+        Ok(())
+    }
+
+    pub fn compare(&self, a: &Value, b: &Value) -> Ordering {
+        self.directed(compare_sort_values(
+            resolve_path(a, &self.field).first().copied(),
+            resolve_path(b, &self.field).first().copied(),
+        ))
+    }
+
+    /// Like `compare`, against the value of the sort key instead of a second
+    /// document.
+    pub fn compare_to(&self, document: &Value, value: &Value) -> Ordering {
+        self.directed(compare_sort_values(
+            resolve_path(document, &self.field).first().copied(),
+            Some(value),
+        ))
+    }
+
+    fn directed(&self, ordering: Ordering) -> Ordering {
+        match self.direction {
+            QuerySortDirection::Ascending => ordering,
+            QuerySortDirection::Descending => ordering.reverse(),
+        }
+    }
+}
+
+/// Distinct values are taken from a single value per document, so the field
+/// can't have `*` segments.
+pub fn check_distinct_field(field: &str) -> Result<()> {
+    if field.split('.').any(|segment| segment == ANY_ELEMENT) {
+        return Err(StoreError::InvalidQuery(format!(
+            "can't take distinct values of '{}': the field can't address every array element",
+            field
+        )));
+    }
+    Ok(())
+}
+
+/// The position of a value's type in the sort order, used by both backends:
+/// missing and `null` first, then numbers, strings, `false`, `true`, arrays and
+/// objects.
+pub fn sort_rank(value: Option<&Value>) -> u8 {
+    match value {
+        None | Some(Value::Null) => 0,
+        Some(Value::Number(_)) => 1,
+        Some(Value::String(_)) => 2,
+        Some(Value::Bool(false)) => 3,
+        Some(Value::Bool(true)) => 4,
+        Some(Value::Array(_)) => 5,
+        Some(Value::Object(_)) => 6,
+    }
+}
+
+/// Orders two field values for sorting. Numbers compare by value and strings
+/// bytewise; arrays and objects only order by type and tie with each other.
+pub fn compare_sort_values(a: Option<&Value>, b: Option<&Value>) -> Ordering {
+    match (a, b) {
+        (Some(Value::Number(a)), Some(Value::Number(b))) => {
+            compare_numbers(a, b).unwrap_or(Ordering::Equal)
+        }
+        (Some(Value::String(a)), Some(Value::String(b))) => a.as_bytes().cmp(b.as_bytes()),
+        (a, b) => sort_rank(a).cmp(&sort_rank(b)),
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub enum QuerySortDirection {
     #[serde(rename = "1")]
     Ascending,
@@ -191,6 +478,161 @@ pub enum QuerySortDirection {
     Descending,
 }
 
+/// The fields a query returns, as dotted paths of object keys such as
+/// `address.city`. A path selects the whole value under it, so listing both
+/// `address` and `address.city` is the same as listing `address`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub enum Projection {
+    /// Only these fields. An intermediate value that isn't an object is left
+    /// out, since none of the listed fields are in it.
+    Include(Vec<String>),
+    /// Every field but these.
+    Exclude(Vec<String>),
+}
+
+/// The fields of a [`Projection`] nested by path segment, which is how both
+/// the in-memory and the SQL backends walk a document.
+pub type ProjectionFields = BTreeMap<String, ProjectionNode>;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ProjectionNode {
+    /// The whole value under the key.
+    Whole,
+    /// Some of the fields of the object under the key.
+    Fields(ProjectionFields),
+}
+
+impl Projection {
+    pub fn fields(&self) -> &[String] {
+        match self {
+            Projection::Include(fields) | Projection::Exclude(fields) => fields,
+        }
+    }
+
+    /// Projection paths only address object keys, so `*` and empty segments
+    /// are rejected.
+    pub fn check(&self) -> Result<()> {
+        for field in self.fields() {
+            if field
+                .split('.')
+                .any(|segment| segment.is_empty() || segment == ANY_ELEMENT)
+            {
+                return Err(StoreError::InvalidQuery(format!(
+                    "can't project '{}': projection fields are paths of object keys",
+                    field
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    /// The checked fields nested by path segment.
+    pub fn tree(&self) -> Result<ProjectionFields> {
+        self.check()?;
+        let mut tree = ProjectionFields::new();
+        for field in self.fields() {
+            let mut fields = &mut tree;
+            let mut segments = field.split('.').peekable();
+            while let Some(segment) = segments.next() {
+                if segments.peek().is_none() {
+                    fields.insert(segment.to_string(), ProjectionNode::Whole);
+                    break;
+                }
+                let node = fields
+                    .entry(segment.to_string())
+                    .or_insert_with(|| ProjectionNode::Fields(ProjectionFields::new()));
+                match node {
+                    ProjectionNode::Whole => break,
+                    ProjectionNode::Fields(nested) => fields = nested,
+                }
+            }
+        }
+        Ok(tree)
+    }
+
+    pub fn apply(&self, mut value: Value) -> Result<Value> {
+        let tree = self.tree()?;
+        if let Value::Object(object) = &mut value {
+            match self {
+                Projection::Include(_) => include_fields(object, &tree),
+                Projection::Exclude(_) => exclude_fields(object, &tree),
+            }
+        }
+        Ok(value)
+    }
+
+    /// The projection widened so that the values of `fields` survive it, as
+    /// `Store::page` needs for the sort keys of its cursor. Only the object
+    /// keys leading up to an array index are kept.
+    pub fn keeping(&self, fields: &[&str]) -> Projection {
+        let fields: Vec<String> = fields
+            .iter()
+            .map(|field| {
+                field
+                    .split('.')
+                    .take_while(|segment| segment.parse::<i64>().is_err())
+                    .collect::<Vec<_>>()
+                    .join(".")
+            })
+            .filter(|field| !field.is_empty())
+            .collect();
+        match self {
+            Projection::Include(included) => {
+                let mut included = included.clone();
+                for field in fields {
+                    if !included.contains(&field) {
+                        included.push(field);
+                    }
+                }
+                Projection::Include(included)
+            }
+            Projection::Exclude(excluded) => Projection::Exclude(
+                excluded
+                    .iter()
+                    .filter(|excluded| {
+                        !fields
+                            .iter()
+                            .any(|field| is_same_or_nested(field, excluded))
+                    })
+                    .cloned()
+                    .collect(),
+            ),
+        }
+    }
+}
+
+/// Whether one of two paths equals the other or lies under it.
+pub(crate) fn is_same_or_nested(a: &str, b: &str) -> bool {
+    let nested = |path: &str, parent: &str| {
+        path.strip_prefix(parent)
+            .is_some_and(|rest| rest.is_empty() || rest.starts_with('.'))
+    };
+    nested(a, b) || nested(b, a)
+}
+
+fn include_fields(object: &mut Map<String, Value>, fields: &ProjectionFields) {
+    object.retain(|key, value| match (fields.get(key), value) {
+        (Some(ProjectionNode::Whole), _) => true,
+        (Some(ProjectionNode::Fields(nested)), Value::Object(object)) => {
+            include_fields(object, nested);
+            true
+        }
+        _ => false,
+    });
+}
+
+fn exclude_fields(object: &mut Map<String, Value>, fields: &ProjectionFields) {
+    object.retain(|key, value| match (fields.get(key), value) {
+        (Some(ProjectionNode::Whole), _) => false,
+        (Some(ProjectionNode::Fields(nested)), Value::Object(object)) => {
+            exclude_fields(object, nested);
+            true
+        }
+        _ => true,
+    });
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct QueryLimit {
     pub limit: Option<u32>,
@@ -200,6 +642,8 @@ pub struct QueryLimit {
 #[derive(Serialize, Deserialize, Debug, Default)]
 pub struct QueryBuilder {
     filter: Vec<QueryFilterItem>,
+    sort: Vec<QuerySortItem>,
+    projection: Option<Projection>,
 }
 
 #[allow(dead_code)]
@@ -236,7 +680,44 @@ impl QueryBuilder {
         self.wher(field, value)
     }
 
-    pub fn and<F>(&mut self, mut query_fn: F) -> &mut QueryBuilder
+    pub fn not_wher(&mut self, field: &str, value: Value) -> &mut QueryBuilder {
+        self.filter.push(QueryFilterItem::Filter(QueryFilterFilter {
+            operation: QueryFilterOperation::Not,
+            filter: QueryFilter {
+                field: field.to_string(),
+                operator: QueryFilterOperator::Equals,
+                value,
+            },
+        }));
+        self
+    }
+
+    pub fn and<F>(&mut self, query_fn: F) -> &mut QueryBuilder
+    where
+        F: FnMut(QueryBuilder) -> Query,
+    {
+        self.condition(QueryFilterOperation::And, query_fn)
+    }
+
+    pub fn or<F>(&mut self, query_fn: F) -> &mut QueryBuilder
+    where
+        F: FnMut(QueryBuilder) -> Query,
+    {
+        self.condition(QueryFilterOperation::Or, query_fn)
+    }
+
+    pub fn not<F>(&mut self, query_fn: F) -> &mut QueryBuilder
+    where
+        F: FnMut(QueryBuilder) -> Query,
+    {
+        self.condition(QueryFilterOperation::Not, query_fn)
+    }
+
+    fn condition<F>(
+        &mut self,
+        operation: QueryFilterOperation,
+        mut query_fn: F,
+    ) -> &mut QueryBuilder
     where
         F: FnMut(QueryBuilder) -> Query,
     {
@@ -244,63 +725,227 @@ impl QueryBuilder {
         let query = query_fn(query_builder);
         self.filter
             .push(QueryFilterItem::Condition(QueryFilterCondition {
-                operation: QueryFilterOperation::And,
+                operation,
                 filter: query.filter.unwrap(),
             }));
         self
     }
 
     pub fn eq(&mut self, field: &str, value: Value) -> &mut QueryBuilder {
-        self.filter.push(QueryFilterItem::Filter(QueryFilterFilter {
-            operation: QueryFilterOperation::And,
-            filter: QueryFilter {
-                field: field.to_string(),
-                operator: QueryFilterOperator::Equals,
-                value,
-            },
-        }));
-        self
+        self.filter(field, QueryFilterOperator::Equals, value)
     }
 
     pub fn not_eq(&mut self, field: &str, value: Value) -> &mut QueryBuilder {
-        self.filter.push(QueryFilterItem::Filter(QueryFilterFilter {
-            operation: QueryFilterOperation::And,
-            filter: QueryFilter {
-                field: field.to_string(),
-                operator: QueryFilterOperator::NotEquals,
-                value,
-            },
-        }));
-        self
+        self.filter(field, QueryFilterOperator::NotEquals, value)
     }
 
     pub fn gt(&mut self, field: &str, value: Value) -> &mut QueryBuilder {
+        self.filter(field, QueryFilterOperator::GreaterThan, value)
+    }
+
+    pub fn gte(&mut self, field: &str, value: Value) -> &mut QueryBuilder {
+        self.filter(field, QueryFilterOperator::GreaterThanOrEquals, value)
+    }
+
+    pub fn lt(&mut self, field: &str, value: Value) -> &mut QueryBuilder {
+        self.filter(field, QueryFilterOperator::LessThan, value)
+    }
+
+    pub fn lte(&mut self, field: &str, value: Value) -> &mut QueryBuilder {
+        self.filter(field, QueryFilterOperator::LessThanOrEquals, value)
+    }
+
+    pub fn exists(&mut self, field: &str) -> &mut QueryBuilder {
+        self.filter(field, QueryFilterOperator::Exists, Value::Null)
+    }
+
+    pub fn not_exists(&mut self, field: &str) -> &mut QueryBuilder {
+        self.filter(field, QueryFilterOperator::NotExists, Value::Null)
+    }
+
+    pub fn is_in(&mut self, field: &str, values: Vec<Value>) -> &mut QueryBuilder {
+        self.filter(field, QueryFilterOperator::In, Value::Array(values))
+    }
+
+    pub fn not_in(&mut self, field: &str, values: Vec<Value>) -> &mut QueryBuilder {
+        self.filter(field, QueryFilterOperator::NotIn, Value::Array(values))
+    }
+
+    fn filter(
+        &mut self,
+        field: &str,
+        operator: QueryFilterOperator,
+        value: Value,
+    ) -> &mut QueryBuilder {
         self.filter.push(QueryFilterItem::Filter(QueryFilterFilter {
             operation: QueryFilterOperation::And,
             filter: QueryFilter {
                 field: field.to_string(),
-                operator: QueryFilterOperator::GreaterThan,
+                operator,
                 value,
             },
         }));
         self
     }
 
+    pub fn order_by(&mut self, field: &str) -> &mut QueryBuilder {
+        self.sort.push(QuerySortItem {
+            field: field.to_string(),
+            direction: QuerySortDirection::Ascending,
+        });
+        self
+    }
+
+    pub fn order_by_desc(&mut self, field: &str) -> &mut QueryBuilder {
+        self.sort.push(QuerySortItem {
+            field: field.to_string(),
+            direction: QuerySortDirection::Descending,
+        });
+        self
+    }
+
+    /// Returns only these fields, see [`Projection::Include`].
+    pub fn select(&mut self, fields: &[&str]) -> &mut QueryBuilder {
+        self.projection = Some(Projection::Include(
+            fields.iter().map(|field| field.to_string()).collect(),
+        ));
+        self
+    }
+
+    /// Returns every field but these, see [`Projection::Exclude`].
+    pub fn exclude(&mut self, fields: &[&str]) -> &mut QueryBuilder {
+        self.projection = Some(Projection::Exclude(
+            fields.iter().map(|field| field.to_string()).collect(),
+        ));
+        self
+    }
+
     pub fn build(&self) -> Query {
         Query {
             filter: Some(self.filter.clone()),
-            sort: None,
+            sort: (!self.sort.is_empty()).then(|| self.sort.clone()),
             limit: None,
+            after: None,
+            projection: self.projection.clone(),
         }
     }
 }
 
 #[cfg(test)]
-mod tests {
+pub(crate) mod tests {
     use serde_json::json;
 
     use super::*;
 
+    pub(crate) struct BooleanCase {
+        pub name: &'static str,
+        pub query: Query,
+        /// The `WHERE` clause `sql::items_to_sql` renders, with `?` placeholders.
+        pub sql: &'static str,
+        /// The ids of [`boolean_docs`] the query selects.
+        pub ids: Vec<i64>,
+    }
+
+    pub(crate) fn boolean_docs() -> Vec<Value> {
+        vec![
+            json!({"id": 1, "a": 1, "b": 1}),
+            json!({"id": 2, "a": 1, "b": 2}),
+            json!({"id": 3, "a": 2, "b": 1}),
+            json!({"id": 4, "a": 2}),
+        ]
+    }
+
+    /// Boolean evaluation cases shared by the in-memory, SQL rendering and
+    /// Postgres tests, so every engine is held to the same answers.
+    pub(crate) fn boolean_cases() -> Vec<BooleanCase> {
+        vec![
+            BooleanCase {
+                name: "and binds tighter than or",
+                query: Query::builder()
+                    .wher("a", json!(1))
+                    .or_wher("a", json!(2))
+                    .and_wher("b", json!(1))
+                    .build(),
+                sql: "data #> ? = ? OR (data #> ? = ? AND data #> ? = ?)",
+                ids: vec![1, 2, 3],
+            },
+            BooleanCase {
+                name: "leading not negates the first filter",
+                query: Query::builder().not_wher("b", json!(1)).build(),
+                sql: "(data #> ? = ?) IS NOT TRUE",
+                ids: vec![2, 4],
+            },
+            BooleanCase {
+                name: "not is and not",
+                query: Query::builder()
+                    .wher("a", json!(2))
+                    .not_wher("b", json!(1))
+                    .build(),
+                sql: "data #> ? = ? AND (data #> ? = ?) IS NOT TRUE",
+                ids: vec![4],
+            },
+            BooleanCase {
+                name: "negated condition",
+                query: Query::builder()
+                    .not(|mut q| q.wher("a", json!(1)).or_wher("b", json!(1)).build())
+                    .build(),
+                sql: "(data #> ? = ? OR data #> ? = ?) IS NOT TRUE",
+                ids: vec![4],
+            },
+            BooleanCase {
+                name: "condition groups an or",
+                query: Query::builder()
+                    .and(|mut q| q.wher("a", json!(1)).or_wher("a", json!(2)).build())
+                    .and_wher("b", json!(2))
+                    .build(),
+                sql: "(data #> ? = ? OR data #> ? = ?) AND data #> ? = ?",
+                ids: vec![2],
+            },
+            BooleanCase {
+                name: "or condition",
+                query: Query::builder()
+                    .wher("a", json!(1))
+                    .or(|mut q| q.wher("b", json!(1)).not_wher("a", json!(1)).build())
+                    .build(),
+                sql: "data #> ? = ? OR (data #> ? = ? AND (data #> ? = ?) IS NOT TRUE)",
+                ids: vec![1, 2, 3],
+            },
+            BooleanCase {
+                name: "or not",
+                query: Query::builder()
+                    .wher("b", json!(1))
+                    .or(|mut q| q.not_wher("b", json!(2)).build())
+                    .build(),
+                sql: "data #> ? = ? OR ((data #> ? = ?) IS NOT TRUE)",
+                ids: vec![1, 3, 4],
+            },
+            BooleanCase {
+                name: "empty condition matches everything",
+                query: Query::builder()
+                    .and(|q| q.build())
+                    .and_wher("a", json!(2))
+                    .build(),
+                sql: "(TRUE) AND data #> ? = ?",
+                ids: vec![3, 4],
+            },
+        ]
+    }
+
+    #[test]
+    fn test_boolean_semantics() -> anyhow::Result<()> {
+        for case in boolean_cases() {
+            let mut ids = vec![];
+            for doc in boolean_docs() {
+                if case.query.matches(&doc)? {
+                    ids.push(doc["id"].as_i64().unwrap());
+                }
+            }
+            assert_eq!(ids, case.ids, "{}", case.name);
+        }
+
+        Ok(())
+    }
+
     #[test]
     fn test_query_builder() {
         let query = Query::builder()
@@ -342,6 +987,8 @@ mod tests {
                 limit: Some(10),
                 offset: Some(30),
             }),
+            after: None,
+            projection: None,
         };
 
         let json = serde_json::to_value(&query).unwrap();
@@ -389,6 +1036,8 @@ mod tests {
                 limit: Some(10),
                 offset: Some(30),
             }),
+            after: None,
+            projection: None,
         };
 
         // FIXME improve the test like test_query
@@ -396,4 +1045,293 @@ mod tests {
         let json = serde_json::to_string(&query).unwrap();
         assert_eq!(json, expected);
     }
+
+    #[test]
+    fn test_filter_operators() -> anyhow::Result<()> {
+        let doc = json!({
+            "age": 30,
+            "score": 1.5,
+            "name": "John",
+            "created": "2022-11-05T10:00:00Z",
+            "nickname": null,
+        });
+
+        let cases = vec![
+            (Query::builder().eq("age", json!(30.0)).build(), true),
+            (Query::builder().eq("missing", json!(30)).build(), false),
+            (Query::builder().not_eq("age", json!(31)).build(), true),
+            (Query::builder().not_eq("missing", json!(31)).build(), false),
+            (Query::builder().eq("nickname", Value::Null).build(), true),
+            (Query::builder().gt("age", json!(29)).build(), true),
+            (Query::builder().gt("age", json!(30)).build(), false),
+            (Query::builder().gte("age", json!(30)).build(), true),
+            (Query::builder().lt("score", json!(2)).build(), true),
+            (Query::builder().lte("score", json!(1.5)).build(), true),
+            (Query::builder().lt("name", json!(10)).build(), false),
+            (Query::builder().gt("name", json!("Jane")).build(), true),
+            (Query::builder().lt("name", json!("john")).build(), true),
+            (
+                Query::builder().gte("created", json!("2022-11-05")).build(),
+                true,
+            ),
+            (
+                Query::builder()
+                    .lt("created", json!("2022-11-05T09:59:59Z"))
+                    .build(),
+                false,
+            ),
+            (Query::builder().gt("age", json!("29")).build(), false),
+            (Query::builder().exists("nickname").build(), true),
+            (Query::builder().exists("missing").build(), false),
+            (Query::builder().not_exists("missing").build(), true),
+            (
+                Query::builder()
+                    .is_in("age", vec![json!(1), json!(30)])
+                    .build(),
+                true,
+            ),
+            (Query::builder().is_in("age", vec![]).build(), false),
+            (Query::builder().not_in("age", vec![json!(1)]).build(), true),
+            (Query::builder().not_in("age", vec![]).build(), true),
+            (
+                Query::builder().not_in("missing", vec![json!(1)]).build(),
+                false,
+            ),
+        ];
+        for (query, expected) in cases {
+            assert_eq!(query.matches(&doc)?, expected, "{:?}", query.filter);
+        }
+
+        assert!(Query::builder()
+            .gt("age", json!(true))
+            .build()
+            .matches(&doc)
+            .is_err());
+        assert!(Query::builder()
+            .filter("age", QueryFilterOperator::In, json!(30))
+            .build()
+            .matches(&doc)
+            .is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_sort() {
+        let query = Query::builder()
+            .order_by("name")
+            .order_by_desc("age")
+            .build();
+        let mut values = vec![
+            json!({"name": "b", "age": 1}),
+            json!({"name": "a", "age": 1}),
+            json!({"name": "a", "age": 2}),
+            json!({"name": "B"}),
+            json!({"name": 10}),
+            json!({"age": 5}),
+        ];
+        query.sort(&mut values).unwrap();
+
+        assert_eq!(
+            values,
+            vec![
+                json!({"age": 5}),
+                json!({"name": 10}),
+                json!({"name": "B"}),
+                json!({"name": "a", "age": 2}),
+                json!({"name": "a", "age": 1}),
+                json!({"name": "b", "age": 1}),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_after() -> anyhow::Result<()> {
+        let mut query = Query::builder()
+            .order_by("name")
+            .order_by_desc("age")
+            .build();
+        query.after = Some(vec![json!("a"), json!(1)]);
+
+        let cases = vec![
+            (json!({"name": "a", "age": 2}), false),
+            (json!({"name": "a", "age": 1}), false),
+            (json!({"name": "a", "age": 0}), true),
+            (json!({"name": "a"}), true),
+            (json!({"name": "b", "age": 5}), true),
+            (json!({"name": "B", "age": 0}), false),
+            (json!({"name": 10}), false),
+            (json!({"name": true}), true),
+        ];
+        for (doc, expected) in cases {
+            assert_eq!(query.matches(&doc)?, expected, "{}", doc);
+        }
+
+        query.after = Some(vec![json!("a")]);
+        assert!(query.matches(&json!({"name": "b"})).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_resolve_path() {
+        let doc = json!({
+            "address": {"city": "Lisbon", "0": "zero"},
+            "items": [{"sku": "A1", "qty": 2}, {"sku": "B2", "tags": ["x", "y"]}],
+        });
+
+        assert_eq!(resolve_path(&doc, "address.city"), vec!["Lisbon"]);
+        assert_eq!(resolve_path(&doc, "address.0"), vec!["zero"]);
+        assert_eq!(resolve_path(&doc, "items.0.sku"), vec!["A1"]);
+        assert_eq!(resolve_path(&doc, "items.-1.sku"), vec!["B2"]);
+        assert!(resolve_path(&doc, "items.2.sku").is_empty());
+        assert!(resolve_path(&doc, "items.sku").is_empty());
+        assert_eq!(resolve_path(&doc, "items.*.sku"), vec!["A1", "B2"]);
+        assert_eq!(resolve_path(&doc, "items.*.tags.*"), vec!["x", "y"]);
+        assert!(resolve_path(&doc, "address.*").is_empty());
+    }
+
+    #[test]
+    fn test_nested_filters() -> anyhow::Result<()> {
+        let doc = json!({
+            "address": {"city": "Lisbon"},
+            "items": [{"sku": "A1", "qty": 2}, {"sku": "B2", "qty": 5}],
+        });
+
+        let cases = vec![
+            (
+                Query::builder().eq("address.city", json!("Lisbon")).build(),
+                true,
+            ),
+            (
+                Query::builder().eq("items.1.sku", json!("B2")).build(),
+                true,
+            ),
+            (
+                Query::builder().eq("items.*.sku", json!("B2")).build(),
+                true,
+            ),
+            (Query::builder().gt("items.*.qty", json!(4)).build(), true),
+            (Query::builder().gt("items.*.qty", json!(5)).build(), false),
+            (
+                Query::builder().not_eq("items.*.sku", json!("B2")).build(),
+                false,
+            ),
+            (
+                Query::builder().not_eq("items.*.sku", json!("C3")).build(),
+                true,
+            ),
+            (
+                Query::builder()
+                    .not_in("items.*.sku", vec![json!("A1")])
+                    .build(),
+                false,
+            ),
+            (Query::builder().exists("items.*.qty").build(), true),
+            (Query::builder().not_exists("items.*.price").build(), true),
+            (
+                Query::builder()
+                    .not_eq("missing.*.sku", json!("A1"))
+                    .build(),
+                false,
+            ),
+        ];
+        for (query, expected) in cases {
+            assert_eq!(query.matches(&doc)?, expected, "{:?}", query.filter);
+        }
+
+        let query = Query::builder().order_by("items.*.sku").build();
+        assert!(query.sort(&mut [doc]).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_projection() -> anyhow::Result<()> {
+        let doc = json!({
+            "id": 1,
+            "name": "John",
+            "address": {"city": "Lisbon", "zip": "1000", "geo": {"lat": 38.7, "lng": -9.1}},
+            "tags": ["a", "b"],
+            "note": "text",
+        });
+
+        let cases = vec![
+            (
+                Projection::Include(vec!["id".into(), "name".into()]),
+                json!({"id": 1, "name": "John"}),
+            ),
+            (
+                Projection::Include(vec!["address.city".into(), "address.geo.lat".into()]),
+                json!({"address": {"city": "Lisbon", "geo": {"lat": 38.7}}}),
+            ),
+            (
+                Projection::Include(vec!["address".into(), "address.city".into()]),
+                json!({"address": {"city": "Lisbon", "zip": "1000", "geo": {"lat": 38.7, "lng": -9.1}}}),
+            ),
+            // `note` isn't an object and `missing` isn't there at all
+            (
+                Projection::Include(vec!["id".into(), "note.x".into(), "missing".into()]),
+                json!({"id": 1}),
+            ),
+            (Projection::Include(vec![]), json!({})),
+            (
+                Projection::Exclude(vec!["address".into(), "tags".into(), "note".into()]),
+                json!({"id": 1, "name": "John"}),
+            ),
+            (
+                Projection::Exclude(vec![
+                    "address.geo".into(),
+                    "address.zip".into(),
+                    "note.x".into(),
+                ]),
+                json!({"id": 1, "name": "John", "address": {"city": "Lisbon"}, "tags": ["a", "b"], "note": "text"}),
+            ),
+            (Projection::Exclude(vec![]), doc.clone()),
+        ];
+        for (projection, expected) in cases {
+            assert_eq!(projection.apply(doc.clone())?, expected, "{:?}", projection);
+        }
+
+        assert!(Projection::Include(vec!["tags.*".into()]).check().is_err());
+        assert!(Projection::Exclude(vec!["address..city".into()])
+            .check()
+            .is_err());
+
+        let query = Query::builder().select(&["id", "name"]).build();
+        assert_eq!(
+            query.project(doc.clone())?,
+            json!({"id": 1, "name": "John"})
+        );
+        let json = serde_json::to_value(&query)?;
+        assert_eq!(json["projection"], json!({"include": ["id", "name"]}));
+        assert_eq!(Query::builder().build().project(doc.clone())?, doc);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_projection_keeping() {
+        let include = Projection::Include(vec!["name".into()]);
+        assert_eq!(
+            include.keeping(&["name", "address.city", "items.0.sku", "id"]),
+            Projection::Include(vec![
+                "name".into(),
+                "address.city".into(),
+                "items".into(),
+                "id".into()
+            ])
+        );
+
+        let exclude = Projection::Exclude(vec![
+            "address".into(),
+            "geo.lat".into(),
+            "note".into(),
+            "notes".into(),
+        ]);
+        assert_eq!(
+            exclude.keeping(&["address.city", "geo", "notes.0"]),
+            Projection::Exclude(vec!["note".into()])
+        );
+    }
 }
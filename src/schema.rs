@@ -0,0 +1,718 @@
+use std::fmt;
+
+use jsonschema::error::ValidationErrorKind;
+use jsonschema::{ValidationError, Validator};
+use serde_json::{Map, Value};
+
+use crate::error::{Result, StoreError};
+use crate::migration::SCHEMA_VERSION_KEY;
+use crate::query::{Query, QueryFilter, QueryFilterItem, QueryFilterOperator, ANY_ELEMENT};
+use crate::update::{Update, UpdateOperation};
+
+/// What a path reaches in a schema that doesn't constrain it, such as a
+/// `serde_json::Value` field.
+static ANYTHING: Value = Value::Bool(true);
+
+/// How deep `$ref`s and combinators are followed when looking up a field, so
+/// a recursive type doesn't loop.
+const MAX_DEPTH: usize = 32;
+
+/// The JSON Schema generated for a type, for `Collection::schema`. The type
+/// derives `schemars::JsonSchema`, which follows its serde attributes.
+pub fn generate<T: schemars::JsonSchema>() -> Value {
+    schemars::schema_for!(T).to_value()
+}
+
+/// A problem with one field of a document, query or update that doesn't
+/// conform to a [`Schema`]. `field` is a dotted path such as `address.city`,
+/// with array elements as their index, and is empty for the whole document.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FieldError {
+    pub field: String,
+    pub message: String,
+}
+
+impl FieldError {
+    fn new(field: impl Into<String>, message: impl Into<String>) -> Self {
+        Self {
+            field: field.into(),
+            message: message.into(),
+        }
+    }
+}
+
+impl fmt::Display for FieldError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.field.as_str() {
+            "" => write!(f, "{}", self.message),
+            field => write!(f, "{}: {}", field, self.message),
+        }
+    }
+}
+
+/// A collection's JSON Schema, compiled. `Store` checks every document it
+/// writes against it, and the same schema checks the field names and values
+/// of queries and updates, see [`Schema::validate_query`].
+///
+/// The schema version key migrations stamp documents with, see
+/// [`crate::migration::SCHEMA_VERSION_KEY`], is always allowed and isn't
+/// checked.
+#[derive(Debug)]
+pub struct Schema {
+    document: Value,
+    validator: Validator,
+}
+
+impl Schema {
+    /// Compiles a JSON Schema document. `$ref`s can only point within it.
+    pub fn new(document: Value) -> Result<Self> {
+        let validator = jsonschema::validator_for(&document)
+            .map_err(|err| StoreError::InvalidQuery(format!("invalid JSON Schema: {}", err)))?;
+        Ok(Self {
+            document,
+            validator,
+        })
+    }
+
+    pub fn document(&self) -> &Value {
+        &self.document
+    }
+
+    /// Checks a document, failing with every field that doesn't conform.
+    pub fn validate(&self, document: &Value) -> Result<()> {
+        let stamped = document
+            .as_object()
+            .is_some_and(|map| map.contains_key(SCHEMA_VERSION_KEY));
+        let errors = match stamped {
+            true => {
+                let mut document = document.clone();
+                document.as_object_mut().unwrap().remove(SCHEMA_VERSION_KEY);
+                self.field_errors("", self.validator.iter_errors(&document))?
+            }
+            false => self.field_errors("", self.validator.iter_errors(document))?,
+        };
+        into_result(errors)
+    }
+
+    /// Checks that the fields a query filters, sorts and projects on are
+    /// declared by the schema, and that the values it compares them with, or
+    /// each of those of `in` and `not_in`, are valid values of the field.
+    ///
+    /// A field is unknown when none of the object schemas on its path list it
+    /// under `properties` or allow it through `additionalProperties` or
+    /// `patternProperties`. Objects that declare no properties at all, like
+    /// values without a schema, allow any field below them.
+    pub fn validate_query(&self, query: &Query) -> Result<()> {
+        let mut errors = vec![];
+        if let Some(filter) = &query.filter {
+            self.check_filter(filter, &mut errors)?;
+        }
+        for item in query.sort.iter().flatten() {
+            self.check_known(&item.field, &mut errors);
+        }
+        for field in query
+            .projection
+            .iter()
+            .flat_map(|projection| projection.fields())
+        {
+            self.check_known(field, &mut errors);
+        }
+        into_result(errors)
+    }
+
+    /// Checks an update the way [`Schema::validate_query`] checks a query,
+    /// as far as that is possible without the documents it applies to: `set`
+    /// values must be valid values of the field, `push` and `pull` values
+    /// valid elements of it, and `inc` is only checked to apply to numbers,
+    /// since its value is a difference. Fields the schema requires can't be
+    /// unset or renamed.
+    pub fn validate_update(&self, update: &Update) -> Result<()> {
+        let mut errors = vec![];
+        for operation in &update.operations {
+            match operation {
+                UpdateOperation::Set { field, value } => {
+                    self.check_value(field, field, value, false, &mut errors)?;
+                }
+                UpdateOperation::Inc { field, value } => {
+                    let value = Value::Number(value.clone());
+                    self.check_value(field, field, &value, true, &mut errors)?;
+                }
+                UpdateOperation::Push { field, value } | UpdateOperation::Pull { field, value } => {
+                    let elements = format!("{}.{}", field, ANY_ELEMENT);
+                    self.check_value(field, &elements, value, false, &mut errors)?;
+                }
+                UpdateOperation::Unset { field } => {
+                    self.check_optional(field, &mut errors);
+                }
+                UpdateOperation::Rename { field, to } => {
+                    self.check_optional(field, &mut errors);
+                    self.check_known(to, &mut errors);
+                }
+            }
+        }
+        into_result(errors)
+    }
+
+    fn check_filter(&self, items: &[QueryFilterItem], errors: &mut Vec<FieldError>) -> Result<()> {
+        for item in items {
+            match item {
+                QueryFilterItem::Filter(filter) => {
+                    self.check_query_filter(&filter.filter, errors)?
+                }
+                QueryFilterItem::Condition(condition) => {
+                    self.check_filter(&condition.filter, errors)?
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn check_query_filter(&self, filter: &QueryFilter, errors: &mut Vec<FieldError>) -> Result<()> {
+        let field = &filter.field;
+        match (&filter.operator, &filter.value) {
+            (QueryFilterOperator::Exists | QueryFilterOperator::NotExists, _) => {
+                self.check_known(field, errors);
+            }
+            (QueryFilterOperator::In | QueryFilterOperator::NotIn, Value::Array(values)) => {
+                let before = errors.len();
+                for value in values {
+                    self.check_value(field, field, value, false, errors)?;
+                    if errors.len() > before {
+                        break;
+                    }
+                }
+                if values.is_empty() {
+                    self.check_known(field, errors);
+                }
+            }
+            (_, value) => self.check_value(field, field, value, false, errors)?,
+        }
+        Ok(())
+    }
+
+    /// Records an error when the schema doesn't declare the field.
+    fn check_known(&self, field: &str, errors: &mut Vec<FieldError>) -> bool {
+        if field == SCHEMA_VERSION_KEY || !self.field_schemas(field).is_empty() {
+            return true;
+        }
+        errors.push(FieldError::new(field, "is not a field of the schema"));
+        false
+    }
+
+    /// Records an error when `value` isn't valid for any of the schemas at
+    /// `path`, reported as `field`. With `types_only` only the type of the
+    /// value is checked.
+    fn check_value(
+        &self,
+        field: &str,
+        path: &str,
+        value: &Value,
+        types_only: bool,
+        errors: &mut Vec<FieldError>,
+    ) -> Result<()> {
+        if field == SCHEMA_VERSION_KEY {
+            return Ok(());
+        }
+        let schemas = self.field_schemas(path);
+        if schemas.is_empty() {
+            errors.push(FieldError::new(field, "is not a field of the schema"));
+            return Ok(());
+        }
+
+        let mut first = None;
+        for schema in schemas {
+            let validator = self.subschema_validator(schema)?;
+            let mut failures = validator.iter_errors(value).filter(|err| {
+                !types_only
+                    || matches!(
+                        err.kind,
+                        ValidationErrorKind::Type { .. }
+                            | ValidationErrorKind::AnyOf
+                            | ValidationErrorKind::OneOfNotValid
+                    )
+            });
+            match failures.next() {
+                None => return Ok(()),
+                Some(err) => {
+                    first.get_or_insert_with(|| err.to_string());
+                }
+            }
+        }
+        errors.push(FieldError::new(field, first.unwrap_or_default()));
+        Ok(())
+    }
+
+    /// Records an error when the schema requires the field, or doesn't
+    /// declare it.
+    fn check_optional(&self, field: &str, errors: &mut Vec<FieldError>) {
+        if !self.check_known(field, errors) {
+            return;
+        }
+        let (parent, name) = match field.rsplit_once('.') {
+            Some((parent, name)) => (self.field_schemas(parent), name),
+            None => (vec![&self.document], field),
+        };
+        let required = parent
+            .into_iter()
+            .flat_map(|schema| self.expand(schema, 0))
+            .filter_map(|schema| schema.get("required")?.as_array())
+            .any(|required| required.iter().any(|key| key == name));
+        if required {
+            errors.push(FieldError::new(field, "is required"));
+        }
+    }
+
+    /// The schemas the values at a dotted path must conform to, or none when
+    /// the schema doesn't declare the field. Any of them may match, as the
+    /// path can lead through `anyOf` and `oneOf` alternatives.
+    fn field_schemas(&self, field: &str) -> Vec<&Value> {
+        let mut current = vec![&self.document];
+        for segment in field.split('.') {
+            current = current
+                .into_iter()
+                .flat_map(|schema| self.expand(schema, 0))
+                .flat_map(|schema| step(schema, segment))
+                .collect();
+            if current.is_empty() {
+                break;
+            }
+        }
+        current
+    }
+
+    /// A schema along with those its `$ref`, `allOf`, `anyOf` and `oneOf`
+    /// lead to.
+    fn expand<'a>(&'a self, schema: &'a Value, depth: usize) -> Vec<&'a Value> {
+        let mut schemas = vec![schema];
+        if depth >= MAX_DEPTH {
+            return schemas;
+        }
+        let referenced = schema
+            .get("$ref")
+            .and_then(Value::as_str)
+            .and_then(|reference| reference.strip_prefix('#'))
+            .and_then(|pointer| self.document.pointer(pointer));
+        schemas.extend(
+            referenced
+                .map(|schema| self.expand(schema, depth + 1))
+                .into_iter()
+                .flatten(),
+        );
+        for keyword in ["allOf", "anyOf", "oneOf"] {
+            for schema in schema
+                .get(keyword)
+                .and_then(Value::as_array)
+                .into_iter()
+                .flatten()
+            {
+                schemas.extend(self.expand(schema, depth + 1));
+            }
+        }
+        schemas
+    }
+
+    /// Turns the validator's errors into field errors under `prefix`, one per
+    /// missing or unexpected property.
+    fn field_errors<'a>(
+        &self,
+        prefix: &str,
+        errors: impl Iterator<Item = ValidationError<'a>>,
+    ) -> Result<Vec<FieldError>> {
+        let mut new = vec![];
+        for err in errors {
+            let path = join(prefix, &dotted_path(err.instance_path.as_str()));
+            match &err.kind {
+                ValidationErrorKind::Required { property } => {
+                    let name = property
+                        .as_str()
+                        .map_or_else(|| property.to_string(), String::from);
+                    new.push(FieldError::new(join(&path, &name), "is required"));
+                }
+                ValidationErrorKind::AdditionalProperties { unexpected }
+                | ValidationErrorKind::UnevaluatedProperties { unexpected } => {
+                    for name in unexpected {
+                        new.push(FieldError::new(
+                            join(&path, name),
+                            "is not a field of the schema",
+                        ));
+                    }
+                }
+                ValidationErrorKind::AnyOf | ValidationErrorKind::OneOfNotValid => {
+                    match self.branch_errors(&path, &err.instance)? {
+                        Some(errors) => new.extend(errors),
+                        None => new.push(FieldError::new(path, err.to_string())),
+                    }
+                }
+                _ => new.push(FieldError::new(path, err.to_string())),
+            }
+        }
+        Ok(new)
+    }
+
+    /// The errors of the one `anyOf` or `oneOf` alternative at `path` whose
+    /// type fits the value, such as the struct of an `Option` that isn't
+    /// `null`. `None` when several fit, or none does.
+    fn branch_errors(&self, path: &str, value: &Value) -> Result<Option<Vec<FieldError>>> {
+        let candidates = match path {
+            "" => vec![&self.document],
+            path => self.field_schemas(path),
+        };
+        let combinators = ["$ref", "allOf", "anyOf", "oneOf"];
+        let alternatives = candidates
+            .into_iter()
+            .flat_map(|schema| self.expand(schema, 0))
+            .filter(|schema| {
+                !combinators
+                    .iter()
+                    .any(|keyword| schema.get(keyword).is_some())
+            });
+
+        let mut fitting = None;
+        for schema in alternatives {
+            let validator = self.subschema_validator(schema)?;
+            let errors: Vec<_> = validator.iter_errors(value).collect();
+            let wrong_type = errors.iter().any(|err| {
+                err.instance_path.as_str().is_empty()
+                    && matches!(err.kind, ValidationErrorKind::Type { .. })
+            });
+            if wrong_type {
+                continue;
+            }
+            if fitting.is_some() {
+                return Ok(None);
+            }
+            fitting = Some(self.field_errors(path, errors.into_iter())?);
+        }
+        Ok(fitting)
+    }
+
+    /// Compiles a schema found within the document, along with the
+    /// definitions its `$ref`s may point to.
+    fn subschema_validator(&self, schema: &Value) -> Result<Validator> {
+        let mut schema = schema.clone();
+        if let Value::Object(map) = &mut schema {
+            for keyword in ["$schema", "$defs", "definitions"] {
+                if let Some(value) = self.document.get(keyword) {
+                    map.entry(keyword).or_insert_with(|| value.clone());
+                }
+            }
+        }
+        jsonschema::validator_for(&schema)
+            .map_err(|err| StoreError::InvalidQuery(format!("invalid JSON Schema: {}", err)))
+    }
+}
+
+/// The schemas one path segment leads to from `schema`, without following
+/// its `$ref`s and combinators, which `Schema::expand` already did.
+fn step<'a>(schema: &'a Value, segment: &str) -> Vec<&'a Value> {
+    let map = match schema {
+        Value::Bool(true) => return vec![&ANYTHING],
+        Value::Object(map) => map,
+        _ => return vec![],
+    };
+    let allows = |kind: &str| match map.get("type") {
+        None => true,
+        Some(Value::String(ty)) => ty == kind,
+        Some(Value::Array(types)) => types.iter().any(|ty| ty == kind),
+        Some(_) => false,
+    };
+    let combined = ["$ref", "allOf", "anyOf", "oneOf"]
+        .iter()
+        .any(|keyword| map.contains_key(*keyword));
+
+    let mut next = vec![];
+    if allows("object") {
+        next.extend(object_step(map, segment, combined));
+    }
+    let index = segment.parse::<i64>().ok();
+    if allows("array") && (segment == ANY_ELEMENT || index.is_some()) {
+        let prefix = map.get("prefixItems").and_then(Value::as_array);
+        match (prefix, index) {
+            (Some(prefix), Some(index)) if index >= 0 => next.extend(prefix.get(index as usize)),
+            (Some(prefix), None) => next.extend(prefix),
+            _ => {}
+        }
+        match map.get("items") {
+            Some(items) => next.push(items),
+            None if prefix.is_none() && !combined && map.contains_key("type") => {
+                next.push(&ANYTHING)
+            }
+            None => {}
+        }
+    }
+    next
+}
+
+fn object_step<'a>(map: &'a Map<String, Value>, segment: &str, combined: bool) -> Vec<&'a Value> {
+    let properties = map.get("properties").and_then(Value::as_object);
+    if let Some(schema) = properties.and_then(|properties| properties.get(segment)) {
+        return vec![schema];
+    }
+    if map.contains_key("patternProperties") {
+        return vec![&ANYTHING];
+    }
+    match map.get("additionalProperties") {
+        Some(Value::Bool(false)) => vec![],
+        Some(schema) => vec![schema],
+        // an object schema that declares no fields, like `{}` or a plain
+        // `"type": "object"`, allows any
+        None if properties.is_none() && !combined => vec![&ANYTHING],
+        None => vec![],
+    }
+}
+
+/// A dotted path under `prefix`.
+fn join(prefix: &str, path: &str) -> String {
+    match (prefix, path) {
+        ("", path) => path.to_string(),
+        (prefix, "") => prefix.to_string(),
+        (prefix, path) => format!("{}.{}", prefix, path),
+    }
+}
+
+/// A JSON pointer such as `/items/0/sku` as the dotted path `items.0.sku`.
+fn dotted_path(pointer: &str) -> String {
+    pointer
+        .split('/')
+        .skip(1)
+        .map(|segment| segment.replace("~1", "/").replace("~0", "~"))
+        .collect::<Vec<_>>()
+        .join(".")
+}
+
+fn into_result(errors: Vec<FieldError>) -> Result<()> {
+    match errors.is_empty() {
+        true => Ok(()),
+        false => Err(StoreError::Validation(errors)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use schemars::JsonSchema;
+    use serde::{Deserialize, Serialize};
+    use serde_json::json;
+
+    use super::*;
+
+    #[derive(Serialize, Deserialize, JsonSchema)]
+    struct Order {
+        id: Option<String>,
+        #[schemars(length(min = 1))]
+        customer: String,
+        #[schemars(range(min = 0))]
+        stock: u32,
+        shipping: Option<Address>,
+        lines: Vec<Line>,
+        tags: HashMap<String, bool>,
+        extra: Value,
+    }
+
+    #[derive(Serialize, Deserialize, JsonSchema)]
+    struct Address {
+        city: String,
+        zip: Option<String>,
+    }
+
+    #[derive(Serialize, Deserialize, JsonSchema)]
+    struct Line {
+        sku: String,
+        quantity: i64,
+    }
+
+    fn schema() -> Schema {
+        Schema::new(generate::<Order>()).unwrap()
+    }
+
+    fn errors(result: Result<()>) -> Vec<String> {
+        match result {
+            Err(StoreError::Validation(errors)) => {
+                errors.iter().map(FieldError::to_string).collect()
+            }
+            other => panic!("expected a validation error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_validate() -> anyhow::Result<()> {
+        let schema = schema();
+        let valid = json!({
+            "id": null, "customer": "Ann", "stock": 2, "shipping": {"city": "Porto", "zip": null},
+            "lines": [{"sku": "A", "quantity": 1}], "tags": {"gift": true}, "extra": [1],
+            "_schema": 3,
+        });
+        schema.validate(&valid)?;
+
+        let invalid = json!({
+            "id": 1, "customer": "", "stock": -1, "shipping": {"zip": "4000"},
+            "lines": [{"sku": "A", "quantity": "1"}], "tags": {"gift": "yes"}, "extra": null,
+        });
+        let mut found = errors(schema.validate(&invalid));
+        found.sort();
+        assert_eq!(
+            found,
+            [
+                r#"customer: "" is shorter than 1 character"#,
+                r#"id: 1 is not of types "null", "string""#,
+                r#"lines.0.quantity: "1" is not of type "integer""#,
+                // the nested error of the alternative that fits, not the
+                // `anyOf` of the option
+                "shipping.city: is required",
+                "stock: -1 is less than the minimum of 0",
+                r#"tags.gift: "yes" is not of type "boolean""#,
+            ]
+        );
+
+        let strict = Schema::new(json!({
+            "type": "object",
+            "properties": {"name": {"type": "string"}},
+            "required": ["name"],
+            "additionalProperties": false,
+        }))?;
+        assert_eq!(
+            errors(strict.validate(&json!({"nmae": "Ann"}))),
+            ["nmae: is not a field of the schema", "name: is required"]
+        );
+        assert_eq!(
+            errors(strict.validate(&json!([]))),
+            [r#"[] is not of type "object""#]
+        );
+
+        assert!(matches!(
+            Schema::new(json!({"type": "text"})),
+            Err(StoreError::InvalidQuery(_))
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_validate_query() -> anyhow::Result<()> {
+        let schema = schema();
+        let valid = [
+            Query::builder().eq("customer", json!("Ann")).build(),
+            Query::builder().eq("shipping.city", json!("Porto")).build(),
+            Query::builder().eq("shipping.zip", Value::Null).build(),
+            Query::builder().eq("shipping", Value::Null).build(),
+            Query::builder().gt("lines.*.quantity", json!(2)).build(),
+            Query::builder().eq("lines.0.sku", json!("A")).build(),
+            Query::builder().eq("tags.gift", json!(true)).build(),
+            Query::builder()
+                .eq("extra.anything.at.all", json!([]))
+                .build(),
+            Query::builder()
+                .is_in("stock", vec![json!(1), json!(2)])
+                .build(),
+            Query::builder().exists("shipping.zip").build(),
+            Query::builder().lt("_schema", json!(3)).build(),
+            Query::builder()
+                .order_by("customer")
+                .select(&["shipping.city"])
+                .build(),
+        ];
+        for query in valid {
+            schema.validate_query(&query)?;
+        }
+
+        let invalid = [
+            (
+                Query::builder().eq("custmer", json!("Ann")).build(),
+                "custmer: is not a field of the schema",
+            ),
+            (
+                Query::builder().eq("shipping.country", json!("PT")).build(),
+                "shipping.country: is not a field of the schema",
+            ),
+            (
+                Query::builder().eq("stock", json!("2")).build(),
+                r#"stock: "2" is not of type "integer""#,
+            ),
+            (
+                Query::builder().eq("customer", Value::Null).build(),
+                r#"customer: null is not of type "string""#,
+            ),
+            (
+                Query::builder().eq("lines.*.quantity", json!(1.5)).build(),
+                r#"lines.*.quantity: 1.5 is not of type "integer""#,
+            ),
+            (
+                Query::builder().eq("lines.*.price", json!(1)).build(),
+                "lines.*.price: is not a field of the schema",
+            ),
+            (
+                Query::builder().eq("customer.name", json!("Ann")).build(),
+                "customer.name: is not a field of the schema",
+            ),
+            (
+                Query::builder()
+                    .is_in("stock", vec![json!(1), json!("2")])
+                    .build(),
+                r#"stock: "2" is not of type "integer""#,
+            ),
+            (
+                Query::builder()
+                    .or(|mut query| query.not_exists("shiping").build())
+                    .build(),
+                "shiping: is not a field of the schema",
+            ),
+            (
+                Query::builder().order_by("created_at").build(),
+                "created_at: is not a field of the schema",
+            ),
+        ];
+        for (query, error) in invalid {
+            assert_eq!(errors(schema.validate_query(&query)), [error]);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_validate_update() -> anyhow::Result<()> {
+        let schema = schema();
+        let valid = Update::builder()
+            .set("shipping.city", json!("Braga"))
+            .inc("stock", -1)
+            .push("lines", json!({"sku": "B", "quantity": 2}))
+            .unset("shipping.zip")
+            .rename("tags.gift", "tags.wrapped")
+            .build();
+        schema.validate_update(&valid)?;
+
+        let invalid = [
+            (
+                Update::builder().set("stock", json!("many")).build(),
+                r#"stock: "many" is not of type "integer""#,
+            ),
+            (
+                Update::builder().inc("customer", 1).build(),
+                r#"customer: 1 is not of type "string""#,
+            ),
+            (
+                Update::builder().push("lines", json!({"sku": "B"})).build(),
+                r#"lines: "quantity" is a required property"#,
+            ),
+            (
+                Update::builder().unset("customer").build(),
+                "customer: is required",
+            ),
+            (
+                Update::builder()
+                    .rename("shipping.city", "shipping.zip")
+                    .build(),
+                "shipping.city: is required",
+            ),
+            (
+                Update::builder().set("notes", json!("")).build(),
+                "notes: is not a field of the schema",
+            ),
+        ];
+        for (update, error) in invalid {
+            assert_eq!(errors(schema.validate_update(&update)), [error]);
+        }
+
+        Ok(())
+    }
+}
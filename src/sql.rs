@@ -1,63 +1,868 @@
 use serde_json::Value;
 
+use crate::aggregate::{AccumulatorOperator, Aggregation};
+use crate::error::{Result, StoreError};
+use crate::index::{Index, IndexKind};
 use crate::query::{
-    Query, QueryFilterCondition, QueryFilterFilter, QueryFilterItem, QueryFilterOperation,
-    QueryFilterOperator,
+    check_distinct_field, sort_rank, Projection, ProjectionFields, ProjectionNode, Query,
+    QueryFilter, QueryFilterCondition, QueryFilterFilter, QueryFilterItem, QueryFilterOperation,
+    QueryFilterOperator, QueryLimit, QuerySortDirection, QuerySortItem, ANY_ELEMENT,
 };
+use crate::update::{Update, UpdateOperation};
 
-pub fn to_sql(table: &str, query: &Option<Query>) -> anyhow::Result<(String, Vec<Value>)> {
+/// A bind parameter of the generated SQL. Nothing that comes from a query is
+/// ever interpolated into the statement itself: values and field paths are
+/// always passed as parameters.
+#[derive(Debug, Clone, PartialEq)]
+pub enum SqlParam {
+    /// A JSON value, bound as `jsonb` in Postgres and as JSON text in SQLite.
+    Json(Value),
+    /// The segments of a field path, bound as `text[]` for `#>` and `#>>`.
+    Path(Vec<String>),
+    /// Plain text, such as a SQLite JSON path.
+    Text(String),
+}
+
+/// The SQL flavour statements are rendered for. The statement builders and the
+/// walk over `QueryFilterItem`s are shared; a dialect decides how a field path
+/// is read out of the `data` column and how a single filter or sort key on it
+/// is written.
+pub trait Dialect {
+    /// Renders the value `segments` reach from the JSON expression `base`,
+    /// resolving them the way `query::resolve_path` does.
+    fn path(&self, base: &str, segments: &[&str]) -> PathSql;
+
+    /// Renders a `FROM` item over the elements of `array`, yielding no rows when
+    /// it is not an array, and returns it with the JSON expression of an element
+    /// of the row aliased `alias`.
+    fn elements(&self, array: &PathSql, alias: &str) -> (String, Vec<SqlParam>, String);
+
+    /// Holds when the value at `path` equals `value`, or when it exists and
+    /// differs from it if `negated`.
+    fn equals(&self, path: &PathSql, value: &Value, negated: bool) -> (String, Vec<SqlParam>);
+
+    /// Like `equals`, for a non-empty list of candidate values.
+    fn is_in(&self, path: &PathSql, values: &[Value], negated: bool) -> (String, Vec<SqlParam>);
+
+    /// Compares the value at `path` with `value`, a number or a string, holding
+    /// only when both have the same JSON type.
+    fn compare(&self, path: &PathSql, operator: &str, value: &Value) -> (String, Vec<SqlParam>);
+
+    /// Renders the type rank of `query::sort_rank` for the value at `path`.
+    fn sort_rank(&self, path: &PathSql) -> (String, Vec<SqlParam>);
+
+    /// Renders the `ORDER BY` keys of a path: the type rank of
+    /// `query::sort_rank`, then the number and then the string bytewise.
+    fn sort_keys(&self, path: &PathSql, direction: &str) -> (String, Vec<SqlParam>);
+
+    /// Renders the column a group key is read into: the JSON value at `path`,
+    /// or JSON `null` when it is missing.
+    fn group_key(&self, path: &PathSql) -> (String, Vec<SqlParam>);
+
+    /// Renders what rows are grouped by for the group key column `column`, and
+    /// the key's JSON in the grouped rows.
+    fn group_by(&self, column: &str) -> (String, String);
+
+    /// Renders an accumulator over the JSON column `column` of a group, as
+    /// JSON, with the semantics of `aggregate::AccumulatorOperator`.
+    fn accumulator(&self, operator: &AccumulatorOperator, column: &str) -> String;
+
+    /// Renders a JSON object from its entries, each a `?` for the key,
+    /// bound as text, followed by a JSON expression for the value.
+    fn json_object(&self, entries: &[String]) -> String;
+
+    /// Renders the JSON value of the member row `alias` of `object_members`,
+    /// and a condition holding when that value is an object.
+    fn member(&self, alias: &str) -> (String, String);
+
+    /// Renders an object with the members of the JSON object `object` for
+    /// which `condition` holds, each with the value `value`. Both refer to the
+    /// member row as `alias`, whose `key` column is the member's key.
+    fn object_members(&self, object: &str, alias: &str, value: &str, condition: &str) -> String;
+
+    /// Rewrites the `?` placeholders of a statement to the dialect's syntax.
+    fn placeholders(&self, sql: &str) -> String;
+
+    /// The type of the `data` column documents are stored in.
+    fn column_type(&self) -> &'static str;
+
+    fn limit(&self, limit: &QueryLimit) -> String {
+        let mut limit_str = String::new();
+        if let Some(limit) = limit.limit {
+            limit_str.push_str(format!(" LIMIT {}", limit).as_str());
+        }
+        if let Some(offset) = limit.offset {
+            limit_str.push_str(format!(" OFFSET {}", offset).as_str());
+        }
+        limit_str
+    }
+}
+
+/// Postgres, with documents in a `jsonb` column.
+pub struct Postgres;
+
+/// SQLite, with documents stored as JSON text and read with the `->` and `->>`
+/// operators.
+pub struct Sqlite;
+
+/// Validates a table name and quotes it. Only ASCII letters, digits and
+/// underscores are accepted, starting with a letter or an underscore and up to
+/// Postgres' 63 byte identifier limit.
+pub fn quote_identifier(name: &str) -> Result<String> {
+    let mut chars = name.chars();
+    let valid = matches!(chars.next(), Some(c) if c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+        && name.len() <= 63;
+    if !valid {
+        return Err(StoreError::InvalidQuery(format!(
+            "invalid collection name: {:?}",
+            name
+        )));
+    }
+    Ok(format!("\"{}\"", name))
+}
+
+/// Creates a collection's table unless it exists.
+pub fn create_table_sql(dialect: &dyn Dialect, table: &str) -> Result<String> {
+    Ok(format!(
+        "CREATE TABLE IF NOT EXISTS {} (data {})",
+        quote_identifier(table)?,
+        dialect.column_type()
+    ))
+}
+
+/// Creates an expression index on a field of a collection unless one of that
+/// name exists. Postgres only.
+///
+/// DDL takes no bind parameters, so unlike everywhere else the field path is
+/// written into the statement, as an escaped string literal. The expression is
+/// the `data #> path` that filters render, which the planner matches once the
+/// path parameter is known: `hash` and `ordered` indexes serve `eq` and `in`,
+/// and `ordered` ones number ranges too.
+pub fn create_index_sql(table: &str, index: &Index) -> Result<String> {
+    if index.field.is_empty() || index.field.split('.').any(|segment| segment == ANY_ELEMENT) {
+        return Err(StoreError::UnsupportedOperator(format!(
+            "can't index field '{}': an index needs a single value per document",
+            index.field
+        )));
+    }
+    let quoted = quote_identifier(table)?;
+    let name = quote_identifier(&index_name(table, index))?;
+    let table = quoted;
+    let segments: Vec<String> = index
+        .field
+        .split('.')
+        .map(|segment| format!("'{}'", segment.replace('\'', "''")))
+        .collect();
+    let path = format!("data #> ARRAY[{}]::text[]", segments.join(", "));
+
+    let sql = match index.kind {
+        IndexKind::Hash => format!("{} ON {} USING hash (({}))", name, table, path),
+        IndexKind::Ordered => format!("{} ON {} (({}))", name, table, path),
+        IndexKind::Gin => format!(
+            "{} ON {} USING gin (({}) jsonb_path_ops)",
+            name, table, path
+        ),
+    };
+    Ok(format!("CREATE INDEX IF NOT EXISTS {}", sql))
+}
+
+/// Names an index after its table, field and kind, with the characters an
+/// identifier can't have replaced. Names too long for Postgres are cut short
+/// and told apart by a hash of the field.
+fn index_name(table: &str, index: &Index) -> String {
+    let kind = match index.kind {
+        IndexKind::Hash => "hash",
+        IndexKind::Ordered => "ordered",
+        IndexKind::Gin => "gin",
+    };
+    let field: String = index
+        .field
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect();
+    let name = format!("{}_{}_{}", table, field, kind);
+    if name.len() <= 63 {
+        return name;
+    }
+
+    // FNV-1a, which unlike `DefaultHasher` is stable across Rust releases
+    let hash = index
+        .field
+        .bytes()
+        .fold(0xcbf29ce484222325u64, |hash, byte| {
+            (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+        });
+    let suffix = format!("_{:016x}_{}", hash, kind);
+    let prefix = format!("{}_{}", table, field);
+    format!("{}{}", &prefix[..63 - suffix.len()], suffix)
+}
+
+pub fn to_sql(
+    dialect: &dyn Dialect,
+    table: &str,
+    query: &Option<Query>,
+) -> Result<(String, Vec<SqlParam>)> {
+    let table = quote_identifier(table)?;
     let Some(query) = query else {
         return Ok((format!("SELECT data FROM {}", table), vec![]));
     };
 
-    let (where_str, where_values) = match &query.filter {
-        Some(filters) => items_to_sql(&filters)?,
-        None => (String::new(), vec![]),
+    let (where_str, mut params) = where_to_sql(dialect, query)?;
+
+    let sort_str = match &query.sort {
+        Some(sort) if !sort.is_empty() => {
+            let mut keys = vec![];
+            for item in sort {
+                let (key, key_params) = sort_to_sql(dialect, item)?;
+                keys.push(key);
+                params.extend(key_params);
+            }
+            format!(" ORDER BY {}", keys.join(", "))
+        }
+        _ => String::new(),
     };
 
-    let mut limit_str = String::new();
-    if let Some(limit_def) = &query.limit {
-        if let Some(limit) = limit_def.limit {
-            limit_str.push_str(format!(" LIMIT {}", limit).as_str());
+    let limit_str = match &query.limit {
+        Some(limit) => dialect.limit(limit),
+        None => String::new(),
+    };
+
+    // the projected column is left unnamed, so `data` in the rest of the
+    // statement still refers to the whole document
+    let select_str = match &query.projection {
+        Some(projection) => {
+            let (select, select_params) =
+                projection_to_sql(dialect, "data", projection, &projection.tree()?, 0);
+            params = [select_params, params].concat();
+            select
         }
-        if let Some(offset) = limit_def.offset {
-            limit_str.push_str(format!(" OFFSET {}", offset).as_str());
+        None => "data".to_string(),
+    };
+
+    let sql = format!(
+        "SELECT {} FROM {}{}{}{}",
+        select_str, table, where_str, sort_str, limit_str
+    );
+    Ok((dialect.placeholders(sql.as_str()), params))
+}
+
+/// Renders the JSON object `object` with only the members `fields` selects, or
+/// without them for `Projection::Exclude`, recursing into the members that
+/// list nested fields when they are objects; see `Projection::apply`.
+fn projection_to_sql(
+    dialect: &dyn Dialect,
+    object: &str,
+    projection: &Projection,
+    fields: &ProjectionFields,
+    depth: usize,
+) -> (String, Vec<SqlParam>) {
+    let alias = format!("p{}", depth);
+    let (member, is_object) = dialect.member(&alias);
+
+    let mut params = vec![];
+    let mut whole = vec![];
+    let mut nested = vec![];
+    let mut cases = vec![];
+    for (key, node) in fields {
+        match node {
+            ProjectionNode::Whole => whole.push(key),
+            ProjectionNode::Fields(fields) => {
+                let (value, value_params) = projection_to_sql(
+                    dialect,
+                    &format!("{}.value", alias),
+                    projection,
+                    fields,
+                    depth + 1,
+                );
+                cases.push(format!(
+                    "WHEN {}.key = ? AND {} THEN {}",
+                    alias, is_object, value
+                ));
+                params.push(SqlParam::Text(key.clone()));
+                params.extend(value_params);
+                nested.push(key);
+            }
+        }
+    }
+    let value = match cases.is_empty() {
+        true => member,
+        false => format!("CASE {} ELSE {} END", cases.join(" "), member),
+    };
+
+    let keys_in = |keys: &[&String], params: &mut Vec<SqlParam>| {
+        params.extend(keys.iter().map(|key| SqlParam::Text(key.to_string())));
+        format!("{}.key IN ({})", alias, vec!["?"; keys.len()].join(", "))
+    };
+    let condition = match projection {
+        Projection::Include(_) => {
+            let mut conditions = vec![];
+            if !whole.is_empty() {
+                conditions.push(keys_in(&whole, &mut params));
+            }
+            if !nested.is_empty() {
+                let keys = keys_in(&nested, &mut params);
+                conditions.push(format!("({} AND {})", keys, is_object));
+            }
+            match conditions.is_empty() {
+                true => "FALSE".to_string(),
+                false => conditions.join(" OR "),
+            }
+        }
+        Projection::Exclude(_) => match whole.is_empty() {
+            true => "TRUE".to_string(),
+            false => format!("NOT {}", keys_in(&whole, &mut params)),
+        },
+    };
+
+    let sql = dialect.object_members(object, &alias, &value, &condition);
+    (sql, params)
+}
+
+pub fn insert_to_sql(
+    dialect: &dyn Dialect,
+    table: &str,
+    data: &Value,
+) -> Result<(String, Vec<SqlParam>)> {
+    let sql = format!(
+        "INSERT INTO {} (data) VALUES (?) RETURNING data",
+        quote_identifier(table)?
+    );
+    Ok((
+        dialect.placeholders(sql.as_str()),
+        vec![SqlParam::Json(data.clone())],
+    ))
+}
+
+/// Replaces the document of every row matched by the query filter. Sort and
+/// limit are ignored, since Postgres has no `UPDATE ... LIMIT`.
+pub fn update_to_sql(
+    dialect: &dyn Dialect,
+    table: &str,
+    query: &Query,
+    data: &Value,
+) -> Result<(String, Vec<SqlParam>)> {
+    let table = quote_identifier(table)?;
+    let (where_str, where_params) = where_to_sql(dialect, query)?;
+
+    let mut params = vec![SqlParam::Json(data.clone())];
+    params.extend(where_params);
+
+    let sql = format!("UPDATE {} SET data = ?{}", table, where_str);
+    Ok((dialect.placeholders(sql.as_str()), params))
+}
+
+/// Applies a partial update to every row matched by the query filter, the way
+/// `Update::apply` does, and only writes the rows it changes so the statement
+/// counts the documents modified. Sort and limit are ignored.
+///
+/// Postgres only. Each operator is a `jsonb` expression over the document the
+/// previous one produced, so the new document is computed from the row being
+/// written and concurrent updates don't lose each other's changes.
+pub fn patch_to_sql(
+    table: &str,
+    query: &Query,
+    update: &Update,
+) -> Result<(String, Vec<SqlParam>)> {
+    update.check()?;
+    let table = quote_identifier(table)?;
+    let (mut conditions, where_params) = conditions_to_sql(&Postgres, query)?;
+
+    let mut patched = "data".to_string();
+    let mut patched_params = vec![];
+    for operation in update.operations.iter() {
+        let (sql, params) = operation_to_sql(operation);
+        // `OFFSET 0` keeps the planner from inlining `d` into every place the
+        // operator refers to it, which would grow exponentially with them
+        patched = format!(
+            "(SELECT {} FROM (SELECT {} AS d OFFSET 0) AS patched)",
+            sql, patched
+        );
+        patched_params = [params, patched_params].concat();
+    }
+    conditions.push(format!("data <> {}", patched));
+
+    let params = [patched_params.clone(), where_params, patched_params].concat();
+    let sql = format!(
+        "UPDATE {} SET data = {}{}",
+        table,
+        patched,
+        where_clause(&conditions)
+    );
+    Ok((Postgres.placeholders(sql.as_str()), params))
+}
+
+/// Renders an update operator as a `jsonb` expression over the document `d`.
+fn operation_to_sql(operation: &UpdateOperation) -> (String, Vec<SqlParam>) {
+    let document = ("d".to_string(), vec![]);
+    let segments: Vec<&str> = operation.field().split('.').collect();
+    let field = patch_path(&document, &segments);
+    let (parents, parents_params) = objects_to_sql(&document, &segments[..segments.len() - 1]);
+
+    let (sql, params) = match operation {
+        UpdateOperation::Set { value, .. } => {
+            let leaf = ("?::jsonb".to_string(), vec![SqlParam::Json(value.clone())]);
+            put_to_sql(&document, &segments, 0, &leaf)
+        }
+        UpdateOperation::Unset { .. } => (
+            format!("CASE WHEN {} THEN d #- ? ELSE d END", parents),
+            [parents_params, field.params.clone()].concat(),
+        ),
+        UpdateOperation::Inc { value, .. } => {
+            let value = SqlParam::Json(Value::Number(value.clone()));
+            let leaf = (
+                "CASE WHEN jsonb_typeof({json}) = 'number' \
+                 THEN to_jsonb(({json})::numeric + (?::jsonb)::numeric) \
+                 WHEN {json} IS NULL OR {json} = 'null' THEN ?::jsonb ELSE {json} END"
+                    .to_string(),
+                vec![value.clone(), value],
+            );
+            put_to_sql(&document, &segments, 0, &leaf)
+        }
+        UpdateOperation::Push { value, .. } => {
+            let value = SqlParam::Json(value.clone());
+            let leaf = (
+                "CASE WHEN jsonb_typeof({json}) = 'array' THEN {json} || jsonb_build_array(?::jsonb) \
+                 WHEN {json} IS NULL OR {json} = 'null' THEN jsonb_build_array(?::jsonb) \
+                 ELSE {json} END"
+                    .to_string(),
+                vec![value.clone(), value],
+            );
+            put_to_sql(&document, &segments, 0, &leaf)
+        }
+        UpdateOperation::Pull { value, .. } => {
+            let template = format!(
+                "CASE WHEN {} AND jsonb_typeof({{json}}) = 'array' \
+                 THEN jsonb_set(d, ?, (SELECT COALESCE(jsonb_agg(e ORDER BY i), '[]') \
+                 FROM jsonb_array_elements({{json}}) WITH ORDINALITY AS pulled(e, i) \
+                 WHERE e <> ?::jsonb)) ELSE d END",
+                parents
+            );
+            let path = SqlParam::Path(segments.iter().map(|s| s.to_string()).collect());
+            let values = [parents_params, vec![path, SqlParam::Json(value.clone())]].concat();
+            field.render(&template, values)
+        }
+        UpdateOperation::Rename { to, .. } => {
+            let to: Vec<&str> = to.split('.').collect();
+            let (clear, clear_params) = clear_to_sql(&document, &to);
+            let (value, value_params) = field.render("{json}", vec![]);
+            let (exists, exists_params) = field.render("{json} IS NOT NULL", vec![]);
+            let removed = ("(d #- ?)".to_string(), field.params.clone());
+            let (renamed, renamed_params) = put_to_sql(&removed, &to, 0, &(value, value_params));
+            (
+                format!(
+                    "CASE WHEN {} AND {} AND {} THEN {} ELSE d END",
+                    parents, exists, clear, renamed
+                ),
+                [parents_params, exists_params, clear_params, renamed_params].concat(),
+            )
+        }
+    };
+    (sql, params)
+}
+
+/// The path `segments` reaches from the `jsonb` expression `base`, which may
+/// bind parameters of its own.
+fn patch_path(base: &(String, Vec<SqlParam>), segments: &[&str]) -> PathSql {
+    let mut path = Postgres.path(&base.0, segments);
+    path.params = [base.1.clone(), path.params].concat();
+    path
+}
+
+/// Sets the value at `segments` to `leaf` the way `set` does, creating the
+/// objects leading up to it where they are missing or `null`, and renders the
+/// value at the first `depth` of them. `{json}` in the leaf is the value at
+/// `segments`; a leaf that evaluates to it changes nothing. Each level refers
+/// to the one below once, so the expression grows linearly with the path.
+fn put_to_sql(
+    base: &(String, Vec<SqlParam>),
+    segments: &[&str],
+    depth: usize,
+    leaf: &(String, Vec<SqlParam>),
+) -> (String, Vec<SqlParam>) {
+    let current = patch_path(base, &segments[..depth]);
+    if depth == segments.len() {
+        return current.render(&leaf.0, leaf.1.clone());
+    }
+
+    let (child, child_params) = put_to_sql(base, segments, depth + 1, leaf);
+    let template = match depth {
+        0 => format!(
+            "CASE WHEN jsonb_typeof({{json}}) = 'object' \
+             THEN {{json}} || jsonb_build_object(?::text, {}) ELSE {{json}} END",
+            child
+        ),
+        _ => format!(
+            "CASE WHEN COALESCE(jsonb_typeof({{json}}), 'null') IN ('object', 'null') \
+             THEN COALESCE(NULLIF({{json}}, 'null'), '{{}}') || jsonb_build_object(?::text, {}) \
+             ELSE {{json}} END",
+            child
+        ),
+    };
+    let values = [
+        vec![SqlParam::Text(segments[depth].to_string())],
+        child_params,
+    ]
+    .concat();
+    current.render(&template, values)
+}
+
+/// Holds when the values at every prefix of `segments`, the document itself
+/// included, are objects.
+fn objects_to_sql(base: &(String, Vec<SqlParam>), segments: &[&str]) -> (String, Vec<SqlParam>) {
+    let mut conditions = vec![];
+    let mut params = vec![];
+    for depth in 0..=segments.len() {
+        let (condition, condition_params) =
+            patch_path(base, &segments[..depth]).render("jsonb_typeof({json}) = 'object'", vec![]);
+        conditions.push(condition);
+        params.extend(condition_params);
+    }
+    (conditions.join(" AND "), params)
+}
+
+/// Holds when `put_to_sql` can create its way to `segments`, as
+/// `update::is_clear` does: no value on the way is anything but an object,
+/// missing or `null`.
+fn clear_to_sql(base: &(String, Vec<SqlParam>), segments: &[&str]) -> (String, Vec<SqlParam>) {
+    let (document, document_params) =
+        patch_path(base, &[]).render("jsonb_typeof({json}) = 'object'", vec![]);
+    let mut conditions = vec![document];
+    let mut params = document_params;
+    for depth in 1..segments.len() {
+        let (condition, condition_params) = patch_path(base, &segments[..depth]).render(
+            "COALESCE(jsonb_typeof({json}), 'null') IN ('object', 'null')",
+            vec![],
+        );
+        conditions.push(condition);
+        params.extend(condition_params);
+    }
+    (conditions.join(" AND "), params)
+}
+
+/// Selects the `rowid` and document of every row matched by the query filter.
+/// SQLite applies a partial update in memory instead of rendering it the way
+/// `patch_to_sql` does, and writes the rows it changes back by `rowid` with
+/// `update_rowid_to_sql`. Sort and limit are ignored.
+pub fn rowids_to_sql(table: &str, query: &Query) -> Result<(String, Vec<SqlParam>)> {
+    let table = quote_identifier(table)?;
+    let (where_str, params) = where_to_sql(&Sqlite, query)?;
+    let sql = format!("SELECT rowid, data FROM {}{}", table, where_str);
+    Ok((Sqlite.placeholders(sql.as_str()), params))
+}
+
+/// Replaces the document of the row with a given `rowid`, bound after it.
+pub fn update_rowid_to_sql(table: &str) -> Result<String> {
+    let sql = format!(
+        "UPDATE {} SET data = ? WHERE rowid = ?",
+        quote_identifier(table)?
+    );
+    Ok(Sqlite.placeholders(sql.as_str()))
+}
+
+/// Deletes every row matched by the query filter. Sort and limit are ignored.
+pub fn delete_to_sql(
+    dialect: &dyn Dialect,
+    table: &str,
+    query: &Query,
+) -> Result<(String, Vec<SqlParam>)> {
+    let table = quote_identifier(table)?;
+    let (where_str, params) = where_to_sql(dialect, query)?;
+    let sql = format!("DELETE FROM {}{}", table, where_str);
+    Ok((dialect.placeholders(sql.as_str()), params))
+}
+
+/// Counts the rows matched by the query filter. Sort and limit are ignored.
+pub fn count_to_sql(
+    dialect: &dyn Dialect,
+    table: &str,
+    query: &Option<Query>,
+) -> Result<(String, Vec<SqlParam>)> {
+    let table = quote_identifier(table)?;
+    let (where_str, params) = match query {
+        Some(query) => where_to_sql(dialect, query)?,
+        None => (String::new(), vec![]),
+    };
+    let sql = format!("SELECT count(*) FROM {}{}", table, where_str);
+    Ok((dialect.placeholders(sql.as_str()), params))
+}
+
+/// Selects whether any row is matched by the query filter.
+pub fn exists_to_sql(
+    dialect: &dyn Dialect,
+    table: &str,
+    query: &Option<Query>,
+) -> Result<(String, Vec<SqlParam>)> {
+    let table = quote_identifier(table)?;
+    let (where_str, params) = match query {
+        Some(query) => where_to_sql(dialect, query)?,
+        None => (String::new(), vec![]),
+    };
+    let sql = format!("SELECT EXISTS (SELECT 1 FROM {}{})", table, where_str);
+    Ok((dialect.placeholders(sql.as_str()), params))
+}
+
+/// Selects the distinct values of `field` in the rows matched by the query
+/// filter, leaving out rows without it. Values are compared as the database
+/// stores them, so callers still have to deduplicate values like `1` and
+/// `1.0` that SQLite keeps apart.
+pub fn distinct_to_sql(
+    dialect: &dyn Dialect,
+    table: &str,
+    field: &str,
+    query: &Option<Query>,
+) -> Result<(String, Vec<SqlParam>)> {
+    check_distinct_field(field)?;
+    let table = quote_identifier(table)?;
+    let segments: Vec<&str> = field.split('.').collect();
+    let path = dialect.path("data", &segments);
+
+    let (value, mut params) = path.render("{json}", vec![]);
+    let (mut conditions, where_params) = match query {
+        Some(query) => conditions_to_sql(dialect, query)?,
+        None => (vec![], vec![]),
+    };
+    params.extend(where_params);
+    let (present, present_params) = path.render("{json} IS NOT NULL", vec![]);
+    conditions.push(present);
+    params.extend(present_params);
+
+    let sql = format!(
+        "SELECT DISTINCT {} FROM {}{}",
+        value,
+        table,
+        where_clause(&conditions)
+    );
+    Ok((dialect.placeholders(sql.as_str()), params))
+}
+
+/// Runs an aggregation in three steps: the matching rows are read into
+/// columns for the group keys and accumulated fields, the groups of those
+/// become rows of JSON objects, and those are sorted and limited like
+/// documents.
+pub fn aggregate_to_sql(
+    dialect: &dyn Dialect,
+    table: &str,
+    aggregation: &Aggregation,
+) -> Result<(String, Vec<SqlParam>)> {
+    aggregation.check()?;
+    let table = quote_identifier(table)?;
+
+    let mut params = vec![];
+    let mut entries = vec![];
+    let mut groups = vec![];
+    for (i, key) in aggregation.group.iter().enumerate() {
+        let (group, value) = dialect.group_by(&format!("k{}", i));
+        params.push(SqlParam::Text(key.name.clone()));
+        entries.push(format!("?, {}", value));
+        groups.push(group);
+    }
+    for (i, accumulator) in aggregation.accumulators.iter().enumerate() {
+        let value = dialect.accumulator(&accumulator.operator, &format!("v{}", i));
+        params.push(SqlParam::Text(accumulator.name.clone()));
+        entries.push(format!("?, {}", value));
+    }
+
+    let mut columns = vec![];
+    for (i, key) in aggregation.group.iter().enumerate() {
+        let segments: Vec<&str> = key.field.split('.').collect();
+        let (column, column_params) = dialect.group_key(&dialect.path("data", &segments));
+        columns.push(format!("{} AS k{}", column, i));
+        params.extend(column_params);
+    }
+    for (i, accumulator) in aggregation.accumulators.iter().enumerate() {
+        let Some(field) = &accumulator.field else {
+            columns.push(format!("NULL AS v{}", i));
+            continue;
+        };
+        let segments: Vec<&str> = field.split('.').collect();
+        let (column, column_params) = dialect.path("data", &segments).render("{json}", vec![]);
+        columns.push(format!("{} AS v{}", column, i));
+        params.extend(column_params);
+    }
+
+    let query = aggregation.query();
+    let (where_str, where_params) = where_to_sql(dialect, &query)?;
+    params.extend(where_params);
+    let group_str = match groups.is_empty() {
+        true => String::new(),
+        false => format!(" GROUP BY {}", groups.join(", ")),
+    };
+
+    let mut sort_str = String::new();
+    if let Some(sort) = query.sort.as_ref().filter(|sort| !sort.is_empty()) {
+        let mut keys = vec![];
+        for item in sort {
+            let (key, key_params) = sort_to_sql(dialect, item)?;
+            keys.push(key);
+            params.extend(key_params);
         }
+        sort_str = format!(" ORDER BY {}", keys.join(", "));
     }
+    let limit_str = match &query.limit {
+        Some(limit) => dialect.limit(limit),
+        None => String::new(),
+    };
 
     let sql = format!(
-        "SELECT data FROM {} WHERE {}{}",
+        "SELECT data FROM (SELECT {} AS data FROM (SELECT {} FROM {}{}) AS matched{}) AS grouped{}{}",
+        dialect.json_object(&entries),
+        columns.join(", "),
         table,
-        enumerate_placeholders(where_str.as_str()),
+        where_str,
+        group_str,
+        sort_str,
         limit_str
     );
-    Ok((sql, where_values))
+    Ok((dialect.placeholders(sql.as_str()), params))
 }
 
-fn items_to_sql(items: &Vec<QueryFilterItem>) -> anyhow::Result<(String, Vec<Value>)> {
-    let mut where_str = String::new();
+/// Orders by the type rank of `query::sort_rank`, then numerically and then
+/// bytewise within numbers and strings, so rows come back in the same order
+/// `Query::sort` produces in memory.
+fn sort_to_sql(dialect: &dyn Dialect, item: &QuerySortItem) -> Result<(String, Vec<SqlParam>)> {
+    item.check_field()?;
+    let segments: Vec<&str> = item.field.split('.').collect();
+    let path = dialect.path("data", &segments);
+    let direction = match item.direction {
+        QuerySortDirection::Ascending => "ASC",
+        QuerySortDirection::Descending => "DESC",
+    };
+    Ok(dialect.sort_keys(&path, direction))
+}
+
+fn where_to_sql(dialect: &dyn Dialect, query: &Query) -> Result<(String, Vec<SqlParam>)> {
+    let (conditions, params) = conditions_to_sql(dialect, query)?;
+    Ok((where_clause(&conditions), params))
+}
+
+/// The conditions of a query's `WHERE` clause: the filter, and `after` when it
+/// is set.
+fn conditions_to_sql(dialect: &dyn Dialect, query: &Query) -> Result<(Vec<String>, Vec<SqlParam>)> {
+    let mut conditions = vec![];
+    let mut params = vec![];
+    if let Some(filters) = query.filter.as_ref().filter(|filters| !filters.is_empty()) {
+        let (condition, filter_params) = items_to_sql(dialect, filters)?;
+        conditions.push(condition);
+        params.extend(filter_params);
+    }
+    if let Some(after) = query.check_after()? {
+        let sort = query.sort.as_deref().unwrap_or_default();
+        let (condition, after_params) = after_to_sql(dialect, sort, after)?;
+        conditions.push(condition);
+        params.extend(after_params);
+    }
+    Ok((conditions, params))
+}
+
+fn where_clause(conditions: &[String]) -> String {
+    match conditions {
+        [] => String::new(),
+        [condition] => format!(" WHERE {}", condition),
+        _ => format!(" WHERE ({})", conditions.join(") AND (")),
+    }
+}
+
+/// Renders `Query::after` the way `Query::matches` evaluates it: a row is after
+/// the values when it ties with them on some leading sort keys and sorts after
+/// the next one. Ties and "after" follow `sort_keys`: by type rank first, then
+/// by value among numbers and among strings only.
+fn after_to_sql(
+    dialect: &dyn Dialect,
+    sort: &[QuerySortItem],
+    after: &[Value],
+) -> Result<(String, Vec<SqlParam>)> {
+    let mut alternatives = vec![];
+    let mut params = vec![];
+    let mut ties: Vec<(String, Vec<SqlParam>)> = vec![];
+    for (item, value) in sort.iter().zip(after) {
+        let segments: Vec<&str> = item.field.split('.').collect();
+        let path = dialect.path("data", &segments);
+        let (rank, rank_params) = dialect.sort_rank(&path);
+        let value_rank = sort_rank(Some(value));
+        let (rank_operator, operator) = match item.direction {
+            QuerySortDirection::Ascending => (">", ">"),
+            QuerySortDirection::Descending => ("<", "<"),
+        };
+
+        let mut later = format!("{} {} {}", rank, rank_operator, value_rank);
+        let mut later_params = rank_params.clone();
+        let mut tie = format!("{} = {}", rank, value_rank);
+        let mut tie_params = rank_params;
+        if matches!(value, Value::Number(_) | Value::String(_)) {
+            let (compare, compare_params) = dialect.compare(&path, operator, value);
+            later = format!("({} OR {})", later, compare);
+            later_params.extend(compare_params);
+            (tie, tie_params) = dialect.equals(&path, value, false);
+        }
+
+        let mut alternative: Vec<String> = ties.iter().map(|(tie, _)| tie.clone()).collect();
+        for (_, tie_params) in ties.iter() {
+            params.extend(tie_params.iter().cloned());
+        }
+        alternative.push(later);
+        params.extend(later_params);
+        alternatives.push(format!("({})", alternative.join(" AND ")));
+        ties.push((tie, tie_params));
+    }
+
+    if alternatives.is_empty() {
+        return Ok(("FALSE".to_string(), vec![]));
+    }
+    Ok((format!("({})", alternatives.join(" OR ")), params))
+}
+
+/// Renders filter items with the grouping `query::items_match` evaluates: `or`
+/// starts a new group, each group is the `AND` of its items, and a `not` item
+/// becomes `(item) IS NOT TRUE`, which also holds when the item is `NULL`
+/// because the field is missing. Groups are parenthesized explicitly instead of
+/// relying on SQL operator precedence.
+fn items_to_sql(
+    dialect: &dyn Dialect,
+    items: &[QueryFilterItem],
+) -> Result<(String, Vec<SqlParam>)> {
+    if items.is_empty() {
+        return Ok(("TRUE".to_string(), vec![]));
+    }
+
+    let mut groups: Vec<Vec<String>> = vec![];
     let mut where_values = vec![];
-    for (i, filter_item) in items.iter().enumerate() {
-        match filter_item {
+    for filter_item in items {
+        let (mut item_str, values) = match filter_item {
             QueryFilterItem::Filter(filter) => {
-                if i > 0 {
-                    where_str.push_str(&format!(" {} ", operation_to_sql(&filter.operation)));
+                let (filter_str, values) = filter_to_sql(dialect, filter)?;
+                match filter.operation {
+                    QueryFilterOperation::Not => (format!("({})", filter_str), values),
+                    _ => (filter_str, values),
                 }
-                let (filter_str, value) = filter_to_sql(filter)?;
-                where_str.push_str(filter_str.as_str());
-                where_values.push(value);
             }
-            QueryFilterItem::Condition(condition) => {
-                if i > 0 {
-                    where_str.push_str(&format!(" {} ", operation_to_sql(&condition.operation)));
-                }
-                let (condition_str, values) = to_sql_condition(condition)?;
-                where_str.push_str(condition_str.as_str());
-                where_values.extend(values);
+            QueryFilterItem::Condition(condition) => to_sql_condition(dialect, condition)?,
+        };
+        where_values.extend(values);
+
+        if matches!(filter_item.operation(), QueryFilterOperation::Not) {
+            item_str.push_str(" IS NOT TRUE");
+        }
+
+        match groups.last_mut() {
+            Some(group) if !matches!(filter_item.operation(), QueryFilterOperation::Or) => {
+                group.push(item_str)
             }
+            _ => groups.push(vec![item_str]),
         }
     }
 
+    let grouped = groups.len() > 1;
+    let where_str = groups
+        .into_iter()
+        .map(|group| match group.len() {
+            1 => group.join(""),
+            _ if grouped => format!("({})", group.join(" AND ")),
+            _ => group.join(" AND "),
+        })
+        .collect::<Vec<_>>()
+        .join(" OR ");
+
     Ok((where_str, where_values))
 }
 
@@ -71,7 +876,7 @@ fn enumerate_placeholders(sql: &str) -> String {
     sql
 }
 
-// fn filters_to_sql(items: &Vec<QueryFilterFilter>) -> anyhow::Result<(String, Vec<Value>)> {
+// fn filters_to_sql(items: &Vec<QueryFilterFilter>) -> Result<(String, Vec<Value>)> {
 //     let mut where_str = String::new();
 //     let mut where_values = vec![];
 //     for (i, item) in items.iter().enumerate() {
@@ -85,49 +890,588 @@ fn enumerate_placeholders(sql: &str) -> String {
 //     Ok((where_str, where_values))
 // }
 
-fn operation_to_sql(oper: &QueryFilterOperation) -> String {
-    match oper {
-        QueryFilterOperation::And => "AND",
-        QueryFilterOperation::Or => "OR",
-        QueryFilterOperation::Not => "NOT",
+/// SQL expressions for the JSON value a field path reaches and for its text,
+/// with the parameters each of them binds.
+pub struct PathSql {
+    json: String,
+    text: String,
+    params: Vec<SqlParam>,
+}
+
+impl PathSql {
+    /// Expands `{json}` and `{text}` in a template to the path expressions and
+    /// collects the parameters of the result in placeholder order, taking
+    /// `values` for the template's own `?` placeholders.
+    fn render(&self, template: &str, values: Vec<SqlParam>) -> (String, Vec<SqlParam>) {
+        let mut values = values.into_iter();
+        let mut sql = String::new();
+        let mut params = vec![];
+        let mut rest = template;
+        while let Some(index) = rest.find(['{', '?']) {
+            sql.push_str(&rest[..index]);
+            rest = &rest[index..];
+            if let Some(tail) = rest.strip_prefix("{json}") {
+                sql.push_str(&self.json);
+                params.extend(self.params.iter().cloned());
+                rest = tail;
+            } else if let Some(tail) = rest.strip_prefix("{text}") {
+                sql.push_str(&self.text);
+                params.extend(self.params.iter().cloned());
+                rest = tail;
+            } else {
+                if rest.starts_with('?') {
+                    params.extend(values.next());
+                }
+                sql.push_str(&rest[..1]);
+                rest = &rest[1..];
+            }
+        }
+        sql.push_str(rest);
+        (sql, params)
     }
-    .to_string()
 }
 
-fn filter_to_sql(def: &QueryFilterFilter) -> anyhow::Result<(String, Value)> {
+fn filter_to_sql(
+    dialect: &dyn Dialect,
+    def: &QueryFilterFilter,
+) -> Result<(String, Vec<SqlParam>)> {
     let filter = &def.filter;
+    filter.check_value()?;
+
+    let segments: Vec<&str> = filter.field.split('.').collect();
+    path_filter_to_sql(dialect, "data", &segments, filter, 0)
+}
+
+/// Renders a filter over the values `segments` reaches from `base`. A `*`
+/// segment becomes an `EXISTS` over the array's elements, aliased `e{depth}`;
+/// `NotEquals`, `NotIn` and `NotExists` are rendered as the negation of their
+/// positive operator, so they hold when no element matches.
+fn path_filter_to_sql(
+    dialect: &dyn Dialect,
+    base: &str,
+    segments: &[&str],
+    filter: &QueryFilter,
+    depth: usize,
+) -> Result<(String, Vec<SqlParam>)> {
+    let Some(i) = segments.iter().position(|segment| *segment == ANY_ELEMENT) else {
+        return value_filter_to_sql(dialect, &dialect.path(base, segments), filter);
+    };
 
-    match &filter.operator {
-        QueryFilterOperator::Equals => Ok((
-            format!("data->'{}' = ?", &filter.field),
-            filter.value.clone(),
-        )),
-        QueryFilterOperator::NotEquals => Ok((
-            format!("data->'{}' <> ?", &filter.field),
-            filter.value.clone(),
-        )),
-        QueryFilterOperator::GreaterThan => Ok((
-            format!("data->'{}' > ?", &filter.field),
-            filter.value.clone(),
-        )),
-        operator => Err(anyhow::anyhow!(
-            "operator '{:?}' not yet implemented",
-            operator
-        )),
-    }
-}
-
-fn to_sql_condition(condition: &QueryFilterCondition) -> anyhow::Result<(String, Vec<Value>)> {
-    let (where_str, params) = items_to_sql(&condition.filter)?;
+    let array = dialect.path(base, &segments[..i]);
+    let alias = format!("e{}", depth);
+    let (from, from_params, element) = dialect.elements(&array, &alias);
+    let any = |filter: &QueryFilter| -> Result<(String, Vec<SqlParam>)> {
+        let (sql, values) =
+            path_filter_to_sql(dialect, &element, &segments[i + 1..], filter, depth + 1)?;
+        let mut params = from_params.clone();
+        params.extend(values);
+        let sql = format!("EXISTS (SELECT 1 FROM {from} AS {alias} WHERE {sql})");
+        Ok((sql, params))
+    };
+    let with_operator = |operator| QueryFilter {
+        operator,
+        ..filter.clone()
+    };
+
+    match filter.operator {
+        QueryFilterOperator::NotExists => {
+            let (exists, params) = any(&with_operator(QueryFilterOperator::Exists))?;
+            Ok((format!("NOT {}", exists), params))
+        }
+        QueryFilterOperator::NotEquals | QueryFilterOperator::NotIn => {
+            let positive = match filter.operator {
+                QueryFilterOperator::NotEquals => QueryFilterOperator::Equals,
+                _ => QueryFilterOperator::In,
+            };
+            let (exists, mut params) = any(&with_operator(QueryFilterOperator::Exists))?;
+            let (matched, matched_params) = any(&with_operator(positive))?;
+            params.extend(matched_params);
+            Ok((format!("({} AND NOT {})", exists, matched), params))
+        }
+        _ => any(filter),
+    }
+}
+
+/// Mirrors `QueryFilter::matches` for a single value: a missing field yields
+/// `NULL`, which never satisfies a comparison, and ordering operators are
+/// guarded by the JSON type of the value so mixed types don't fall back to the
+/// database's cross-type order.
+fn value_filter_to_sql(
+    dialect: &dyn Dialect,
+    path: &PathSql,
+    filter: &QueryFilter,
+) -> Result<(String, Vec<SqlParam>)> {
+    let sql = match &filter.operator {
+        QueryFilterOperator::Equals => dialect.equals(path, &filter.value, false),
+        QueryFilterOperator::NotEquals => dialect.equals(path, &filter.value, true),
+        QueryFilterOperator::GreaterThan => comparison_to_sql(dialect, path, filter, ">")?,
+        QueryFilterOperator::GreaterThanOrEquals => comparison_to_sql(dialect, path, filter, ">=")?,
+        QueryFilterOperator::LessThan => comparison_to_sql(dialect, path, filter, "<")?,
+        QueryFilterOperator::LessThanOrEquals => comparison_to_sql(dialect, path, filter, "<=")?,
+        QueryFilterOperator::Exists => path.render("{json} IS NOT NULL", vec![]),
+        QueryFilterOperator::NotExists => path.render("{json} IS NULL", vec![]),
+        QueryFilterOperator::In | QueryFilterOperator::NotIn => {
+            let values = filter.values()?;
+            let negated = matches!(filter.operator, QueryFilterOperator::NotIn);
+            if values.is_empty() {
+                return Ok(if negated {
+                    path.render("{json} IS NOT NULL", vec![])
+                } else {
+                    ("FALSE".to_string(), vec![])
+                });
+            }
+            dialect.is_in(path, values, negated)
+        }
+    };
+    Ok(sql)
+}
+
+fn comparison_to_sql(
+    dialect: &dyn Dialect,
+    path: &PathSql,
+    filter: &QueryFilter,
+    operator: &str,
+) -> Result<(String, Vec<SqlParam>)> {
+    match &filter.value {
+        Value::Number(_) | Value::String(_) => Ok(dialect.compare(path, operator, &filter.value)),
+        value => Err(StoreError::UnsupportedOperator(format!(
+            "operator {:?} on field '{}' requires a number or a string, got {}",
+            filter.operator, filter.field, value
+        ))),
+    }
+}
+
+fn to_sql_condition(
+    dialect: &dyn Dialect,
+    condition: &QueryFilterCondition,
+) -> Result<(String, Vec<SqlParam>)> {
+    let (where_str, params) = items_to_sql(dialect, &condition.filter)?;
     Ok((format!("({})", where_str), params))
 }
 
+impl Dialect for Postgres {
+    /// Uses the `#>`/`#>>` operators, which resolve segments the same way
+    /// `query::resolve_path` does. The segments are bound as a `text[]`
+    /// parameter, so field names never reach the statement text.
+    fn path(&self, base: &str, segments: &[&str]) -> PathSql {
+        if segments.is_empty() {
+            return PathSql {
+                json: base.to_string(),
+                text: format!("({} #>> '{{}}')", base),
+                params: vec![],
+            };
+        }
+
+        PathSql {
+            json: format!("{} #> ?", base),
+            text: format!("{} #>> ?", base),
+            params: vec![SqlParam::Path(
+                segments.iter().map(|segment| segment.to_string()).collect(),
+            )],
+        }
+    }
+
+    fn elements(&self, array: &PathSql, alias: &str) -> (String, Vec<SqlParam>, String) {
+        let (from, params) = array.render(
+            "jsonb_array_elements(CASE WHEN jsonb_typeof({json}) = 'array' THEN {json} END)",
+            vec![],
+        );
+        (from, params, alias.to_string())
+    }
+
+    fn equals(&self, path: &PathSql, value: &Value, negated: bool) -> (String, Vec<SqlParam>) {
+        let template = if negated { "{json} <> ?" } else { "{json} = ?" };
+        path.render(template, vec![SqlParam::Json(value.clone())])
+    }
+
+    fn is_in(&self, path: &PathSql, values: &[Value], negated: bool) -> (String, Vec<SqlParam>) {
+        let placeholders = vec!["?"; values.len()].join(", ");
+        let operator = if negated { "NOT IN" } else { "IN" };
+        path.render(
+            &format!("{{json}} {} ({})", operator, placeholders),
+            values.iter().cloned().map(SqlParam::Json).collect(),
+        )
+    }
+
+    fn compare(&self, path: &PathSql, operator: &str, value: &Value) -> (String, Vec<SqlParam>) {
+        let template = match value {
+            Value::Number(_) => format!(
+                "(jsonb_typeof({{json}}) = 'number' AND {{json}} {} ?)",
+                operator
+            ),
+            // jsonb compares strings with the database collation; compare the
+            // text bytewise instead so results match the in-memory engine.
+            _ => format!(
+                "(jsonb_typeof({{json}}) = 'string' AND ({{text}}) COLLATE \"C\" {} (?::jsonb #>> '{{}}'))",
+                operator
+            ),
+        };
+        path.render(&template, vec![SqlParam::Json(value.clone())])
+    }
+
+    fn sort_rank(&self, path: &PathSql) -> (String, Vec<SqlParam>) {
+        path.render(
+            "CASE jsonb_typeof({json}) WHEN 'number' THEN 1 WHEN 'string' THEN 2 \
+             WHEN 'boolean' THEN CASE WHEN {json} = 'true' THEN 4 ELSE 3 END \
+             WHEN 'array' THEN 5 WHEN 'object' THEN 6 ELSE 0 END",
+            vec![],
+        )
+    }
+
+    fn sort_keys(&self, path: &PathSql, direction: &str) -> (String, Vec<SqlParam>) {
+        let (rank, mut params) = self.sort_rank(path);
+        let template = [
+            "CASE WHEN jsonb_typeof({json}) = 'number' THEN ({json})::numeric END",
+            "CASE WHEN jsonb_typeof({json}) = 'string' THEN ({text}) COLLATE \"C\" END",
+        ]
+        .map(|key| format!("{} {}", key, direction))
+        .join(", ");
+        let (keys, key_params) = path.render(&template, vec![]);
+        params.extend(key_params);
+        (format!("{} {}, {}", rank, direction, keys), params)
+    }
+
+    fn group_key(&self, path: &PathSql) -> (String, Vec<SqlParam>) {
+        path.render("COALESCE({json}, 'null'::jsonb)", vec![])
+    }
+
+    /// `jsonb` equality already takes `1` and `1.0` for the same value.
+    fn group_by(&self, column: &str) -> (String, String) {
+        (column.to_string(), column.to_string())
+    }
+
+    fn accumulator(&self, operator: &AccumulatorOperator, column: &str) -> String {
+        let number =
+            format!("CASE WHEN jsonb_typeof({column}) = 'number' THEN ({column})::numeric END");
+        let path = self.path(column, &[]);
+        let (rank, _) = self.sort_rank(&path);
+        match operator {
+            AccumulatorOperator::Count => "to_jsonb(count(*))".to_string(),
+            AccumulatorOperator::Sum => format!("to_jsonb(sum({}))", number),
+            AccumulatorOperator::Avg => format!("to_jsonb(avg({}))", number),
+            AccumulatorOperator::Min | AccumulatorOperator::Max => {
+                let direction = match operator {
+                    AccumulatorOperator::Min => "ASC",
+                    _ => "DESC",
+                };
+                let (keys, _) = self.sort_keys(&path, direction);
+                format!(
+                    "(array_agg({} ORDER BY {}) FILTER (WHERE {} > 0))[1]",
+                    column, keys, rank
+                )
+            }
+        }
+    }
+
+    fn json_object(&self, entries: &[String]) -> String {
+        let entries: Vec<String> = entries
+            .iter()
+            .map(|entry| entry.replacen('?', "?::text", 1))
+            .collect();
+        format!("jsonb_build_object({})", entries.join(", "))
+    }
+
+    fn member(&self, alias: &str) -> (String, String) {
+        (
+            format!("{}.value", alias),
+            format!("jsonb_typeof({}.value) = 'object'", alias),
+        )
+    }
+
+    fn object_members(&self, object: &str, alias: &str, value: &str, condition: &str) -> String {
+        format!(
+            "(SELECT COALESCE(jsonb_object_agg({alias}.key, {value}), '{{}}'::jsonb) \
+             FROM jsonb_each({object}) AS {alias} WHERE {condition})"
+        )
+    }
+
+    fn placeholders(&self, sql: &str) -> String {
+        enumerate_placeholders(sql)
+    }
+
+    fn column_type(&self) -> &'static str {
+        "JSONB"
+    }
+}
+
+impl Dialect for Sqlite {
+    /// Steps through the document with `->` and a JSON path bound as text, with
+    /// each segment written as a quoted label so any key can be addressed (keys
+    /// with escaped quotes need the SQLite bundled by rusqlite 0.37). An
+    /// integer segment is either a key or an array index depending on the value
+    /// it is applied to, so both lookups are tried.
+    fn path(&self, base: &str, segments: &[&str]) -> PathSql {
+        let mut json = base.to_string();
+        let mut params = vec![];
+        let mut labels = String::new();
+        for segment in segments {
+            let label = format!(".{}", Value::String(segment.to_string()));
+            let Ok(index) = segment.parse::<i64>() else {
+                labels.push_str(&label);
+                continue;
+            };
+
+            if !labels.is_empty() {
+                json = format!("{} -> ?", json);
+                params.push(SqlParam::Text(format!("${}", labels)));
+                labels.clear();
+            }
+            let index = if index < 0 {
+                format!("$[#{}]", index)
+            } else {
+                format!("$[{}]", index)
+            };
+            json = format!("COALESCE({json} -> ?, {json} -> ?)");
+            params = [
+                params.clone(),
+                vec![SqlParam::Text(format!("${}", label))],
+                params,
+                vec![SqlParam::Text(index)],
+            ]
+            .concat();
+        }
+        if !labels.is_empty() {
+            json = format!("{} -> ?", json);
+            params.push(SqlParam::Text(format!("${}", labels)));
+        }
+
+        PathSql {
+            text: format!("({} ->> '$')", json),
+            json,
+            params,
+        }
+    }
+
+    /// `json_each` yields booleans as integers and strings unquoted, so the
+    /// element is turned back into JSON text from the row's `type`.
+    fn elements(&self, array: &PathSql, alias: &str) -> (String, Vec<SqlParam>, String) {
+        let (from, params) = array.render(
+            "json_each(CASE WHEN json_type({json}) = 'array' THEN {json} END)",
+            vec![],
+        );
+        let element = format!(
+            "(CASE {alias}.type WHEN 'true' THEN 'true' WHEN 'false' THEN 'false' \
+             ELSE json_quote({alias}.value) END)"
+        );
+        (from, params, element)
+    }
+
+    /// Compares the JSON type and then the SQL value `->>` extracts, so `1` and
+    /// `1.0` are equal while `1` and `true` are not. Arrays and objects are
+    /// compared as minified JSON text.
+    fn equals(&self, path: &PathSql, value: &Value, negated: bool) -> (String, Vec<SqlParam>) {
+        let param = || vec![SqlParam::Json(value.clone())];
+        let (sql, params) = match value {
+            Value::Null => path.render("json_type({json}) = 'null'", vec![]),
+            Value::Bool(true) => path.render("json_type({json}) = 'true'", vec![]),
+            Value::Bool(false) => path.render("json_type({json}) = 'false'", vec![]),
+            Value::Number(_) => path.render(
+                "(json_type({json}) IN ('integer', 'real') AND {text} = (? ->> '$'))",
+                param(),
+            ),
+            Value::String(_) => path.render(
+                "(json_type({json}) = 'text' AND {text} = (? ->> '$'))",
+                param(),
+            ),
+            Value::Array(_) => path.render(
+                "(json_type({json}) = 'array' AND json({json}) = json(?))",
+                param(),
+            ),
+            Value::Object(_) => path.render(
+                "(json_type({json}) = 'object' AND json({json}) = json(?))",
+                param(),
+            ),
+        };
+        if !negated {
+            return (sql, params);
+        }
+
+        let (exists, mut exists_params) = path.render("{json} IS NOT NULL", vec![]);
+        exists_params.extend(params);
+        (format!("({} AND NOT {})", exists, sql), exists_params)
+    }
+
+    fn is_in(&self, path: &PathSql, values: &[Value], negated: bool) -> (String, Vec<SqlParam>) {
+        let mut conditions = vec![];
+        let mut params = vec![];
+        for value in values {
+            let (sql, value_params) = self.equals(path, value, false);
+            conditions.push(sql);
+            params.extend(value_params);
+        }
+        let sql = format!("({})", conditions.join(" OR "));
+        if !negated {
+            return (sql, params);
+        }
+
+        let (exists, mut exists_params) = path.render("{json} IS NOT NULL", vec![]);
+        exists_params.extend(params);
+        (format!("({} AND NOT {})", exists, sql), exists_params)
+    }
+
+    /// SQLite compares text with `memcmp`, which is already bytewise.
+    fn compare(&self, path: &PathSql, operator: &str, value: &Value) -> (String, Vec<SqlParam>) {
+        let types = match value {
+            Value::Number(_) => "IN ('integer', 'real')",
+            _ => "= 'text'",
+        };
+        path.render(
+            &format!(
+                "(json_type({{json}}) {} AND {{text}} {} (? ->> '$'))",
+                types, operator
+            ),
+            vec![SqlParam::Json(value.clone())],
+        )
+    }
+
+    fn sort_rank(&self, path: &PathSql) -> (String, Vec<SqlParam>) {
+        path.render(
+            "CASE json_type({json}) WHEN 'integer' THEN 1 WHEN 'real' THEN 1 WHEN 'text' THEN 2 \
+             WHEN 'false' THEN 3 WHEN 'true' THEN 4 WHEN 'array' THEN 5 WHEN 'object' THEN 6 \
+             ELSE 0 END",
+            vec![],
+        )
+    }
+
+    fn sort_keys(&self, path: &PathSql, direction: &str) -> (String, Vec<SqlParam>) {
+        let (rank, mut params) = self.sort_rank(path);
+        let template = [
+            "CASE WHEN json_type({json}) IN ('integer', 'real') THEN {text} END",
+            "CASE WHEN json_type({json}) = 'text' THEN {text} END",
+        ]
+        .map(|key| format!("{} {}", key, direction))
+        .join(", ");
+        let (keys, key_params) = path.render(&template, vec![]);
+        params.extend(key_params);
+        (format!("{} {}, {}", rank, direction, keys), params)
+    }
+
+    fn group_key(&self, path: &PathSql) -> (String, Vec<SqlParam>) {
+        path.render("COALESCE({json}, 'null')", vec![])
+    }
+
+    /// The key column holds JSON text, in which `1` and `1.0` differ, so
+    /// numbers are grouped by their value instead. Any of the texts of a group
+    /// stands for its key.
+    fn group_by(&self, column: &str) -> (String, String) {
+        (
+            format!(
+                "CASE WHEN json_type({column}) IN ('integer', 'real') \
+                 THEN {column} ->> '$' ELSE {column} END"
+            ),
+            format!("json({})", column),
+        )
+    }
+
+    fn accumulator(&self, operator: &AccumulatorOperator, column: &str) -> String {
+        let number = format!(
+            "CASE WHEN json_type({column}) IN ('integer', 'real') THEN {column} ->> '$' END"
+        );
+        let path = self.path(column, &[]);
+        let (rank, _) = self.sort_rank(&path);
+        match operator {
+            AccumulatorOperator::Count => "count(*)".to_string(),
+            AccumulatorOperator::Sum => format!("sum({})", number),
+            AccumulatorOperator::Avg => format!("avg({})", number),
+            AccumulatorOperator::Min | AccumulatorOperator::Max => {
+                let direction = match operator {
+                    AccumulatorOperator::Min => "ASC",
+                    _ => "DESC",
+                };
+                let (keys, _) = self.sort_keys(&path, direction);
+                format!(
+                    "json((json_group_array(json({}) ORDER BY {}) FILTER (WHERE {} > 0)) -> 0)",
+                    column, keys, rank
+                )
+            }
+        }
+    }
+
+    fn json_object(&self, entries: &[String]) -> String {
+        format!("json_object({})", entries.join(", "))
+    }
+
+    /// Like `elements`, scalars are turned back into JSON from the row's
+    /// `type`; arrays and objects already are JSON.
+    fn member(&self, alias: &str) -> (String, String) {
+        (
+            format!(
+                "(CASE {alias}.type WHEN 'true' THEN json('true') WHEN 'false' THEN json('false') \
+                 ELSE json_quote({alias}.value) END)"
+            ),
+            format!("{}.type = 'object'", alias),
+        )
+    }
+
+    /// The aggregate is wrapped in `json()` since a subquery loses the JSON
+    /// subtype that makes an enclosing object embed it as JSON.
+    fn object_members(&self, object: &str, alias: &str, value: &str, condition: &str) -> String {
+        format!(
+            "json((SELECT json_group_object({alias}.key, {value}) \
+             FROM json_each({object}) AS {alias} WHERE {condition}))"
+        )
+    }
+
+    fn placeholders(&self, sql: &str) -> String {
+        sql.to_string()
+    }
+
+    fn column_type(&self) -> &'static str {
+        "TEXT"
+    }
+
+    /// SQLite only accepts `OFFSET` after a `LIMIT`; a negative limit means
+    /// none.
+    fn limit(&self, limit: &QueryLimit) -> String {
+        match (limit.limit, limit.offset) {
+            (None, Some(offset)) => format!(" LIMIT -1 OFFSET {}", offset),
+            (Some(limit), Some(offset)) => format!(" LIMIT {} OFFSET {}", limit, offset),
+            (Some(limit), None) => format!(" LIMIT {}", limit),
+            (None, None) => String::new(),
+        }
+    }
+}
+
+/// Bookkeeping table of `Persistence::increment_counter`, created on first use.
+pub(crate) const COUNTERS_TABLE: &str =
+    "CREATE TABLE IF NOT EXISTS store_counters (collection TEXT PRIMARY KEY, value BIGINT NOT NULL)";
+
+pub(crate) const INCREMENT_COUNTER: &str =
+    "INSERT INTO store_counters (collection, value) VALUES (?, 1) \
+     ON CONFLICT (collection) DO UPDATE SET value = store_counters.value + 1 RETURNING value";
+
+/// Bookkeeping table of `Store::migrate`: the schema version each collection
+/// was last migrated to, created on first use.
+pub(crate) const MIGRATIONS_TABLE: &str = "CREATE TABLE IF NOT EXISTS store_migrations (\
+     collection TEXT PRIMARY KEY, version INTEGER NOT NULL, \
+     migrated_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP)";
+
+pub(crate) const MIGRATED_VERSION: &str =
+    "SELECT version FROM store_migrations WHERE collection = ?";
+
+/// Never moves a collection's version back, should an older build of the
+/// application run its migration.
+pub(crate) const RECORD_MIGRATION: &str =
+    "INSERT INTO store_migrations (collection, version) VALUES (?, ?) \
+     ON CONFLICT (collection) DO UPDATE SET version = excluded.version, \
+     migrated_at = CURRENT_TIMESTAMP WHERE store_migrations.version < excluded.version";
+
 #[cfg(test)]
 mod tests {
-    use crate::query::{QueryFilter, QueryFilterOperation};
+    use serde_json::json;
+
+    use crate::query::{tests::boolean_cases, QueryFilterOperation, QueryLimit};
 
     use super::*;
 
+    fn path(field: &str) -> SqlParam {
+        SqlParam::Path(field.split('.').map(str::to_string).collect())
+    }
+
+    fn param(value: Value) -> SqlParam {
+        SqlParam::Json(value)
+    }
+
     #[test]
     fn test_enumerate_placeholders() {
         let str = "VALUES (?, ?)";
@@ -154,14 +1498,16 @@ mod tests {
                 },
             }),
         ];
-        let (where_str, where_values) = items_to_sql(&items).unwrap();
-        let sql = "data->'id' = ? OR data->'id' = ?";
+        let (where_str, where_values) = items_to_sql(&Postgres, &items).unwrap();
+        let sql = "data #> ? = ? OR data #> ? = ?";
         assert_eq!(where_str, sql);
         assert_eq!(
             where_values,
             vec![
-                Value::String("123".to_string()),
-                Value::String("456".to_string())
+                path("id"),
+                param(Value::String("123".to_string())),
+                path("id"),
+                param(Value::String("456".to_string()))
             ]
         );
     }
@@ -199,28 +1545,655 @@ mod tests {
                 ],
             }),
         ];
-        let (where_str, where_values) = items_to_sql(&items).unwrap();
+        let (where_str, where_values) = items_to_sql(&Postgres, &items).unwrap();
 
-        let sql = "data->'id' = ? AND (data->'name' = ? OR data->'name' = ?)";
+        let sql = "data #> ? = ? AND (data #> ? = ? OR data #> ? = ?)";
         assert_eq!(sql, where_str);
-        assert_eq!(where_values.len(), 3);
-        assert_eq!(where_values[0], Value::String("123".to_string()));
-        assert_eq!(where_values[1], Value::String("test1".to_string()));
-        assert_eq!(where_values[2], Value::String("test2".to_string()));
+        assert_eq!(where_values.len(), 6);
+        assert_eq!(where_values[1], param(Value::String("123".to_string())));
+        assert_eq!(where_values[2], path("name"));
+        assert_eq!(where_values[3], param(Value::String("test1".to_string())));
+        assert_eq!(where_values[5], param(Value::String("test2".to_string())));
     }
 
     #[test]
     fn test_to_sql() -> anyhow::Result<()> {
         let json = r#"{"filter":[{"type":"filter","operation":"and","filter":{"field":"id","operator":"equals","value":2}},{"type":"filter","operation":"or","filter":{"field":"name","operator":"equals","value":"John"}},{"type":"condition","operation":"and","filter":[{"type":"condition","operation":"and","filter":[{"type":"filter","operation":"and","filter":{"field":"id","operator":"notEquals","value":1}},{"type":"filter","operation":"and","filter":{"field":"name","operator":"equals","value":"Felipe"}},{"type":"condition","operation":"and","filter":[{"type":"filter","operation":"and","filter":{"field":"age","operator":"greaterThan","value":18}}]}]}]}],"sort":null,"limit":null}"#;
         let query = Query::from_json(json).unwrap();
-        let (sql, params) = to_sql("users", &Some(query))?;
-        assert_eq!(sql, "SELECT data FROM users WHERE data->'id' = $1 OR data->'name' = $2 AND ((data->'id' <> $3 AND data->'name' = $4 AND (data->'age' > $5)))");
-        assert_eq!(params.len(), 5);
-        assert_eq!(params[0], 2);
-        assert_eq!(params[1], "John");
-        assert_eq!(params[2], 1);
-        assert_eq!(params[3], "Felipe");
-        assert_eq!(params[4], 18);
+        let (sql, params) = to_sql(&Postgres, "users", &Some(query))?;
+        assert_eq!(sql, "SELECT data FROM \"users\" WHERE data #> $1 = $2 OR (data #> $3 = $4 AND ((data #> $5 <> $6 AND data #> $7 = $8 AND ((jsonb_typeof(data #> $9) = 'number' AND data #> $10 > $11)))))");
+        assert_eq!(params.len(), 11);
+        assert_eq!(params[0], path("id"));
+        assert_eq!(params[1], param(json!(2)));
+        assert_eq!(params[3], param(json!("John")));
+        assert_eq!(params[5], param(json!(1)));
+        assert_eq!(params[7], param(json!("Felipe")));
+        assert_eq!(params[8], path("age"));
+        assert_eq!(params[9], path("age"));
+        assert_eq!(params[10], param(json!(18)));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_write_to_sql() -> anyhow::Result<()> {
+        let query = Query::builder().eq("id", json!("123")).build();
+        let data = json!({"id": "123", "name": "John"});
+
+        let (sql, params) = insert_to_sql(&Postgres, "users", &data)?;
+        assert_eq!(
+            sql,
+            "INSERT INTO \"users\" (data) VALUES ($1) RETURNING data"
+        );
+        assert_eq!(params, vec![param(data.clone())]);
+
+        let (sql, params) = update_to_sql(&Postgres, "users", &query, &data)?;
+        assert_eq!(sql, "UPDATE \"users\" SET data = $1 WHERE data #> $2 = $3");
+        assert_eq!(params, vec![param(data), path("id"), param(json!("123"))]);
+
+        let (sql, params) = delete_to_sql(&Postgres, "users", &query)?;
+        assert_eq!(sql, "DELETE FROM \"users\" WHERE data #> $1 = $2");
+        assert_eq!(params, vec![path("id"), param(json!("123"))]);
+
+        let (sql, params) = delete_to_sql(&Postgres, "users", &Query::builder().build())?;
+        assert_eq!(sql, "DELETE FROM \"users\"");
+        assert!(params.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_patch_to_sql() -> anyhow::Result<()> {
+        let query = Query::builder().eq("id", json!("123")).build();
+        let update = Update::builder().set("name", json!("Ann")).build();
+
+        let (sql, params) = patch_to_sql("users", &query, &update)?;
+        let patched = |key: usize, value: usize| {
+            format!(
+                "(SELECT CASE WHEN jsonb_typeof(d) = 'object' \
+                 THEN d || jsonb_build_object(${}::text, ${}::jsonb) ELSE d END \
+                 FROM (SELECT data AS d OFFSET 0) AS patched)",
+                key, value
+            )
+        };
+        assert_eq!(
+            sql,
+            format!(
+                "UPDATE \"users\" SET data = {} WHERE (data #> $3 = $4) AND (data <> {})",
+                patched(1, 2),
+                patched(5, 6)
+            )
+        );
+        let set = vec![text("name"), param(json!("Ann"))];
+        assert_eq!(
+            params,
+            [set.clone(), vec![path("id"), param(json!("123"))], set].concat()
+        );
+
+        // each operator reads the document the previous one produced
+        let update = Update::builder()
+            .unset("address.city")
+            .inc("visits", 1)
+            .build();
+        let (sql, params) = patch_to_sql("users", &Query::builder().build(), &update)?;
+        let unset = |parent: usize, field: usize| {
+            format!(
+                "FROM (SELECT (SELECT CASE WHEN jsonb_typeof(d) = 'object' \
+                 AND jsonb_typeof(d #> ${}) = 'object' THEN d #- ${} ELSE d END \
+                 FROM (SELECT data AS d OFFSET 0) AS patched) AS d OFFSET 0) AS patched)",
+                parent, field
+            )
+        };
+        let (set, condition) = sql.split_once(" WHERE data <> ").unwrap();
+        assert!(set.ends_with(&unset(9, 10)), "{}", set);
+        assert!(condition.ends_with(&unset(19, 20)), "{}", condition);
+        assert_eq!(params[0], text("visits"));
+        let (first, second) = params.split_at(params.len() / 2);
+        assert_eq!(first, second);
+
+        let invalid = Update::builder().set("tags.*", json!(1)).build();
+        assert!(patch_to_sql("users", &query, &invalid).is_err());
+        assert!(patch_to_sql("users; --", &query, &update).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_operators_to_sql() -> anyhow::Result<()> {
+        let cases = vec![
+            (
+                Query::builder().gte("age", json!(18)).build(),
+                "(jsonb_typeof(data #> $1) = 'number' AND data #> $2 >= $3)",
+                3,
+            ),
+            (
+                Query::builder().lt("created", json!("2022-11-05")).build(),
+                "(jsonb_typeof(data #> $1) = 'string' AND (data #>> $2) COLLATE \"C\" < ($3::jsonb #>> '{}'))",
+                3,
+            ),
+            (
+                Query::builder().exists("name").build(),
+                "data #> $1 IS NOT NULL",
+                1,
+            ),
+            (
+                Query::builder().not_exists("name").build(),
+                "data #> $1 IS NULL",
+                1,
+            ),
+            (
+                Query::builder().is_in("id", vec![json!(1), json!(2)]).build(),
+                "data #> $1 IN ($2, $3)",
+                3,
+            ),
+            (
+                Query::builder().not_in("id", vec![json!(1)]).build(),
+                "data #> $1 NOT IN ($2)",
+                2,
+            ),
+            (Query::builder().is_in("id", vec![]).build(), "FALSE", 0),
+            (
+                Query::builder().not_in("id", vec![]).build(),
+                "data #> $1 IS NOT NULL",
+                1,
+            ),
+        ];
+        for (query, where_str, param_count) in cases {
+            let (sql, params) = to_sql(&Postgres, "users", &Some(query))?;
+            assert_eq!(
+                sql,
+                format!("SELECT data FROM \"users\" WHERE {}", where_str)
+            );
+            assert_eq!(params.len(), param_count);
+        }
+
+        let query = Query::builder().gt("age", json!(null)).build();
+        assert!(to_sql(&Postgres, "users", &Some(query)).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_boolean_to_sql() -> anyhow::Result<()> {
+        for case in boolean_cases() {
+            let (where_str, _) = items_to_sql(&Postgres, case.query.filter.as_ref().unwrap())?;
+            assert_eq!(where_str, case.sql, "{}", case.name);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_sort_to_sql() -> anyhow::Result<()> {
+        let mut query = Query::builder()
+            .gt("age", json!(18))
+            .order_by_desc("age")
+            .order_by("name")
+            .build();
+        query.limit = Some(QueryLimit {
+            limit: Some(10),
+            offset: Some(20),
+        });
+
+        let (sql, params) = to_sql(&Postgres, "users", &Some(query))?;
+        let (_, order_by) = sql.split_once(" ORDER BY ").unwrap();
+        let keys: Vec<&str> = order_by.split(", CASE").collect();
+        assert_eq!(keys.len(), 6);
+        assert!(keys[..3].iter().all(|key| key.ends_with(" DESC")));
+        assert!(keys[5].ends_with("COLLATE \"C\" END ASC LIMIT 10 OFFSET 20"));
+        assert_eq!(params.len(), 3 + 12);
+        assert_eq!(params[2], param(json!(18)));
+        assert!(params[3..9].iter().all(|p| *p == path("age")));
+        assert!(params[9..].iter().all(|p| *p == path("name")));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_after_to_sql() -> anyhow::Result<()> {
+        let mut query = Query::builder()
+            .eq("active", json!(true))
+            .order_by_desc("age")
+            .order_by("id")
+            .build();
+        query.after = Some(vec![json!(30), json!(null)]);
+
+        let (sql, params) = to_sql(&Postgres, "users", &Some(query.clone()))?;
+        let (where_str, _) = sql.split_once(" ORDER BY ").unwrap();
+        let (_, after) = where_str.split_once(") AND (((").unwrap();
+        let alternatives: Vec<&str> = after.split(") OR (").collect();
+        assert_eq!(alternatives.len(), 2);
+        assert!(alternatives[0].contains(" < 1 OR (jsonb_typeof(data #> $"));
+        assert!(alternatives[1].starts_with("data #> $"));
+        assert!(alternatives[1].contains(" > 0"));
+        assert_eq!(params[1], param(json!(true)));
+        assert!(params.contains(&param(json!(30))));
+
+        let (sql, _) = to_sql(&Sqlite, "users", &Some(query.clone()))?;
+        assert!(sql.contains("ELSE 0 END > 0"));
+
+        query.after = Some(vec![]);
+        assert!(to_sql(&Postgres, "users", &Some(query)).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_count_to_sql() -> anyhow::Result<()> {
+        let query = Some(
+            Query::builder()
+                .eq("age", json!(30))
+                .order_by("name")
+                .build(),
+        );
+
+        let (sql, params) = count_to_sql(&Postgres, "users", &query)?;
+        assert_eq!(sql, "SELECT count(*) FROM \"users\" WHERE data #> $1 = $2");
+        assert_eq!(params, vec![path("age"), param(json!(30))]);
+
+        let (sql, _) = exists_to_sql(&Postgres, "users", &None)?;
+        assert_eq!(sql, "SELECT EXISTS (SELECT 1 FROM \"users\")");
+
+        let (sql, params) = distinct_to_sql(&Postgres, "users", "address.city", &query)?;
+        assert_eq!(
+            sql,
+            "SELECT DISTINCT data #> $1 FROM \"users\" \
+             WHERE (data #> $2 = $3) AND (data #> $4 IS NOT NULL)"
+        );
+        assert_eq!(params[0], path("address.city"));
+        assert_eq!(params[3], path("address.city"));
+
+        let (sql, params) = distinct_to_sql(&Sqlite, "users", "city", &None)?;
+        assert_eq!(
+            sql,
+            "SELECT DISTINCT data -> ? FROM \"users\" WHERE data -> ? IS NOT NULL"
+        );
+        assert_eq!(params, vec![text("$.\"city\""), text("$.\"city\"")]);
+
+        assert!(distinct_to_sql(&Postgres, "users", "items.*.sku", &None).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_aggregate_to_sql() -> anyhow::Result<()> {
+        let aggregation = Aggregation::builder()
+            .matching(&Query::builder().eq("paid", json!(true)).build())
+            .group_by("city", "address.city")
+            .sum("total", "amount")
+            .count("orders")
+            .order_by_desc("total")
+            .limit(3)
+            .build();
+
+        let (sql, params) = aggregate_to_sql(&Postgres, "orders", &aggregation)?;
+        assert_eq!(
+            sql,
+            "SELECT data FROM (SELECT jsonb_build_object($1::text, k0, \
+             $2::text, to_jsonb(sum(CASE WHEN jsonb_typeof(v0) = 'number' THEN (v0)::numeric END)), \
+             $3::text, to_jsonb(count(*))) AS data \
+             FROM (SELECT COALESCE(data #> $4, 'null'::jsonb) AS k0, data #> $5 AS v0, NULL AS v1 \
+             FROM \"orders\" WHERE data #> $6 = $7) AS matched GROUP BY k0) AS grouped \
+             ORDER BY CASE jsonb_typeof(data #> $8) WHEN 'number' THEN 1 WHEN 'string' THEN 2 \
+             WHEN 'boolean' THEN CASE WHEN data #> $9 = 'true' THEN 4 ELSE 3 END \
+             WHEN 'array' THEN 5 WHEN 'object' THEN 6 ELSE 0 END DESC, \
+             CASE WHEN jsonb_typeof(data #> $10) = 'number' THEN (data #> $11)::numeric END DESC, \
+             CASE WHEN jsonb_typeof(data #> $12) = 'string' THEN (data #>> $13) COLLATE \"C\" END DESC \
+             LIMIT 3"
+        );
+        assert_eq!(params[..3], [text("city"), text("total"), text("orders")]);
+        assert_eq!(params[3], path("address.city"));
+        assert_eq!(params[4], path("amount"));
+        assert_eq!(params[6], param(json!(true)));
+        assert!(params[7..].iter().all(|p| *p == path("total")));
+
+        let (sql, params) = aggregate_to_sql(&Sqlite, "orders", &aggregation)?;
+        assert!(sql.starts_with("SELECT data FROM (SELECT json_object(?, json(k0), ?, sum("));
+        assert!(sql.contains(" GROUP BY CASE WHEN json_type(k0) IN ('integer', 'real') "));
+        assert_eq!(params[3], text("$.\"address\".\"city\""));
+
+        assert!(aggregate_to_sql(&Postgres, "orders", &Aggregation::default()).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_projection_to_sql() -> anyhow::Result<()> {
+        let query = Query::builder()
+            .eq("active", json!(true))
+            .select(&["id", "name", "address.city"])
+            .build();
+        let (sql, params) = to_sql(&Postgres, "users", &Some(query))?;
+        assert_eq!(
+            sql,
+            "SELECT (SELECT COALESCE(jsonb_object_agg(p0.key, \
+             CASE WHEN p0.key = $1 AND jsonb_typeof(p0.value) = 'object' \
+             THEN (SELECT COALESCE(jsonb_object_agg(p1.key, p1.value), '{}'::jsonb) \
+             FROM jsonb_each(p0.value) AS p1 WHERE p1.key IN ($2)) ELSE p0.value END), '{}'::jsonb) \
+             FROM jsonb_each(data) AS p0 \
+             WHERE p0.key IN ($3, $4) OR (p0.key IN ($5) AND jsonb_typeof(p0.value) = 'object')) \
+             FROM \"users\" WHERE data #> $6 = $7"
+        );
+        assert_eq!(
+            params[..5],
+            [
+                text("address"),
+                text("city"),
+                text("id"),
+                text("name"),
+                text("address")
+            ]
+        );
+        assert_eq!(params[5], path("active"));
+
+        let query = Query::builder().exclude(&["password"]).build();
+        let (sql, params) = to_sql(&Sqlite, "users", &Some(query))?;
+        assert_eq!(
+            sql,
+            "SELECT json((SELECT json_group_object(p0.key, (CASE p0.type \
+             WHEN 'true' THEN json('true') WHEN 'false' THEN json('false') \
+             ELSE json_quote(p0.value) END)) FROM json_each(data) AS p0 \
+             WHERE NOT p0.key IN (?))) FROM \"users\""
+        );
+        assert_eq!(params, [text("password")]);
+
+        let query = Query::builder().select(&["items.*.sku"]).build();
+        assert!(to_sql(&Postgres, "users", &Some(query)).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_paths_to_sql() -> anyhow::Result<()> {
+        let query = Query::builder()
+            .eq("address.city", json!("Lisbon"))
+            .eq("items.0.sku", json!("A1"))
+            .build();
+        let (sql, params) = to_sql(&Postgres, "users", &Some(query))?;
+        assert_eq!(
+            sql,
+            "SELECT data FROM \"users\" WHERE data #> $1 = $2 AND data #> $3 = $4"
+        );
+        assert_eq!(params[0], path("address.city"));
+        assert_eq!(params[2], path("items.0.sku"));
+
+        let query = Query::builder().eq("items.*.sku", json!("A1")).build();
+        let (sql, params) = to_sql(&Postgres, "users", &Some(query))?;
+        assert_eq!(
+            sql,
+            "SELECT data FROM \"users\" WHERE EXISTS (SELECT 1 FROM jsonb_array_elements(CASE WHEN jsonb_typeof(data #> $1) = 'array' THEN data #> $2 END) AS e0 WHERE e0 #> $3 = $4)"
+        );
+        assert_eq!(
+            params,
+            vec![
+                path("items"),
+                path("items"),
+                path("sku"),
+                param(json!("A1"))
+            ]
+        );
+
+        let query = Query::builder().not_eq("items.*.sku", json!("A1")).build();
+        let (sql, params) = to_sql(&Postgres, "users", &Some(query))?;
+        assert!(sql.contains("WHERE (EXISTS (SELECT 1"));
+        assert!(sql.contains("e0 #> $3 IS NOT NULL) AND NOT EXISTS (SELECT 1"));
+        assert_eq!(params.len(), 7);
+
+        let query = Query::builder().order_by("address.city").build();
+        let (sql, _) = to_sql(&Postgres, "users", &Some(query))?;
+        assert!(sql.contains("(data #>> $6) COLLATE"));
+
+        let query = Query::builder().order_by("items.*.sku").build();
+        assert!(to_sql(&Postgres, "users", &Some(query)).is_err());
+
+        Ok(())
+    }
+
+    fn text(value: &str) -> SqlParam {
+        SqlParam::Text(value.to_string())
+    }
+
+    #[test]
+    fn test_sqlite_to_sql() -> anyhow::Result<()> {
+        let query = Query::builder()
+            .eq("address.city", json!("Lisbon"))
+            .gt("age", json!(18))
+            .build();
+        let (sql, params) = to_sql(&Sqlite, "users", &Some(query))?;
+        assert_eq!(
+            sql,
+            "SELECT data FROM \"users\" WHERE (json_type(data -> ?) = 'text' AND (data -> ? ->> '$') = (? ->> '$')) \
+             AND (json_type(data -> ?) IN ('integer', 'real') AND (data -> ? ->> '$') > (? ->> '$'))"
+        );
+        assert_eq!(
+            params,
+            vec![
+                text("$.\"address\".\"city\""),
+                text("$.\"address\".\"city\""),
+                param(json!("Lisbon")),
+                text("$.\"age\""),
+                text("$.\"age\""),
+                param(json!(18)),
+            ]
+        );
+
+        // an integer segment is a key of an object or an index of an array
+        let query = Query::builder().exists("items.-1.sku").build();
+        let (sql, params) = to_sql(&Sqlite, "users", &Some(query))?;
+        assert_eq!(
+            sql,
+            "SELECT data FROM \"users\" WHERE COALESCE(data -> ? -> ?, data -> ? -> ?) -> ? IS NOT NULL"
+        );
+        assert_eq!(
+            params,
+            vec![
+                text("$.\"items\""),
+                text("$.\"-1\""),
+                text("$.\"items\""),
+                text("$[#-1]"),
+                text("$.\"sku\""),
+            ]
+        );
+
+        let query = Query::builder().not_eq("tags.*", json!(true)).build();
+        let (sql, _) = to_sql(&Sqlite, "users", &Some(query))?;
+        assert!(sql.contains(
+            "FROM json_each(CASE WHEN json_type(data -> ?) = 'array' THEN data -> ? END) AS e0"
+        ));
+        assert!(sql.contains("json_type((CASE e0.type WHEN 'true' THEN 'true'"));
+
+        let mut query = Query::builder().build();
+        query.limit = Some(QueryLimit {
+            limit: None,
+            offset: Some(20),
+        });
+        let (sql, _) = to_sql(&Sqlite, "users", &Some(query))?;
+        assert_eq!(sql, "SELECT data FROM \"users\" LIMIT -1 OFFSET 20");
+
+        let (sql, _) = insert_to_sql(&Sqlite, "users", &json!({}))?;
+        assert_eq!(
+            sql,
+            "INSERT INTO \"users\" (data) VALUES (?) RETURNING data"
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_quote_identifier() {
+        assert_eq!(quote_identifier("users").unwrap(), "\"users\"");
+        assert_eq!(
+            quote_identifier("_Line_Items2").unwrap(),
+            "\"_Line_Items2\""
+        );
+        for name in [
+            "",
+            "2users",
+            "users; DROP TABLE users",
+            "users\"",
+            "us?ers",
+            "public.users",
+            "üsers",
+            &"a".repeat(64),
+        ] {
+            assert!(quote_identifier(name).is_err(), "{:?}", name);
+            assert!(to_sql(&Postgres, name, &None).is_err(), "{:?}", name);
+        }
+    }
+
+    #[test]
+    fn test_create_sql() -> anyhow::Result<()> {
+        assert_eq!(
+            create_table_sql(&Postgres, "users")?,
+            "CREATE TABLE IF NOT EXISTS \"users\" (data JSONB)"
+        );
+        assert_eq!(
+            create_table_sql(&Sqlite, "users")?,
+            "CREATE TABLE IF NOT EXISTS \"users\" (data TEXT)"
+        );
+        assert!(create_table_sql(&Postgres, "users; --").is_err());
+
+        let cases = [
+            (
+                Index::ordered("address.city"),
+                "CREATE INDEX IF NOT EXISTS \"users_address_city_ordered\" ON \"users\" \
+                 ((data #> ARRAY['address', 'city']::text[]))",
+            ),
+            (
+                Index::hash("email"),
+                "CREATE INDEX IF NOT EXISTS \"users_email_hash\" ON \"users\" \
+                 USING hash ((data #> ARRAY['email']::text[]))",
+            ),
+            (
+                Index::gin("tags"),
+                "CREATE INDEX IF NOT EXISTS \"users_tags_gin\" ON \"users\" \
+                 USING gin ((data #> ARRAY['tags']::text[]) jsonb_path_ops)",
+            ),
+            (
+                Index::hash("it's"),
+                "CREATE INDEX IF NOT EXISTS \"users_it_s_hash\" ON \"users\" \
+                 USING hash ((data #> ARRAY['it''s']::text[]))",
+            ),
+        ];
+        for (index, expected) in cases {
+            assert_eq!(create_index_sql("users", &index)?, expected);
+        }
+
+        // long names are cut short, and fields differing past the cut apart
+        let long = "a".repeat(60);
+        let first = create_index_sql("users", &Index::ordered(&format!("{}.b", long)))?;
+        let second = create_index_sql("users", &Index::ordered(&format!("{}.c", long)))?;
+        assert_ne!(first, second);
+        for index in [Index::hash("items.*.sku"), Index::ordered("")] {
+            assert!(matches!(
+                create_index_sql("users", &index),
+                Err(StoreError::UnsupportedOperator(_))
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// A small xorshift generator so the fuzz cases are reproducible without
+    /// pulling in a random number crate.
+    fn hostile_fields(seed: u64, count: usize) -> Vec<String> {
+        const PIECES: &[&str] = &[
+            "'",
+            "\"",
+            "\\",
+            "?",
+            "$1",
+            "--",
+            ";",
+            "/*",
+            "*/",
+            "{",
+            "}",
+            ",",
+            ".",
+            "*",
+            " ",
+            "\n",
+            "\0",
+            "DROP TABLE users",
+            "') OR 1=1 --",
+            "->",
+            "#>",
+            "::",
+            "é",
+            "🦀",
+            "a",
+        ];
+
+        let mut state = seed;
+        let mut next = move || {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state
+        };
+
+        (0..count)
+            .map(|_| {
+                let len = next() % 8 + 1;
+                (0..len)
+                    .map(|_| PIECES[(next() % PIECES.len() as u64) as usize])
+                    .collect()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_hostile_field_names() -> anyhow::Result<()> {
+        for field in hostile_fields(0x5eed, 500) {
+            let query = Query::builder()
+                .eq(&field, json!("x"))
+                .gt(&field, json!("x"))
+                .is_in(&field, vec![json!(1)])
+                .not_exists(&field)
+                .order_by_desc(&field)
+                .build();
+
+            let (sql, params) = match to_sql(&Postgres, "users", &Some(query)) {
+                Ok(sql) => sql,
+                // sorting on a path with a `*` segment is rejected up front
+                Err(_) if field.split('.').any(|s| s == ANY_ELEMENT) => continue,
+                Err(err) => return Err(err.into()),
+            };
+
+            // The statement is built from fixed fragments only: stripping the
+            // placeholders must leave no trace of the field name.
+            assert!(!sql.contains('?'), "{}", sql);
+            assert!(!sql.contains("DROP"), "{}", sql);
+            assert!(!sql.contains("--"), "{}", sql);
+            assert!(!sql.contains(';'), "{}", sql);
+            assert_eq!(sql.matches('$').count(), params.len(), "{}", sql);
+
+            let segments: Vec<String> = field.split('.').map(str::to_string).collect();
+            assert!(params
+                .iter()
+                .filter_map(|p| match p {
+                    SqlParam::Path(path) => Some(path),
+                    _ => None,
+                })
+                .all(|path| segments.ends_with(path) || segments.starts_with(path)));
+
+            let query = Query::builder()
+                .eq(&field, json!("x"))
+                .not_in(&field, vec![json!(1), json!([2])])
+                .order_by(&field)
+                .build();
+            let (sql, params) = to_sql(&Sqlite, "users", &Some(query))?;
+            assert!(!sql.contains("DROP"), "{}", sql);
+            assert!(!sql.contains("--"), "{}", sql);
+            assert!(!sql.contains(';'), "{}", sql);
+            assert_eq!(sql.matches('?').count(), params.len(), "{}", sql);
+            assert!(params.iter().all(|p| match p {
+                SqlParam::Text(path) => path.starts_with('$'),
+                SqlParam::Json(_) => true,
+                SqlParam::Path(_) => false,
+            }));
+        }
 
         Ok(())
     }
@@ -0,0 +1,498 @@
+use std::{
+    collections::HashSet,
+    sync::{Arc, Mutex},
+};
+
+use async_trait::async_trait;
+use rusqlite::OptionalExtension;
+use serde_json::Value;
+
+use crate::{
+    aggregate::Aggregation,
+    error::{Result, StoreError},
+    index::Index,
+    query::{Query, QueryLimit},
+    sql::{
+        aggregate_to_sql, count_to_sql, create_table_sql, delete_to_sql, distinct_to_sql,
+        exists_to_sql, insert_to_sql, rowids_to_sql, to_sql, update_rowid_to_sql, update_to_sql,
+        Dialect, SqlParam, Sqlite, COUNTERS_TABLE, INCREMENT_COUNTER, MIGRATED_VERSION,
+        MIGRATIONS_TABLE, RECORD_MIGRATION,
+    },
+    store::{
+        distinct_values, receiver_stream, Data, DataStream, Persistence, Transaction,
+        STREAM_BATCH_SIZE,
+    },
+    update::Update,
+};
+
+/// Documents in SQLite, one table per collection with a `data` column of JSON
+/// text. rusqlite is blocking, so statements run on the blocking thread pool
+/// over a single shared connection.
+#[derive(Clone)]
+pub struct SqlitePersistence {
+    conn: Arc<tokio::sync::Mutex<rusqlite::Connection>>,
+    /// Tables known to exist, which are created on first use, as on Postgres.
+    tables: Arc<Mutex<HashSet<String>>>,
+    /// Set on the handles `begin` returns, which hold the connection until the
+    /// transaction is committed or rolled back.
+    transaction: Option<Arc<SqliteTransaction>>,
+}
+
+type SqliteConnection = tokio::sync::OwnedMutexGuard<rusqlite::Connection>;
+
+/// The connection an open transaction holds, taken out while a statement runs
+/// on it and once the transaction is finished.
+struct SqliteTransaction {
+    conn: tokio::sync::Mutex<Option<SqliteConnection>>,
+}
+
+impl Drop for SqliteTransaction {
+    fn drop(&mut self) {
+        if let Some(conn) = self.conn.get_mut() {
+            if !conn.is_autocommit() {
+                let _ = conn.execute_batch("ROLLBACK");
+            }
+        }
+    }
+}
+
+impl SqlitePersistence {
+    /// Opens the database at `path`; `:memory:` gives a private in-memory one.
+    pub fn new(path: &str) -> Result<Self> {
+        let conn = rusqlite::Connection::open(path)?;
+        Ok(Self {
+            conn: Arc::new(tokio::sync::Mutex::new(conn)),
+            tables: Arc::new(Mutex::new(HashSet::new())),
+            transaction: None,
+        })
+    }
+
+    /// Runs `f` on the connection, creating `table` first when it is used for
+    /// the first time.
+    async fn run_on<T, F>(&self, table: &str, f: F) -> Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut rusqlite::Connection) -> Result<T> + Send + 'static,
+    {
+        if self.tables.lock().unwrap().contains(table) {
+            return self.run(f).await;
+        }
+
+        let sql = create_table_sql(&Sqlite, table)?;
+        let result = self
+            .run(move |conn| {
+                conn.execute_batch(&sql)?;
+                f(conn)
+            })
+            .await?;
+        // A transaction's table is gone again if it rolls back.
+        if self.transaction.is_none() {
+            self.tables.lock().unwrap().insert(table.to_string());
+        }
+        Ok(result)
+    }
+
+    async fn run<T, F>(&self, f: F) -> Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut rusqlite::Connection) -> Result<T> + Send + 'static,
+    {
+        let Some(transaction) = &self.transaction else {
+            let mut conn = self.conn.clone().lock_owned().await;
+            return tokio::task::spawn_blocking(move || f(&mut conn)).await?;
+        };
+
+        let mut slot = transaction.conn.lock().await;
+        let mut conn = slot
+            .take()
+            .ok_or_else(|| StoreError::backend("transaction already finished"))?;
+        let (conn, result) = tokio::task::spawn_blocking(move || {
+            let result = f(&mut conn);
+            (conn, result)
+        })
+        .await?;
+        *slot = Some(conn);
+        result
+    }
+
+    async fn finish(&self, statement: &'static str) -> Result<()> {
+        let transaction = self
+            .transaction
+            .as_ref()
+            .ok_or_else(|| StoreError::backend("no transaction in progress"))?;
+        let conn = transaction
+            .conn
+            .lock()
+            .await
+            .take()
+            .ok_or_else(|| StoreError::backend("transaction already finished"))?;
+        tokio::task::spawn_blocking(move || conn.execute_batch(statement)).await??;
+        Ok(())
+    }
+}
+
+fn sqlite_params(params: &[SqlParam]) -> Result<Vec<String>> {
+    params
+        .iter()
+        .map(|param| match param {
+            SqlParam::Json(value) => Ok(value.to_string()),
+            SqlParam::Text(text) => Ok(text.clone()),
+            SqlParam::Path(_) => Err(StoreError::backend("SQLite does not bind path arrays")),
+        })
+        .collect()
+}
+
+fn sqlite_select(conn: &rusqlite::Connection, sql: &str, params: &[SqlParam]) -> Result<Vec<Data>> {
+    let mut stmt = conn.prepare(sql)?;
+    let mut rows = stmt.query(rusqlite::params_from_iter(sqlite_params(params)?))?;
+    let mut new: Vec<Data> = vec![];
+    while let Some(row) = rows.next()? {
+        let data: String = row.get(0)?;
+        new.push(serde_json::from_str(&data)?);
+    }
+    Ok(new)
+}
+
+/// Reads the rows of a query into `sender` one at a time, blocking while the
+/// channel is full.
+fn sqlite_stream(
+    conn: &rusqlite::Connection,
+    sql: &str,
+    params: &[SqlParam],
+    sender: &tokio::sync::mpsc::Sender<Result<Data>>,
+) -> Result<()> {
+    let mut stmt = conn.prepare(sql)?;
+    let mut rows = stmt.query(rusqlite::params_from_iter(sqlite_params(params)?))?;
+    while let Some(row) = rows.next()? {
+        let data: String = row.get(0)?;
+        if sender
+            .blocking_send(Ok(serde_json::from_str(&data)?))
+            .is_err()
+        {
+            break;
+        }
+    }
+    Ok(())
+}
+
+fn sqlite_execute(conn: &rusqlite::Connection, sql: &str, params: &[SqlParam]) -> Result<u64> {
+    let params = rusqlite::params_from_iter(sqlite_params(params)?);
+    Ok(conn.execute(sql, params)? as u64)
+}
+
+#[async_trait]
+impl Persistence for SqlitePersistence {
+    async fn find(&self, table: &str, query: Option<Query>) -> Result<Vec<Data>> {
+        let (sql, params) = to_sql(&Sqlite, table, &query)?;
+        self.run_on(table, move |conn| sqlite_select(conn, &sql, &params))
+            .await
+    }
+
+    async fn find_one(&self, table: &str, query: Option<Query>) -> Result<Option<Data>> {
+        let mut query = query.unwrap_or(Query {
+            filter: None,
+            sort: None,
+            limit: None,
+            after: None,
+            projection: None,
+        });
+        query.limit = Some(QueryLimit {
+            limit: Some(1),
+            offset: query.limit.and_then(|limit| limit.offset),
+        });
+        let (sql, params) = to_sql(&Sqlite, table, &Some(query))?;
+        let mut data = self
+            .run_on(table, move |conn| sqlite_select(conn, &sql, &params))
+            .await?;
+        Ok(data.pop())
+    }
+
+    async fn count(&self, table: &str, query: Option<Query>) -> Result<u64> {
+        let (sql, params) = count_to_sql(&Sqlite, table, &query)?;
+        self.run_on(table, move |conn| {
+            let params = rusqlite::params_from_iter(sqlite_params(&params)?);
+            Ok(conn.query_row(&sql, params, |row| row.get(0))?)
+        })
+        .await
+    }
+
+    async fn exists(&self, table: &str, query: Option<Query>) -> Result<bool> {
+        let (sql, params) = exists_to_sql(&Sqlite, table, &query)?;
+        self.run_on(table, move |conn| {
+            let params = rusqlite::params_from_iter(sqlite_params(&params)?);
+            Ok(conn.query_row(&sql, params, |row| row.get(0))?)
+        })
+        .await
+    }
+
+    async fn distinct(&self, table: &str, field: &str, query: Option<Query>) -> Result<Vec<Value>> {
+        let (sql, params) = distinct_to_sql(&Sqlite, table, field, &query)?;
+        let values = self
+            .run_on(table, move |conn| sqlite_select(conn, &sql, &params))
+            .await?;
+        Ok(distinct_values(values))
+    }
+
+    async fn aggregate(&self, table: &str, aggregation: Aggregation) -> Result<Vec<Value>> {
+        let (sql, params) = aggregate_to_sql(&Sqlite, table, &aggregation)?;
+        self.run_on(table, move |conn| sqlite_select(conn, &sql, &params))
+            .await
+    }
+
+    async fn stream(&self, table: &str, query: Option<Query>) -> Result<DataStream> {
+        let (sql, params) = to_sql(&Sqlite, table, &query)?;
+        self.run_on(table, |_| Ok(())).await?;
+        let (sender, receiver) = tokio::sync::mpsc::channel(STREAM_BATCH_SIZE);
+
+        let Some(transaction) = &self.transaction else {
+            let conn = self.conn.clone().lock_owned().await;
+            tokio::task::spawn_blocking(move || {
+                if let Err(err) = sqlite_stream(&conn, &sql, &params, &sender) {
+                    let _ = sender.blocking_send(Err(err));
+                }
+            });
+            return Ok(receiver_stream(receiver));
+        };
+
+        // As on Postgres, return once the task holds the transaction's
+        // connection.
+        let transaction = transaction.clone();
+        let (ready, locked) = tokio::sync::oneshot::channel();
+        tokio::task::spawn_blocking(move || {
+            let conn = transaction.conn.blocking_lock();
+            let Some(conn) = conn.as_ref() else {
+                let _ = ready.send(Err(StoreError::backend("transaction already finished")));
+                return;
+            };
+            let _ = ready.send(Ok(()));
+            if let Err(err) = sqlite_stream(conn, &sql, &params, &sender) {
+                let _ = sender.blocking_send(Err(err));
+            }
+        });
+        locked
+            .await
+            .map_err(|_| StoreError::backend("query task ended before it started"))??;
+        Ok(receiver_stream(receiver))
+    }
+
+    async fn insert(&self, table: &str, record: Data) -> Result<Data> {
+        let (sql, params) = insert_to_sql(&Sqlite, table, &record)?;
+        let mut data = self
+            .run_on(table, move |conn| sqlite_select(conn, &sql, &params))
+            .await?;
+        data.pop()
+            .ok_or_else(|| StoreError::backend(format!("insert into {} returned no row", table)))
+    }
+
+    async fn update(&self, table: &str, query: Query, record: Data) -> Result<u64> {
+        let (sql, params) = update_to_sql(&Sqlite, table, &query, &record)?;
+        self.run_on(table, move |conn| sqlite_execute(conn, &sql, &params))
+            .await
+    }
+
+    /// Reads the matching rows and applies the update to them in memory. The
+    /// connection is held from the read to the last write, so no other update
+    /// can slip in between.
+    async fn patch(&self, table: &str, query: Query, update: Update) -> Result<u64> {
+        update.check()?;
+        let (select_sql, params) = rowids_to_sql(table, &query)?;
+        let update_sql = update_rowid_to_sql(table)?;
+        self.run_on(table, move |conn| {
+            let tx = conn.savepoint()?;
+            let mut rows = vec![];
+            {
+                let mut stmt = tx.prepare(&select_sql)?;
+                let mut selected =
+                    stmt.query(rusqlite::params_from_iter(sqlite_params(&params)?))?;
+                while let Some(row) = selected.next()? {
+                    let data: String = row.get(1)?;
+                    rows.push((row.get::<_, i64>(0)?, serde_json::from_str::<Data>(&data)?));
+                }
+            }
+
+            let mut modified = 0;
+            for (rowid, mut data) in rows {
+                if update.apply(&mut data)? {
+                    tx.execute(&update_sql, rusqlite::params![data.to_string(), rowid])?;
+                    modified += 1;
+                }
+            }
+            tx.commit()?;
+            Ok(modified)
+        })
+        .await
+    }
+
+    async fn upsert(&self, table: &str, query: Query, record: Data) -> Result<Data> {
+        let (update_sql, update_params) = update_to_sql(&Sqlite, table, &query, &record)?;
+        let (insert_sql, insert_params) = insert_to_sql(&Sqlite, table, &record)?;
+        self.run_on(table, move |conn| {
+            // a savepoint also nests inside a transaction started by `begin`
+            let tx = conn.savepoint()?;
+            let updated = sqlite_execute(&tx, &update_sql, &update_params)?;
+
+            let data = if updated == 0 {
+                sqlite_select(&tx, &insert_sql, &insert_params)?
+                    .pop()
+                    .unwrap_or(record)
+            } else {
+                record
+            };
+
+            tx.commit()?;
+            Ok(data)
+        })
+        .await
+    }
+
+    async fn insert_if_absent(
+        &self,
+        table: &str,
+        query: Query,
+        record: Data,
+    ) -> Result<Option<Data>> {
+        let (exists_sql, exists_params) = exists_to_sql(&Sqlite, table, &Some(query))?;
+        let (insert_sql, insert_params) = insert_to_sql(&Sqlite, table, &record)?;
+        self.run_on(table, move |conn| {
+            let params = rusqlite::params_from_iter(sqlite_params(&exists_params)?);
+            if conn.query_row(&exists_sql, params, |row| row.get(0))? {
+                return Ok(None);
+            }
+            Ok(sqlite_select(conn, &insert_sql, &insert_params)?.pop())
+        })
+        .await
+    }
+
+    async fn delete(&self, table: &str, query: Query) -> Result<u64> {
+        let (sql, params) = delete_to_sql(&Sqlite, table, &query)?;
+        self.run_on(table, move |conn| sqlite_execute(conn, &sql, &params))
+            .await
+    }
+
+    async fn increment_counter(&self, collection: &str) -> Result<i64> {
+        let collection = collection.to_string();
+        self.run(move |conn| {
+            conn.execute(COUNTERS_TABLE, [])?;
+            let sql = Sqlite.placeholders(INCREMENT_COUNTER);
+            Ok(conn.query_row(&sql, [collection], |row| row.get(0))?)
+        })
+        .await
+    }
+
+    async fn migrated_version(&self, collection: &str) -> Result<u32> {
+        let collection = collection.to_string();
+        self.run(move |conn| {
+            conn.execute(MIGRATIONS_TABLE, [])?;
+            let sql = Sqlite.placeholders(MIGRATED_VERSION);
+            let version = conn
+                .query_row(&sql, [collection], |row| row.get(0))
+                .optional()?;
+            Ok(version.unwrap_or(0))
+        })
+        .await
+    }
+
+    async fn record_migration(&self, collection: &str, version: u32) -> Result<()> {
+        let collection = collection.to_string();
+        self.run(move |conn| {
+            conn.execute(MIGRATIONS_TABLE, [])?;
+            let sql = Sqlite.placeholders(RECORD_MIGRATION);
+            conn.execute(&sql, rusqlite::params![collection, version])?;
+            Ok(())
+        })
+        .await
+    }
+
+    /// Creates the table only: queries bind field paths as parameters, and
+    /// SQLite's planner can't match those to an expression index.
+    async fn ensure_collection(&self, collection: &str, _indexes: &[Index]) -> Result<()> {
+        self.run_on(collection, |_| Ok(())).await
+    }
+
+    async fn begin(&self) -> Result<Arc<dyn Transaction>> {
+        if self.transaction.is_some() {
+            return Err(StoreError::UnsupportedOperator(
+                "nested transactions are not supported".to_string(),
+            ));
+        }
+
+        let conn = self.conn.clone().lock_owned().await;
+        let conn = tokio::task::spawn_blocking(move || {
+            conn.execute_batch("BEGIN")?;
+            Ok::<_, StoreError>(conn)
+        })
+        .await??;
+        Ok(Arc::new(Self {
+            conn: self.conn.clone(),
+            tables: self.tables.clone(),
+            transaction: Some(Arc::new(SqliteTransaction {
+                conn: tokio::sync::Mutex::new(Some(conn)),
+            })),
+        }))
+    }
+}
+
+#[async_trait]
+impl Transaction for SqlitePersistence {
+    async fn commit(&self) -> Result<()> {
+        self.finish("COMMIT").await
+    }
+
+    async fn rollback(&self) -> Result<()> {
+        self.finish("ROLLBACK").await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::{
+        conformance,
+        store::{
+            tests::{assert_migrations, assert_transactions, Contact},
+            Store,
+        },
+    };
+    #[tokio::test]
+    async fn test_conformance_with_sqlite() -> anyhow::Result<()> {
+        let persistence = SqlitePersistence::new(":memory:")?;
+        conformance::check(&persistence, "conformance").await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_transactions_with_sqlite() -> anyhow::Result<()> {
+        let persistence = SqlitePersistence::new(":memory:")?;
+        assert_transactions(&Store::new(persistence.clone()), false).await?;
+
+        let tx = persistence.begin().await?;
+        tx.commit().await?;
+        assert!(tx.find("stock", None).await.is_err());
+        assert!(tx.commit().await.is_err());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_migrations_with_sqlite() -> anyhow::Result<()> {
+        let persistence = SqlitePersistence::new(":memory:")?;
+        let store = Store::new(persistence.clone());
+        assert_migrations(&store).await?;
+
+        // the recorded version makes later runs return right away
+        assert_eq!(persistence.migrated_version("contacts").await?, 3);
+        persistence
+            .insert(
+                "contacts",
+                json!({"id": "6", "name": "Fay", "email": "F@X"}),
+            )
+            .await?;
+        assert_eq!(store.migrate::<Contact>().await?, 0);
+        persistence.record_migration("contacts", 2).await?;
+        assert_eq!(persistence.migrated_version("contacts").await?, 3);
+
+        Ok(())
+    }
+}
@@ -1,260 +1,896 @@
 use std::{
+    any::TypeId,
     collections::HashMap,
+    fmt,
+    future::Future,
     sync::{Arc, Mutex},
 };
 
 use async_trait::async_trait;
-use bb8_postgres::tokio_postgres::NoTls;
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use futures::{stream::BoxStream, Stream, StreamExt};
 use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use serde_json::Value;
 
 use crate::{
+    aggregate::Aggregation,
+    error::{Result, StoreError},
+    id::IdStrategy,
     identity::Identity,
-    query::{Query, QueryLimit},
-    sql::to_sql,
+    index::Index,
+    migration::{Migrations, SCHEMA_VERSION_KEY},
+    query::{
+        compare_sort_values, json_eq, resolve_path, Query, QueryLimit, QuerySortDirection,
+        QuerySortItem,
+    },
+    schema::Schema,
+    update::{Update, UpdateOperation},
 };
 
-type Data = Value;
+pub use crate::{
+    file::FilePersistence, memory::TestPersistence, postgres::PostgresPersistence,
+    sqlite::SqlitePersistence,
+};
+
+pub(crate) type Data = Value;
+
+/// Documents as a backend reads them, see [`Persistence::stream`].
+pub type DataStream = BoxStream<'static, Result<Data>>;
+
+/// How many documents a stream reads ahead of its consumer, and how many rows
+/// a Postgres portal fetches per round trip.
+pub(crate) const STREAM_BATCH_SIZE: usize = 256;
 
+/// How many outdated documents `Store::migrate` reads at a time.
+const MIGRATION_BATCH_SIZE: u32 = 256;
+
+/// A document backend. Methods take `&self` so a single instance can serve
+/// concurrent callers; implementations synchronize internally.
 #[async_trait]
-pub trait Persistence {
-    async fn find(&mut self, collection: &str, query: Option<Query>) -> anyhow::Result<Vec<Data>>;
+pub trait Persistence: Send + Sync {
+    async fn find(&self, collection: &str, query: Option<Query>) -> Result<Vec<Data>>;
+
+    async fn find_one(&self, collection: &str, query: Option<Query>) -> Result<Option<Data>>;
+
+    /// Counts the documents matching the query. Sort and limit are ignored.
+    async fn count(&self, collection: &str, query: Option<Query>) -> Result<u64>;
 
-    async fn find_one(
-        &mut self,
+    /// Whether any document matches the query.
+    async fn exists(&self, collection: &str, query: Option<Query>) -> Result<bool>;
+
+    /// The distinct values of `field` in the documents matching the query, in
+    /// the order sorting by them gives. Documents without the field are left
+    /// out, and numbers that are equal in value count once.
+    async fn distinct(
+        &self,
         collection: &str,
+        field: &str,
         query: Option<Query>,
-    ) -> anyhow::Result<Option<Data>>;
+    ) -> Result<Vec<Value>>;
+
+    /// Runs an aggregation over the collection, returning its rows.
+    async fn aggregate(&self, collection: &str, aggregation: Aggregation) -> Result<Vec<Value>>;
+
+    /// Like `find`, but yields the documents one at a time as they are read,
+    /// so memory stays bounded however many of them match. The stream holds
+    /// on to its connection until it ends or is dropped.
+    async fn stream(&self, collection: &str, query: Option<Query>) -> Result<DataStream>;
+
+    /// Stores a new document and returns it as persisted.
+    async fn insert(&self, collection: &str, record: Data) -> Result<Data>;
+
+    /// Replaces every document matching the query with `record`, returning the
+    /// number of documents replaced.
+    async fn update(&self, collection: &str, query: Query, record: Data) -> Result<u64>;
+
+    /// Applies a partial update to every document matching the query in place,
+    /// returning the number of documents it changed.
+    async fn patch(&self, collection: &str, query: Query, update: Update) -> Result<u64>;
+
+    /// Replaces the documents matching the query with `record`, or inserts it
+    /// when nothing matches. Upserts with the same query run one at a time,
+    /// so of several made at once with nothing matching, only the first
+    /// inserts.
+    async fn upsert(&self, collection: &str, query: Query, record: Data) -> Result<Data>;
+
+    /// Stores `record` as a new document unless one matches the query, and
+    /// returns it as persisted, or `None` when one did. Like `upsert`, calls
+    /// with the same query run one at a time, so only one of them inserts.
+    async fn insert_if_absent(
+        &self,
+        collection: &str,
+        query: Query,
+        record: Data,
+    ) -> Result<Option<Data>>;
+
+    /// Removes every document matching the query, returning how many were
+    /// removed.
+    async fn delete(&self, collection: &str, query: Query) -> Result<u64>;
+
+    /// Advances the collection's id counter and returns its new value, starting
+    /// at 1. Backs `IdStrategy::Sequential`.
+    async fn increment_counter(&self, collection: &str) -> Result<i64>;
+
+    /// Returns the next value of a database sequence. Backs
+    /// `IdStrategy::Sequence`, which only Postgres supports.
+    async fn next_sequence_value(&self, sequence: &str) -> Result<i64> {
+        Err(StoreError::UnsupportedOperator(format!(
+            "sequence {:?} requested, but this backend has no sequences",
+            sequence
+        )))
+    }
+
+    /// The schema version `Store::migrate` last brought every document of the
+    /// collection to, or 0. Backends that keep no record return 0, and the
+    /// migration then looks for outdated documents every time.
+    async fn migrated_version(&self, _collection: &str) -> Result<u32> {
+        Ok(0)
+    }
+
+    /// Records that every document of the collection is at `version`.
+    async fn record_migration(&self, _collection: &str, _version: u32) -> Result<()> {
+        Ok(())
+    }
+
+    /// Creates the collection's table, on backends with tables, and the given
+    /// indexes on it, leaving those that already exist alone.
+    async fn ensure_collection(&self, collection: &str, indexes: &[Index]) -> Result<()>;
+
+    /// Starts a transaction. Every operation on the returned handle is part of
+    /// it until it is committed or rolled back.
+    async fn begin(&self) -> Result<Arc<dyn Transaction>>;
+}
 
-    // fn create(&mut self, record: &Data) -> anyhow::Result<Data>;
+/// A `Persistence` whose operations all belong to one transaction. Id counters
+/// and sequences stay outside of it, like Postgres sequences do. Dropping a
+/// transaction that was neither committed nor rolled back rolls it back.
+#[async_trait]
+pub trait Transaction: Persistence {
+    async fn commit(&self) -> Result<()>;
+
+    async fn rollback(&self) -> Result<()>;
+}
+
+/// The error `Store::save` fails with when the stored document's version isn't
+/// the record's: it was saved by someone else since the record was read, or
+/// was already stored when the record was new. It reaches callers as
+/// [`StoreError::Conflict`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct VersionConflict {
+    pub collection: String,
+    pub id: Value,
+    /// The version the record was read at, 0 for a new record.
+    pub expected: i64,
+}
+
+impl fmt::Display for VersionConflict {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} {} is no longer at version {}",
+            self.collection, self.id, self.expected
+        )
+    }
+}
+
+impl std::error::Error for VersionConflict {}
+
+/// One page of records from [`Store::page`].
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Page<T> {
+    pub items: Vec<T>,
+    /// The token that continues with the next page, `None` on the last one.
+    pub next_cursor: Option<String>,
+}
+
+/// What a page token holds: the sort it continues and the sort key values of
+/// the last record of its page. Tokens are base64 encoded JSON, and callers
+/// should treat them as opaque.
+#[derive(Serialize, Deserialize)]
+struct Cursor {
+    sort: Vec<QuerySortItem>,
+    after: Vec<Value>,
+}
 
-    // fn update(&mut self, record: &Data) -> anyhow::Result<Data>;
+impl Cursor {
+    fn encode(sort: &[QuerySortItem], last: &Data) -> Result<String> {
+        let after = sort
+            .iter()
+            .map(|item| {
+                let value = resolve_path(last, &item.field).first().copied();
+                value.cloned().unwrap_or(Value::Null)
+            })
+            .collect();
+        let cursor = Cursor {
+            sort: sort.to_vec(),
+            after,
+        };
+        Ok(URL_SAFE_NO_PAD.encode(serde_json::to_vec(&cursor)?))
+    }
 
-    // fn load(&mut self, id: Value) -> anyhow::Result<Option<Data>>;
+    /// Decodes a token, returning the values to continue after.
+    fn decode(token: &str, sort: &[QuerySortItem]) -> Result<Vec<Value>> {
+        let cursor: Cursor = URL_SAFE_NO_PAD
+            .decode(token)
+            .ok()
+            .and_then(|json| serde_json::from_slice(&json).ok())
+            .ok_or_else(|| StoreError::InvalidQuery("invalid cursor".to_string()))?;
+        if cursor.sort != sort {
+            return Err(StoreError::InvalidQuery(
+                "cursor was issued for a different sort".to_string(),
+            ));
+        }
+        Ok(cursor.after)
+    }
 }
 
+/// Typed entry point over a [`Persistence`]. Cloning is cheap and clones share
+/// the same backend, so a `Store` can be handed to as many tasks as needed.
 #[derive(Clone)]
 pub struct Store {
-    persistence: Arc<Mutex<dyn Persistence + Sync>>,
+    persistence: Arc<dyn Persistence>,
+    /// The compiled schemas of the types written so far, or `None` for those
+    /// without one. Types that share a collection can declare different
+    /// schemas, so this isn't keyed by the collection's name.
+    schemas: Arc<Mutex<HashMap<TypeId, Option<Arc<Schema>>>>>,
 }
 
-#[allow(dead_code)]
 impl Store {
-    fn new(persistence: impl Persistence + Sync + 'static) -> Self {
+    /// A store over one of the backends, such as `SqlitePersistence`.
+    pub fn new(persistence: impl Persistence + 'static) -> Self {
         Self {
-            persistence: Arc::new(Mutex::new(persistence)),
+            persistence: Arc::new(persistence),
+            schemas: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// The collection's schema, see `Collection::schema`, compiled on first
+    /// use.
+    fn schema<T>(&self) -> Result<Option<Arc<Schema>>>
+    where
+        T: Collection + 'static,
+    {
+        let key = TypeId::of::<T>();
+        if let Some(schema) = self.schemas.lock().unwrap().get(&key) {
+            return Ok(schema.clone());
+        }
+        let schema = T::schema().map(Schema::new).transpose()?.map(Arc::new);
+        self.schemas.lock().unwrap().insert(key, schema.clone());
+        Ok(schema)
+    }
+
+    /// Checks a document about to be written against the collection's
+    /// schema, when it has one.
+    fn validate<T>(&self, data: &Value) -> Result<()>
+    where
+        T: Collection + 'static,
+    {
+        match self.schema::<T>()? {
+            Some(schema) => schema.validate(data),
+            None => Ok(()),
         }
     }
 
-    pub async fn get<T>(&mut self, id: Value) -> anyhow::Result<Option<T>>
+    /// Checks a query, such as one parsed from a request, against the
+    /// collection's schema, see [`Schema::validate_query`]. Any query passes
+    /// for a collection without a schema.
+    pub fn validate_query<T>(&self, query: &Query) -> Result<()>
+    where
+        T: Collection + 'static,
+    {
+        match self.schema::<T>()? {
+            Some(schema) => schema.validate_query(query),
+            None => Ok(()),
+        }
+    }
+
+    /// Creates the collection's table and the indexes its `Collection` impl
+    /// declares, unless they exist. Postgres creates tables on first use on
+    /// its own, but not indexes, so run this when starting up.
+    pub async fn ensure_collection<T>(&self) -> Result<()>
+    where
+        T: Collection,
+    {
+        let collection = T::name();
+        self.persistence
+            .ensure_collection(&collection, &T::indexes())
+            .await
+    }
+
+    pub async fn get<T>(&self, id: Value) -> Result<Option<T>>
     where
         T: DeserializeOwned + Collection + Identity,
     {
         let collection = T::name();
         let query = T::identity_query(id);
 
-        let mut persistence = self.persistence.lock().unwrap();
-        let data = persistence.find_one(&collection, Some(query)).await?;
+        let data = self.persistence.find_one(&collection, Some(query)).await?;
         match data {
-            Some(data) => Ok(Some(serde_json::from_value(data)?)),
+            Some(data) => Ok(Some(from_document(&T::migrations(), data)?)),
             None => Ok(None),
         }
     }
 
-    pub async fn find<T>(&mut self, query: Option<Query>) -> anyhow::Result<Vec<T>>
+    pub async fn find<T>(&self, query: Option<Query>) -> Result<Vec<T>>
     where
         T: DeserializeOwned + Collection,
     {
-        let mut persistence = self.persistence.lock().unwrap();
         let collection = T::name();
-        let values = persistence.find(&collection, query).await?;
+        let migrations = T::migrations();
+        let query = query.map(|query| keep_schema_version(&migrations, query));
+        let values = self.persistence.find(&collection, query).await?;
 
         let mut new: Vec<T> = vec![];
         for v in values.into_iter() {
-            new.push(serde_json::from_value(v)?);
+            new.push(from_document(&migrations, v)?);
         }
         Ok(new)
     }
 
-    pub async fn find_one<T>(&mut self, query: Option<Query>) -> anyhow::Result<Option<T>>
+    pub async fn find_one<T>(&self, query: Option<Query>) -> Result<Option<T>>
     where
         T: DeserializeOwned + Collection,
     {
-        let mut persistence = self.persistence.lock().unwrap();
         let collection = T::name();
-        let value = persistence.find_one(&collection, query).await?;
+        let migrations = T::migrations();
+        let query = query.map(|query| keep_schema_version(&migrations, query));
+        let value = self.persistence.find_one(&collection, query).await?;
         match value {
-            Some(value) => Ok(Some(serde_json::from_value(value)?)),
+            Some(value) => Ok(Some(from_document(&migrations, value)?)),
             None => Ok(None),
         }
     }
-}
 
-struct TestPersistence {
-    records: HashMap<String, Vec<Data>>,
-}
+    /// Counts the records matching the query, ignoring its sort and limit.
+    pub async fn count<T>(&self, query: Option<Query>) -> Result<u64>
+    where
+        T: Collection,
+    {
+        let collection = T::name();
+        self.persistence.count(&collection, query).await
+    }
 
-#[async_trait]
-impl Persistence for TestPersistence {
-    async fn find(&mut self, collection: &str, query: Option<Query>) -> anyhow::Result<Vec<Data>> {
-        let records = self.records.get(collection).unwrap();
-        match query {
-            Some(query) => {
-                let mut new: Vec<Data> = vec![];
-                for record in records.into_iter() {
-                    if query.matches(record)? {
-                        new.push(record.clone());
-                    }
-                }
-                Ok(new)
-            }
-            None => Ok(records.clone()),
+    /// Whether any record matches the query.
+    pub async fn exists<T>(&self, query: Option<Query>) -> Result<bool>
+    where
+        T: Collection,
+    {
+        let collection = T::name();
+        self.persistence.exists(&collection, query).await
+    }
+
+    /// The distinct values of a field, possibly a dotted path, among the
+    /// records matching the query; see [`Persistence::distinct`].
+    pub async fn distinct<T>(&self, field: &str, query: Option<Query>) -> Result<Vec<Value>>
+    where
+        T: Collection,
+    {
+        let collection = T::name();
+        self.persistence.distinct(&collection, field, query).await
+    }
+
+    /// Runs an aggregation over the collection, deserializing each of its
+    /// rows into an `R`.
+    pub async fn aggregate<T, R>(&self, aggregation: Aggregation) -> Result<Vec<R>>
+    where
+        T: Collection,
+        R: DeserializeOwned,
+    {
+        let collection = T::name();
+        let rows = self.persistence.aggregate(&collection, aggregation).await?;
+
+        let mut new: Vec<R> = vec![];
+        for row in rows.into_iter() {
+            new.push(serde_json::from_value(row)?);
         }
+        Ok(new)
     }
 
-    async fn find_one(
-        &mut self,
-        collection: &str,
+    /// Streams the records matching the query, deserializing each one as it
+    /// is read. Use it over `find` for result sets too large to hold in memory.
+    ///
+    /// The stream keeps a connection busy until it ends or is dropped. Within a
+    /// transaction that is the transaction's connection, so finish or drop the
+    /// stream before running anything else on it.
+    pub async fn stream<T>(&self, query: Option<Query>) -> Result<impl Stream<Item = Result<T>>>
+    where
+        T: DeserializeOwned + Collection,
+    {
+        let collection = T::name();
+        let migrations = T::migrations();
+        let query = query.map(|query| keep_schema_version(&migrations, query));
+        let stream = self.persistence.stream(&collection, query).await?;
+        Ok(stream.map(move |data| from_document(&migrations, data?)))
+    }
+
+    /// Returns a page of up to `size` records matching the query, continuing
+    /// after the page `cursor` came with, or from the start without one.
+    ///
+    /// Pages are keyset paginated: the query's sort is completed with the
+    /// identity key as a tie breaker and each page picks up after the sort key
+    /// values of the previous one's last record. Unlike an offset, that stays
+    /// fast on large collections and neither skips nor repeats records when
+    /// others are written between pages. The query can't set a limit, and a
+    /// projection is widened to keep the sort keys the cursor is made from.
+    pub async fn page<T>(
+        &self,
         query: Option<Query>,
-    ) -> anyhow::Result<Option<Data>> {
-        let records = self.records.get(collection).unwrap().clone();
+        size: u32,
+        cursor: Option<&str>,
+    ) -> Result<Page<T>>
+    where
+        T: DeserializeOwned + Collection + Identity,
+    {
+        if size == 0 {
+            return Err(StoreError::InvalidQuery(
+                "page size must be at least 1".to_string(),
+            ));
+        }
+        let mut query = query.unwrap_or_else(|| Query::builder().build());
+        if query.limit.is_some() || query.after.is_some() {
+            return Err(StoreError::InvalidQuery(
+                "a paginated query can't set a limit, an offset or 'after'".to_string(),
+            ));
+        }
+
+        let key = T::identity_key();
+        let mut sort = query.sort.take().unwrap_or_default();
+        if !sort.iter().any(|item| item.field == key) {
+            sort.push(QuerySortItem {
+                field: key.to_string(),
+                direction: QuerySortDirection::Ascending,
+            });
+        }
+        if let Some(cursor) = cursor {
+            query.after = Some(Cursor::decode(cursor, &sort)?);
+        }
+        if let Some(projection) = &query.projection {
+            let fields: Vec<&str> = sort.iter().map(|item| item.field.as_str()).collect();
+            query.projection = Some(projection.keeping(&fields));
+        }
+        let migrations = T::migrations();
+        let mut query = keep_schema_version(&migrations, query);
+        query.sort = Some(sort);
+        // one more than asked for tells whether there is a next page
+        query.limit = Some(QueryLimit {
+            limit: Some(size + 1),
+            offset: None,
+        });
 
-        let Some(query) = query else {
-            return Ok(records.get(0).cloned());
+        let collection = T::name();
+        let mut values = self
+            .persistence
+            .find(&collection, Some(query.clone()))
+            .await?;
+        let mut next_cursor = None;
+        if values.len() > size as usize {
+            values.truncate(size as usize);
+            let sort = query.sort.as_deref().unwrap_or_default();
+            next_cursor = Some(Cursor::encode(sort, values.last().unwrap())?);
+        }
+
+        let mut items: Vec<T> = vec![];
+        for v in values.into_iter() {
+            items.push(from_document(&migrations, v)?);
+        }
+        Ok(Page { items, next_cursor })
+    }
+
+    /// Inserts the record as a new document. When it has no id, one is
+    /// generated with the collection's `IdStrategy` and written back into the
+    /// returned record.
+    pub async fn insert<T>(&self, record: &T) -> Result<T>
+    where
+        T: Serialize + DeserializeOwned + Collection + Identity + 'static,
+    {
+        let collection = T::name();
+        let mut data = serde_json::to_value(record)?;
+        self.assign_id::<T>(record.key(), &mut data).await?;
+        if let Some(key) = T::version_key() {
+            set_version::<T>(key, &mut data, 1)?;
+        }
+        self.validate::<T>(&data)?;
+        T::migrations().stamp(&mut data)?;
+
+        let data = self.persistence.insert(&collection, data).await?;
+        Ok(serde_json::from_value(data)?)
+    }
+
+    /// Inserts the record, or replaces the stored document with the same id. A
+    /// record without an id is always inserted, with a generated id.
+    ///
+    /// For a versioned collection, see `Identity::version_key`, the stored
+    /// document is only replaced while it is at the record's version, which is
+    /// then bumped; otherwise the save fails with [`VersionConflict`]. A new
+    /// record, whose version is missing, `null` or 0, is inserted at version 1.
+    ///
+    /// Saves of the same id made at once never store it twice: without a
+    /// version the last of them wins, and with one all but the first fail.
+    pub async fn save<T>(&self, record: &T) -> Result<T>
+    where
+        T: Serialize + DeserializeOwned + Collection + Identity + 'static,
+    {
+        let collection = T::name();
+        let mut data = serde_json::to_value(record)?;
+        let generated = self.assign_id::<T>(record.key(), &mut data).await?;
+        let version = match T::version_key() {
+            None => None,
+            Some(key) => {
+                let expected = match data.get(key) {
+                    None | Some(Value::Null) => 0,
+                    Some(version) => version.as_i64().ok_or_else(|| {
+                        StoreError::document(format!(
+                            "the {} of a {} record is not an integer",
+                            key, collection
+                        ))
+                    })?,
+                };
+                set_version::<T>(key, &mut data, expected + 1)?;
+                Some((key, expected))
+            }
+        };
+        self.validate::<T>(&data)?;
+        T::migrations().stamp(&mut data)?;
+        let Some((key, expected)) = version else {
+            let data = match generated {
+                true => self.persistence.insert(&collection, data).await?,
+                false => {
+                    let query = T::identity_query(record.id()?);
+                    self.persistence.upsert(&collection, query, data).await?
+                }
+            };
+            return Ok(serde_json::from_value(data)?);
+        };
+
+        if generated {
+            let data = self.persistence.insert(&collection, data).await?;
+            return Ok(serde_json::from_value(data)?);
+        }
+
+        // documents stored before the collection was versioned have none
+        let id = record.id()?;
+        let query = match expected {
+            0 => Query::builder()
+                .and(|_| T::identity_query(id.clone()))
+                .and(|mut query| {
+                    query
+                        .not_exists(key)
+                        .or(|mut query| query.is_in(key, vec![Value::Null, 0.into()]).build())
+                        .build()
+                })
+                .build(),
+            _ => Query::builder()
+                .and(|_| T::identity_query(id.clone()))
+                .eq(key, expected.into())
+                .build(),
+        };
+        let updated = self
+            .persistence
+            .update(&collection, query, data.clone())
+            .await?;
+        if updated > 0 {
+            return Ok(serde_json::from_value(data)?);
+        }
+
+        let inserted = match expected {
+            0 => {
+                let stored = T::identity_query(id.clone());
+                self.persistence
+                    .insert_if_absent(&collection, stored, data)
+                    .await?
+            }
+            _ => None,
+        };
+        match inserted {
+            Some(data) => Ok(serde_json::from_value(data)?),
+            None => Err(VersionConflict {
+                collection,
+                id,
+                expected,
+            }
+            .into()),
+        }
+    }
+
+    /// Sets a generated id at `key` when the document has none, returning
+    /// whether it did.
+    async fn assign_id<T>(&self, key: &str, data: &mut Value) -> Result<bool>
+    where
+        T: Collection,
+    {
+        if !crate::id::is_unset(data.get(key)) {
+            return Ok(false);
+        }
+        let collection = T::name();
+        let Some(id) = T::id_strategy()
+            .generate(self.persistence.as_ref(), &collection)
+            .await?
+        else {
+            return Ok(false);
         };
 
-        let records = self.records.get(collection).unwrap().clone();
-        for record in records.into_iter() {
-            if query.matches(&record)? {
-                return Ok(Some(record.clone()));
+        match data {
+            Value::Object(map) => {
+                map.insert(key.to_string(), id);
+                Ok(true)
             }
+            _ => Err(StoreError::document(format!(
+                "cannot assign an id to a {} record that is not an object",
+                collection
+            ))),
         }
+    }
 
-        Ok(None)
+    /// Applies a partial update to every record matching the query, returning
+    /// how many it changed. The backend applies it to each record as stored,
+    /// so concurrent updates such as `inc` don't overwrite one another.
+    ///
+    /// In a versioned collection the update also bumps the version of every
+    /// record it matches, so saves of copies read before it conflict.
+    ///
+    /// With a schema, the update is checked as far as it can be without the
+    /// records, see [`Schema::validate_update`].
+    pub async fn patch<T>(&self, query: Query, mut update: Update) -> Result<u64>
+    where
+        T: Collection + Identity + 'static,
+    {
+        let collection = T::name();
+        if let Some(schema) = self.schema::<T>()? {
+            schema.validate_update(&update)?;
+        }
+        if let Some(key) = T::version_key() {
+            update.operations.push(UpdateOperation::Inc {
+                field: key.to_string(),
+                value: 1.into(),
+            });
+        }
+        self.persistence.patch(&collection, query, update).await
     }
 
-    // async fn create<T>(&mut self, record: &T) -> anyhow::Result<T> {
-    //     println!("create");
-    //     Ok(record.clone())
-    // }
-    // async fn update<T>(&mut self, record: &T) -> anyhow::Result<T> {
-    //     println!("update");
-    //     Ok(record.clone())
-    // }
-    // async fn load<T>(&mut self, id: Value) -> anyhow::Result<Option<T>> {
-    //     println!("load");
-    //     Ok(None)
-    // }
-}
+    /// Deletes the record with the given id, returning whether it existed.
+    pub async fn delete<T>(&self, id: Value) -> Result<bool>
+    where
+        T: Collection + Identity,
+    {
+        let collection = T::name();
+        let query = T::identity_query(id);
 
-#[derive(Clone, Debug)]
-struct PostgresPersistence {
-    pool: bb8::Pool<bb8_postgres::PostgresConnectionManager<NoTls>>,
-}
+        let deleted = self.persistence.delete(&collection, query).await?;
+        Ok(deleted > 0)
+    }
 
-impl PostgresPersistence {
-    async fn new(conn_str: &str) -> anyhow::Result<Self> {
-        let manager =
-            bb8_postgres::PostgresConnectionManager::new_from_stringlike(conn_str, NoTls)?;
-        let pool = bb8::Pool::builder().build(manager).await?;
-        Ok(Self { pool })
+    /// Deletes every record matching the query, returning how many were removed.
+    pub async fn delete_many<T>(&self, query: Query) -> Result<u64>
+    where
+        T: Collection,
+    {
+        let collection = T::name();
+        self.persistence.delete(&collection, query).await
     }
-}
 
-#[async_trait]
-impl Persistence for PostgresPersistence {
-    async fn find(&mut self, table: &str, query: Option<Query>) -> anyhow::Result<Vec<Data>> {
-        let conn = self.pool.get().await?;
-
-        let (sql, params) = to_sql(table, &query)?;
-        let params: Vec<&(dyn tokio_postgres::types::ToSql + Sync)> =
-            params.iter().map(|v| v as _).collect();
-        let rows = conn.query(&sql, &params).await?;
-        let mut new: Vec<Data> = vec![];
-        for row in rows.into_iter() {
-            let data: Data = row.get(0);
-            new.push(data);
+    /// Upgrades the stored documents of the collection to the latest schema
+    /// version of its migrations, returning how many it rewrote. Once done
+    /// the version is recorded, where the backend keeps track, and later runs
+    /// return right away.
+    ///
+    /// Documents are read in batches and each is only written back while it
+    /// is still at the version it was read at, so running this alongside
+    /// other writers, or another run, loses no writes. The document version,
+    /// see `Identity::version_key`, isn't bumped: a copy read before holds the
+    /// same upgrade and saves over it without conflict.
+    pub async fn migrate<T>(&self) -> Result<u64>
+    where
+        T: Collection + Identity + 'static,
+    {
+        let collection = T::name();
+        let migrations = T::migrations();
+        let version = migrations.version();
+        if version == 0 || self.persistence.migrated_version(&collection).await? >= version {
+            return Ok(0);
         }
-        Ok(new)
+
+        let key = T::identity_key();
+        let mut migrated = 0;
+        loop {
+            let mut query = migrations.outdated();
+            query.limit = Some(QueryLimit {
+                limit: Some(MIGRATION_BATCH_SIZE),
+                offset: None,
+            });
+            let documents = self.persistence.find(&collection, Some(query)).await?;
+            if documents.is_empty() {
+                break;
+            }
+
+            for mut document in documents {
+                let id = match document.get(key) {
+                    None | Some(Value::Null) => {
+                        return Err(StoreError::document(format!(
+                            "a {} document without an id can't be migrated",
+                            collection
+                        )))
+                    }
+                    Some(id) => id.clone(),
+                };
+                let from = Migrations::document_version(&document)?;
+                migrations.upgrade(&mut document)?;
+                self.validate::<T>(&document)?;
+                let query = Query::builder()
+                    .and(|_| T::identity_query(id.clone()))
+                    .and(|_| Migrations::at_version(from))
+                    .build();
+                migrated += self
+                    .persistence
+                    .update(&collection, query, document)
+                    .await?;
+            }
+        }
+
+        self.persistence
+            .record_migration(&collection, version)
+            .await?;
+        Ok(migrated)
     }
 
-    async fn find_one(
-        &mut self,
-        table: &str,
-        query: Option<Query>,
-    ) -> anyhow::Result<Option<Data>> {
-        let conn = self.pool.get().await?;
-
-        let (sql, params) = match query {
-            Some(query) => {
-                let mut query = query.clone();
-                query.limit = Some(QueryLimit {
-                    limit: Some(1),
-                    offset: None,
-                });
-                to_sql(table, &Some(query))?
+    /// Runs `f` in a transaction, committing when it returns `Ok` and rolling
+    /// back when it returns `Err`.
+    ///
+    /// Only operations on the `Store` passed to `f` are part of the transaction.
+    /// Other handles run outside of it, and on SQLite wait until it finishes,
+    /// as writes to files do, so using them inside `f` deadlocks.
+    ///
+    /// `f` may fail with any error a [`StoreError`] converts into. If the
+    /// rollback fails too, the error from `f` is the one returned.
+    pub async fn transaction<F, Fut, R, E>(&self, f: F) -> Result<R, E>
+    where
+        F: FnOnce(Store) -> Fut,
+        Fut: Future<Output = Result<R, E>>,
+        E: From<StoreError>,
+    {
+        let tx = self.persistence.begin().await?;
+        let result = f(Store {
+            persistence: tx.clone(),
+            schemas: self.schemas.clone(),
+        })
+        .await;
+
+        match result {
+            Ok(value) => {
+                tx.commit().await?;
+                Ok(value)
             }
-            None => (format!("SELECT * FROM {} LIMIT 1", table), vec![]),
-        };
-        let params: Vec<&(dyn tokio_postgres::types::ToSql + Sync)> =
-            params.iter().map(|v| v as _).collect();
-        let row = conn.query_one(sql.as_str(), &params).await?;
-        let data: Data = row.get(0);
-        Ok(Some(data))
+            Err(err) => {
+                let _ = tx.rollback().await;
+                Err(err)
+            }
+        }
     }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-struct User {
-    id: String,
-    name: String,
+/// Upgrades a document read from the collection with its migrations, then
+/// deserializes it.
+fn from_document<T>(migrations: &Migrations, mut data: Value) -> Result<T>
+where
+    T: DeserializeOwned,
+{
+    migrations.upgrade(&mut data)?;
+    Ok(serde_json::from_value(data)?)
 }
 
-impl Identity for User {
-    fn identity_query(id: Value) -> Query {
-        Query::builder().eq("id", id).build()
-    }
-
-    fn identity(&self) -> Value {
-        todo!()
+/// Widens a query's projection to keep the schema version, which tells the
+/// migrations a projected document needs.
+fn keep_schema_version(migrations: &Migrations, mut query: Query) -> Query {
+    if let (Some(projection), true) = (&query.projection, migrations.version() > 0) {
+        query.projection = Some(projection.keeping(&[SCHEMA_VERSION_KEY]));
     }
+    query
+}
 
-    fn key(&self) -> &str {
-        todo!()
+/// Sets the version of a document of a versioned collection.
+fn set_version<T>(key: &str, data: &mut Value, version: i64) -> Result<()>
+where
+    T: Collection,
+{
+    match data {
+        Value::Object(map) => {
+            map.insert(key.to_string(), version.into());
+            Ok(())
+        }
+        _ => Err(StoreError::document(format!(
+            "cannot version a {} record that is not an object",
+            T::name()
+        ))),
     }
+}
 
-    fn id(&self) -> Value {
-        todo!()
+/// Deduplicates the values `Persistence::distinct` found and puts them in sort
+/// order, with ties broken by their JSON text so every backend agrees.
+pub(crate) fn distinct_values(values: impl IntoIterator<Item = Value>) -> Vec<Value> {
+    let mut distinct: Vec<Value> = vec![];
+    for value in values {
+        if !distinct.iter().any(|existing| json_eq(existing, &value)) {
+            distinct.push(value);
+        }
     }
+    distinct.sort_by(|a, b| {
+        compare_sort_values(Some(a), Some(b)).then_with(|| a.to_string().cmp(&b.to_string()))
+    });
+    distinct
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-struct Product {
-    id: String,
-    name: String,
+/// A stream over a channel that a backend task fills. Once the stream is
+/// dropped sending fails, which tells the task to stop reading.
+pub(crate) fn receiver_stream(receiver: tokio::sync::mpsc::Receiver<Result<Data>>) -> DataStream {
+    futures::stream::unfold(receiver, |mut receiver| async move {
+        receiver.recv().await.map(|item| (item, receiver))
+    })
+    .boxed()
 }
 
 pub trait Collection {
     fn name() -> String;
-}
 
-impl Collection for User {
-    fn name() -> String {
-        "users".to_string()
+    /// How ids are generated for records stored without one.
+    fn id_strategy() -> IdStrategy {
+        IdStrategy::Provided
+    }
+
+    /// The indexes `Store::ensure_collection` creates for the collection.
+    fn indexes() -> Vec<Index> {
+        vec![]
     }
-}
 
-impl Collection for Product {
-    fn name() -> String {
-        "products".to_string()
+    /// How documents stored under older versions of the type are upgraded,
+    /// see [`Migrations`].
+    fn migrations() -> Migrations {
+        Migrations::default()
+    }
+
+    /// The JSON Schema every document `Store` writes to the collection must
+    /// conform to, or `None` to write them unchecked. See [`Schema`], and
+    /// `schema::generate` for one generated from the type.
+    fn schema() -> Option<Value> {
+        None
     }
 }
 
+/// Derives `Collection` from `#[store(collection = "...")]`, or the type name in
+/// snake_case when it is omitted, `#[store(id_strategy = "...")]`, any number
+/// of `#[store(index(field = "...", kind = "..."))]`,
+/// `#[store(migrations = "path::to::fn")]` and `#[store(schema)]` or
+/// `#[store(schema = "path::to::fn")]`.
+pub use store_derive::Collection;
+
 #[cfg(test)]
-mod tests {
-    use std::{collections::HashMap, env};
+pub(crate) mod tests {
+    use std::collections::HashMap;
+
+    use futures::TryStreamExt;
+    use schemars::JsonSchema;
+    use serde_json::json;
 
     use super::*;
+    use crate::aggregate::tests::sales_docs;
+
+    #[derive(Debug, Serialize, Deserialize, Collection, Identity)]
+    #[store(crate = "crate", collection = "users")]
+    struct User {
+        id: String,
+        name: String,
+    }
+
+    #[derive(Debug, Serialize, Deserialize, Collection, Identity)]
+    #[store(crate = "crate", collection = "products")]
+    struct Product {
+        id: String,
+        name: String,
+    }
+
+    #[derive(Debug, Serialize, Deserialize, Collection, Identity)]
+    #[store(crate = "crate", collection = "sales")]
+    struct Sale {
+        id: i64,
+    }
 
     #[tokio::test]
     async fn test_identity() -> anyhow::Result<()> {
@@ -269,38 +905,687 @@ mod tests {
 
         let mut records = HashMap::new();
         records.insert("users".to_string(), vec![user1, user2]);
-        let persistence = TestPersistence { records };
-        let mut store = Store::new(persistence);
+        let persistence = TestPersistence::new(records);
+        let store = Store::new(persistence);
         let user = store.get::<User>(Value::String("456".to_string())).await?;
         assert_eq!(user.unwrap().name, "Jane");
 
         Ok(())
     }
 
+    #[derive(Debug, Serialize, Deserialize, Collection, Identity)]
+    #[serde(rename_all = "camelCase")]
+    #[store(crate = "crate")]
+    struct LineItem {
+        #[store(id)]
+        line_item_id: u64,
+        unit_price: f64,
+    }
+
+    #[derive(Debug, Serialize, Deserialize, Collection, Identity)]
+    #[store(crate = "crate", collection = "accounts")]
+    struct Account {
+        #[serde(rename = "_id")]
+        id: String,
+        owner: String,
+    }
+
+    /// An id JSON has no form for: object keys must be strings.
+    #[derive(Serialize, Identity)]
+    #[store(crate = "crate")]
+    struct Pair {
+        id: HashMap<(u8, u8), u8>,
+    }
+
     #[tokio::test]
-    #[ignore]
-    async fn test_identity_with_postgres() -> anyhow::Result<()> {
-        dotenv::dotenv().ok();
-
-        let persistence = PostgresPersistence::new(&env::var("DATABASE_URL")?).await?;
-        let pool = persistence.pool.clone();
-        let conn = pool.get().await?;
-        conn.execute("DROP TABLE IF EXISTS users", &[]).await?;
-        conn.execute("CREATE TABLE users (data JSONB)", &[]).await?;
-        conn.execute(
-            "INSERT INTO users VALUES ($1)",
-            &[&serde_json::json!({"id": "123", "name": "John"})],
-        )
-        .await?;
-        conn.execute(
-            "INSERT INTO users VALUES ($1)",
-            &[&serde_json::json!({"id": "456", "name": "Jane"})],
-        )
-        .await?;
+    async fn test_derived_identity() -> anyhow::Result<()> {
+        assert_eq!(LineItem::name(), "line_item");
+        assert_eq!(Account::name(), "accounts");
 
-        let mut store = Store::new(persistence.clone());
-        let user = store.get::<User>(Value::String("456".to_string())).await?;
-        assert_eq!(user.unwrap().name, "Jane");
+        let item = LineItem {
+            line_item_id: 7,
+            unit_price: 2.5,
+        };
+        assert_eq!(item.key(), "lineItemId");
+        assert_eq!(item.identity()?, json!({"lineItemId": 7}));
+        let pair = Pair {
+            id: HashMap::from([((1, 2), 3)]),
+        };
+        assert!(matches!(pair.id(), Err(StoreError::Deserialize(_))));
+
+        let store = Store::new(TestPersistence::new(HashMap::new()));
+        store.save(&item).await?;
+        store
+            .save(&Account {
+                id: "a1".to_string(),
+                owner: "John".to_string(),
+            })
+            .await?;
+        store
+            .save(&Account {
+                id: "a1".to_string(),
+                owner: "Jane".to_string(),
+            })
+            .await?;
+
+        let item = store.get::<LineItem>(json!(7)).await?.unwrap();
+        assert_eq!(item.unit_price, 2.5);
+        let accounts: Vec<Account> = store.find(None).await?;
+        assert_eq!(accounts.len(), 1);
+        assert_eq!(accounts[0].owner, "Jane");
+        let stored = store.persistence.find("accounts", None).await?;
+        assert_eq!(stored, vec![json!({"_id": "a1", "owner": "Jane"})]);
+
+        Ok(())
+    }
+
+    #[derive(Debug, Clone, Serialize, Deserialize, Collection, Identity)]
+    #[store(crate = "crate", collection = "documents")]
+    struct Document {
+        id: String,
+        body: String,
+        #[serde(rename = "_rev", default)]
+        #[store(version)]
+        revision: u64,
+    }
+
+    /// Saves documents from stale and fresh copies, and checks that only the
+    /// fresh ones are written.
+    #[tokio::test]
+    async fn test_versions() -> anyhow::Result<()> {
+        let store = &Store::new(TestPersistence::new(HashMap::new()));
+        assert_eq!(Document::version_key(), Some("_rev"));
+        assert_eq!(User::version_key(), None);
+
+        let draft = Document {
+            id: "d1".to_string(),
+            body: "draft".to_string(),
+            revision: 0,
+        };
+        let saved = store.save(&draft).await?;
+        assert_eq!(saved.revision, 1);
+        let conflict = store.save(&draft).await.unwrap_err();
+        assert!(matches!(
+            conflict,
+            StoreError::Conflict(VersionConflict { ref collection, ref id, expected: 0 })
+                if collection == "documents" && *id == json!("d1")
+        ));
+
+        // two copies read at the same version: the second save loses
+        let mut first = store.get::<Document>(json!("d1")).await?.unwrap();
+        let mut second = first.clone();
+        first.body = "first".to_string();
+        assert_eq!(store.save(&first).await?.revision, 2);
+        second.body = "second".to_string();
+        let conflict = store.save(&second).await.unwrap_err();
+        let StoreError::Conflict(conflict) = conflict else {
+            panic!("expected a version conflict, got {}", conflict);
+        };
+        assert_eq!(conflict.expected, 1);
+        let stored = store.get::<Document>(json!("d1")).await?.unwrap();
+        assert_eq!((stored.body.as_str(), stored.revision), ("first", 2));
+
+        // a partial update moves the version on too
+        let update = Update::builder().set("body", json!("patched")).build();
+        let query = Document::identity_query(json!("d1"));
+        assert_eq!(store.patch::<Document>(query, update).await?, 1);
+        assert!(store.save(&stored).await.is_err());
+        let stored = store.get::<Document>(json!("d1")).await?.unwrap();
+        assert_eq!((stored.body.as_str(), stored.revision), ("patched", 3));
+
+        let inserted = store
+            .insert(&Document {
+                id: "d2".to_string(),
+                body: "inserted".to_string(),
+                revision: 7,
+            })
+            .await?;
+        assert_eq!(inserted.revision, 1);
+        let deleted = Document {
+            id: "d3".to_string(),
+            body: "gone".to_string(),
+            revision: 4,
+        };
+        assert!(store.save(&deleted).await.is_err());
+
+        // documents stored before versioning are taken over at version 1
+        let legacy = json!({"id": "d4", "body": "legacy"});
+        store.persistence.insert("documents", legacy).await?;
+        let legacy = store.get::<Document>(json!("d4")).await?.unwrap();
+        assert_eq!(store.save(&legacy).await?.revision, 1);
+        assert_eq!(store.count::<Document>(None).await?, 3);
+
+        Ok(())
+    }
+
+    /// Saves the same new record from several tasks at once, and checks it is
+    /// stored once.
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_concurrent_saves() -> anyhow::Result<()> {
+        let store = &Store::new(TestPersistence::new(HashMap::new()));
+        let mut handles = vec![];
+        for i in 0..8 {
+            let store = store.clone();
+            handles.push(tokio::spawn(async move {
+                let user = User {
+                    id: "u1".to_string(),
+                    name: format!("user {}", i),
+                };
+                store.save(&user).await
+            }));
+        }
+        for handle in handles {
+            handle.await??;
+        }
+        assert_eq!(store.count::<User>(None).await?, 1);
+
+        let mut handles = vec![];
+        for _ in 0..8 {
+            let store = store.clone();
+            handles.push(tokio::spawn(async move {
+                let draft = Document {
+                    id: "d1".to_string(),
+                    body: "draft".to_string(),
+                    revision: 0,
+                };
+                store.save(&draft).await
+            }));
+        }
+        let mut saved = 0;
+        for handle in handles {
+            match handle.await? {
+                Ok(_) => saved += 1,
+                Err(StoreError::Conflict(_)) => {}
+                Err(err) => return Err(err.into()),
+            }
+        }
+        assert_eq!(saved, 1);
+        assert_eq!(store.count::<Document>(None).await?, 1);
+
+        Ok(())
+    }
+
+    /// Checks the failure modes callers match on, which every backend reports
+    /// alike.
+    #[tokio::test]
+    async fn test_errors() -> anyhow::Result<()> {
+        let store = &Store::new(TestPersistence::new(HashMap::new()));
+        let none = Query::builder().eq("id", json!("nope")).build();
+        assert!(store.find_one::<Document>(Some(none)).await?.is_none());
+        assert!(store.get::<Document>(json!("nope")).await?.is_none());
+
+        store
+            .persistence
+            .insert("documents", json!({"id": "bad", "body": 1}))
+            .await?;
+        assert!(matches!(
+            store.get::<Document>(json!("bad")).await,
+            Err(StoreError::Deserialize(_))
+        ));
+
+        let query = Query::builder().gt("body", json!([1])).build();
+        assert!(matches!(
+            store.find::<Document>(Some(query)).await,
+            Err(StoreError::UnsupportedOperator(_))
+        ));
+        assert!(matches!(
+            store.page::<Document>(None, 0, None).await,
+            Err(StoreError::InvalidQuery(_))
+        ));
+        assert!(matches!(
+            store.page::<Document>(None, 10, Some("garbage")).await,
+            Err(StoreError::InvalidQuery(_))
+        ));
+
+        Ok(())
+    }
+
+    #[derive(Debug, Serialize, Deserialize, Collection, Identity)]
+    #[store(crate = "crate", collection = "tickets", id_strategy = "uuid_v4")]
+    struct Ticket {
+        id: Option<String>,
+        title: String,
+    }
+
+    #[derive(Debug, Serialize, Deserialize, Collection, Identity)]
+    #[store(crate = "crate", collection = "orders", id_strategy = "sequential")]
+    pub(crate) struct Order {
+        #[serde(rename = "_id")]
+        pub(crate) id: Option<i64>,
+        pub(crate) total: f64,
+    }
+
+    #[derive(Debug, Serialize, Deserialize, Collection, Identity)]
+    #[store(crate = "crate", collection = "invoices", id_strategy = "sequence")]
+    pub(crate) struct Invoice {
+        pub(crate) id: Option<i64>,
+    }
+
+    pub(crate) async fn assert_generated_ids(store: &Store) -> anyhow::Result<()> {
+        let ticket = store
+            .insert(&Ticket {
+                id: None,
+                title: "Broken login".to_string(),
+            })
+            .await?;
+        let id = ticket.id.unwrap();
+        assert_eq!(uuid::Uuid::parse_str(&id)?.get_version_num(), 4);
+        let stored = store.get::<Ticket>(json!(id)).await?.unwrap();
+        assert_eq!(stored.title, "Broken login");
+
+        let mut ids = vec![];
+        for total in [10.0, 20.0] {
+            let order = store.save(&Order { id: None, total }).await?;
+            ids.push(order.id.unwrap());
+        }
+        let order = store
+            .insert(&Order {
+                id: None,
+                total: 30.0,
+            })
+            .await?;
+        ids.push(order.id.unwrap());
+        assert_eq!(ids, vec![1, 2, 3]);
+
+        // a record that has an id keeps it and is replaced on save
+        store
+            .save(&Order {
+                id: Some(2),
+                total: 25.0,
+            })
+            .await?;
+        let orders: Vec<Order> = store.find(None).await?;
+        assert_eq!(orders.len(), 3);
+        let order = store.get::<Order>(json!(2)).await?.unwrap();
+        assert_eq!(order.total, 25.0);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_generated_ids() -> anyhow::Result<()> {
+        let store = Store::new(TestPersistence::new(HashMap::new()));
+        assert_generated_ids(&store).await?;
+
+        let result = store.insert(&Invoice { id: None }).await;
+        assert!(result.is_err());
+
+        Ok(())
+    }
+
+    #[derive(Debug, Serialize, Deserialize, Collection, Identity)]
+    #[store(crate = "crate", collection = "stock")]
+    struct Stock {
+        id: String,
+        quantity: i64,
+    }
+
+    /// Records an order and takes its items out of stock, failing when there
+    /// isn't enough of it left.
+    async fn place_order(store: &Store, total: f64, quantity: i64) -> anyhow::Result<i64> {
+        store
+            .transaction(|tx| async move {
+                let order = tx.insert(&Order { id: None, total }).await?;
+
+                let mut stock = tx.get::<Stock>(json!("widget")).await?.unwrap();
+                stock.quantity -= quantity;
+                if stock.quantity < 0 {
+                    return Err(anyhow::anyhow!("out of stock"));
+                }
+                tx.save(&stock).await?;
+
+                Ok(order.id.unwrap())
+            })
+            .await
+    }
+
+    pub(crate) async fn assert_transactions(store: &Store, isolated: bool) -> anyhow::Result<()> {
+        store
+            .save(&Stock {
+                id: "widget".to_string(),
+                quantity: 5,
+            })
+            .await?;
+
+        place_order(store, 10.0, 3).await?;
+        assert!(place_order(store, 20.0, 3).await.is_err());
+        let orders: Vec<Order> = store.find(None).await?;
+        assert_eq!(orders.len(), 1);
+        assert_eq!(orders[0].total, 10.0);
+        let stock = store.get::<Stock>(json!("widget")).await?.unwrap();
+        assert_eq!(stock.quantity, 2);
+
+        let outside = store.clone();
+        store
+            .transaction(|tx| async move {
+                tx.delete_many::<Order>(Query::builder().build()).await?;
+                assert!(tx.find::<Order>(None).await?.is_empty());
+                if isolated {
+                    assert_eq!(outside.find::<Order>(None).await?.len(), 1);
+                }
+                Ok::<_, StoreError>(())
+            })
+            .await?;
+        assert!(store.find::<Order>(None).await?.is_empty());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_transactions() -> anyhow::Result<()> {
+        let store = Store::new(TestPersistence::new(HashMap::new()));
+        assert_transactions(&store, true).await
+    }
+
+    #[derive(Debug, Serialize, Deserialize, Collection, Identity)]
+    #[store(crate = "crate", collection = "readings")]
+    struct Reading {
+        id: i64,
+        value: i64,
+    }
+
+    /// Streams typed readings from the store and from inside a transaction.
+    #[tokio::test]
+    async fn test_streaming() -> anyhow::Result<()> {
+        let store = &Store::new(TestPersistence::new(HashMap::new()));
+        let count = 20;
+        for id in 1..=count {
+            store.insert(&Reading { id, value: id % 7 }).await?;
+        }
+
+        let streamed: Vec<Reading> = store.stream(None).await?.try_collect().await?;
+        assert_eq!(streamed.len(), count as usize);
+
+        let query = Query::builder()
+            .gt("value", json!(0))
+            .order_by_desc("value")
+            .order_by("id")
+            .build();
+        let found: Vec<Reading> = store.find(Some(query.clone())).await?;
+        let streamed: Vec<Reading> = store.stream(Some(query)).await?.try_collect().await?;
+        let ids = |readings: &[Reading]| readings.iter().map(|r| r.id).collect::<Vec<_>>();
+        assert_eq!(ids(&streamed), ids(&found));
+        assert_eq!(streamed[0].value, 6);
+
+        store
+            .transaction(|tx| async move {
+                tx.insert(&Reading {
+                    id: count + 1,
+                    value: 0,
+                })
+                .await?;
+                let mut stream = tx.stream::<Reading>(None).await?;
+                let mut streamed = 0;
+                while let Some(reading) = stream.next().await {
+                    reading?;
+                    streamed += 1;
+                }
+                drop(stream);
+                assert_eq!(streamed, count + 1);
+                tx.delete::<Reading>(json!(count + 1)).await?;
+                Ok::<_, StoreError>(())
+            })
+            .await?;
+        assert_eq!(store.find::<Reading>(None).await?.len(), count as usize);
+
+        Ok(())
+    }
+
+    #[derive(Debug, Serialize, Deserialize, Collection, Identity)]
+    #[store(crate = "crate", collection = "entries")]
+    struct Entry {
+        id: i64,
+        #[serde(default)]
+        score: Value,
+    }
+
+    /// Entries with scores of every type, ties included.
+    fn entry_docs() -> Vec<Data> {
+        vec![
+            json!({"id": 1, "score": 3}),
+            json!({"id": 2, "score": "b"}),
+            json!({"id": 3}),
+            json!({"id": 4, "score": 3.0}),
+            json!({"id": 5, "score": 1.5}),
+            json!({"id": 6, "score": null}),
+            json!({"id": 7, "score": true}),
+            json!({"id": 8, "score": "a"}),
+            json!({"id": 9, "score": 3}),
+            json!({"id": 10, "score": [1]}),
+            json!({"id": 11, "score": {"x": 1}}),
+            json!({"id": 12, "score": [2]}),
+        ]
+    }
+
+    async fn all_pages(store: &Store, query: Option<Query>, size: u32) -> anyhow::Result<Vec<i64>> {
+        let mut ids = vec![];
+        let mut cursor = None;
+        loop {
+            let page: Page<Entry> = store.page(query.clone(), size, cursor.as_deref()).await?;
+            assert!(page.items.len() <= size as usize);
+            ids.extend(page.items.iter().map(|entry| entry.id));
+            match page.next_cursor {
+                Some(next) => cursor = Some(next),
+                None => return Ok(ids),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_pagination() -> anyhow::Result<()> {
+        let store = &Store::new(TestPersistence::new(HashMap::new()));
+        for doc in entry_docs() {
+            store.persistence.insert("entries", doc).await?;
+        }
+        let ids = |entries: Vec<Entry>| entries.iter().map(|entry| entry.id).collect::<Vec<_>>();
+
+        assert_eq!(
+            all_pages(store, None, 5).await?,
+            (1..=12).collect::<Vec<_>>()
+        );
+
+        let queries = [
+            Query::builder().order_by_desc("score").build(),
+            Query::builder()
+                .not_eq("id", json!(5))
+                .order_by("score")
+                .build(),
+        ];
+        for query in queries {
+            let mut sorted = query.clone();
+            sorted.sort.as_mut().unwrap().push(QuerySortItem {
+                field: "id".to_string(),
+                direction: QuerySortDirection::Ascending,
+            });
+            let expected = ids(store.find(Some(sorted)).await?);
+            for size in [1, 3, 12] {
+                assert_eq!(all_pages(store, Some(query.clone()), size).await?, expected);
+            }
+        }
+
+        // an entry written between pages, ahead of the cursor, shifts nothing
+        let query = Query::builder().order_by_desc("score").build();
+        let first: Page<Entry> = store.page(Some(query.clone()), 4, None).await?;
+        let cursor = first.next_cursor.unwrap();
+        store
+            .persistence
+            .insert("entries", json!({"id": 13, "score": {"y": 1}}))
+            .await?;
+        let rest: Page<Entry> = store.page(Some(query.clone()), 20, Some(&cursor)).await?;
+        let mut paged = ids(first.items);
+        paged.extend(ids(rest.items));
+        assert_eq!(paged, vec![11, 10, 12, 7, 2, 8, 1, 4, 9, 5, 3, 6]);
+        assert!(rest.next_cursor.is_none());
+
+        let other = Query::builder().order_by("score").build();
+        assert!(store
+            .page::<Entry>(Some(other), 4, Some(&cursor))
+            .await
+            .is_err());
+        assert!(store
+            .page::<Entry>(None, 4, Some("not a cursor"))
+            .await
+            .is_err());
+        assert!(store.page::<Entry>(None, 0, None).await.is_err());
+        let mut limited = query;
+        limited.limit = Some(QueryLimit {
+            limit: Some(1),
+            offset: None,
+        });
+        assert!(store.page::<Entry>(Some(limited), 4, None).await.is_err());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_counts() -> anyhow::Result<()> {
+        let store = Store::new(TestPersistence::new(HashMap::new()));
+        assert_eq!(store.count::<User>(None).await?, 0);
+        assert!(!store.exists::<User>(None).await?);
+        store
+            .insert(&User {
+                id: "1".to_string(),
+                name: "John".to_string(),
+            })
+            .await?;
+        assert_eq!(store.count::<User>(None).await?, 1);
+        assert!(store.exists::<User>(None).await?);
+        assert_eq!(
+            store.distinct::<User>("name", None).await?,
+            vec![json!("John")]
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_aggregations() -> anyhow::Result<()> {
+        let persistence = TestPersistence::new(HashMap::new());
+        for doc in sales_docs() {
+            persistence.insert("sales", doc).await?;
+        }
+
+        #[derive(Debug, Deserialize)]
+        struct Region {
+            region: Value,
+            sales: u64,
+        }
+        let store = Store::new(persistence);
+        let aggregation = Aggregation::builder()
+            .group_by("region", "region")
+            .count("sales")
+            .order_by_desc("sales")
+            .order_by("region")
+            .build();
+        let regions: Vec<Region> = store.aggregate::<Sale, _>(aggregation).await?;
+        assert_eq!(regions[0].region, json!(null));
+        assert_eq!(regions[0].sales, 2);
+        assert_eq!(regions.len(), 3);
+
+        Ok(())
+    }
+
+    /// A light view of the contact documents, loaded through a projection.
+    #[derive(Debug, Serialize, Deserialize, Collection, Identity)]
+    #[store(crate = "crate", collection = "contacts")]
+    struct ContactName {
+        id: i64,
+        name: String,
+    }
+
+    fn contact_docs() -> Vec<Data> {
+        vec![
+            json!({
+                "id": 1, "name": "Ann", "email": "ann@example.com", "active": true,
+                "address": {"city": "Lisbon", "zip": "1000", "geo": {"lat": 38.7, "lng": -9.1}},
+                "tags": ["a", "b"],
+            }),
+            json!({"id": 2, "name": "Bob", "email": null, "active": false, "address": "unknown", "tags": []}),
+            json!({"id": 3, "name": "Cid", "address": {"city": "Porto", "geo": null}, "score": 1.5}),
+            json!({
+                "id": 4, "name": "Dee", "active": true,
+                "address": {"zip": "4000", "geo": {"lat": 41.1}},
+                "notes": {"x": {"y": "z"}, "w": 1},
+            }),
+        ]
+    }
+
+    /// Selects fields into a narrower type, and pages through projected
+    /// records by a sort key the projection leaves out.
+    #[tokio::test]
+    async fn test_projection() -> anyhow::Result<()> {
+        let store = &Store::new(TestPersistence::new(HashMap::new()));
+        for doc in contact_docs() {
+            store.persistence.insert("contacts", doc).await?;
+        }
+
+        let names: Vec<ContactName> = store
+            .find(Some(
+                Query::builder()
+                    .eq("active", json!(true))
+                    .select(&["id", "name"])
+                    .order_by("id")
+                    .build(),
+            ))
+            .await?;
+        let names: Vec<(i64, &str)> = names.iter().map(|c| (c.id, c.name.as_str())).collect();
+        assert_eq!(names, vec![(1, "Ann"), (4, "Dee")]);
+
+        // the sort keys survive the projection, so the cursors still work
+        let query = Query::builder()
+            .select(&["name"])
+            .order_by_desc("address.city")
+            .build();
+        let mut ids = vec![];
+        let mut cursor = None;
+        loop {
+            let page: Page<ContactName> = store
+                .page(Some(query.clone()), 1, cursor.as_deref())
+                .await?;
+            ids.extend(page.items.iter().map(|contact| contact.id));
+            match page.next_cursor {
+                Some(next) => cursor = Some(next),
+                None => break,
+            }
+        }
+        assert_eq!(ids, vec![3, 1, 2, 4]);
+
+        Ok(())
+    }
+
+    #[derive(Debug, Serialize, Deserialize, Collection, Identity)]
+    #[store(crate = "crate", collection = "counters")]
+    struct Counter {
+        id: i64,
+        hits: i64,
+    }
+
+    /// Patches typed records from concurrent tasks, and rejects an update
+    /// the backend cannot apply.
+    #[tokio::test]
+    async fn test_patches() -> anyhow::Result<()> {
+        let store = &Store::new(TestPersistence::new(HashMap::new()));
+        let invalid = Update::builder().rename("address", "address.city").build();
+        assert!(store
+            .patch::<Counter>(Query::builder().build(), invalid)
+            .await
+            .is_err());
+
+        store.insert(&Counter { id: 1, hits: 0 }).await?;
+        let mut handles = vec![];
+        for _ in 0..20 {
+            let store = store.clone();
+            handles.push(tokio::spawn(async move {
+                let query = Query::builder().eq("id", json!(1)).build();
+                let update = Update::builder().inc("hits", 1).build();
+                store.patch::<Counter>(query, update).await
+            }));
+        }
+        for handle in handles {
+            assert_eq!(handle.await??, 1);
+        }
+        let counter: Option<Counter> = store.get(json!(1)).await?;
+        assert_eq!(counter.unwrap().hits, 20);
 
         Ok(())
     }
@@ -326,8 +1611,8 @@ mod tests {
         })?;
         records.insert("users".to_string(), vec![user1, user2]);
         records.insert("products".to_string(), vec![product1, product2]);
-        let persistence = TestPersistence { records };
-        let mut store = Store::new(persistence);
+        let persistence = TestPersistence::new(records);
+        let store = Store::new(persistence);
         let users: Vec<User> = store.find(None).await?;
         let products: Vec<Product> = store.find(None).await?;
 
@@ -342,4 +1627,403 @@ mod tests {
 
         Ok(())
     }
+
+    #[tokio::test]
+    async fn test_save_and_delete() -> anyhow::Result<()> {
+        let persistence = TestPersistence::new(HashMap::new());
+        let store = Store::new(persistence);
+
+        let user = store
+            .save(&User {
+                id: "123".to_string(),
+                name: "John".to_string(),
+            })
+            .await?;
+        assert_eq!(user.name, "John");
+
+        store
+            .save(&User {
+                id: "123".to_string(),
+                name: "Johnny".to_string(),
+            })
+            .await?;
+        let users: Vec<User> = store.find(None).await?;
+        assert_eq!(users.len(), 1);
+        assert_eq!(users[0].name, "Johnny");
+
+        assert!(
+            store
+                .delete::<User>(Value::String("123".to_string()))
+                .await?
+        );
+        assert!(
+            !store
+                .delete::<User>(Value::String("123".to_string()))
+                .await?
+        );
+        let users: Vec<User> = store.find(None).await?;
+        assert!(users.is_empty());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_tasks() -> anyhow::Result<()> {
+        let store = Store::new(TestPersistence::new(HashMap::new()));
+
+        let mut handles = vec![];
+        for i in 0..20 {
+            let store = store.clone();
+            handles.push(tokio::spawn(async move {
+                store
+                    .save(&User {
+                        id: i.to_string(),
+                        name: format!("User {}", i),
+                    })
+                    .await?;
+                store.get::<User>(Value::String(i.to_string())).await
+            }));
+        }
+        for handle in handles {
+            assert!(handle.await??.is_some());
+        }
+
+        let users: Vec<User> = store.find(None).await?;
+        assert_eq!(users.len(), 20);
+
+        Ok(())
+    }
+
+    #[derive(Debug, Serialize, Deserialize, Collection, Identity)]
+    #[store(crate = "crate", collection = "places")]
+    #[store(index(field = "city"), index(field = "address.zip", kind = "hash"))]
+    #[store(index(field = "tags", kind = "gin"))]
+    pub(crate) struct Place {
+        id: String,
+        city: String,
+        address: Value,
+        tags: Vec<String>,
+    }
+
+    pub(crate) fn place(id: &str, city: &str, zip: &str) -> Place {
+        Place {
+            id: id.to_string(),
+            city: city.to_string(),
+            address: json!({"zip": zip}),
+            tags: vec![],
+        }
+    }
+
+    /// Ensures the collection of `Place` on a backend that has no table for
+    /// it yet, and checks it can then be written and queried.
+    #[tokio::test]
+    async fn test_ensure_collection() -> anyhow::Result<()> {
+        let persistence = TestPersistence::new(HashMap::new());
+        let store = &Store::new(persistence.clone());
+        assert_eq!(
+            Place::indexes(),
+            vec![
+                Index::ordered("city"),
+                Index::hash("address.zip"),
+                Index::gin("tags"),
+            ]
+        );
+        assert!(User::indexes().is_empty());
+
+        store.ensure_collection::<Place>().await?;
+        store.ensure_collection::<Place>().await?;
+        store.insert(&place("1", "Lisbon", "1000-001")).await?;
+        store.insert(&place("2", "Porto", "4000-001")).await?;
+        let query = Query::builder()
+            .eq("address.zip", json!("4000-001"))
+            .build();
+        let places: Vec<Place> = store.find(Some(query)).await?;
+        assert_eq!(places.len(), 1);
+        assert_eq!(places[0].city, "Porto");
+        let query = Query::builder().gte("city", json!("M")).build();
+        assert_eq!(store.count::<Place>(Some(query)).await?, 1);
+        assert_eq!(persistence.indexes.read().unwrap()["places"].len(), 3);
+
+        Ok(())
+    }
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize, Collection, Identity)]
+    #[store(crate = "crate", collection = "contacts")]
+    #[store(migrations = "contact_migrations")]
+    pub(crate) struct Contact {
+        id: String,
+        full_name: String,
+        email: String,
+        active: bool,
+    }
+
+    fn contact_migrations() -> Migrations {
+        Migrations::builder()
+            .rename(1, "name", "full_name")
+            .set_default(2, "active", json!(true))
+            .transform(3, |document| {
+                if let Some(Value::String(email)) = document.get_mut("email") {
+                    *email = email.to_lowercase();
+                }
+                Ok(())
+            })
+            .build()
+    }
+
+    fn contact(id: &str, full_name: &str, email: &str, active: bool) -> Contact {
+        Contact {
+            id: id.to_string(),
+            full_name: full_name.to_string(),
+            email: email.to_string(),
+            active,
+        }
+    }
+
+    /// Stores `Contact` documents of each schema version behind the store's
+    /// back, reads them upgraded, then migrates them.
+    pub(crate) async fn assert_migrations(store: &Store) -> anyhow::Result<()> {
+        let persistence = store.persistence.clone();
+        let stored = [
+            json!({"id": "1", "name": "Ann", "email": "ANN@X"}),
+            json!({"id": "2", "full_name": "Bob", "email": "BOB@X", "_schema": 1}),
+            json!({"id": "3", "full_name": "Cid", "email": "CID@X", "active": false, "_schema": 2}),
+            json!({"id": "4", "full_name": "Dee", "email": "dee@x", "active": false, "_schema": 3}),
+        ];
+        for document in &stored {
+            persistence.insert("contacts", document.clone()).await?;
+        }
+        let expected = vec![
+            contact("1", "Ann", "ann@x", true),
+            contact("2", "Bob", "bob@x", true),
+            contact("3", "Cid", "cid@x", false),
+            contact("4", "Dee", "dee@x", false),
+        ];
+
+        // upgraded lazily on every read, leaving the stored documents alone
+        let by_id = Query::builder().order_by("id").build();
+        let contacts: Vec<Contact> = store.find(Some(by_id.clone())).await?;
+        assert_eq!(contacts, expected);
+        let first: Option<Contact> = store.get(json!("1")).await?;
+        assert_eq!(first.as_ref(), Some(&expected[0]));
+        let query = Query::builder().eq("id", json!("2")).build();
+        let second: Option<Contact> = store.find_one(Some(query)).await?;
+        assert_eq!(second.as_ref(), Some(&expected[1]));
+        let streamed: Vec<Contact> = store
+            .stream(Some(by_id.clone()))
+            .await?
+            .try_collect()
+            .await?;
+        assert_eq!(streamed, expected);
+        let page: Page<Contact> = store.page(None, 2, None).await?;
+        assert_eq!(page.items, expected[..2]);
+        let raw = persistence.find("contacts", Some(by_id.clone())).await?;
+        assert_eq!(raw, stored);
+
+        // written at the latest version
+        store.insert(&contact("5", "Eve", "eve@x", true)).await?;
+        let query = Query::builder().eq("id", json!("5")).build();
+        let raw = persistence.find_one("contacts", Some(query)).await?;
+        assert_eq!(raw.unwrap()["_schema"], json!(3));
+
+        assert_eq!(store.migrate::<Contact>().await?, 3);
+        let raw = persistence.find("contacts", Some(by_id.clone())).await?;
+        let upgraded: Vec<Contact> = raw
+            .iter()
+            .map(|document| serde_json::from_value(document.clone()))
+            .collect::<std::result::Result<_, _>>()?;
+        assert_eq!(upgraded[..4], expected);
+        assert!(raw.iter().all(|document| document["_schema"] == json!(3)));
+        assert_eq!(store.migrate::<Contact>().await?, 0);
+
+        // migrating a collection without migrations is a no-op
+        assert_eq!(store.migrate::<Place>().await?, 0);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_migrations() -> anyhow::Result<()> {
+        let store = Store::new(TestPersistence::new(HashMap::new()));
+        assert_migrations(&store).await?;
+
+        // without a record of past migrations, outdated documents are looked
+        // for on every run
+        store
+            .persistence
+            .insert(
+                "contacts",
+                json!({"id": "6", "name": "Fay", "email": "F@X"}),
+            )
+            .await?;
+        assert_eq!(store.migrate::<Contact>().await?, 1);
+
+        // a document without an id can't be written back
+        store
+            .persistence
+            .insert("contacts", json!({"name": "Gus", "email": "G@X"}))
+            .await?;
+        assert!(matches!(
+            store.migrate::<Contact>().await,
+            Err(StoreError::Deserialize(_))
+        ));
+
+        Ok(())
+    }
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize, JsonSchema, Collection, Identity)]
+    #[store(crate = "crate", collection = "members", schema)]
+    struct Member {
+        id: String,
+        #[schemars(length(min = 3))]
+        handle: String,
+        #[schemars(range(min = 0))]
+        balance: i64,
+    }
+
+    #[derive(Debug, Serialize, Deserialize, Collection, Identity)]
+    #[store(crate = "crate", collection = "signups", schema = "signup_schema")]
+    struct Signup {
+        id: String,
+        email: String,
+    }
+
+    /// A view of members without their schema.
+    #[derive(Debug, Serialize, Deserialize, Collection, Identity)]
+    #[store(crate = "crate", collection = "members")]
+    struct MemberHandle {
+        id: String,
+        handle: String,
+    }
+
+    fn signup_schema() -> Value {
+        json!({
+            "type": "object",
+            "properties": {
+                "id": {"type": "string"},
+                "email": {"type": "string", "pattern": "@"},
+            },
+            "required": ["id", "email"],
+            "additionalProperties": false,
+        })
+    }
+
+    fn member(id: &str, handle: &str, balance: i64) -> Member {
+        Member {
+            id: id.to_string(),
+            handle: handle.to_string(),
+            balance,
+        }
+    }
+
+    fn invalid_fields(result: Result<impl fmt::Debug>) -> Vec<String> {
+        match result {
+            Err(StoreError::Validation(errors)) => {
+                errors.into_iter().map(|error| error.field).collect()
+            }
+            other => panic!("expected a validation error, got {:?}", other),
+        }
+    }
+
+    /// Writes documents that do and don't conform to the collections'
+    /// schemas, and checks only the former are stored.
+    #[tokio::test]
+    async fn test_schemas() -> anyhow::Result<()> {
+        let store = &Store::new(TestPersistence::new(HashMap::new()));
+        store.insert(&member("1", "ann", 10)).await?;
+        assert_eq!(
+            invalid_fields(store.insert(&member("2", "bo", -5)).await),
+            ["balance", "handle"]
+        );
+        assert_eq!(
+            invalid_fields(store.save(&member("1", "ann", -1)).await),
+            ["balance"]
+        );
+        let stored: Vec<Member> = store.find(None).await?;
+        assert_eq!(stored, [member("1", "ann", 10)]);
+
+        let by_id = Member::identity_query(json!("1"));
+        let update = Update::builder().set("balance", json!("ten")).build();
+        assert_eq!(
+            invalid_fields(store.patch::<Member>(by_id.clone(), update).await),
+            ["balance"]
+        );
+        let update = Update::builder().unset("handle").build();
+        assert_eq!(
+            invalid_fields(store.patch::<Member>(by_id.clone(), update).await),
+            ["handle"]
+        );
+        let update = Update::builder().inc("balance", -1).build();
+        assert_eq!(store.patch::<Member>(by_id, update).await?, 1);
+        let stored: Option<Member> = store.get(json!("1")).await?;
+        assert_eq!(stored, Some(member("1", "ann", 9)));
+
+        let signup = Signup {
+            id: "1".to_string(),
+            email: "nobody".to_string(),
+        };
+        assert_eq!(invalid_fields(store.insert(&signup).await), ["email"]);
+        assert_eq!(store.count::<Signup>(None).await?, 0);
+
+        // transactions check writes the same way
+        let result = store
+            .transaction(|tx| async move { tx.insert(&member("3", "x", 0)).await })
+            .await;
+        assert_eq!(invalid_fields(result), ["handle"]);
+        assert_eq!(store.count::<Member>(None).await?, 1);
+
+        let query = Query::builder()
+            .eq("handle", json!("ann"))
+            .order_by("balance")
+            .build();
+        store.validate_query::<Member>(&query)?;
+        let query = Query::builder()
+            .eq("balance", json!("10"))
+            .or_wher("nickname", json!("ann"))
+            .build();
+        assert_eq!(
+            invalid_fields(store.validate_query::<Member>(&query)),
+            ["balance", "nickname"]
+        );
+        store.validate_query::<Product>(&query)?;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_schemas_by_type() -> anyhow::Result<()> {
+        let store = Store::new(TestPersistence::new(HashMap::new()));
+        let handle = MemberHandle {
+            id: "1".to_string(),
+            handle: "x".to_string(),
+        };
+        store.insert(&handle).await?;
+        assert_eq!(
+            invalid_fields(store.insert(&member("2", "bo", 0)).await),
+            ["handle"]
+        );
+        assert_eq!(store.count::<Member>(None).await?, 1);
+        Ok(())
+    }
+
+    #[derive(Debug, Serialize, Deserialize, JsonSchema, Collection, Identity)]
+    #[store(crate = "crate", collection = "bookings", schema)]
+    struct Booking {
+        id: String,
+        #[store(version)]
+        #[schemars(range(min = 1))]
+        version: i64,
+    }
+
+    #[tokio::test]
+    async fn test_schemas_check_saved_version() -> anyhow::Result<()> {
+        let store = Store::new(TestPersistence::new(HashMap::new()));
+        let booking = |id: &str| Booking {
+            id: id.to_string(),
+            version: 0,
+        };
+        assert_eq!(store.insert(&booking("1")).await?.version, 1);
+        assert_eq!(store.save(&booking("2")).await?.version, 1);
+        assert_eq!(store.count::<Booking>(None).await?, 2);
+        Ok(())
+    }
 }
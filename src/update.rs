@@ -0,0 +1,473 @@
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Number, Value};
+
+use crate::error::{Result, StoreError};
+use crate::query::{is_same_or_nested, json_eq, ANY_ELEMENT};
+
+/// A partial update: operators applied in order to each document a query
+/// matches, in place of replacing the whole document. Fields are dotted paths
+/// of object keys such as `address.city`.
+///
+/// An operator that can't apply to a document leaves it as it is: one whose
+/// field lies under a value that isn't an object, `inc` on a value that isn't a
+/// number, `push` and `pull` on a value that isn't an array, and `unset`,
+/// `pull` and `rename` on a missing field.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct Update {
+    #[serde(default)]
+    pub operations: Vec<UpdateOperation>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "operator", rename_all = "camelCase")]
+pub enum UpdateOperation {
+    /// Sets the field, creating the objects leading up to it where they are
+    /// missing or `null`.
+    Set { field: String, value: Value },
+    /// Removes the field.
+    Unset { field: String },
+    /// Adds to the number at the field, which is set to `value` when it is
+    /// missing or `null`. Integers stay integers while the sum fits.
+    Inc { field: String, value: Number },
+    /// Appends to the array at the field, which becomes `[value]` when it is
+    /// missing or `null`.
+    Push { field: String, value: Value },
+    /// Removes the elements equal to `value` from the array at the field,
+    /// numbers compared by value.
+    Pull { field: String, value: Value },
+    /// Moves the field's value to `to`, which is set like `set` does. The two
+    /// fields can't be the same or lie under one another.
+    Rename { field: String, to: String },
+}
+
+impl Update {
+    pub fn builder() -> UpdateBuilder {
+        UpdateBuilder::default()
+    }
+
+    /// Update fields only address object keys, so `*` and empty segments are
+    /// rejected.
+    pub fn check(&self) -> Result<()> {
+        for operation in self.operations.iter() {
+            check_field(operation.field())?;
+            if let UpdateOperation::Rename { field, to } = operation {
+                check_field(to)?;
+                if is_same_or_nested(field, to) {
+                    return Err(StoreError::InvalidQuery(format!(
+                        "can't rename '{}' to '{}': the fields overlap",
+                        field, to
+                    )));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Applies the operators to a document in memory, the way
+    /// `sql::patch_to_sql` does in SQL, returning whether it changed.
+    pub fn apply(&self, document: &mut Value) -> Result<bool> {
+        self.check()?;
+        let before = document.clone();
+        for operation in self.operations.iter() {
+            operation.apply(document);
+        }
+        Ok(!json_eq(&before, document))
+    }
+}
+
+fn check_field(field: &str) -> Result<()> {
+    if field
+        .split('.')
+        .any(|segment| segment.is_empty() || segment == ANY_ELEMENT)
+    {
+        return Err(StoreError::InvalidQuery(format!(
+            "can't update '{}': update fields are paths of object keys",
+            field
+        )));
+    }
+    Ok(())
+}
+
+impl UpdateOperation {
+    pub fn field(&self) -> &str {
+        match self {
+            UpdateOperation::Set { field, .. }
+            | UpdateOperation::Unset { field }
+            | UpdateOperation::Inc { field, .. }
+            | UpdateOperation::Push { field, .. }
+            | UpdateOperation::Pull { field, .. }
+            | UpdateOperation::Rename { field, .. } => field,
+        }
+    }
+
+    fn apply(&self, document: &mut Value) {
+        let segments: Vec<&str> = self.field().split('.').collect();
+        let key = segments[segments.len() - 1].to_string();
+        match self {
+            UpdateOperation::Set { value, .. } => {
+                if let Some(parent) = parent_mut(document, &segments, true) {
+                    parent.insert(key, value.clone());
+                }
+            }
+            UpdateOperation::Unset { .. } => {
+                if let Some(parent) = parent_mut(document, &segments, false) {
+                    parent.remove(&key);
+                }
+            }
+            UpdateOperation::Inc { value, .. } => {
+                let Some(parent) = parent_mut(document, &segments, true) else {
+                    return;
+                };
+                let sum = match parent.get(&key) {
+                    None | Some(Value::Null) => Some(value.clone()),
+                    Some(Value::Number(number)) => add(number, value),
+                    Some(_) => None,
+                };
+                if let Some(sum) = sum {
+                    parent.insert(key, Value::Number(sum));
+                }
+            }
+            UpdateOperation::Push { value, .. } => {
+                let Some(parent) = parent_mut(document, &segments, true) else {
+                    return;
+                };
+                match parent.get_mut(&key) {
+                    None | Some(Value::Null) => {
+                        parent.insert(key, Value::Array(vec![value.clone()]));
+                    }
+                    Some(Value::Array(array)) => array.push(value.clone()),
+                    Some(_) => {}
+                }
+            }
+            UpdateOperation::Pull { value, .. } => {
+                let parent = parent_mut(document, &segments, false);
+                if let Some(Value::Array(array)) = parent.and_then(|parent| parent.get_mut(&key)) {
+                    array.retain(|element| !json_eq(element, value));
+                }
+            }
+            UpdateOperation::Rename { to, .. } => {
+                let to: Vec<&str> = to.split('.').collect();
+                if !is_clear(document, &to) {
+                    return;
+                }
+                let parent = parent_mut(document, &segments, false);
+                let Some(value) = parent.and_then(|parent| parent.remove(&key)) else {
+                    return;
+                };
+                if let Some(parent) = parent_mut(document, &to, true) {
+                    parent.insert(to[to.len() - 1].to_string(), value);
+                }
+            }
+        }
+    }
+}
+
+/// The object holding the last of `segments`, when every value leading up to
+/// it is an object. With `create`, missing and `null` values on the way are
+/// replaced by empty objects; past one of those the rest is missing too, so
+/// nothing is created for a path that turns out to be blocked.
+fn parent_mut<'a>(
+    document: &'a mut Value,
+    segments: &[&str],
+    create: bool,
+) -> Option<&'a mut Map<String, Value>> {
+    let (_, parents) = segments.split_last()?;
+    let mut object = document.as_object_mut()?;
+    for segment in parents {
+        let value = match create {
+            true => {
+                let value = object.entry(segment.to_string()).or_insert(Value::Null);
+                if value.is_null() {
+                    *value = Value::Object(Map::new());
+                }
+                value
+            }
+            false => object.get_mut(*segment)?,
+        };
+        object = value.as_object_mut()?;
+    }
+    Some(object)
+}
+
+/// Whether `parent_mut` would create its way to the last of `segments`.
+fn is_clear(document: &Value, segments: &[&str]) -> bool {
+    let Some(mut object) = document.as_object() else {
+        return false;
+    };
+    for segment in segments[..segments.len() - 1].iter() {
+        match object.get(*segment) {
+            None | Some(Value::Null) => return true,
+            Some(Value::Object(nested)) => object = nested,
+            Some(_) => return false,
+        }
+    }
+    true
+}
+
+/// Adds two numbers, as integers while both are and the sum fits. `None` when
+/// the sum isn't finite.
+fn add(a: &Number, b: &Number) -> Option<Number> {
+    if let Some(sum) = a
+        .as_i64()
+        .zip(b.as_i64())
+        .and_then(|(a, b)| a.checked_add(b))
+    {
+        return Some(sum.into());
+    }
+    Number::from_f64(a.as_f64()? + b.as_f64()?)
+}
+
+#[derive(Debug, Default)]
+pub struct UpdateBuilder {
+    update: Update,
+}
+
+#[allow(dead_code)]
+impl UpdateBuilder {
+    pub fn set(&mut self, field: &str, value: Value) -> &mut UpdateBuilder {
+        self.push_operation(UpdateOperation::Set {
+            field: field.to_string(),
+            value,
+        })
+    }
+
+    pub fn unset(&mut self, field: &str) -> &mut UpdateBuilder {
+        self.push_operation(UpdateOperation::Unset {
+            field: field.to_string(),
+        })
+    }
+
+    pub fn inc(&mut self, field: &str, value: impl Into<Number>) -> &mut UpdateBuilder {
+        self.push_operation(UpdateOperation::Inc {
+            field: field.to_string(),
+            value: value.into(),
+        })
+    }
+
+    pub fn push(&mut self, field: &str, value: Value) -> &mut UpdateBuilder {
+        self.push_operation(UpdateOperation::Push {
+            field: field.to_string(),
+            value,
+        })
+    }
+
+    pub fn pull(&mut self, field: &str, value: Value) -> &mut UpdateBuilder {
+        self.push_operation(UpdateOperation::Pull {
+            field: field.to_string(),
+            value,
+        })
+    }
+
+    pub fn rename(&mut self, field: &str, to: &str) -> &mut UpdateBuilder {
+        self.push_operation(UpdateOperation::Rename {
+            field: field.to_string(),
+            to: to.to_string(),
+        })
+    }
+
+    fn push_operation(&mut self, operation: UpdateOperation) -> &mut UpdateBuilder {
+        self.update.operations.push(operation);
+        self
+    }
+
+    pub fn build(&self) -> Update {
+        self.update.clone()
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::query::Query;
+
+    pub(crate) fn update_docs() -> Vec<Value> {
+        vec![
+            json!({
+                "id": 1, "name": "Ann", "visits": 1, "tags": ["a", "b", "a"],
+                "address": {"city": "Lisbon"},
+            }),
+            json!({"id": 2, "name": "Bob", "visits": 2.5, "tags": "none", "address": "unknown"}),
+            json!({"id": 3, "name": "Cid", "address": {"city": "Porto", "geo": null}, "score": null}),
+            json!({"id": 4, "name": "Dee", "visits": "many", "tags": [1, 1.0, 2]}),
+        ]
+    }
+
+    /// Updates of [`update_docs`], with the documents they match and the ids
+    /// of the ones they modify.
+    pub(crate) fn update_cases() -> Vec<(Query, Update, Vec<i64>)> {
+        let all = || Query::builder().build();
+        vec![
+            (
+                all(),
+                Update::builder().inc("visits", 1).build(),
+                vec![1, 2, 3],
+            ),
+            (
+                Query::builder()
+                    .is_in("id", vec![json!(1), json!(2), json!(3)])
+                    .build(),
+                Update::builder()
+                    .set("address.geo.lat", json!(38.7))
+                    .build(),
+                vec![1, 3],
+            ),
+            (
+                all(),
+                Update::builder()
+                    .unset("address.city")
+                    .unset("score")
+                    .unset("missing.field")
+                    .build(),
+                vec![1, 3],
+            ),
+            (
+                all(),
+                Update::builder().push("tags", json!({"c": [1]})).build(),
+                vec![1, 3, 4],
+            ),
+            (
+                all(),
+                Update::builder()
+                    .pull("tags", json!("a"))
+                    .pull("tags", json!(1))
+                    .build(),
+                vec![1, 4],
+            ),
+            (
+                all(),
+                Update::builder()
+                    .rename("name", "profile.name")
+                    .rename("address.city", "city")
+                    .rename("missing", "other")
+                    .build(),
+                vec![1, 2, 3, 4],
+            ),
+            (
+                Query::builder().eq("id", json!(1)).build(),
+                Update::builder()
+                    .set("name", json!("Ann"))
+                    .inc("visits", 0)
+                    .build(),
+                vec![],
+            ),
+            (
+                Query::builder().gte("id", json!(3)).build(),
+                Update::builder()
+                    .set("meta.created", json!("2024-01-01"))
+                    .inc("meta.count", 2)
+                    .inc("meta.count", Number::from_f64(0.5).unwrap())
+                    .push("meta.log", json!(null))
+                    .set("name.first", json!("x"))
+                    .build(),
+                vec![3, 4],
+            ),
+        ]
+    }
+
+    #[test]
+    fn test_apply() -> anyhow::Result<()> {
+        let cases = vec![
+            (
+                Update::builder().inc("visits", 1).build(),
+                vec![
+                    json!({"id": 1, "name": "Ann", "visits": 2, "tags": ["a", "b", "a"], "address": {"city": "Lisbon"}}),
+                    json!({"id": 2, "name": "Bob", "visits": 3.5, "tags": "none", "address": "unknown"}),
+                    json!({"id": 3, "name": "Cid", "visits": 1, "address": {"city": "Porto", "geo": null}, "score": null}),
+                    json!({"id": 4, "name": "Dee", "visits": "many", "tags": [1, 1.0, 2]}),
+                ],
+            ),
+            (
+                Update::builder()
+                    .set("address.geo.lat", json!(38.7))
+                    .unset("tags")
+                    .build(),
+                vec![
+                    json!({"id": 1, "name": "Ann", "visits": 1, "address": {"city": "Lisbon", "geo": {"lat": 38.7}}}),
+                    json!({"id": 2, "name": "Bob", "visits": 2.5, "address": "unknown"}),
+                    json!({"id": 3, "name": "Cid", "address": {"city": "Porto", "geo": {"lat": 38.7}}, "score": null}),
+                    json!({"id": 4, "name": "Dee", "visits": "many", "address": {"geo": {"lat": 38.7}}}),
+                ],
+            ),
+            (
+                Update::builder()
+                    .push("tags", json!("c"))
+                    .pull("tags", json!(1))
+                    .rename("address.city", "city")
+                    .build(),
+                vec![
+                    json!({"id": 1, "name": "Ann", "visits": 1, "tags": ["a", "b", "a", "c"], "address": {}, "city": "Lisbon"}),
+                    json!({"id": 2, "name": "Bob", "visits": 2.5, "tags": "none", "address": "unknown"}),
+                    json!({"id": 3, "name": "Cid", "tags": ["c"], "address": {"geo": null}, "score": null, "city": "Porto"}),
+                    json!({"id": 4, "name": "Dee", "visits": "many", "tags": [2, "c"]}),
+                ],
+            ),
+        ];
+        for (update, expected) in cases {
+            let mut docs = update_docs();
+            for doc in docs.iter_mut() {
+                update.apply(doc)?;
+            }
+            assert_eq!(docs, expected, "{:?}", update);
+        }
+
+        for (query, update, modified) in update_cases() {
+            let mut ids = vec![];
+            for mut doc in update_docs() {
+                if query.matches(&doc)? && update.apply(&mut doc)? {
+                    ids.push(doc["id"].as_i64().unwrap());
+                }
+            }
+            assert_eq!(ids, modified, "{:?}", update);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_check() {
+        let invalid = vec![
+            Update::builder().set("tags.*", json!(1)).build(),
+            Update::builder().unset("address..city").build(),
+            Update::builder().inc("", 1).build(),
+            Update::builder().rename("address", "address.city").build(),
+            Update::builder().rename("a.b", "a").build(),
+            Update::builder().rename("a", "a").build(),
+        ];
+        for update in invalid {
+            assert!(update.check().is_err(), "{:?}", update);
+        }
+        assert!(Update::builder()
+            .rename("address", "addresses")
+            .build()
+            .check()
+            .is_ok());
+    }
+
+    #[test]
+    fn test_serialize() -> anyhow::Result<()> {
+        let update = Update::builder()
+            .set("name", json!("Ann"))
+            .inc("visits", 1)
+            .rename("city", "address.city")
+            .build();
+
+        let json = serde_json::to_value(&update)?;
+        assert_eq!(
+            json,
+            json!({"operations": [
+                {"operator": "set", "field": "name", "value": "Ann"},
+                {"operator": "inc", "field": "visits", "value": 1},
+                {"operator": "rename", "field": "city", "to": "address.city"},
+            ]})
+        );
+        assert_eq!(serde_json::from_value::<Update>(json)?, update);
+        assert!(serde_json::from_value::<Update>(
+            json!({"operations": [{"operator": "inc", "field": "visits", "value": "1"}]})
+        )
+        .is_err());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,600 @@
+//! Derives for the `Collection` and `Identity` traits of the `store` crate.
+//!
+//! ```ignore
+//! #[derive(Serialize, Deserialize, Collection, Identity)]
+//! #[store(collection = "users", id_strategy = "uuid_v7")]
+//! struct User {
+//!     #[serde(rename = "_id")]
+//!     #[store(id)]
+//!     user_id: Option<String>,
+//!     name: String,
+//! }
+//! ```
+//!
+//! The identity key is the name the id field is serialized under, so serde's
+//! `rename` and `rename_all` are taken into account. `id_strategy` is one of
+//! `provided` (the default), `uuid_v4`, `uuid_v7`, `ulid`, `sequential` or
+//! `sequence`; the latter uses the Postgres sequence `{collection}_id_seq`
+//! unless `sequence = "..."` names another.
+//!
+//! An integer field marked `#[store(version)]` becomes the document version
+//! `Store::save` checks and bumps, see `Identity::version_key`.
+//!
+//! Each `#[store(index(field = "address.city", kind = "hash"))]` declares an
+//! index `Store::ensure_collection` creates. `field` is a dotted path without
+//! `*` segments and `kind` one of `ordered` (the default), `hash` or `gin`.
+//!
+//! `#[store(migrations = "contact_migrations")]` names a function returning
+//! the collection's `Migrations`, see `Collection::migrations`.
+//!
+//! `#[store(schema)]` checks the documents `Store` writes against the JSON
+//! Schema generated from the type, which then also derives
+//! `schemars::JsonSchema`; `#[store(schema = "contact_schema")]` names a
+//! function returning one instead, see `Collection::schema`.
+
+use proc_macro::TokenStream;
+use proc_macro2::Span;
+use quote::quote;
+use syn::{
+    parse_macro_input, spanned::Spanned, Attribute, Data, DeriveInput, Error, Fields, LitStr, Path,
+    Result,
+};
+
+#[proc_macro_derive(Collection, attributes(store))]
+pub fn derive_collection(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    expand_collection(&input)
+        .unwrap_or_else(Error::into_compile_error)
+        .into()
+}
+
+#[proc_macro_derive(Identity, attributes(store))]
+pub fn derive_identity(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    expand_identity(&input)
+        .unwrap_or_else(Error::into_compile_error)
+        .into()
+}
+
+/// The `#[store(...)]` attributes of the type being derived.
+#[derive(Default)]
+struct ContainerAttrs {
+    collection: Option<LitStr>,
+    krate: Option<Path>,
+    id_strategy: Option<LitStr>,
+    sequence: Option<LitStr>,
+    indexes: Vec<IndexAttr>,
+    migrations: Option<Path>,
+    schema: Option<SchemaAttr>,
+}
+
+/// Where the JSON Schema of `#[store(schema ...)]` comes from.
+enum SchemaAttr {
+    /// Generated from the type.
+    Generated,
+    /// Returned by the named function.
+    Function(Path),
+}
+
+/// A `#[store(index(...))]` attribute.
+struct IndexAttr {
+    field: LitStr,
+    kind: Option<LitStr>,
+}
+
+impl ContainerAttrs {
+    fn parse(attrs: &[Attribute]) -> Result<Self> {
+        let mut container = Self::default();
+        for attr in attrs.iter().filter(|attr| attr.path().is_ident("store")) {
+            attr.parse_nested_meta(|meta| {
+                if meta.path.is_ident("collection") {
+                    container.collection = Some(meta.value()?.parse()?);
+                    Ok(())
+                } else if meta.path.is_ident("crate") {
+                    let path: LitStr = meta.value()?.parse()?;
+                    container.krate = Some(path.parse()?);
+                    Ok(())
+                } else if meta.path.is_ident("id_strategy") {
+                    container.id_strategy = Some(meta.value()?.parse()?);
+                    Ok(())
+                } else if meta.path.is_ident("sequence") {
+                    container.sequence = Some(meta.value()?.parse()?);
+                    Ok(())
+                } else if meta.path.is_ident("index") {
+                    let (mut field, mut kind) = (None, None);
+                    meta.parse_nested_meta(|nested| {
+                        if nested.path.is_ident("field") {
+                            field = Some(nested.value()?.parse()?);
+                            Ok(())
+                        } else if nested.path.is_ident("kind") {
+                            kind = Some(nested.value()?.parse()?);
+                            Ok(())
+                        } else {
+                            Err(nested.error("expected `field` or `kind`"))
+                        }
+                    })?;
+                    let field = field.ok_or_else(|| meta.error("missing `field`"))?;
+                    container.indexes.push(IndexAttr { field, kind });
+                    Ok(())
+                } else if meta.path.is_ident("migrations") {
+                    let path: LitStr = meta.value()?.parse()?;
+                    container.migrations = Some(path.parse()?);
+                    Ok(())
+                } else if meta.path.is_ident("schema") {
+                    container.schema = Some(match meta.input.peek(syn::Token![=]) {
+                        true => {
+                            let path: LitStr = meta.value()?.parse()?;
+                            SchemaAttr::Function(path.parse()?)
+                        }
+                        false => SchemaAttr::Generated,
+                    });
+                    Ok(())
+                } else {
+                    Err(meta.error(
+                        "expected `collection`, `crate`, `id_strategy`, `sequence`, `index`, \
+                         `migrations` or `schema`",
+                    ))
+                }
+            })?;
+        }
+        Ok(container)
+    }
+
+    /// The path the generated code reaches the `store` crate through;
+    /// `#[store(crate = "crate")]` is used within the crate itself.
+    fn krate(&self) -> Path {
+        self.krate
+            .clone()
+            .unwrap_or_else(|| syn::parse_quote!(::store))
+    }
+}
+
+fn expand_collection(input: &DeriveInput) -> Result<proc_macro2::TokenStream> {
+    let attrs = ContainerAttrs::parse(&input.attrs)?;
+    let krate = attrs.krate();
+
+    let name = match &attrs.collection {
+        Some(collection) => {
+            if !is_identifier(&collection.value()) {
+                return Err(Error::new(
+                    collection.span(),
+                    "collection names may only contain ASCII letters, digits and underscores, \
+                     must not start with a digit and are at most 63 bytes",
+                ));
+            }
+            collection.value()
+        }
+        None => {
+            let name = to_snake_case(&input.ident.to_string());
+            if !is_identifier(&name) {
+                return Err(Error::new(
+                    input.ident.span(),
+                    format!(
+                        "the default collection name `{}` isn't a valid table name, \
+                         set one with `#[store(collection = \"...\")]`",
+                        name
+                    ),
+                ));
+            }
+            name
+        }
+    };
+
+    let id_strategy = match &attrs.id_strategy {
+        Some(strategy) => {
+            let variant = match strategy.value().as_str() {
+                "provided" => quote!(Provided),
+                "uuid_v4" => quote!(UuidV4),
+                "uuid_v7" => quote!(UuidV7),
+                "ulid" => quote!(Ulid),
+                "sequential" => quote!(Sequential),
+                "sequence" => {
+                    let sequence = match &attrs.sequence {
+                        Some(sequence) => sequence.value(),
+                        None => format!("{}_id_seq", name),
+                    };
+                    quote!(Sequence(#sequence.to_string()))
+                }
+                _ => {
+                    return Err(Error::new(
+                        strategy.span(),
+                        "expected one of `provided`, `uuid_v4`, `uuid_v7`, `ulid`, \
+                         `sequential` or `sequence`",
+                    ))
+                }
+            };
+            Some(quote! {
+                fn id_strategy() -> #krate::id::IdStrategy {
+                    #krate::id::IdStrategy::#variant
+                }
+            })
+        }
+        None => None,
+    };
+    if let (Some(sequence), false) = (
+        &attrs.sequence,
+        matches!(&attrs.id_strategy, Some(strategy) if strategy.value() == "sequence"),
+    ) {
+        return Err(Error::new(
+            sequence.span(),
+            "`sequence` requires `id_strategy = \"sequence\"`",
+        ));
+    }
+
+    let mut indexes = vec![];
+    for index in &attrs.indexes {
+        let field = index.field.value();
+        if field.is_empty() || field.split('.').any(|segment| segment == "*") {
+            return Err(Error::new(
+                index.field.span(),
+                "an index needs a field path without `*` segments",
+            ));
+        }
+        let kind = match index.kind.as_ref().map(LitStr::value).as_deref() {
+            None | Some("ordered") => quote!(ordered),
+            Some("hash") => quote!(hash),
+            Some("gin") => quote!(gin),
+            Some(_) => {
+                return Err(Error::new(
+                    index.kind.span(),
+                    "expected one of `ordered`, `hash` or `gin`",
+                ))
+            }
+        };
+        indexes.push(quote!(#krate::index::Index::#kind(#field)));
+    }
+    let indexes = (!indexes.is_empty()).then(|| {
+        quote! {
+            fn indexes() -> Vec<#krate::index::Index> {
+                vec![#(#indexes),*]
+            }
+        }
+    });
+
+    let migrations = attrs.migrations.as_ref().map(|path| {
+        quote! {
+            fn migrations() -> #krate::migration::Migrations {
+                #path()
+            }
+        }
+    });
+
+    let schema = attrs.schema.as_ref().map(|schema| {
+        let schema = match schema {
+            SchemaAttr::Generated => quote!(#krate::schema::generate::<Self>()),
+            SchemaAttr::Function(path) => quote!(#path()),
+        };
+        quote! {
+            fn schema() -> Option<#krate::__private::serde_json::Value> {
+                Some(#schema)
+            }
+        }
+    });
+
+    let ident = &input.ident;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+    Ok(quote! {
+        impl #impl_generics #krate::store::Collection for #ident #ty_generics #where_clause {
+            fn name() -> String {
+                #name.to_string()
+            }
+
+            #id_strategy
+
+            #indexes
+
+            #migrations
+
+            #schema
+        }
+    })
+}
+
+fn expand_identity(input: &DeriveInput) -> Result<proc_macro2::TokenStream> {
+    let attrs = ContainerAttrs::parse(&input.attrs)?;
+    let krate = attrs.krate();
+
+    let fields = match &input.data {
+        Data::Struct(data) => match &data.fields {
+            Fields::Named(fields) => &fields.named,
+            _ => {
+                return Err(Error::new(
+                    input.ident.span(),
+                    "Identity can only be derived for structs with named fields",
+                ))
+            }
+        },
+        _ => {
+            return Err(Error::new(
+                input.ident.span(),
+                "Identity can only be derived for structs",
+            ))
+        }
+    };
+
+    let mut marked = vec![];
+    let mut versions = vec![];
+    for field in fields {
+        let (mut is_id, mut is_version) = (false, false);
+        for attr in field
+            .attrs
+            .iter()
+            .filter(|attr| attr.path().is_ident("store"))
+        {
+            attr.parse_nested_meta(|meta| {
+                if meta.path.is_ident("id") {
+                    is_id = true;
+                    Ok(())
+                } else if meta.path.is_ident("version") {
+                    is_version = true;
+                    Ok(())
+                } else {
+                    Err(meta.error("expected `id` or `version`"))
+                }
+            })?;
+        }
+        if is_id && is_version {
+            return Err(Error::new(
+                field.span(),
+                "the identity field can't also be the version",
+            ));
+        }
+        if is_id {
+            marked.push(field);
+        }
+        if is_version {
+            versions.push(field);
+        }
+    }
+    let field = match marked.as_slice() {
+        [field] => *field,
+        [] => fields
+            .iter()
+            .find(|field| field.ident.as_ref().is_some_and(|ident| ident == "id"))
+            .ok_or_else(|| {
+                Error::new(
+                    input.ident.span(),
+                    "no `id` field: mark the identity field with `#[store(id)]`",
+                )
+            })?,
+        [_, second, ..] => {
+            return Err(Error::new(
+                second.span(),
+                "only one field can be marked `#[store(id)]`",
+            ))
+        }
+    };
+
+    let field_ident = field.ident.as_ref().unwrap();
+    let key = serialized_name(field, &input.attrs)?;
+    let version_key = match versions.as_slice() {
+        [] => None,
+        [version] => {
+            let version_key = serialized_name(version, &input.attrs)?;
+            Some(quote! {
+                fn version_key() -> Option<&'static str> {
+                    Some(#version_key)
+                }
+            })
+        }
+        [_, second, ..] => {
+            return Err(Error::new(
+                second.span(),
+                "only one field can be marked `#[store(version)]`",
+            ))
+        }
+    };
+
+    let ident = &input.ident;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+    let json = quote!(#krate::__private::serde_json);
+    Ok(quote! {
+        impl #impl_generics #krate::identity::Identity for #ident #ty_generics #where_clause {
+            fn identity_query(id: #json::Value) -> #krate::query::Query {
+                #krate::query::Query::builder().eq(#key, id).build()
+            }
+
+            fn key(&self) -> &str {
+                #key
+            }
+
+            fn identity_key() -> &'static str {
+                #key
+            }
+
+            fn id(&self) -> #krate::error::Result<#json::Value> {
+                Ok(#json::to_value(&self.#field_ident)?)
+            }
+
+            fn identity(&self) -> #krate::error::Result<#json::Value> {
+                let mut identity = #json::Map::new();
+                identity.insert(#key.to_string(), self.id()?);
+                Ok(#json::Value::Object(identity))
+            }
+
+            #version_key
+        }
+    })
+}
+
+/// The name serde serializes a field under, from its `rename` or the
+/// container's `rename_all`.
+fn serialized_name(field: &syn::Field, container: &[Attribute]) -> Result<String> {
+    if let Some(rename) = serde_rename(&field.attrs)? {
+        return Ok(rename);
+    }
+    let name = field.ident.as_ref().unwrap().to_string();
+    let name = name.strip_prefix("r#").unwrap_or(&name).to_string();
+    Ok(match serde_rename_all(container)? {
+        Some(rule) => rule.apply(&name),
+        None => name,
+    })
+}
+
+/// The serialized name from `#[serde(rename = "...")]` or
+/// `#[serde(rename(serialize = "..."))]`.
+fn serde_rename(attrs: &[Attribute]) -> Result<Option<String>> {
+    serde_name_value(attrs, "rename")
+}
+
+fn serde_rename_all(attrs: &[Attribute]) -> Result<Option<RenameRule>> {
+    match serde_name_value(attrs, "rename_all")? {
+        Some(rule) => RenameRule::from_str(&rule)
+            .map(Some)
+            .ok_or_else(|| Error::new(Span::call_site(), format!("unknown rename rule: {}", rule))),
+        None => Ok(None),
+    }
+}
+
+/// Looks up `name = "..."` or `name(serialize = "...")` among the serde
+/// attributes, ignoring everything else serde accepts.
+fn serde_name_value(attrs: &[Attribute], name: &str) -> Result<Option<String>> {
+    let mut value = None;
+    for attr in attrs.iter().filter(|attr| attr.path().is_ident("serde")) {
+        attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident(name) {
+                if meta.input.peek(syn::Token![=]) {
+                    value = Some(meta.value()?.parse::<LitStr>()?.value());
+                } else {
+                    meta.parse_nested_meta(|nested| {
+                        let lit: LitStr = nested.value()?.parse()?;
+                        if nested.path.is_ident("serialize") {
+                            value = Some(lit.value());
+                        }
+                        Ok(())
+                    })?;
+                }
+            } else if meta.input.peek(syn::Token![=]) {
+                meta.value()?.parse::<syn::Expr>()?;
+            } else if meta.input.peek(syn::token::Paren) {
+                meta.input.parse::<proc_macro2::Group>()?;
+            }
+            Ok(())
+        })?;
+    }
+    Ok(value)
+}
+
+/// The field renaming rules of `#[serde(rename_all = "...")]`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum RenameRule {
+    Lower,
+    Upper,
+    Pascal,
+    Camel,
+    Snake,
+    ScreamingSnake,
+    Kebab,
+    ScreamingKebab,
+}
+
+impl RenameRule {
+    fn from_str(rule: &str) -> Option<Self> {
+        Some(match rule {
+            "lowercase" => Self::Lower,
+            "UPPERCASE" => Self::Upper,
+            "PascalCase" => Self::Pascal,
+            "camelCase" => Self::Camel,
+            "snake_case" => Self::Snake,
+            "SCREAMING_SNAKE_CASE" => Self::ScreamingSnake,
+            "kebab-case" => Self::Kebab,
+            "SCREAMING-KEBAB-CASE" => Self::ScreamingKebab,
+            _ => return None,
+        })
+    }
+
+    /// Renames a snake_case field the way serde does.
+    fn apply(self, field: &str) -> String {
+        match self {
+            Self::Lower | Self::Snake => field.to_string(),
+            Self::Upper | Self::ScreamingSnake => field.to_ascii_uppercase(),
+            Self::Pascal => {
+                let mut pascal = String::new();
+                let mut capitalize = true;
+                for c in field.chars() {
+                    if c == '_' {
+                        capitalize = true;
+                    } else if capitalize {
+                        pascal.push(c.to_ascii_uppercase());
+                        capitalize = false;
+                    } else {
+                        pascal.push(c);
+                    }
+                }
+                pascal
+            }
+            Self::Camel => {
+                let pascal = Self::Pascal.apply(field);
+                pascal[..1].to_ascii_lowercase() + &pascal[1..]
+            }
+            Self::Kebab => field.replace('_', "-"),
+            Self::ScreamingKebab => field.replace('_', "-").to_ascii_uppercase(),
+        }
+    }
+}
+
+/// The default collection name of a type: `LineItem` becomes `line_item`.
+fn to_snake_case(name: &str) -> String {
+    let mut snake = String::new();
+    for (i, c) in name.char_indices() {
+        if c.is_uppercase() && i > 0 {
+            snake.push('_');
+        }
+        snake.push(c.to_ascii_lowercase());
+    }
+    snake
+}
+
+/// Mirrors the table name check of `sql::quote_identifier`, so a bad name fails
+/// at compile time instead of on the first query.
+fn is_identifier(name: &str) -> bool {
+    let mut chars = name.chars();
+    matches!(chars.next(), Some(c) if c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+        && name.len() <= 63
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rename_rules() {
+        let cases = [
+            ("lowercase", "user_id"),
+            ("UPPERCASE", "USER_ID"),
+            ("PascalCase", "UserId"),
+            ("camelCase", "userId"),
+            ("snake_case", "user_id"),
+            ("SCREAMING_SNAKE_CASE", "USER_ID"),
+            ("kebab-case", "user-id"),
+            ("SCREAMING-KEBAB-CASE", "USER-ID"),
+        ];
+        for (rule, expected) in cases {
+            assert_eq!(
+                RenameRule::from_str(rule).unwrap().apply("user_id"),
+                expected
+            );
+        }
+        assert_eq!(RenameRule::from_str("Title Case"), None);
+    }
+
+    #[test]
+    fn test_collection_names() {
+        assert_eq!(to_snake_case("User"), "user");
+        assert_eq!(to_snake_case("LineItem"), "line_item");
+        assert!(is_identifier("line_items"));
+        assert!(!is_identifier("line-items"));
+        assert!(!is_identifier("1users"));
+
+        let input: DeriveInput = syn::parse_quote!(
+            struct LineItem;
+        );
+        assert!(expand_collection(&input).is_ok());
+        let input: DeriveInput = syn::parse_quote!(
+            struct Café;
+        );
+        let err = expand_collection(&input).unwrap_err();
+        assert!(err.to_string().contains("`café`"), "{}", err);
+    }
+}